@@ -0,0 +1,31 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::process::Command;
+
+// Records the commit being built for `amvideo version`, when built from a git checkout
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=AMVIDEO_COMMIT={}", commit);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
@@ -0,0 +1,285 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Error};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use serde::{Deserialize, Serialize};
+use winapi::shared::minwindef::FARPROC;
+use winapi::shared::winerror::ERROR_INVALID_PARAMETER;
+use winapi::um::libloaderapi::{
+    LoadLibraryExW, LoadLibraryW, LOAD_LIBRARY_SEARCH_DEFAULT_DIRS,
+    LOAD_LIBRARY_SEARCH_DLL_LOAD_DIR,
+};
+
+use crate::error::{AmVideoCrateError, Result};
+use crate::library_handle::LibraryHandle;
+use crate::pe::{DataFile, Exports};
+use crate::registry;
+use crate::wide::to_wide;
+
+/// Export an AM library is bound by, looked up by its usual ordinal
+#[derive(Clone, Copy, Debug)]
+pub struct Export {
+    pub name: &'static str,
+    pub ordinal: u16,
+}
+
+/// Where to find an export in a DLL variant: by ordinal, or by name
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ExportRef {
+    Ordinal(u16),
+    Name(String),
+}
+
+/// Ordinals or names of the amVideo exports in builds that do not keep them at 1 through 4, such
+/// as some repacked and regional builds. Unset exports stay at the usual ordinal.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExportMap {
+    pub open: Option<ExportRef>,
+    pub close: Option<ExportRef>,
+    pub setres: Option<ExportRef>,
+    pub vbios: Option<ExportRef>,
+}
+
+impl ExportMap {
+    pub fn is_empty(&self) -> bool {
+        self.open.is_none() && self.close.is_none() && self.setres.is_none() && self.vbios.is_none()
+    }
+
+    /// Where to find each of `AM_VIDEO`'s exports, in its order
+    fn locations(&self) -> Vec<(&'static str, ExportRef)> {
+        let overrides = [&self.open, &self.close, &self.setres, &self.vbios];
+        AM_VIDEO
+            .exports
+            .iter()
+            .zip(overrides.iter().copied())
+            .map(|(export, location)| {
+                let location = location
+                    .clone()
+                    .unwrap_or(ExportRef::Ordinal(export.ordinal));
+                (export.name, location)
+            })
+            .collect()
+    }
+}
+
+/// AM platform library configured under `HKLM\System\Sega\SystemProperty`
+#[derive(Debug)]
+pub struct Library {
+    /// Name of the library's key, which holds the DLL name in its `name` value
+    pub key: &'static str,
+    /// Exports the wrapper binds, which may be empty for libraries only reported on
+    pub exports: &'static [Export],
+}
+
+pub const AM_VIDEO: Library = Library {
+    key: "amVideo",
+    exports: &[
+        Export {
+            name: "amDllVideoOpen",
+            ordinal: 1,
+        },
+        Export {
+            name: "amDllVideoClose",
+            ordinal: 2,
+        },
+        Export {
+            name: "amDllVideoSetResolution",
+            ordinal: 3,
+        },
+        Export {
+            name: "amDllVideoGetVBiosVersion",
+            ordinal: 4,
+        },
+    ],
+};
+
+/// OS and platform information library. Its calls are not bound until their signatures are
+/// known, so it is only loaded and reported on.
+pub const AM_OSINFO: Library = Library {
+    key: "amOsinfo",
+    exports: &[],
+};
+
+/// Monitor control library, reported on like [`AM_OSINFO`]
+pub const AM_MONITOR: Library = Library {
+    key: "amMonitor",
+    exports: &[],
+};
+
+/// Every AM library of the video and monitor stack
+pub const LIBRARIES: &[&Library] = &[&AM_VIDEO, &AM_OSINFO, &AM_MONITOR];
+
+impl Library {
+    /// Registry key path relative to `HKLM`
+    pub fn key_path(&self) -> String {
+        format!("{}\\{}", registry::SYSTEM_PROPERTY_KEY, self.key)
+    }
+
+    /// DLL name configured for this machine
+    pub fn dll_name(&self) -> Result<OsString> {
+        registry::library_dll_name(&self.key_path())
+    }
+}
+
+/// Load an AM library DLL. A DLL loaded by path has its own dependencies resolved from its
+/// directory and the safe default directories only.
+pub fn load<T: AsRef<OsStr>>(name: T) -> Result<LibraryHandle> {
+    let name = name.as_ref();
+    let wide = to_wide(name);
+    let absolute = Path::new(name).is_absolute();
+    let mut lib = ptr::null_mut();
+    if absolute {
+        lib = unsafe {
+            LoadLibraryExW(
+                wide.as_ptr(),
+                ptr::null_mut(),
+                LOAD_LIBRARY_SEARCH_DLL_LOAD_DIR | LOAD_LIBRARY_SEARCH_DEFAULT_DIRS,
+            )
+        };
+    }
+    // Windows 7 without KB2533623 does not know the search flags
+    if lib.is_null()
+        && (!absolute
+            || Error::last_os_error().raw_os_error() == Some(ERROR_INVALID_PARAMETER as i32))
+    {
+        lib = unsafe { LoadLibraryW(wide.as_ptr()) };
+    }
+    if lib.is_null() {
+        let source = Error::last_os_error();
+        let name = name.to_string_lossy();
+        let name = name.trim_end_matches('\0').to_string();
+        return Err(AmVideoCrateError::Load { name, source });
+    }
+
+    Ok(LibraryHandle::new(lib))
+}
+
+/// Look up every export by ordinal, failing with all of the missing ones at once
+///
+/// # Safety
+///
+/// The returned pointers must be transmuted to the exports' real signatures before they are
+/// called.
+pub unsafe fn bind(lib: &LibraryHandle, exports: &[Export]) -> Result<Vec<FARPROC>> {
+    let locations: Vec<_> = exports
+        .iter()
+        .map(|export| (export.name, ExportRef::Ordinal(export.ordinal)))
+        .collect();
+    bind_at(lib, &locations)
+}
+
+/// Look up the amVideo exports where `map` says they are
+///
+/// # Safety
+///
+/// See [`bind`].
+pub unsafe fn bind_mapped(lib: &LibraryHandle, map: &ExportMap) -> Result<Vec<FARPROC>> {
+    bind_at(lib, &map.locations())
+}
+
+unsafe fn bind_at(lib: &LibraryHandle, locations: &[(&str, ExportRef)]) -> Result<Vec<FARPROC>> {
+    let results: Vec<_> = locations
+        .iter()
+        .map(|(name, location)| match location {
+            ExportRef::Ordinal(ordinal) => lib.get_func_named_ordinal(name, *ordinal),
+            ExportRef::Name(export) => lib.get_func_named(export),
+        })
+        .collect();
+
+    let bad_funcs: Vec<_> = results
+        .iter()
+        .flat_map(|result| result.as_ref().err())
+        .map(|e| e.name().to_string())
+        .collect();
+    if !bad_funcs.is_empty() {
+        return Err(AmVideoCrateError::Resolve {
+            functions: bad_funcs,
+        });
+    }
+
+    // All lookups succeeded, checked above
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Loaded AM library that is not amVideo, for reporting what the platform has installed
+#[derive(Debug)]
+pub struct AmLibrary {
+    library: &'static Library,
+    lib: LibraryHandle,
+    functions: Vec<FARPROC>,
+}
+
+// The bound functions are plain code addresses inside the module the handle keeps loaded
+unsafe impl Send for AmLibrary {}
+unsafe impl Sync for AmLibrary {}
+
+impl AmLibrary {
+    /// Load the library's configured DLL and bind its exports
+    pub fn open(library: &'static Library) -> Result<Self> {
+        Self::open_named(library, library.dll_name()?)
+    }
+
+    /// Load the library from a DLL other than the configured one
+    pub fn open_named<T: AsRef<OsStr>>(library: &'static Library, name: T) -> Result<Self> {
+        let lib = load(name)?;
+        let functions = unsafe { bind(&lib, library.exports)? };
+
+        Ok(Self {
+            library,
+            lib,
+            functions,
+        })
+    }
+
+    pub const fn library(&self) -> &'static Library {
+        self.library
+    }
+
+    pub fn handle(&self) -> &LibraryHandle {
+        &self.lib
+    }
+
+    /// Address of each bound export
+    pub fn bound(&self) -> Vec<(&'static str, FARPROC)> {
+        self.library
+            .exports
+            .iter()
+            .map(|export| export.name)
+            .zip(self.functions.iter().copied())
+            .collect()
+    }
+
+    /// Path the DLL was loaded from
+    pub fn path(&self) -> io::Result<PathBuf> {
+        self.lib.path()
+    }
+
+    /// Every export in the DLL file, read from its export table, by ordinal and name
+    pub fn exports(&self) -> io::Result<Exports> {
+        let file = DataFile::read(self.path()?)?;
+        Ok(file.exports().unwrap_or_default())
+    }
+
+    /// Build banner embedded in the DLL, if it has one
+    pub fn banner(&self) -> Option<String> {
+        DataFile::read(self.path().ok()?).ok()?.banner()
+    }
+}
@@ -0,0 +1,142 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+
+use crate::ccd;
+use crate::display::{self, DisplayMode};
+use crate::error::Result;
+use crate::setting::AmVideoResolution;
+
+/// Aspect ratios closer than this count as the same, so 1366x768 and 1360x768 pass as 16:9
+const TOLERANCE: f64 = 0.01;
+
+/// How a mode of one aspect ratio fits onto a panel of another when scaled up as far as it goes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fit {
+    /// Size of the scaled picture on the panel
+    pub width: u32,
+    pub height: u32,
+    /// Black bar left and right of the picture, in panel pixels
+    pub pillarbox: u32,
+    /// Black bar above and below the picture, in panel pixels
+    pub letterbox: u32,
+}
+
+/// Aspect ratio as width over height, displayed as e.g. `16:9` or `1.60:1`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ratio(pub f64);
+
+impl Ratio {
+    pub fn of(width: u32, height: u32) -> Self {
+        Self(f64::from(width) / f64::from(height))
+    }
+
+    pub fn matches(self, other: Self) -> bool {
+        (self.0 - other.0).abs() / other.0 < TOLERANCE
+    }
+}
+
+impl From<&AmVideoResolution> for Ratio {
+    fn from(resolution: &AmVideoResolution) -> Self {
+        Self::of(u32::from(resolution.width), u32::from(resolution.height))
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMED: [(u32, u32); 6] = [(4, 3), (5, 4), (16, 9), (16, 10), (21, 9), (32, 9)];
+
+        match NAMED
+            .iter()
+            .find(|(width, height)| self.matches(Self::of(*width, *height)))
+        {
+            Some((width, height)) => write!(f, "{}:{}", width, height),
+            None => write!(f, "{:.2}:1", self.0),
+        }
+    }
+}
+
+impl Fit {
+    /// Scale a `mode` picture up to fill `panel` along one axis, keeping its aspect ratio
+    pub fn of(mode: &AmVideoResolution, panel: &AmVideoResolution) -> Self {
+        let (width, height) = (u64::from(mode.width), u64::from(mode.height));
+        let (panel_width, panel_height) = (u64::from(panel.width), u64::from(panel.height));
+
+        // Whichever axis runs out of panel first limits the scale
+        let (scaled_width, scaled_height) = if width * panel_height > height * panel_width {
+            (panel_width, height * panel_width / width)
+        } else {
+            (width * panel_height / height, panel_height)
+        };
+
+        Self {
+            width: scaled_width as u32,
+            height: scaled_height as u32,
+            pillarbox: ((panel_width - scaled_width) / 2) as u32,
+            letterbox: ((panel_height - scaled_height) / 2) as u32,
+        }
+    }
+}
+
+/// Native resolution of the monitor behind `device`, or behind the primary display if `None`
+pub fn native_resolution(device: Option<&str>) -> Result<AmVideoResolution> {
+    let device = match device {
+        Some(device) => device.to_string(),
+        None => display::attached_displays()
+            .into_iter()
+            .find(|display| display.primary)
+            .map(|display| display.name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No primary display"))?,
+    };
+
+    let (paths, _) = ccd::active_paths()?;
+    for path in &paths {
+        if ccd::source_name(path)?.eq_ignore_ascii_case(&device) {
+            let (width, height) = ccd::preferred_mode(path)?;
+            return Ok(AmVideoResolution::new(width as u16, height as u16));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} is not an active display", device),
+    )
+    .into())
+}
+
+/// The mode in `modes` with the aspect ratio of `panel` whose area is closest to `requested`
+pub fn nearest_same_aspect(
+    requested: &AmVideoResolution,
+    panel: &AmVideoResolution,
+    modes: &[DisplayMode],
+) -> Option<AmVideoResolution> {
+    let ratio = Ratio::from(panel);
+    let area = i64::from(requested.width) * i64::from(requested.height);
+
+    modes
+        .iter()
+        .filter(|mode| Ratio::of(mode.width, mode.height).matches(ratio))
+        .filter_map(|mode| {
+            Some(AmVideoResolution::new(
+                mode.width.try_into().ok()?,
+                mode.height.try_into().ok()?,
+            ))
+        })
+        .min_by_key(|mode| (i64::from(mode.width) * i64::from(mode.height) - area).abs())
+}
@@ -0,0 +1,185 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::ptr;
+use std::slice;
+
+use winapi::shared::guiddef::{GUID, REFCLSID, REFIID};
+use winapi::shared::minwindef::LPVOID;
+use winapi::shared::ntdef::{LPCWSTR, LPWSTR};
+use winapi::shared::winerror::HRESULT;
+use winapi::shared::wtypesbase::CLSCTX_INPROC_SERVER;
+use winapi::um::combaseapi::{CoCreateInstance, CoTaskMemFree, PropVariantClear};
+use winapi::um::coml2api::STGM_READ;
+use winapi::um::functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName;
+use winapi::um::mmdeviceapi::{
+    eCommunications, eConsole, eMultimedia, eRender, ERole, IMMDevice, IMMDeviceCollection,
+    IMMDeviceEnumerator, MMDeviceEnumerator, DEVICE_STATE_ACTIVE,
+};
+use winapi::um::propidl::PROPVARIANT;
+use winapi::um::propsys::IPropertyStore;
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+use winapi::{Class, Interface};
+
+use crate::com::{self, check, ComPtr};
+use crate::wide::to_wide;
+
+/// `PolicyConfigClient`, the undocumented coclass the Sound control panel sets the default
+/// endpoint through
+const CLSID_POLICY_CONFIG_CLIENT: GUID = GUID {
+    Data1: 0x870a_f99c,
+    Data2: 0x171d,
+    Data3: 0x4f9e,
+    Data4: [0xaf, 0x0d, 0xe6, 0x3d, 0xf4, 0x0c, 0x2b, 0xc9],
+};
+/// `IPolicyConfig` as of Windows 7
+const IID_POLICY_CONFIG: GUID = GUID {
+    Data1: 0xf867_9f50,
+    Data2: 0x850a,
+    Data3: 0x41cf,
+    Data4: [0x9c, 0x72, 0x43, 0x0f, 0x29, 0x02, 0x90, 0xc8],
+};
+
+/// `IPolicyConfig` vtable up to `SetDefaultEndpoint`, the only method called
+#[repr(C)]
+struct PolicyConfigVtbl {
+    parent: IUnknownVtbl,
+    /// `GetMixFormat` through `SetPropertyValue`
+    _unused: [usize; 10],
+    set_default_endpoint:
+        unsafe extern "system" fn(this: *mut IUnknown, id: LPCWSTR, role: ERole) -> HRESULT,
+}
+
+/// Audio output device
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    /// Endpoint ID, stable across reboots
+    pub id: String,
+    /// Name shown in the Sound control panel, e.g. `DELL U2415 (NVIDIA High Definition Audio)`
+    /// for a monitor's HDMI or DisplayPort audio
+    pub name: String,
+}
+
+/// Every active audio output
+pub fn endpoints() -> io::Result<Vec<Endpoint>> {
+    let enumerator = enumerator()?;
+    unsafe {
+        let mut collection: *mut IMMDeviceCollection = ptr::null_mut();
+        check(enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE, &mut collection))?;
+        let collection = ComPtr::from_raw(collection);
+
+        // winapi declares the out parameter `*const`
+        let count = 0;
+        check(collection.GetCount(&count))?;
+        (0..count)
+            .map(|index| {
+                let mut device: *mut IMMDevice = ptr::null_mut();
+                check(collection.Item(index, &mut device))?;
+                endpoint(&ComPtr::from_raw(device))
+            })
+            .collect()
+    }
+}
+
+/// The default audio output for games and media, if there is one
+pub fn default_endpoint() -> io::Result<Option<Endpoint>> {
+    let enumerator = enumerator()?;
+    unsafe {
+        let mut device: *mut IMMDevice = ptr::null_mut();
+        if check(enumerator.GetDefaultAudioEndpoint(eRender, eConsole, &mut device)).is_err() {
+            return Ok(None);
+        }
+        endpoint(&ComPtr::from_raw(device)).map(Some)
+    }
+}
+
+/// Make the endpoint `id` the default audio output for every role
+pub fn set_default(id: &str) -> io::Result<()> {
+    com::init_mta()?;
+    let id = to_wide(id);
+    unsafe {
+        let mut config: *mut IUnknown = ptr::null_mut();
+        check(CoCreateInstance(
+            &CLSID_POLICY_CONFIG_CLIENT as REFCLSID,
+            ptr::null_mut(),
+            CLSCTX_INPROC_SERVER,
+            &IID_POLICY_CONFIG as REFIID,
+            &mut config as *mut _ as *mut LPVOID,
+        ))?;
+        let config = ComPtr::from_raw(config);
+
+        let vtbl = *(config.as_raw() as *const *const PolicyConfigVtbl);
+        for role in [eConsole, eMultimedia, eCommunications] {
+            check(((*vtbl).set_default_endpoint)(
+                config.as_raw(),
+                id.as_ptr(),
+                role,
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn enumerator() -> io::Result<ComPtr<IMMDeviceEnumerator>> {
+    com::init_mta()?;
+    unsafe {
+        let mut enumerator: *mut IMMDeviceEnumerator = ptr::null_mut();
+        check(CoCreateInstance(
+            &MMDeviceEnumerator::uuidof(),
+            ptr::null_mut(),
+            CLSCTX_INPROC_SERVER,
+            &IMMDeviceEnumerator::uuidof(),
+            &mut enumerator as *mut _ as *mut LPVOID,
+        ))?;
+        Ok(ComPtr::from_raw(enumerator))
+    }
+}
+
+/// # Safety
+///
+/// `device` must be a live endpoint.
+unsafe fn endpoint(device: &ComPtr<IMMDevice>) -> io::Result<Endpoint> {
+    let mut id: LPWSTR = ptr::null_mut();
+    check(device.GetId(&mut id))?;
+    let owned_id = take_string(id);
+
+    let mut store: *mut IPropertyStore = ptr::null_mut();
+    check(device.OpenPropertyStore(STGM_READ, &mut store))?;
+    let store = ComPtr::from_raw(store);
+    let mut value: PROPVARIANT = std::mem::zeroed();
+    check(store.GetValue(&PKEY_Device_FriendlyName, &mut value))?;
+    let name = wide_string(*value.data.pwszVal());
+    PropVariantClear(&mut value);
+
+    Ok(Endpoint { id: owned_id, name })
+}
+
+/// Copy a string COM allocated and free it
+unsafe fn take_string(s: LPWSTR) -> String {
+    let string = wide_string(s);
+    CoTaskMemFree(s as LPVOID);
+    string
+}
+
+unsafe fn wide_string(s: LPCWSTR) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    let len = (0..).take_while(|&i| *s.add(i) != 0).count();
+    String::from_utf16_lossy(slice::from_raw_parts(s, len))
+}
@@ -0,0 +1,153 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+
+use amvideo::audio::{self, Endpoint};
+use amvideo::topology::Topology;
+
+use crate::config::{AudioConfig, Profile};
+use crate::state;
+
+/// Make the audio endpoint of the profile's display the default output, remembering the one it
+/// replaces unless the profile keeps the switch
+pub fn switch(profile: &Profile) -> Result<()> {
+    let config = match &profile.audio {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let names = match &config.endpoint {
+        Some(endpoint) => vec![endpoint.clone()],
+        None => monitor_names(config)?,
+    };
+
+    let endpoints = audio::endpoints().context("Failed to list audio endpoints")?;
+    let endpoint = find(&endpoints, &names)?;
+    let current = audio::default_endpoint().context("Failed to read the default audio endpoint")?;
+    if current.as_ref().map(|current| &current.id) == Some(&endpoint.id) {
+        println!("Audio already plays through {}", endpoint.name);
+        return Ok(());
+    }
+
+    if let (Some(current), false) = (&current, config.keep) {
+        state::note_audio_endpoint(&current.id)
+            .context("Failed to record the audio endpoint to restore")?;
+    }
+    audio::set_default(&endpoint.id).with_context(|| {
+        format!(
+            "Failed to make {} the default audio endpoint",
+            endpoint.name
+        )
+    })?;
+    println!("Switched audio to {}", endpoint.name);
+
+    Ok(())
+}
+
+/// Switch back to the audio endpoint that was the default before the first apply that changed
+/// it. A failure only leaves the sound on the display, so it is reported.
+pub fn restore() {
+    let id = match state::take_audio_endpoint() {
+        Ok(Some(id)) => id,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Failed to read the audio endpoint to restore: {:#}", e);
+            return;
+        }
+    };
+
+    let name = audio::endpoints()
+        .ok()
+        .and_then(|endpoints| endpoints.into_iter().find(|endpoint| endpoint.id == id))
+        .map_or_else(|| id.clone(), |endpoint| endpoint.name);
+    match audio::set_default(&id) {
+        Ok(()) => println!("Switched audio back to {}", name),
+        Err(e) => eprintln!("Failed to switch audio back to {}: {}", name, e),
+    }
+}
+
+/// Names of the monitors on the configured display, which their HDMI or DisplayPort audio
+/// endpoints are named after
+fn monitor_names(config: &AudioConfig) -> Result<Vec<String>> {
+    let topology = Topology::query().context("Failed to query display topology")?;
+    let route = topology
+        .displays
+        .into_iter()
+        .find(|route| match &config.display {
+            Some(selector) => {
+                route.device.eq_ignore_ascii_case(selector)
+                    || route
+                        .targets
+                        .iter()
+                        .any(|target| target.monitor_name.eq_ignore_ascii_case(selector))
+            }
+            None => route.primary,
+        })
+        .ok_or_else(|| match &config.display {
+            Some(selector) => anyhow!("No display matches '{}'", selector),
+            None => anyhow!("No primary display to take the audio endpoint of"),
+        })?;
+
+    let names: Vec<String> = route
+        .targets
+        .into_iter()
+        .map(|target| target.monitor_name)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return Err(anyhow!(
+            "{} reports no monitor name to find its audio endpoint by, set 'endpoint' instead",
+            route.device
+        ));
+    }
+    Ok(names)
+}
+
+/// The one endpoint whose name contains one of `names`
+fn find<'a>(endpoints: &'a [Endpoint], names: &[String]) -> Result<&'a Endpoint> {
+    let found: Vec<&Endpoint> = endpoints
+        .iter()
+        .filter(|endpoint| {
+            let endpoint = endpoint.name.to_lowercase();
+            names
+                .iter()
+                .any(|name| endpoint.contains(&name.to_lowercase()))
+        })
+        .collect();
+
+    match found.as_slice() {
+        [endpoint] => Ok(endpoint),
+        [] => Err(anyhow!(
+            "No audio endpoint matches {}, the active ones are: {}",
+            names.join(" or "),
+            endpoints
+                .iter()
+                .map(|endpoint| endpoint.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        _ => Err(anyhow!(
+            "{} audio endpoints match {}, set 'endpoint' to one of: {}",
+            found.len(),
+            names.join(" or "),
+            found
+                .iter()
+                .map(|endpoint| endpoint.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
@@ -0,0 +1,197 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use amvideo::display::DisplayMode;
+use amvideo::snapshot::Snapshot;
+use amvideo::{AmVideoCrateError, AmVideoResolution, AmVideoSetting};
+
+static AUDIT_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    SessionStart {
+        version: &'static str,
+        args: Vec<String>,
+    },
+    DllResolved {
+        name: &'a str,
+        base: usize,
+        exports: Vec<Export>,
+    },
+    Open {
+        #[serde(flatten)]
+        outcome: Outcome,
+        duration_us: u64,
+    },
+    VbiosVersion {
+        #[serde(flatten)]
+        outcome: Outcome,
+        version: Option<&'a str>,
+        duration_us: u64,
+    },
+    SetResolution {
+        setting: &'a AmVideoSetting,
+        #[serde(flatten)]
+        outcome: Outcome,
+        duration_us: u64,
+    },
+    Verify {
+        expected: &'a AmVideoResolution,
+        actual: Option<DisplayMode>,
+        ok: bool,
+    },
+    Close {
+        #[serde(flatten)]
+        outcome: Outcome,
+        duration_us: u64,
+    },
+    Restore {
+        snapshot: &'a Snapshot,
+        ok: bool,
+    },
+    Bypass {
+        check: &'static str,
+        reason: String,
+    },
+    Skip {
+        operation: &'static str,
+    },
+    StepFailed {
+        step: &'static str,
+        reason: String,
+    },
+    Timeout {
+        stage: &'static str,
+        operation: &'static str,
+        limit_ms: u64,
+    },
+    ChildExit {
+        command: &'a str,
+        code: Option<i32>,
+        crashed: bool,
+    },
+    SessionEnd {
+        error: Option<String>,
+    },
+}
+
+/// Outcome of a DLL call: `code` is its return code, 0 on success, and `null` with only `error`
+/// for a failure that did not come from the DLL, such as a timeout or a crashed broker
+#[derive(Serialize)]
+pub struct Outcome {
+    pub code: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Export {
+    pub name: String,
+    pub address: usize,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp_ms: u128,
+    pid: u32,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Start appending audit records to `path`
+pub fn init(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log '{}'", path.display()))?;
+
+    AUDIT_LOG
+        .set(Mutex::new(file))
+        .map_err(|_| anyhow!("Audit log already initialized"))
+}
+
+/// Append `event` to the audit log, if one is configured. Failing to write the audit log never
+/// fails the operation being audited.
+pub fn record(event: Event) {
+    let log = match AUDIT_LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let record = Record {
+        timestamp_ms,
+        pid: process::id(),
+        event: &event,
+    };
+
+    let result = serde_json::to_vec(&record)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+
+            // Write each record in one call so a crash never leaves a partial line behind
+            let mut file = log.lock().unwrap_or_else(|e| e.into_inner());
+            file.write_all(&line)?;
+            Ok(())
+        });
+    if let Err(e) = result {
+        eprintln!("Failed to write audit log: {:#}", e);
+    }
+}
+
+/// Outcome to record for a DLL call that failed with `error`, or succeeded if `None`
+pub fn outcome(error: Option<&AmVideoCrateError>) -> Outcome {
+    match error {
+        Some(error) => Outcome {
+            code: error.code(),
+            error: Some(error.to_string()),
+        },
+        None => Outcome {
+            code: Some(0),
+            error: None,
+        },
+    }
+}
+
+/// Record the outcome of a `display::verify` call
+pub fn record_verify(expected: &AmVideoResolution, result: &amvideo::Result<DisplayMode>) {
+    record(Event::Verify {
+        expected,
+        actual: match result {
+            Ok(mode) => Some(*mode),
+            Err(AmVideoCrateError::Verify { actual, .. })
+            | Err(AmVideoCrateError::VerifyRefresh { actual, .. }) => Some(*actual),
+            Err(_) => None,
+        },
+        ok: result.is_ok(),
+    });
+}
@@ -0,0 +1,146 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error, Result};
+use clap::Args;
+
+use amvideo::display;
+
+use crate::audit;
+use crate::config::Config;
+use crate::headless;
+use crate::png;
+use crate::state;
+use crate::timeout::{self, Stage};
+use crate::timing;
+use crate::trace;
+use crate::{apply_profile, GlobalOpts};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Time to wait for a display and a working driver when neither `--timeout` nor the config's
+/// `ready` timeout says otherwise, longer than elsewhere as drivers load last at boot
+const READY_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Args)]
+pub struct BootOpts {
+    /// Profile to apply
+    #[arg(short, long)]
+    profile: String,
+
+    /// Time to let the display settle after applying before verifying, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    settle_ms: u64,
+}
+
+/// Stage a boot apply failed at, reported to the task scheduler as the exit code
+#[derive(Clone, Copy, Debug)]
+pub enum Failed {
+    DisplayNotReady = 2,
+    Apply = 3,
+    Verify = 4,
+}
+
+impl Failed {
+    pub const fn exit_code(self) -> i32 {
+        self as i32
+    }
+
+    pub const fn class(self) -> &'static str {
+        match self {
+            Self::DisplayNotReady => "boot-display-not-ready",
+            Self::Apply => "boot-apply",
+            Self::Verify => "boot-verify",
+        }
+    }
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Self::DisplayNotReady => "No display became ready",
+            Self::Apply => "Failed to apply the profile",
+            Self::Verify => "The display did not switch to the profile's resolution",
+        };
+        f.write_str(message)
+    }
+}
+
+/// `amvideo boot`: wait for the display stack to come up, apply a profile, and verify it, for a
+/// scheduled task or service started at boot
+pub fn run(global: &GlobalOpts, opts: &BootOpts) -> Result<()> {
+    let config = Config::load(global.config.as_deref())?;
+    let profile = &config.profile(&opts.profile)?;
+    let timeout = timeout::limit_or(Stage::Ready, READY_TIMEOUT).unwrap_or(Duration::MAX);
+    let deadline = Instant::now().checked_add(timeout);
+
+    if headless::enabled().is_none() {
+        let display = display::wait_for_display(None, timeout).context(Failed::DisplayNotReady)?;
+        println!("Found {} ({})", display.name, display.description);
+    }
+
+    // The GPU driver often finishes loading after the first display shows up, which the DLL
+    // reports as a failed open, so keep trying until the deadline
+    let mut duration;
+    loop {
+        let (result, took) = timing::time(|| apply_profile(profile));
+        duration = took;
+        if result.is_err() {
+            state::record(Some(&opts.profile), &result, took);
+        }
+        match result {
+            Ok(()) => break,
+            Err(e)
+                if deadline.is_none_or(|deadline| Instant::now() + RETRY_INTERVAL < deadline) =>
+            {
+                eprintln!("Apply failed, retrying in {:?}: {:#}", RETRY_INTERVAL, e);
+                thread::sleep(RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e.context(Failed::Apply)),
+        }
+    }
+
+    // Nothing changed to verify
+    if trace::enabled() {
+        return Ok(());
+    }
+    if headless::enabled().is_some() {
+        println!("Applied '{}' at boot: {}", opts.profile, headless::STATUS);
+        state::record(Some(&opts.profile), &Ok::<_, Error>(()), duration);
+        return Ok(());
+    }
+
+    thread::sleep(Duration::from_millis(opts.settle_ms));
+    let resolution = profile.fitted_resolution();
+    let result = timeout::poll(timeout::limit(Stage::Verify), || {
+        display::verify_mode(&resolution, profile.refresh_rate)
+    });
+    audit::record_verify(&resolution, &result);
+    state::record(Some(&opts.profile), &result, duration);
+    let mode = result.context(Failed::Verify)?;
+    println!("Applied '{}' at boot: {}", opts.profile, mode);
+    // The apply worked, a missing screenshot only means less to look at remotely
+    if let Some(path) = &global.screenshot {
+        if let Err(e) = png::save_screenshot(path) {
+            eprintln!("{:#}", e);
+        }
+    }
+
+    Ok(())
+}
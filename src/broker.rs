@@ -0,0 +1,503 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+use winapi::um::winnt::PROCESS_TERMINATE;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use amvideo::amlib::ExportMap;
+use amvideo::{
+    AmVideo, AmVideoCrateError, AmVideoSetting, AmVideoSettingExtras, Closed, Open, VbiosVersion,
+};
+
+use crate::context_diff;
+use crate::context_file;
+use crate::export_map;
+use crate::protocol;
+use crate::timeout::{self, Stage, TimedOut};
+use crate::warn_hooks;
+
+static ISOLATE: AtomicBool = AtomicBool::new(false);
+
+/// Make DLL calls in a broker process from now on, as requested with `--isolate`
+pub fn enable() {
+    ISOLATE.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ISOLATE.load(Ordering::Relaxed)
+}
+
+/// One DLL call per line, answered by one `Reply` line
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "call", rename_all = "kebab-case")]
+pub enum Call {
+    /// First call on every broker, offering the highest protocol version the parent speaks
+    Hello {
+        version: u32,
+    },
+    Load {
+        dll: String,
+        context_version: Option<u32>,
+        #[serde(default, skip_serializing_if = "ExportMap::is_empty")]
+        exports: ExportMap,
+    },
+    Open,
+    VbiosVersion {
+        buffer: u32,
+    },
+    SetResolution {
+        setting: AmVideoSetting,
+        #[serde(default)]
+        extras: AmVideoSettingExtras,
+    },
+    Close,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Status returned by the DLL, for failed calls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loaded: Option<Loaded>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vbios: Option<VbiosVersion>,
+    /// Protocol version agreed on, in reply to `hello`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+}
+
+/// Where the DLL and its exports ended up in the broker
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Loaded {
+    pub base: usize,
+    pub exports: Vec<(String, usize)>,
+    /// Context version `open` starts from
+    pub context_version: u32,
+}
+
+/// The DLL crashed the broker partway through a call
+#[derive(Debug)]
+pub struct Crashed {
+    call: &'static str,
+    /// Process exit code, which is the exception code for an unhandled exception
+    status: Option<u32>,
+}
+
+impl fmt::Display for Crashed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(code) if code >= 0xC000_0000 => write!(
+                f,
+                "DLL crashed during {} (exception {:#010X})",
+                self.call, code
+            ),
+            Some(code) => write!(f, "Broker exited during {} with status {}", self.call, code),
+            None => write!(f, "Broker exited during {}", self.call),
+        }
+    }
+}
+
+impl Crashed {
+    pub const fn status(&self) -> Option<u32> {
+        self.status
+    }
+}
+
+impl Error for Crashed {}
+
+/// DLL calls made while applying a setting, either in this process or in a broker. Each call is
+/// held to the `dll` timeout: a hung broker is killed, a hung call in this process ends it.
+pub trait Session {
+    /// Open the context, returning the context version the DLL accepted
+    fn open(&mut self) -> Result<u32>;
+    fn vbios_version(&mut self, buffer: u32) -> Result<VbiosVersion>;
+    fn set_resolution(
+        &mut self,
+        setting: &AmVideoSetting,
+        extras: &AmVideoSettingExtras,
+    ) -> Result<()>;
+    fn close(&mut self) -> Result<()>;
+}
+
+/// DLL loaded into this process
+pub enum Local {
+    Closed(AmVideo<Closed>),
+    Open(AmVideo<Open>),
+    /// Between states, or after a call consumed the handle
+    Gone,
+}
+
+impl Local {
+    fn take(&mut self) -> Self {
+        mem::replace(self, Self::Gone)
+    }
+}
+
+impl Session for Local {
+    fn open(&mut self) -> Result<u32> {
+        let amvideo = match self.take() {
+            Self::Closed(amvideo) => amvideo,
+            other => {
+                *self = other;
+                return Err(anyhow!("amVideo is not closed"));
+            }
+        };
+
+        let saved = match context_file::saved() {
+            Ok(saved) => saved,
+            Err(e) => {
+                *self = Self::Closed(amvideo);
+                return Err(e);
+            }
+        };
+        if let Some(saved) = saved {
+            return match amvideo.resume(&saved) {
+                Ok(amvideo) => {
+                    let version = amvideo.context_version();
+                    println!("Resumed a saved context instead of opening");
+                    context_diff::stage("resume", &amvideo.context_bytes());
+                    *self = Self::Open(amvideo);
+                    Ok(version)
+                }
+                Err(e) => {
+                    let error = anyhow!("{}", e);
+                    *self = Self::Closed(e.into_closed());
+                    Err(error.context("Failed to resume the saved context"))
+                }
+            };
+        }
+
+        match timeout::watch(Stage::Dll, "amDllVideoOpen", || amvideo.open()) {
+            Ok(amvideo) => {
+                let version = amvideo.context_version();
+                context_diff::stage("open", &amvideo.context_bytes());
+                *self = Self::Open(amvideo);
+                Ok(version)
+            }
+            Err(e) => {
+                let (amvideo, source) = e.into_parts();
+                *self = Self::Closed(amvideo);
+                Err(anyhow::Error::from(source).context("Failed to open amVideo"))
+            }
+        }
+    }
+
+    fn vbios_version(&mut self, buffer: u32) -> Result<VbiosVersion> {
+        match self {
+            Self::Open(amvideo) => {
+                let result = timeout::watch(Stage::Dll, "amDllVideoGetVBiosVersion", || {
+                    amvideo.vbios_version(buffer)
+                });
+                context_diff::stage("vbios", &amvideo.context_bytes());
+                Ok(result?)
+            }
+            _ => Err(anyhow!("amVideo is not open")),
+        }
+    }
+
+    fn set_resolution(
+        &mut self,
+        setting: &AmVideoSetting,
+        extras: &AmVideoSettingExtras,
+    ) -> Result<()> {
+        match self {
+            Self::Open(amvideo) => {
+                let result = timeout::watch(Stage::Dll, "amDllVideoSetResolution", || {
+                    amvideo.set_resolution(setting, extras)
+                });
+                context_diff::stage("set_resolution", &amvideo.context_bytes());
+                Ok(result?)
+            }
+            _ => Err(anyhow!("amVideo is not open")),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let amvideo = match self.take() {
+            Self::Open(amvideo) => amvideo,
+            other => {
+                *self = other;
+                return Err(anyhow!("amVideo is not open"));
+            }
+        };
+
+        // Saved while still open, so a later run can resume the context as the DLL left it
+        if let Err(e) = context_file::save(&amvideo.context_bytes()) {
+            eprintln!("{:#}", e);
+        }
+
+        match timeout::watch(Stage::Dll, "amDllVideoClose", || amvideo.close()) {
+            Ok(amvideo) => {
+                context_diff::stage("close", &amvideo.context_bytes());
+                *self = Self::Closed(amvideo);
+                Ok(())
+            }
+            Err(e) => {
+                let (amvideo, source) = e.into_parts();
+                *self = Self::Closed(amvideo);
+                Err(anyhow::Error::from(source).context("Failed to close amVideo"))
+            }
+        }
+    }
+}
+
+/// Child process the DLL is loaded into, so a crash in it only takes the broker down
+pub struct Broker {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Broker {
+    /// Start a broker and load `dll` into it
+    pub fn spawn(dll: &OsString, context_version: Option<u32>) -> Result<(Self, Loaded)> {
+        let mut child = Command::new(env::current_exe()?)
+            .arg("broker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to start the amVideo broker")?;
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut broker = Self {
+            child,
+            stdin,
+            stdout,
+        };
+
+        // The broker is normally this same executable, but an upgrade can swap it out from under a
+        // running parent
+        let version = broker
+            .call(
+                "hello",
+                &Call::Hello {
+                    version: protocol::VERSION,
+                },
+            )?
+            .version
+            .unwrap_or(1);
+        if version < 2 {
+            return Err(anyhow!(
+                "Broker speaks protocol version {}, 2 or later is required",
+                version
+            ));
+        }
+
+        let reply = broker.call(
+            "load",
+            &Call::Load {
+                dll: dll.to_string_lossy().into_owned(),
+                context_version,
+                exports: export_map::get(),
+            },
+        )?;
+        let loaded = reply
+            .loaded
+            .ok_or_else(|| anyhow!("Broker did not report the loaded DLL"))?;
+
+        Ok((broker, loaded))
+    }
+
+    fn call(&mut self, name: &'static str, call: &Call) -> Result<Reply> {
+        let mut line = serde_json::to_string(call)?;
+        line.push('\n');
+
+        // A hung call cannot be cancelled, so the broker it hangs in is killed instead, which
+        // ends the read below like a crash would
+        let hung = Arc::new(Mutex::new(None));
+        let pid = self.child.id();
+        let on_timeout = {
+            let hung = Arc::clone(&hung);
+            move |timed_out: TimedOut| {
+                *hung.lock().unwrap_or_else(|e| e.into_inner()) = Some(timed_out);
+                kill(pid);
+            }
+        };
+        let mut reply = String::new();
+        let received = timeout::watch_or(Stage::Dll, name, on_timeout, || {
+            match &mut self.stdin {
+                Some(stdin) => stdin.write_all(line.as_bytes()).and_then(|_| stdin.flush()),
+                None => Err(io::ErrorKind::BrokenPipe.into()),
+            }
+            .and_then(|_| self.stdout.read_line(&mut reply))
+        });
+        if !matches!(received, Ok(n) if n > 0) {
+            let status = self.child.wait().ok().and_then(|status| status.code());
+            let crashed = anyhow::Error::from(Crashed {
+                call: name,
+                status: status.map(|code| code as u32),
+            });
+            return Err(
+                match hung.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    Some(timed_out) => crashed.context(timed_out),
+                    None => crashed,
+                },
+            );
+        }
+
+        let reply: Reply = serde_json::from_str(&reply).context("Invalid reply from the broker")?;
+        if reply.ok {
+            return Ok(reply);
+        }
+
+        let error = anyhow!(reply.error.unwrap_or_else(|| "unknown error".to_string()));
+        Err(match reply.code {
+            Some(code) => anyhow::Error::from(AmVideoCrateError::DllCall { code }).context(error),
+            None => error,
+        })
+    }
+}
+
+/// End the broker process `pid`, with the status amvideo exits with on a timeout
+fn kill(pid: u32) {
+    unsafe {
+        let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if process.is_null() {
+            return;
+        }
+        TerminateProcess(process, timeout::EXIT_CODE as u32);
+        CloseHandle(process);
+    }
+}
+
+impl Session for Broker {
+    fn open(&mut self) -> Result<u32> {
+        self.call("open", &Call::Open)?
+            .context_version
+            .ok_or_else(|| anyhow!("Broker did not report the context version"))
+    }
+
+    fn vbios_version(&mut self, buffer: u32) -> Result<VbiosVersion> {
+        self.call("VBIOS version", &Call::VbiosVersion { buffer })?
+            .vbios
+            .ok_or_else(|| anyhow!("Broker did not report the VBIOS version"))
+    }
+
+    fn set_resolution(
+        &mut self,
+        setting: &AmVideoSetting,
+        extras: &AmVideoSettingExtras,
+    ) -> Result<()> {
+        self.call(
+            "set resolution",
+            &Call::SetResolution {
+                setting: setting.clone(),
+                extras: *extras,
+            },
+        )
+        .map(drop)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.call("close", &Call::Close).map(drop)
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        // End of input tells the broker to close anything left open and exit
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+/// `amvideo broker`: answer `Call` lines on stdin until it closes, keeping stdout for replies
+pub fn serve() -> Result<()> {
+    let mut session = Local::Gone;
+    let stdout = io::stdout();
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let reply = match serde_json::from_str::<Call>(&line) {
+            Ok(call) => handle(&mut session, call),
+            Err(e) => Err(anyhow!("Invalid call: {}", e)),
+        };
+        let reply = reply.unwrap_or_else(|e| Reply {
+            ok: false,
+            code: e
+                .downcast_ref::<AmVideoCrateError>()
+                .and_then(AmVideoCrateError::code),
+            error: Some(format!("{:#}", e)),
+            ..Reply::default()
+        });
+
+        let mut stdout = stdout.lock();
+        serde_json::to_writer(&mut stdout, &reply)?;
+        writeln!(stdout)?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Make one call against a DLL loaded into this process
+pub fn handle(session: &mut Local, call: Call) -> Result<Reply> {
+    let mut reply = Reply {
+        ok: true,
+        ..Reply::default()
+    };
+
+    match call {
+        Call::Hello { version } => reply.version = Some(protocol::negotiate(Some(version))),
+        Call::Load {
+            dll,
+            context_version,
+            exports,
+        } => {
+            let mut amvideo = AmVideo::with_exports(&dll, &exports)?;
+            if let Some(version) = context_version {
+                amvideo.set_context_version(version);
+            }
+            warn_hooks(&amvideo);
+            reply.loaded = Some(Loaded {
+                base: **amvideo.library() as usize,
+                exports: amvideo
+                    .exports()
+                    .iter()
+                    .map(|&(name, func)| (name.to_string(), func as usize))
+                    .collect(),
+                context_version: amvideo.context_version(),
+            });
+            *session = Local::Closed(amvideo);
+        }
+        Call::Open => reply.context_version = Some(session.open()?),
+        Call::VbiosVersion { buffer } => reply.vbios = Some(session.vbios_version(buffer)?),
+        Call::SetResolution { setting, extras } => session.set_resolution(&setting, &extras)?,
+        Call::Close => session.close()?,
+    };
+
+    Ok(reply)
+}
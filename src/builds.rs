@@ -0,0 +1,63 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/// amVideo DLL build whose interface differs from, or has been checked against, the defaults
+#[derive(Debug)]
+pub struct Build {
+    /// Banner string compiled into the DLL
+    pub banner: &'static str,
+    /// `AmVideoSetting` layout `amDllVideoSetResolution` expects
+    pub setting_version: u32,
+    /// `AmVideoContext` version to open with
+    pub context_version: u32,
+    /// Codes `amDllVideoOpen` returns when it does not support the context version, which make
+    /// `open` retry with the next lower version
+    pub bad_version_codes: &'static [usize],
+    /// RVAs of patch targets by symbol name, for when no PDB or linker map is at hand
+    pub offsets: &'static [(&'static str, usize)],
+}
+
+/// Builds identified so far. Builds not listed here are driven with the version-1 layouts.
+pub const BUILDS: &[Build] = &[Build {
+    banner: "amVideoNvidia Build:Jan 30 2015 18:51:29 ($Rev: 4624 $)",
+    setting_version: 1,
+    context_version: 1,
+    bad_version_codes: &[],
+    offsets: &[("g_validateLogLevel", 0x505D4), ("g_logLevel", 0x505D8)],
+}];
+
+/// Printable string around the first `Build:` in a DLL image, which SEGA's builds embed
+pub fn banner(image: &[u8]) -> Option<String> {
+    let needle = b"Build:";
+    let at = image.windows(needle.len()).position(|w| w == needle)?;
+    let printable = |b: &u8| (0x20..0x7f).contains(b);
+    let start = image[..at]
+        .iter()
+        .rposition(|b| !printable(b))
+        .map_or(0, |i| i + 1);
+    let end = at + image[at..].iter().position(|b| !printable(b))?;
+
+    Some(
+        String::from_utf8_lossy(&image[start..end])
+            .trim()
+            .to_string(),
+    )
+}
+
+/// Look up the build with the given banner
+pub fn find(banner: &str) -> Option<&'static Build> {
+    BUILDS.iter().find(|build| build.banner == banner)
+}
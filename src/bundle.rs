@@ -0,0 +1,475 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use amvideo::amlib::ExportMap;
+
+use crate::config::{self, Config, Profile};
+use crate::digest::{self, hex};
+use crate::ed25519;
+use crate::migrate;
+use crate::zip::Archive;
+use crate::{prompt, GlobalOpts};
+
+const MANIFEST: &str = "manifest.json";
+const SIGNATURE: &str = "manifest.sig";
+const ED25519_SIGNATURE: &str = "manifest.ed25519";
+/// Longest profile name a bundle may carry, as it also names the directory unpacked into
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Args)]
+pub struct ProfileOpts {
+    #[command(subcommand)]
+    command: ProfileCommand,
+}
+
+#[derive(Subcommand)]
+enum ProfileCommand {
+    /// Package a profile and the files it references into a bundle
+    Export {
+        name: String,
+        bundle: PathBuf,
+
+        #[command(flatten)]
+        key: KeyOpts,
+
+        /// Ed25519 private key to sign the bundle with, from `profile keygen`
+        #[arg(long, value_name = "PATH")]
+        sign_key: Option<PathBuf>,
+    },
+    /// Add the profile in a bundle to the config, unpacking its files next to it
+    Import {
+        bundle: PathBuf,
+
+        /// Name to import the profile as [default: its name in the bundle]
+        #[arg(long = "as", value_name = "NAME")]
+        rename: Option<String>,
+
+        #[command(flatten)]
+        key: KeyOpts,
+
+        /// Import a bundle signed by no trusted key
+        #[arg(long)]
+        allow_unsigned: bool,
+    },
+    /// Generate an Ed25519 key pair for signing bundles, printing the public key to trust
+    Keygen {
+        /// File to write the private key to
+        path: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct KeyOpts {
+    /// Shared key to sign bundles with, or to require a valid signature from
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+}
+
+/// Profile as stored in a bundle, with file references relative to the bundle
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    name: String,
+    profile: Profile,
+    files: Vec<BundledFile>,
+    /// The exporting config's export map, for the DLL build the cab model ships with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exports: Option<ExportMap>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BundledFile {
+    name: String,
+    sha256: String,
+}
+
+pub fn run(global: &GlobalOpts, opts: &ProfileOpts) -> Result<()> {
+    match &opts.command {
+        ProfileCommand::Export {
+            name,
+            bundle,
+            key,
+            sign_key,
+        } => {
+            let key = key.load()?;
+            let sign_key = sign_key.as_deref().map(load_signing_key).transpose()?;
+            export(global, name, bundle, key.as_deref(), sign_key.as_ref())
+        }
+        ProfileCommand::Import {
+            bundle,
+            rename,
+            key,
+            allow_unsigned,
+        } => {
+            let key = key.load()?;
+            import(
+                global,
+                bundle,
+                rename.as_deref(),
+                key.as_deref(),
+                *allow_unsigned,
+            )
+        }
+        ProfileCommand::Keygen { path } => keygen(path),
+    }
+}
+
+impl KeyOpts {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        self.key_file
+            .as_ref()
+            .map(|path| {
+                fs::read(path).with_context(|| format!("Failed to read key '{}'", path.display()))
+            })
+            .transpose()
+    }
+}
+
+/// The private key in `path`, as written by `profile keygen`, in hex or as the raw 32 bytes
+fn load_signing_key(path: &Path) -> Result<[u8; 32]> {
+    let data =
+        fs::read(path).with_context(|| format!("Failed to read key '{}'", path.display()))?;
+    let raw = std::str::from_utf8(&data)
+        .ok()
+        .and_then(|hex| digest::unhex(hex.trim()))
+        .unwrap_or(data);
+    raw.as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("'{}' is not an Ed25519 private key", path.display()))
+}
+
+fn keygen(path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(anyhow!("'{}' already exists", path.display()));
+    }
+
+    let seed = digest::random::<32>()?;
+    let public = ed25519::public_key(&seed)?;
+    fs::write(path, hex(&seed)).with_context(|| format!("Failed to write '{}'", path.display()))?;
+
+    println!("Wrote the private key to {}", path.display());
+    println!("Trust bundles it signs by adding this to [trust] in the config:");
+    println!("{}", hex(&public));
+    Ok(())
+}
+
+/// Name of the trusted key that signed `manifest`, or an error if the bundle carries an
+/// Ed25519 signature no trusted key made. `None` if it carries none.
+fn trusted_signer(
+    trust: &BTreeMap<String, String>,
+    manifest: &[u8],
+    signature: Option<&[u8]>,
+) -> Result<Option<String>> {
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    let signature: [u8; 64] = std::str::from_utf8(signature)
+        .ok()
+        .and_then(|hex| digest::unhex(hex.trim()))
+        .and_then(|raw| raw.as_slice().try_into().ok())
+        .ok_or_else(|| anyhow!("Bundle has a malformed {}", ED25519_SIGNATURE))?;
+
+    for (name, key) in trust {
+        let key: [u8; 32] = digest::unhex(key.trim())
+            .and_then(|raw| raw.as_slice().try_into().ok())
+            .ok_or_else(|| anyhow!("Trusted key '{}' is not an Ed25519 public key", name))?;
+        if ed25519::verify(&key, manifest, &signature)? {
+            return Ok(Some(name.clone()));
+        }
+    }
+
+    Err(anyhow!(
+        "Bundle is signed, but by no key in the trust store"
+    ))
+}
+
+fn export(
+    global: &GlobalOpts,
+    name: &str,
+    bundle: &Path,
+    key: Option<&[u8]>,
+    sign_key: Option<&[u8; 32]>,
+) -> Result<()> {
+    let config = Config::load(global.config.as_deref())?;
+    let mut profile = config.profile(name)?;
+
+    let mut archive = Archive::default();
+    let mut files = Vec::new();
+    let mut pack = |path: &mut PathBuf, name: &str| -> Result<()> {
+        let data =
+            fs::read(&*path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+        files.push(BundledFile {
+            name: name.to_string(),
+            sha256: hex(&digest::sha256(&data)?),
+        });
+        archive.add(name, data);
+        *path = PathBuf::from(name);
+        Ok(())
+    };
+
+    if let Some(path) = &mut profile.layout {
+        pack(path, "layout.toml")?;
+    }
+    if let Some(path) = profile.color.as_mut().and_then(|color| color.icc.as_mut()) {
+        pack(path, "calibration.icc")?;
+    }
+
+    let manifest = Manifest {
+        name: name.to_string(),
+        profile,
+        files,
+        exports: config.exports.clone(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    if let Some(key) = key {
+        let signature = hex(&digest::hmac_sha256(key, &manifest)?);
+        archive.add(SIGNATURE, signature.into_bytes());
+    }
+    if let Some(sign_key) = sign_key {
+        let signature = hex(&ed25519::sign(sign_key, &manifest)?);
+        archive.add(ED25519_SIGNATURE, signature.into_bytes());
+    }
+    archive.entries.insert(0, (MANIFEST.to_string(), manifest));
+
+    fs::write(bundle, archive.to_bytes())
+        .with_context(|| format!("Failed to write '{}'", bundle.display()))?;
+    println!(
+        "Exported '{}' to {}{}",
+        name,
+        bundle.display(),
+        if key.is_some() || sign_key.is_some() {
+            ", signed"
+        } else {
+            ""
+        }
+    );
+
+    Ok(())
+}
+
+fn import(
+    global: &GlobalOpts,
+    bundle: &Path,
+    rename: Option<&str>,
+    key: Option<&[u8]>,
+    allow_unsigned: bool,
+) -> Result<()> {
+    let config_path = match &global.config {
+        Some(path) => path.clone(),
+        None => config::default_path()?,
+    };
+    // A missing config is fine, importing starts a new one
+    let existing = if config_path.exists() {
+        Config::load(Some(&config_path))?
+    } else {
+        Config::default()
+    };
+
+    let data =
+        fs::read(bundle).with_context(|| format!("Failed to read '{}'", bundle.display()))?;
+    let archive = Archive::from_bytes(&data)?;
+    let manifest_data = archive
+        .get(MANIFEST)
+        .ok_or_else(|| anyhow!("'{}' has no {}", bundle.display(), MANIFEST))?;
+
+    let signer = trusted_signer(
+        &existing.trust,
+        manifest_data,
+        archive.get(ED25519_SIGNATURE),
+    )?;
+    let shared_key = match (key, archive.get(SIGNATURE)) {
+        (Some(key), Some(signature)) => {
+            let expected = hex(&digest::hmac_sha256(key, manifest_data)?);
+            if expected.as_bytes() != signature {
+                return Err(anyhow!("Bundle signature does not match the key"));
+            }
+            true
+        }
+        (Some(_), None) => return Err(anyhow!("Bundle is not signed")),
+        (None, Some(_)) if signer.is_none() => {
+            eprintln!("Bundle is signed, pass --key-file to verify it");
+            false
+        }
+        _ => false,
+    };
+    match &signer {
+        Some(name) => println!("Bundle is signed by '{}'", name),
+        None if shared_key => {}
+        None if allow_unsigned => eprintln!("Importing a bundle signed by no trusted key"),
+        None => {
+            return Err(anyhow!(
+                "Bundle is signed by no trusted key, pass --allow-unsigned to import it anyway"
+            ))
+        }
+    }
+
+    let mut manifest: Manifest =
+        serde_json::from_slice(manifest_data).context("Invalid bundle manifest")?;
+    // An export map decides which code in the DLL gets called, so unlike the profile it is
+    // never taken on --allow-unsigned alone
+    if signer.is_none() && !shared_key && manifest.exports.take().is_some() {
+        eprintln!("Leaving out the bundle's [exports], which only a signed bundle may bring");
+    }
+    // Names come from the bundle and are joined onto paths, so nothing may climb out of the
+    // directory it is unpacked into
+    for file in &manifest.files {
+        check_file_name(&file.name)?;
+        let data = archive
+            .get(&file.name)
+            .ok_or_else(|| anyhow!("Bundle is missing '{}'", file.name))?;
+        if hex(&digest::sha256(data)?) != file.sha256 {
+            return Err(anyhow!("'{}' in the bundle is corrupt", file.name));
+        }
+    }
+
+    let name = rename.unwrap_or(&manifest.name).to_string();
+    check_profile_name(&name)?;
+    if existing.profiles.contains_key(&name) {
+        return Err(anyhow!(
+            "A profile named '{}' already exists, import it with --as to pick another name",
+            name
+        ));
+    }
+
+    let dir = config_path.with_file_name("bundles").join(&name);
+    let files = &manifest.files;
+    let profile = &mut manifest.profile;
+    let relocate = |path: &mut PathBuf| -> Result<()> {
+        let file_name = path
+            .to_str()
+            .filter(|name| files.iter().any(|file| file.name == *name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Bundled profile refers to '{}', which is not in the bundle",
+                    path.display()
+                )
+            })?;
+        *path = dir.join(file_name);
+        Ok(())
+    };
+    if let Some(path) = &mut profile.layout {
+        relocate(path)?;
+    }
+    if let Some(path) = profile.color.as_mut().and_then(|color| color.icc.as_mut()) {
+        relocate(path)?;
+    }
+
+    let mut table = toml::map::Map::new();
+    table.insert(name.clone(), toml::Value::try_from(&*profile)?);
+    let mut profiles = toml::map::Map::new();
+    profiles.insert("profiles".to_string(), toml::Value::Table(table));
+    // The config's own export map wins, it was set up for the DLL actually installed
+    match (&existing.exports, &manifest.exports) {
+        (None, Some(exports)) => {
+            profiles.insert("exports".to_string(), toml::Value::try_from(exports)?);
+        }
+        (Some(ours), Some(theirs))
+            if toml::Value::try_from(ours)? != toml::Value::try_from(theirs)? =>
+        {
+            eprintln!("Keeping the config's [exports], which differ from the bundle's")
+        }
+        _ => {}
+    }
+    let section = toml::to_string(&profiles)?;
+
+    prompt(
+        global,
+        &format!(
+            "About to add profile '{}'{} to {} and unpack {} files into {}",
+            name,
+            if profiles.contains_key("exports") {
+                " and its [exports]"
+            } else {
+                ""
+            },
+            config_path.display(),
+            manifest.files.len(),
+            dir.display()
+        ),
+    )?;
+
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    for file in &manifest.files {
+        let path = dir.join(&file.name);
+        fs::write(&path, archive.get(&file.name).unwrap_or_default())
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    }
+
+    // Appended rather than rewriting the config, so its comments survive, onto a config brought
+    // up to the format the new section is written in. A new one starts with the format stamp.
+    let stamp = if config_path.exists() {
+        migrate::migrate_file(&config_path)?;
+        String::new()
+    } else {
+        format!("version = {}\n", migrate::VERSION)
+    };
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config_path)
+        .and_then(|mut file| write!(file, "{}\n{}", stamp, section))
+        .with_context(|| format!("Failed to update config '{}'", config_path.display()))?;
+    println!("Imported profile '{}'", name);
+
+    Ok(())
+}
+
+/// Refuse profile names that are unusable in the config or unsafe as a directory name
+fn check_profile_name(name: &str) -> Result<()> {
+    if name.contains('+') {
+        return Err(anyhow!(
+            "'+' composes profiles and can't be part of a name, import it with --as"
+        ));
+    }
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(anyhow!(
+            "'{}' is not a valid profile name: use up to {} letters, digits, '-', '_', and '.', \
+             import it with --as",
+            name,
+            MAX_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Refuse bundled file names that are not a bare file name, such as an absolute path, one with
+/// `..`, or one with a drive or stream separator
+fn check_file_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    let bare = matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !name.contains(['/', '\\', ':']);
+    if !bare {
+        return Err(anyhow!("Bundle has a file with an unsafe name: '{}'", name));
+    }
+    Ok(())
+}
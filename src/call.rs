@@ -0,0 +1,219 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::mem;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use winapi::shared::minwindef::FARPROC;
+
+use crate::digest;
+use crate::load;
+use crate::safe_mode;
+
+/// Most arguments a raw call can pass
+pub const MAX_ARGS: usize = 8;
+
+#[derive(Args)]
+pub struct CallOpts {
+    /// Export to call, by ordinal
+    #[arg(long, required_unless_present = "export", conflicts_with = "export")]
+    ordinal: Option<u16>,
+
+    /// Export to call, by name
+    #[arg(long)]
+    export: Option<String>,
+
+    /// Machine-word arguments in hex, or `ctx` for the context and `blob` for the blob buffer
+    #[arg(long, num_args = 0.., value_name = "HEX|ctx|blob")]
+    args: Vec<String>,
+
+    /// Bytes in hex to pass as `blob`, printed again after the call to show what it wrote
+    #[arg(long, default_value = "")]
+    blob: String,
+
+    /// Calling convention of the export; the same on 64-bit, where there is only one
+    #[arg(long, value_enum, default_value_t = Convention::Cdecl)]
+    convention: Convention,
+
+    /// Call without opening the context first
+    #[arg(long)]
+    no_open: bool,
+
+    /// Wrong arguments crash amvideo, and can leave the driver in an unknown state
+    #[arg(long)]
+    i_know_this_can_crash: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Convention {
+    Cdecl,
+    Stdcall,
+    Fastcall,
+}
+
+macro_rules! call_with {
+    ($abi:literal, $func:expr, $args:expr) => {{
+        let func = $func;
+        let a = $args;
+        match a.len() {
+            0 => mem::transmute::<FARPROC, unsafe extern $abi fn() -> usize>(func)(),
+            1 => mem::transmute::<FARPROC, unsafe extern $abi fn(usize) -> usize>(func)(a[0]),
+            2 => mem::transmute::<FARPROC, unsafe extern $abi fn(usize, usize) -> usize>(func)(
+                a[0], a[1],
+            ),
+            3 => mem::transmute::<FARPROC, unsafe extern $abi fn(usize, usize, usize) -> usize>(
+                func,
+            )(a[0], a[1], a[2]),
+            4 => mem::transmute::<
+                FARPROC,
+                unsafe extern $abi fn(usize, usize, usize, usize) -> usize,
+            >(func)(a[0], a[1], a[2], a[3]),
+            5 => mem::transmute::<
+                FARPROC,
+                unsafe extern $abi fn(usize, usize, usize, usize, usize) -> usize,
+            >(func)(a[0], a[1], a[2], a[3], a[4]),
+            6 => mem::transmute::<
+                FARPROC,
+                unsafe extern $abi fn(usize, usize, usize, usize, usize, usize) -> usize,
+            >(func)(a[0], a[1], a[2], a[3], a[4], a[5]),
+            7 => mem::transmute::<
+                FARPROC,
+                unsafe extern $abi fn(usize, usize, usize, usize, usize, usize, usize) -> usize,
+            >(func)(a[0], a[1], a[2], a[3], a[4], a[5], a[6]),
+            _ => mem::transmute::<
+                FARPROC,
+                unsafe extern $abi fn(usize, usize, usize, usize, usize, usize, usize, usize)
+                    -> usize,
+            >(func)(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]),
+        }
+    }};
+}
+
+/// `amvideo call`: invoke an arbitrary export with hand-built arguments, for mapping
+/// undocumented exports
+pub fn run(opts: &CallOpts) -> Result<()> {
+    safe_mode::check("Calling raw exports")?;
+    if !opts.i_know_this_can_crash {
+        return Err(anyhow!(
+            "Raw calls can crash amvideo and leave the driver in an unknown state, pass \
+             --i-know-this-can-crash to go ahead"
+        ));
+    }
+    if opts.args.len() > MAX_ARGS {
+        return Err(anyhow!("At most {} arguments can be passed", MAX_ARGS));
+    }
+    let mut blob = parse_blob(&opts.blob)?;
+
+    let amvideo = load()?;
+    let target = match (&opts.export, opts.ordinal) {
+        (Some(name), _) => name.clone(),
+        (None, ordinal) => format!("#{}", ordinal.unwrap_or_default()),
+    };
+    let func = unsafe {
+        match (&opts.export, opts.ordinal) {
+            (Some(name), _) => amvideo.library().get_func_named(name),
+            (_, ordinal) => amvideo
+                .library()
+                .get_func_named_ordinal("export", ordinal.unwrap_or_default()),
+        }
+    }
+    .map_err(|_| anyhow!("Failed to find export {} in the DLL", target))?;
+
+    // Ok when the context was opened for the call and must be closed again after it
+    let mut amvideo = if opts.no_open {
+        Err(amvideo)
+    } else {
+        Ok(amvideo.open().map_err(|e| anyhow!("{}", e.error()))?)
+    };
+
+    // Neither pointer outlives the call: the context lives as long as the handle, the blob until
+    // the end of this function
+    let context = match &mut amvideo {
+        Ok(opened) => unsafe { opened.raw_exports().context as *mut _ as usize },
+        Err(closed) => unsafe { closed.raw_exports().context as *mut _ as usize },
+    };
+    let args = opts
+        .args
+        .iter()
+        .map(|arg| parse_arg(arg, context, blob.as_mut_ptr() as usize))
+        .collect::<Result<Vec<usize>>>()?;
+
+    println!(
+        "Calling {} at {:#x} ({:?}) with [{}]",
+        target,
+        func as usize,
+        opts.convention,
+        args.iter()
+            .map(|arg| format!("{:#x}", arg))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let result = unsafe { invoke(func, opts.convention, &args) };
+    println!("Returned {} ({:#x})", result as isize, result);
+    if !blob.is_empty() {
+        println!("Blob: {}", digest::hex(&blob));
+    }
+
+    if let Ok(opened) = amvideo {
+        opened.close().map_err(|e| anyhow!("{}", e.error()))?;
+    }
+
+    Ok(())
+}
+
+/// Call `func` with `args` in the given convention
+///
+/// # Safety
+///
+/// Anything goes: the export and its arguments are whatever the user asked for.
+pub unsafe fn invoke(func: FARPROC, convention: Convention, args: &[usize]) -> usize {
+    match convention {
+        Convention::Cdecl => call_with!("C", func, args),
+        #[cfg(target_arch = "x86")]
+        Convention::Stdcall => call_with!("stdcall", func, args),
+        #[cfg(target_arch = "x86")]
+        Convention::Fastcall => call_with!("fastcall", func, args),
+        // 64-bit Windows has a single calling convention, which the other names also mean
+        #[cfg(not(target_arch = "x86"))]
+        Convention::Stdcall | Convention::Fastcall => call_with!("C", func, args),
+    }
+}
+
+/// Machine word for one argument: `ctx`, `blob`, or a hex value
+pub fn parse_arg(arg: &str, context: usize, blob: usize) -> Result<usize> {
+    match arg {
+        "ctx" => Ok(context),
+        "blob" => Ok(blob),
+        word => usize::from_str_radix(word.trim_start_matches("0x"), 16)
+            .with_context(|| format!("Invalid argument '{}'", word)),
+    }
+}
+
+pub fn parse_blob(hex: &str) -> Result<Vec<u8>> {
+    let digits: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("Blob '{}' has an odd number of hex digits", hex));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .with_context(|| format!("Invalid blob byte '{}'", &digits[i..i + 2]))
+        })
+        .collect()
+}
@@ -0,0 +1,196 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::mem;
+use std::ptr;
+
+use winapi::shared::basetsd::UINT32;
+use winapi::shared::ntdef::LONG;
+use winapi::shared::winerror::{ERROR_INSUFFICIENT_BUFFER, ERROR_SUCCESS};
+use winapi::um::wingdi::{
+    DISPLAYCONFIG_DEVICE_INFO_GET_ADVANCED_COLOR_INFO, DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME,
+    DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME, DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_PREFERRED_MODE,
+    DISPLAYCONFIG_DEVICE_INFO_HEADER, DISPLAYCONFIG_DEVICE_INFO_SET_ADVANCED_COLOR_STATE,
+    DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO, DISPLAYCONFIG_MODE_INFO, DISPLAYCONFIG_PATH_INFO,
+    DISPLAYCONFIG_SET_ADVANCED_COLOR_STATE, DISPLAYCONFIG_SOURCE_DEVICE_NAME,
+    DISPLAYCONFIG_TARGET_DEVICE_NAME, DISPLAYCONFIG_TARGET_PREFERRED_MODE,
+    DISPLAYCONFIG_TOPOLOGY_ID, QDC_ONLY_ACTIVE_PATHS, SDC_ALLOW_CHANGES, SDC_APPLY,
+    SDC_SAVE_TO_DATABASE, SDC_USE_SUPPLIED_DISPLAY_CONFIG,
+};
+
+use crate::wide::from_wide;
+
+// Not declared by winapi 0.3
+#[link(name = "user32")]
+extern "system" {
+    fn GetDisplayConfigBufferSizes(
+        flags: UINT32,
+        num_path_array_elements: *mut UINT32,
+        num_mode_info_array_elements: *mut UINT32,
+    ) -> LONG;
+    fn QueryDisplayConfig(
+        flags: UINT32,
+        num_path_array_elements: *mut UINT32,
+        path_array: *mut DISPLAYCONFIG_PATH_INFO,
+        num_mode_info_array_elements: *mut UINT32,
+        mode_info_array: *mut DISPLAYCONFIG_MODE_INFO,
+        current_topology_id: *mut DISPLAYCONFIG_TOPOLOGY_ID,
+    ) -> LONG;
+    fn DisplayConfigGetDeviceInfo(request_packet: *mut DISPLAYCONFIG_DEVICE_INFO_HEADER) -> LONG;
+    fn DisplayConfigSetDeviceInfo(set_packet: *mut DISPLAYCONFIG_DEVICE_INFO_HEADER) -> LONG;
+    fn SetDisplayConfig(
+        num_path_array_elements: UINT32,
+        path_array: *mut DISPLAYCONFIG_PATH_INFO,
+        num_mode_info_array_elements: UINT32,
+        mode_info_array: *mut DISPLAYCONFIG_MODE_INFO,
+        flags: UINT32,
+    ) -> LONG;
+}
+
+fn check(result: LONG) -> io::Result<()> {
+    if result as u32 == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(result))
+    }
+}
+
+/// Query the paths currently active in the Connecting and Configuring Displays database, along
+/// with the modes they reference
+pub(crate) fn active_paths(
+) -> io::Result<(Vec<DISPLAYCONFIG_PATH_INFO>, Vec<DISPLAYCONFIG_MODE_INFO>)> {
+    loop {
+        let mut num_paths = 0;
+        let mut num_modes = 0;
+        check(unsafe {
+            GetDisplayConfigBufferSizes(QDC_ONLY_ACTIVE_PATHS, &mut num_paths, &mut num_modes)
+        })?;
+
+        let mut paths: Vec<DISPLAYCONFIG_PATH_INFO> =
+            vec![unsafe { mem::zeroed() }; num_paths as usize];
+        let mut modes: Vec<DISPLAYCONFIG_MODE_INFO> =
+            vec![unsafe { mem::zeroed() }; num_modes as usize];
+
+        let result = unsafe {
+            QueryDisplayConfig(
+                QDC_ONLY_ACTIVE_PATHS,
+                &mut num_paths,
+                paths.as_mut_ptr(),
+                &mut num_modes,
+                modes.as_mut_ptr(),
+                ptr::null_mut(),
+            )
+        };
+        // The topology changed between the two calls, size the buffers again
+        if result as u32 == ERROR_INSUFFICIENT_BUFFER {
+            continue;
+        }
+        check(result)?;
+
+        paths.truncate(num_paths as usize);
+        modes.truncate(num_modes as usize);
+        return Ok((paths, modes));
+    }
+}
+
+/// Apply and persist a complete set of paths and modes, as returned by `active_paths`
+pub(crate) fn apply(
+    paths: &mut [DISPLAYCONFIG_PATH_INFO],
+    modes: &mut [DISPLAYCONFIG_MODE_INFO],
+) -> io::Result<()> {
+    check(unsafe {
+        SetDisplayConfig(
+            paths.len() as u32,
+            paths.as_mut_ptr(),
+            modes.len() as u32,
+            modes.as_mut_ptr(),
+            SDC_APPLY | SDC_USE_SUPPLIED_DISPLAY_CONFIG | SDC_SAVE_TO_DATABASE | SDC_ALLOW_CHANGES,
+        )
+    })
+}
+
+/// GDI device name (e.g. `\\.\DISPLAY1`) of the source a path scans out from
+pub(crate) fn source_name(path: &DISPLAYCONFIG_PATH_INFO) -> io::Result<String> {
+    let mut name: DISPLAYCONFIG_SOURCE_DEVICE_NAME = unsafe { mem::zeroed() };
+    name.header._type = DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME;
+    name.header.size = mem::size_of::<DISPLAYCONFIG_SOURCE_DEVICE_NAME>() as u32;
+    name.header.adapterId = path.sourceInfo.adapterId;
+    name.header.id = path.sourceInfo.id;
+
+    check(unsafe { DisplayConfigGetDeviceInfo(&mut name.header) })?;
+
+    Ok(from_wide(&name.viewGdiDeviceName))
+}
+
+/// Monitor and connector details of the target a path drives
+pub(crate) fn target_name(
+    path: &DISPLAYCONFIG_PATH_INFO,
+) -> io::Result<DISPLAYCONFIG_TARGET_DEVICE_NAME> {
+    let mut name: DISPLAYCONFIG_TARGET_DEVICE_NAME = unsafe { mem::zeroed() };
+    name.header._type = DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_NAME;
+    name.header.size = mem::size_of::<DISPLAYCONFIG_TARGET_DEVICE_NAME>() as u32;
+    name.header.adapterId = path.targetInfo.adapterId;
+    name.header.id = path.targetInfo.id;
+
+    check(unsafe { DisplayConfigGetDeviceInfo(&mut name.header) })?;
+
+    Ok(name)
+}
+
+/// Native width and height of the monitor a path drives, as its EDID prefers
+pub(crate) fn preferred_mode(path: &DISPLAYCONFIG_PATH_INFO) -> io::Result<(u32, u32)> {
+    let mut mode: DISPLAYCONFIG_TARGET_PREFERRED_MODE = unsafe { mem::zeroed() };
+    mode.header._type = DISPLAYCONFIG_DEVICE_INFO_GET_TARGET_PREFERRED_MODE;
+    mode.header.size = mem::size_of::<DISPLAYCONFIG_TARGET_PREFERRED_MODE>() as u32;
+    mode.header.adapterId = path.targetInfo.adapterId;
+    mode.header.id = path.targetInfo.id;
+
+    check(unsafe { DisplayConfigGetDeviceInfo(&mut mode.header) })?;
+
+    Ok((mode.width, mode.height))
+}
+
+/// Whether the target a path drives supports HDR, and whether it is on
+pub(crate) fn advanced_color(path: &DISPLAYCONFIG_PATH_INFO) -> io::Result<(bool, bool)> {
+    let mut info: DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO = unsafe { mem::zeroed() };
+    info.header._type = DISPLAYCONFIG_DEVICE_INFO_GET_ADVANCED_COLOR_INFO;
+    info.header.size = mem::size_of::<DISPLAYCONFIG_GET_ADVANCED_COLOR_INFO>() as u32;
+    info.header.adapterId = path.targetInfo.adapterId;
+    info.header.id = path.targetInfo.id;
+
+    check(unsafe { DisplayConfigGetDeviceInfo(&mut info.header) })?;
+
+    // `wideColorEnforced`, which winapi does not name, marks advanced color forced on for an SDR
+    // display by auto color management rather than HDR
+    let wide_color_enforced = info.value & 0b100 != 0;
+    Ok((
+        info.advancedColorSupported() != 0,
+        info.advancedColorEnabled() != 0 && !wide_color_enforced,
+    ))
+}
+
+/// Turn HDR on or off for the target a path drives
+pub(crate) fn set_advanced_color(path: &DISPLAYCONFIG_PATH_INFO, enable: bool) -> io::Result<()> {
+    let mut state: DISPLAYCONFIG_SET_ADVANCED_COLOR_STATE = unsafe { mem::zeroed() };
+    state.header._type = DISPLAYCONFIG_DEVICE_INFO_SET_ADVANCED_COLOR_STATE;
+    state.header.size = mem::size_of::<DISPLAYCONFIG_SET_ADVANCED_COLOR_STATE>() as u32;
+    state.header.adapterId = path.targetInfo.adapterId;
+    state.header.id = path.targetInfo.id;
+    state.set_enableAdvancedColor(enable as u32);
+
+    check(unsafe { DisplayConfigSetDeviceInfo(&mut state.header) })
+}
@@ -0,0 +1,104 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::io;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use amvideo::AmVideoCrateError;
+
+/// Community-collected meanings shipped with amvideo
+const BUILTIN: &str = include_str!("../error-codes.toml");
+
+/// User additions, looked for next to the executable
+const FILE_NAME: &str = "error-codes.toml";
+
+#[derive(Debug, Default, Deserialize)]
+struct CodeFile {
+    #[serde(default)]
+    code: Vec<KnownCode>,
+}
+
+/// A return code whose meaning has been worked out
+#[derive(Clone, Debug, Deserialize)]
+pub struct KnownCode {
+    /// Matched against the low 32 bits of the status, so -1 and 0xFFFFFFFF are the same code
+    pub value: i64,
+    pub meaning: String,
+    pub hint: Option<String>,
+}
+
+impl KnownCode {
+    fn matches(&self, code: usize) -> bool {
+        self.value as u32 == code as u32
+    }
+}
+
+/// Every known code, the user's file taking precedence over the built-in one
+fn known() -> Result<Vec<KnownCode>> {
+    let mut codes = Vec::new();
+
+    let path = env::current_exe()
+        .context("Failed to locate the running executable")?
+        .with_file_name(FILE_NAME);
+    match fs::read_to_string(&path) {
+        Ok(toml) => {
+            let file: CodeFile = toml::from_str(&toml)
+                .with_context(|| format!("Failed to parse '{}'", path.display()))?;
+            codes.extend(file.code);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    }
+
+    let builtin: CodeFile = toml::from_str(BUILTIN).context("Failed to parse built-in codes")?;
+    codes.extend(builtin.code);
+
+    Ok(codes)
+}
+
+/// Meaning of `code`, if anyone has worked it out
+pub fn lookup(code: usize) -> Option<KnownCode> {
+    match known() {
+        Ok(codes) => codes.into_iter().find(|known| known.matches(code)),
+        Err(e) => {
+            eprintln!("Not looking up return code: {:#}", e);
+            None
+        }
+    }
+}
+
+/// DLL status behind `error`, if an amVideo export failed
+pub fn dll_code(error: &anyhow::Error) -> Option<usize> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<AmVideoCrateError>())
+        .and_then(AmVideoCrateError::code)
+}
+
+/// Explanation of the DLL status behind `error`, for printing after it
+pub fn explain(error: &anyhow::Error) -> Option<String> {
+    let code = dll_code(error)?;
+    let known = lookup(code)?;
+
+    Some(match known.hint {
+        Some(hint) => format!("Status {:#x}: {}. {}", code, known.meaning, hint),
+        None => format!("Status {:#x}: {}", code, known.meaning),
+    })
+}
@@ -0,0 +1,224 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryInto;
+use std::io;
+use std::ptr;
+
+use winapi::um::wingdi::{CreateDCW, DeleteDC, GetDeviceGammaRamp, SetDeviceGammaRamp};
+
+use crate::display;
+use crate::error::Result;
+use crate::wide::to_wide;
+
+const RAMP_SIZE: usize = 256;
+
+/// Per-channel lookup table loaded into the display hardware with `SetDeviceGammaRamp`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GammaRamp {
+    pub red: [u16; RAMP_SIZE],
+    pub green: [u16; RAMP_SIZE],
+    pub blue: [u16; RAMP_SIZE],
+}
+
+fn invalid_icc(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid ICC profile: {}", message),
+    )
+}
+
+fn be_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid_icc("truncated"))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid_icc("truncated"))
+}
+
+/// `s15Fixed16Number`, of which the `vcgt` formula only uses non-negative values
+fn fixed(data: &[u8], offset: usize) -> io::Result<f64> {
+    Ok(f64::from(be_u32(data, offset)? as i32) / 65536.0)
+}
+
+impl GammaRamp {
+    /// Power curve applied equally to every channel. Values above 1.0 brighten midtones.
+    pub fn from_gamma(gamma: f64) -> Self {
+        let channel = curve(|x| x.powf(1.0 / gamma));
+        Self {
+            red: channel,
+            green: channel,
+            blue: channel,
+        }
+    }
+
+    /// Calibration curves from the `vcgt` tag of an ICC profile. Windows does not load these on
+    /// its own, which is what calibration loaders are for.
+    pub fn from_icc(data: &[u8]) -> Result<Self> {
+        if data.get(36..40) != Some(b"acsp") {
+            return Err(invalid_icc("missing 'acsp' signature").into());
+        }
+
+        // Each tag table entry is 12 bytes, so the file bounds how many there can be
+        let tag_count = be_u32(data, 128)? as usize;
+        if tag_count > (data.len() - 132) / 12 {
+            return Err(invalid_icc("tag count exceeds the file").into());
+        }
+        let vcgt = (0..tag_count)
+            .map(|i| 132 + i * 12)
+            .find(|&entry| data.get(entry..entry + 4) == Some(b"vcgt"))
+            .ok_or_else(|| invalid_icc("no 'vcgt' calibration tag"))?;
+        let offset = be_u32(data, vcgt + 4)? as usize;
+
+        match be_u32(data, offset + 8)? {
+            // Table of `entries` samples per channel
+            0 => {
+                let channels = be_u16(data, offset + 12)? as usize;
+                let entries = be_u16(data, offset + 14)? as usize;
+                let entry_size = be_u16(data, offset + 16)? as usize;
+                if !(channels == 1 || channels == 3) || entries < 2 {
+                    return Err(invalid_icc("unsupported 'vcgt' table layout").into());
+                }
+                let entry_size = match entry_size {
+                    1 | 2 => entry_size,
+                    _ => return Err(invalid_icc("unsupported 'vcgt' entry size").into()),
+                };
+
+                let table = offset + 18;
+                let sample = |channel: usize, index: usize| -> io::Result<f64> {
+                    let at = table + (channel * entries + index) * entry_size;
+                    Ok(match entry_size {
+                        1 => {
+                            f64::from(*data.get(at).ok_or_else(|| invalid_icc("truncated"))?)
+                                / 255.0
+                        }
+                        _ => f64::from(be_u16(data, at)?) / 65535.0,
+                    })
+                };
+                let channel = |channel: usize| -> io::Result<[u16; RAMP_SIZE]> {
+                    let mut ramp = [0; RAMP_SIZE];
+                    for (i, value) in ramp.iter_mut().enumerate() {
+                        // Resample linearly onto the 256 hardware entries
+                        let position = i as f64 * (entries - 1) as f64 / (RAMP_SIZE - 1) as f64;
+                        let low = position.floor() as usize;
+                        let high = (low + 1).min(entries - 1);
+                        let fraction = position - low as f64;
+                        let v = sample(channel, low)? * (1.0 - fraction)
+                            + sample(channel, high)? * fraction;
+                        *value = to_u16(v);
+                    }
+                    Ok(ramp)
+                };
+
+                let red = channel(0)?;
+                let (green, blue) = if channels == 3 {
+                    (channel(1)?, channel(2)?)
+                } else {
+                    (red, red)
+                };
+                Ok(Self { red, green, blue })
+            }
+            // Gamma, minimum, and maximum per channel
+            1 => {
+                let channel = |channel: usize| -> io::Result<[u16; RAMP_SIZE]> {
+                    let at = offset + 12 + channel * 12;
+                    let gamma = fixed(data, at)?;
+                    let min = fixed(data, at + 4)?;
+                    let max = fixed(data, at + 8)?;
+                    Ok(curve(|x| min + (max - min) * x.powf(gamma)))
+                };
+                Ok(Self {
+                    red: channel(0)?,
+                    green: channel(1)?,
+                    blue: channel(2)?,
+                })
+            }
+            _ => Err(invalid_icc("unknown 'vcgt' type").into()),
+        }
+    }
+
+    /// The ramp currently loaded on `device`, or on the primary display if `None`
+    pub fn current(device: Option<&str>) -> Result<Self> {
+        let device = device_name(device)?;
+        let name = to_wide(&device);
+        let hdc = unsafe { CreateDCW(name.as_ptr(), ptr::null(), ptr::null(), ptr::null()) };
+        if hdc.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut ramp = [[0; RAMP_SIZE]; 3];
+        let result = unsafe { GetDeviceGammaRamp(hdc, ramp.as_mut_ptr() as *mut _) };
+        unsafe { DeleteDC(hdc) };
+
+        if result == 0 {
+            return Err(
+                io::Error::other(format!("Failed to read gamma ramp of {}", device)).into(),
+            );
+        }
+
+        let [red, green, blue] = ramp;
+        Ok(Self { red, green, blue })
+    }
+
+    /// Load the ramp onto `device`, or onto the primary display if `None`
+    pub fn apply(&self, device: Option<&str>) -> Result<()> {
+        let device = device_name(device)?;
+        let name = to_wide(&device);
+        let hdc = unsafe { CreateDCW(name.as_ptr(), ptr::null(), ptr::null(), ptr::null()) };
+        if hdc.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut ramp = [self.red, self.green, self.blue];
+        let result = unsafe { SetDeviceGammaRamp(hdc, ramp.as_mut_ptr() as *mut _) };
+        unsafe { DeleteDC(hdc) };
+
+        if result == 0 {
+            // Windows also rejects ramps that stray too far from the identity curve
+            return Err(io::Error::other(format!("Failed to set gamma ramp on {}", device)).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// `device`, or the primary display's name if `None`
+fn device_name(device: Option<&str>) -> io::Result<String> {
+    match device {
+        Some(device) => Ok(device.to_string()),
+        None => display::attached_displays()
+            .into_iter()
+            .find(|display| display.primary)
+            .map(|display| display.name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No primary display")),
+    }
+}
+
+fn to_u16(value: f64) -> u16 {
+    (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+}
+
+fn curve<F: Fn(f64) -> f64>(f: F) -> [u16; RAMP_SIZE] {
+    let mut ramp = [0; RAMP_SIZE];
+    for (i, value) in ramp.iter_mut().enumerate() {
+        *value = to_u16(f(i as f64 / (RAMP_SIZE - 1) as f64));
+    }
+    ramp
+}
@@ -0,0 +1,157 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+
+use winapi::shared::rpcdce::{RPC_C_AUTHN_LEVEL_DEFAULT, RPC_C_IMP_LEVEL_IMPERSONATE};
+use winapi::shared::winerror::{FAILED, HRESULT, RPC_E_CHANGED_MODE, RPC_E_TOO_LATE};
+use winapi::shared::wtypes::{BSTR, VT_BSTR};
+use winapi::um::combaseapi::{CoInitializeEx, CoInitializeSecurity};
+use winapi::um::oaidl::VARIANT;
+use winapi::um::objbase::COINIT_MULTITHREADED;
+use winapi::um::objidlbase::EOAC_NONE;
+use winapi::um::oleauto::{SysAllocString, SysFreeString, SysStringLen, VariantClear};
+use winapi::um::unknwnbase::IUnknown;
+use winapi::um::wbemcli::IWbemClassObject;
+use winapi::Interface;
+
+use crate::wide::to_wide;
+
+/// Owned COM interface pointer, released on drop
+pub struct ComPtr<T: Interface>(*mut T);
+
+/// Owned `BSTR`
+pub struct Bstr(BSTR);
+
+impl<T: Interface> ComPtr<T> {
+    /// Take ownership of a non-null interface pointer returned by COM
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a valid interface pointer whose reference the caller owns.
+    pub unsafe fn from_raw(ptr: *mut T) -> Self {
+        debug_assert!(!ptr.is_null());
+        Self(ptr)
+    }
+
+    pub fn as_raw(&self) -> *mut T {
+        self.0
+    }
+}
+
+impl<T: Interface> Deref for ComPtr<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: Interface> Drop for ComPtr<T> {
+    fn drop(&mut self) {
+        unsafe { (*(self.0 as *mut IUnknown)).Release() };
+    }
+}
+
+impl Bstr {
+    pub fn new(s: &str) -> Self {
+        let wide = to_wide(s);
+        Self(unsafe { SysAllocString(wide.as_ptr()) })
+    }
+
+    pub fn as_raw(&self) -> BSTR {
+        self.0
+    }
+}
+
+impl Drop for Bstr {
+    fn drop(&mut self) {
+        unsafe { SysFreeString(self.0) };
+    }
+}
+
+/// Convert a failed `HRESULT` into an `io::Error`
+pub fn check(hr: HRESULT) -> io::Result<HRESULT> {
+    if FAILED(hr) {
+        Err(io::Error::from_raw_os_error(hr))
+    } else {
+        Ok(hr)
+    }
+}
+
+/// Join the multithreaded apartment on the calling thread and set process-wide default security,
+/// tolerating another component having done so first
+pub fn init_mta() -> io::Result<()> {
+    let hr = unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED) };
+    if hr != RPC_E_CHANGED_MODE {
+        check(hr)?;
+    }
+
+    let hr = unsafe {
+        CoInitializeSecurity(
+            ptr::null_mut(),
+            -1,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            RPC_C_AUTHN_LEVEL_DEFAULT,
+            RPC_C_IMP_LEVEL_IMPERSONATE,
+            ptr::null_mut(),
+            EOAC_NONE,
+            ptr::null_mut(),
+        )
+    };
+    if hr != RPC_E_TOO_LATE {
+        check(hr)?;
+    }
+
+    Ok(())
+}
+
+/// Read a string property of a WMI object, `None` if it is missing or not a string
+pub fn wmi_string(object: &IWbemClassObject, name: &str) -> Option<String> {
+    let name = to_wide(name);
+    let mut value: VARIANT = unsafe { std::mem::zeroed() };
+
+    let hr = unsafe {
+        object.Get(
+            name.as_ptr(),
+            0,
+            &mut value,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    if FAILED(hr) {
+        return None;
+    }
+
+    let result = unsafe {
+        let inner = value.n1.n2();
+        if u32::from(inner.vt) == VT_BSTR {
+            let bstr = *inner.n3.bstrVal();
+            let len = SysStringLen(bstr) as usize;
+            Some(String::from_utf16_lossy(slice::from_raw_parts(bstr, len)))
+        } else {
+            None
+        }
+    };
+    unsafe { VariantClear(&mut value) };
+
+    result
+}
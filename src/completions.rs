@@ -0,0 +1,339 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Write;
+
+use clap::{Command, ValueEnum};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Powershell,
+}
+
+/// Completions for one (sub)command, keyed by the words that lead to it, e.g. `amvideo profile`
+struct Node {
+    path: String,
+    subcommands: Vec<String>,
+    /// Fixed values accepted by positional arguments
+    arguments: Vec<String>,
+    flags: Vec<String>,
+    /// Flags that take one of a fixed set of values, with those values
+    values: Vec<(String, Vec<String>)>,
+}
+
+fn collect(command: &Command, path: String, nodes: &mut Vec<Node>) {
+    let subcommands: Vec<&Command> = command
+        .get_subcommands()
+        // clap's generated `help` subcommand only mirrors the tree
+        .filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help")
+        .collect();
+
+    let mut node = Node {
+        path: path.clone(),
+        subcommands: subcommands
+            .iter()
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect(),
+        arguments: Vec::new(),
+        flags: Vec::new(),
+        values: Vec::new(),
+    };
+    for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        let flags: Vec<String> = arg
+            .get_long()
+            .map(|long| format!("--{}", long))
+            .into_iter()
+            .chain(arg.get_short().map(|short| format!("-{}", short)))
+            .collect();
+        if arg.get_action().takes_values() {
+            let values: Vec<String> = arg
+                .get_possible_values()
+                .iter()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| value.get_name().to_string())
+                .collect();
+            if arg.is_positional() {
+                node.arguments.extend(values);
+            } else if !values.is_empty() {
+                for flag in &flags {
+                    node.values.push((flag.clone(), values.clone()));
+                }
+            }
+        }
+        node.flags.extend(flags);
+    }
+    nodes.push(node);
+
+    for subcommand in subcommands {
+        collect(
+            subcommand,
+            format!("{} {}", path, subcommand.get_name()),
+            nodes,
+        );
+    }
+}
+
+/// Completion script for `shell` covering every subcommand, flag, and enumerated flag value of
+/// `command`
+pub fn generate(shell: Shell, mut command: Command) -> String {
+    // Propagates global flags into every subcommand
+    command.build();
+
+    let name = command.get_name().to_string();
+    let mut nodes = Vec::new();
+    collect(&command, name.clone(), &mut nodes);
+
+    match shell {
+        Shell::Bash => bash(&name, &nodes),
+        Shell::Zsh => zsh(&name, &nodes),
+        Shell::Powershell => powershell(&name, &nodes),
+    }
+}
+
+fn words(node: &Node) -> Vec<String> {
+    node.subcommands
+        .iter()
+        .chain(node.arguments.iter())
+        .chain(node.flags.iter())
+        .cloned()
+        .collect()
+}
+
+/// `case` patterns matching a word that descends into a subcommand, e.g. `"amvideo profile"`
+fn subcommand_patterns(nodes: &[Node]) -> String {
+    nodes
+        .iter()
+        .skip(1)
+        .map(|node| format!("\"{}\"", node.path))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn bash(name: &str, nodes: &[Node]) -> String {
+    let mut script = String::new();
+    let function = format!("_{}", name.replace('-', "_"));
+
+    writeln!(script, "{}() {{", function).unwrap();
+    writeln!(script, "    local cur prev cmd words i").unwrap();
+    writeln!(script, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"").unwrap();
+    writeln!(script, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").unwrap();
+    writeln!(script, "    cmd=\"{}\"", name).unwrap();
+    writeln!(script, "    for ((i = 1; i < COMP_CWORD; i++)); do").unwrap();
+    writeln!(script, "        case \"${{cmd}} ${{COMP_WORDS[i]}}\" in").unwrap();
+    writeln!(
+        script,
+        "            {}) cmd=\"${{cmd}} ${{COMP_WORDS[i]}}\" ;;",
+        subcommand_patterns(nodes)
+    )
+    .unwrap();
+    writeln!(script, "        esac").unwrap();
+    writeln!(script, "    done").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    case \"${{cmd}}|${{prev}}\" in").unwrap();
+    for node in nodes {
+        for (flag, values) in &node.values {
+            writeln!(
+                script,
+                "        \"{}|{}\") words=\"{}\" ;;",
+                node.path,
+                flag,
+                values.join(" ")
+            )
+            .unwrap();
+        }
+    }
+    writeln!(script, "        *)").unwrap();
+    writeln!(script, "            case \"${{cmd}}\" in").unwrap();
+    for node in nodes {
+        writeln!(
+            script,
+            "                \"{}\") words=\"{}\" ;;",
+            node.path,
+            words(node).join(" ")
+        )
+        .unwrap();
+    }
+    writeln!(script, "            esac").unwrap();
+    writeln!(script, "            ;;").unwrap();
+    writeln!(script, "    esac").unwrap();
+    writeln!(script).unwrap();
+    writeln!(
+        script,
+        "    COMPREPLY=($(compgen -W \"${{words}}\" -- \"${{cur}}\"))"
+    )
+    .unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "complete -F {} {} {}.exe", function, name, name).unwrap();
+
+    script
+}
+
+fn zsh(name: &str, nodes: &[Node]) -> String {
+    let mut script = String::new();
+    let function = format!("_{}", name.replace('-', "_"));
+
+    // `path` is tied to `PATH` in zsh, hence `cmd`
+    writeln!(script, "#compdef {} {}.exe", name, name).unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "{}() {{", function).unwrap();
+    writeln!(script, "    local cmd=\"{}\" i", name).unwrap();
+    writeln!(script, "    for ((i = 2; i < CURRENT; i++)); do").unwrap();
+    writeln!(script, "        case \"${{cmd}} ${{words[i]}}\" in").unwrap();
+    writeln!(
+        script,
+        "            ({}) cmd=\"${{cmd}} ${{words[i]}}\" ;;",
+        subcommand_patterns(nodes)
+    )
+    .unwrap();
+    writeln!(script, "        esac").unwrap();
+    writeln!(script, "    done").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    case \"${{cmd}}|${{words[CURRENT-1]}}\" in").unwrap();
+    for node in nodes {
+        for (flag, values) in &node.values {
+            writeln!(
+                script,
+                "        (\"{}|{}\") compadd -- {}; return ;;",
+                node.path,
+                flag,
+                values.join(" ")
+            )
+            .unwrap();
+        }
+    }
+    writeln!(script, "    esac").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    case \"${{cmd}}\" in").unwrap();
+    for node in nodes {
+        writeln!(
+            script,
+            "        (\"{}\") compadd -- {} ;;",
+            node.path,
+            words(node).join(" ")
+        )
+        .unwrap();
+    }
+    writeln!(script, "    esac").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "compdef {} {} {}.exe", function, name, name).unwrap();
+
+    script
+}
+
+fn powershell_list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|item| format!("'{}'", item)).collect();
+    format!("@({})", items.join(", "))
+}
+
+fn powershell(name: &str, nodes: &[Node]) -> String {
+    let mut script = String::new();
+
+    writeln!(
+        script,
+        "Register-ArgumentCompleter -Native -CommandName '{}', '{}.exe' -ScriptBlock {{",
+        name, name
+    )
+    .unwrap();
+    writeln!(
+        script,
+        "    param($wordToComplete, $commandAst, $cursorPosition)"
+    )
+    .unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    $subcommands = @{{").unwrap();
+    for node in nodes {
+        writeln!(
+            script,
+            "        '{}' = {}",
+            node.path,
+            powershell_list(&node.subcommands)
+        )
+        .unwrap();
+    }
+    writeln!(script, "    }}").unwrap();
+    writeln!(script, "    $words = @{{").unwrap();
+    for node in nodes {
+        writeln!(
+            script,
+            "        '{}' = {}",
+            node.path,
+            powershell_list(&words(node))
+        )
+        .unwrap();
+    }
+    writeln!(script, "    }}").unwrap();
+    writeln!(script, "    $values = @{{").unwrap();
+    for node in nodes {
+        for (flag, values) in &node.values {
+            writeln!(
+                script,
+                "        '{}|{}' = {}",
+                node.path,
+                flag,
+                powershell_list(values)
+            )
+            .unwrap();
+        }
+    }
+    writeln!(script, "    }}").unwrap();
+    writeln!(script).unwrap();
+    // Only the words before the one being completed decide where we are
+    writeln!(script, "    $path = '{}'", name).unwrap();
+    writeln!(script, "    $prev = ''").unwrap();
+    writeln!(
+        script,
+        "    foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {{"
+    )
+    .unwrap();
+    writeln!(
+        script,
+        "        if ($element.Extent.EndOffset -ge $cursorPosition) {{ break }}"
+    )
+    .unwrap();
+    writeln!(script, "        $word = $element.ToString()").unwrap();
+    writeln!(
+        script,
+        "        if ($subcommands[$path] -contains $word) {{ $path = \"$path $word\" }}"
+    )
+    .unwrap();
+    writeln!(script, "        $prev = $word").unwrap();
+    writeln!(script, "    }}").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "    $candidates = $values[\"$path|$prev\"]").unwrap();
+    writeln!(
+        script,
+        "    if ($null -eq $candidates) {{ $candidates = $words[$path] }}"
+    )
+    .unwrap();
+    writeln!(
+        script,
+        "    $candidates | Where-Object {{ $_ -like \"$wordToComplete*\" }} | ForEach-Object {{"
+    )
+    .unwrap();
+    writeln!(
+        script,
+        "        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)"
+    )
+    .unwrap();
+    writeln!(script, "    }}").unwrap();
+    writeln!(script, "}}").unwrap();
+
+    script
+}
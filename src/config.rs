@@ -0,0 +1,859 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use amvideo::amlib::ExportMap;
+use amvideo::aspect::{self, Ratio};
+use amvideo::color::GammaRamp;
+use amvideo::display;
+use amvideo::layout::{Layout, OutputLayout};
+use amvideo::nvapi::VrrMode;
+use amvideo::topology::{self, Link, Scaling};
+use amvideo::{AmVideoMode, AmVideoResolution, AmVideoSetting, AmVideoSettingExtras};
+
+use crate::control::ControlCommandName;
+use crate::migrate;
+use crate::schedule::{TimeOfDay, Weekday};
+use crate::timeout;
+
+pub const DEFAULT_CONFIG_NAME: &str = "amvideo.toml";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Kept as raw tables so partial profiles can be composed, see [`Config::profile`]
+    #[serde(default)]
+    pub profiles: BTreeMap<String, toml::Table>,
+    #[serde(default)]
+    pub control: ControlConfig,
+    pub update: Option<UpdateConfig>,
+    pub virtual_display: Option<VirtualDisplayConfig>,
+    /// Export ordinals or names for DLL variants that moved them
+    pub exports: Option<ExportMap>,
+    /// Broker the daemon publishes apply events, status, and health to
+    pub mqtt: Option<MqttConfig>,
+    /// Central endpoint the daemon pulls its profile set from
+    pub pull: Option<PullConfig>,
+    /// Ed25519 public keys in hex, by name, whose signed bundles are accepted
+    #[serde(default)]
+    pub trust: BTreeMap<String, String>,
+    /// Limits overriding `--timeout` for single stages
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    /// Profiles the daemon switches to by time of day, the first rule open winning
+    #[serde(default)]
+    pub schedule: Vec<ScheduleRule>,
+}
+
+/// Named set of parameters for `amDllVideoSetResolution`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default = "default_mode")]
+    pub mode: AmVideoMode,
+    pub resolution: AmVideoResolution,
+    /// Resolution of the second display in dual mode, defaults to `resolution`
+    pub secondary_resolution: Option<AmVideoResolution>,
+    /// Refresh rate in Hz to run the primary display at, e.g. 120 for newer ALLS titles. Left
+    /// at whatever the DLL picks if unset.
+    pub refresh_rate: Option<u32>,
+    #[serde(default = "default_segatiming")]
+    pub segatiming: bool,
+    /// Calibration to reload after the mode switch resets it
+    pub color: Option<ColorConfig>,
+    /// Panel controls to send over DDC/CI
+    pub ddc: Option<DdcConfig>,
+    /// NVIDIA G-SYNC mode to switch to, left alone if unset. VRR judders with SegaTiming's
+    /// fixed-rate modes.
+    pub vrr: Option<VrrMode>,
+    /// Layout file with per-output resolution, position, rotation, and enabled state, applied
+    /// after the amVideo mode
+    pub layout: Option<PathBuf>,
+    /// Rotation and position of the second display in dual mode, applied after the DLL call
+    pub secondary: Option<SecondaryConfig>,
+    /// Settings per display, by GDI device name or monitor name, applied together after the DLL
+    /// call and rolled back together if any of them fails
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub displays: BTreeMap<String, DisplayConfig>,
+    /// Display to make the Windows primary, since several titles render only to the primary
+    pub primary: Option<PrimaryConfig>,
+    /// Apply even over a Surround or Eyefinity spanning group, only warning about it
+    #[serde(default)]
+    pub allow_spanning: bool,
+    /// Seconds to wait for the new settings to be confirmed before reverting them, so a mode
+    /// the panel cannot show rolls itself back
+    pub confirm_within: Option<u64>,
+    /// segatools.ini to keep in step with the applied display setup
+    pub segatools: Option<SegatoolsConfig>,
+    /// `AmVideoContext` version to open the DLL with, for builds missing from the build database
+    pub context_version: Option<u32>,
+    /// Bytes to read the VBIOS version into at first, grown if the string does not fit
+    pub vbios_buffer: Option<u32>,
+    /// GPU scaling to switch every display to after the mode change, since non-native modes
+    /// otherwise get whatever scaling the driver was last set to
+    pub scaling: Option<Scaling>,
+    /// Apply the primary panel's mode of its own aspect ratio closest to `resolution` instead,
+    /// when the two ratios differ
+    #[serde(default)]
+    pub match_aspect: bool,
+    /// Keep the display from sleeping or blanking while the profile is active, until `restore`
+    #[serde(default)]
+    pub kiosk: bool,
+    /// What to do about displays running with Windows HDR on
+    #[serde(default)]
+    pub hdr: HdrPolicy,
+    /// Link to the primary display when it is slower than the GPU connector suggests, e.g. a
+    /// single-link DVI adapter on a DisplayPort output
+    pub link: Option<Link>,
+    /// Second display's link in dual mode, likewise
+    pub secondary_link: Option<Link>,
+    /// Create `resolution` through NVAPI when it is missing from the primary display's modes
+    pub custom_resolution: Option<CustomResolutionConfig>,
+    /// Make the display's own speakers the default audio output while the profile is active
+    pub audio: Option<AudioConfig>,
+}
+
+/// Gamma ramp loaded onto a display after a profile is applied. Exactly one of `icc` and
+/// `gamma` must be set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ColorConfig {
+    /// ICC profile whose `vcgt` calibration curves are loaded
+    pub icc: Option<PathBuf>,
+    /// Plain gamma exponent. Values above 1.0 brighten midtones.
+    pub gamma: Option<f64>,
+    /// GDI device name, e.g. `\\.\DISPLAY1`, defaults to the primary display
+    pub device: Option<String>,
+}
+
+/// Control surfaces served by `amvideo daemon`. Each is disabled unless configured.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ControlConfig {
+    pub tcp: Option<TcpControlConfig>,
+    pub pipe: Option<PipeControlConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TcpControlConfig {
+    pub listen: SocketAddr,
+    /// Pre-shared token clients must present. Required unless `token_file` is set.
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    #[serde(default = "default_allow")]
+    pub allow: Vec<ControlCommandName>,
+    /// Clients with their own tokens and narrower or wider command lists
+    #[serde(default)]
+    pub clients: Vec<ControlClientConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PipeControlConfig {
+    /// Pipe name under `\\.\pipe\`
+    #[serde(default = "default_pipe_name")]
+    pub name: String,
+    /// Security descriptor for the pipe, Administrators and SYSTEM only by default
+    #[serde(default = "default_pipe_sddl")]
+    pub sddl: String,
+    /// Optional token required in addition to the pipe ACL
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    #[serde(default = "default_allow")]
+    pub allow: Vec<ControlCommandName>,
+    /// Clients with their own tokens and narrower or wider command lists
+    #[serde(default)]
+    pub clients: Vec<ControlClientConfig>,
+}
+
+/// Control client identified by its token, e.g. a monitoring system that may only read `status`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ControlClientConfig {
+    /// Name logged with the client's requests
+    pub name: String,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub allow: Vec<ControlCommandName>,
+}
+
+/// Where `amvideo self-update` looks for releases, and the key they must be signed with
+#[derive(Clone, Debug, Deserialize)]
+pub struct UpdateConfig {
+    /// Release manifest, over HTTP(S) or as a local path such as one on a USB stick
+    pub url: Option<String>,
+    /// ECDSA P-256 public key in hex, as the raw point or a DER `SubjectPublicKeyInfo`
+    pub public_key: String,
+}
+
+/// MQTT broker for fleet telemetry, published to under `<topic_prefix>/<cabinet>`
+#[derive(Clone, Debug, Deserialize)]
+pub struct MqttConfig {
+    /// Broker address, e.g. `telemetry.venue.lan:1883`
+    pub broker: String,
+    /// Name of this cab in the topic, defaults to the computer name
+    pub cabinet: Option<String>,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+}
+
+/// Signed profile set the daemon polls for, so a fleet can be reconfigured from one place
+#[derive(Clone, Debug, Deserialize)]
+pub struct PullConfig {
+    /// TOML document of `[profiles.*]` tables, over HTTP(S) or as a local path
+    pub url: String,
+    /// Detached signature of the document, defaults to `url` with `.sig` appended
+    pub signature_url: Option<String>,
+    /// ECDSA P-256 public key in hex, as the raw point or a DER `SubjectPublicKeyInfo`
+    pub public_key: String,
+    /// Seconds between polls
+    #[serde(default = "default_pull_interval")]
+    pub interval: u64,
+}
+
+/// Virtual monitor driver behind `amvideo virtual-display`, such as usbmmidd, so dual mode
+/// titles run on machines with a single monitor
+#[derive(Clone, Debug, Deserialize)]
+pub struct VirtualDisplayConfig {
+    /// Driver control tool, e.g. usbmmidd's `deviceinstaller64.exe`
+    pub tool: PathBuf,
+    /// Arguments that add the virtual monitor
+    #[serde(default = "default_virtual_display_create")]
+    pub create: Vec<String>,
+    /// Arguments that remove it
+    #[serde(default = "default_virtual_display_destroy")]
+    pub destroy: Vec<String>,
+    /// Seconds to wait for the added monitor to attach to the desktop
+    #[serde(default = "default_virtual_display_timeout")]
+    pub timeout: u64,
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "amvideo".to_string()
+}
+
+fn default_pull_interval() -> u64 {
+    300
+}
+
+fn default_virtual_display_create() -> Vec<String> {
+    vec!["enableidd".to_string(), "1".to_string()]
+}
+
+fn default_virtual_display_destroy() -> Vec<String> {
+    vec!["enableidd".to_string(), "0".to_string()]
+}
+
+const fn default_virtual_display_timeout() -> u64 {
+    10
+}
+
+const fn default_mode() -> AmVideoMode {
+    AmVideoMode::Single
+}
+
+const fn default_segatiming() -> bool {
+    true
+}
+
+fn default_allow() -> Vec<ControlCommandName> {
+    vec![ControlCommandName::Status]
+}
+
+fn default_pipe_name() -> String {
+    "amvideo".to_string()
+}
+
+fn default_pipe_sddl() -> String {
+    "D:P(A;;GA;;;BA)(A;;GA;;;SY)".to_string()
+}
+
+impl Config {
+    /// Load the config at `path`, or `amvideo.toml` next to the executable if `None`
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => default_path()?,
+        };
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config '{}'", path.display()))?;
+        let table = migrate::load(&path, &contents)?;
+        let config = Self::deserialize(toml::Value::Table(table))
+            .with_context(|| format!("Failed to parse config '{}'", path.display()))?;
+        config
+            .check()
+            .with_context(|| format!("Invalid config '{}'", path.display()))?;
+        timeout::configure(&config.timeouts);
+        Ok(config)
+    }
+
+    /// Look up a profile, or compose one from several joined with `+`, e.g.
+    /// `alls-ux+chunithm`. Each profile's keys override those of the profiles before it, and
+    /// tables such as `color` are merged key by key.
+    pub fn profile(&self, name: &str) -> Result<Profile> {
+        let mut merged = toml::Table::new();
+        for part in name.split('+') {
+            let table = self
+                .profiles
+                .get(part)
+                .ok_or_else(|| anyhow!("No profile named '{}' in the config", part))?;
+            merge(&mut merged, table);
+        }
+
+        Profile::deserialize(toml::Value::Table(merged))
+            .with_context(|| format!("Invalid profile '{}'", name))
+    }
+
+    /// Check every profile, and every composition the schedule names, deserializes into a
+    /// [`Profile`]
+    pub fn check(&self) -> Result<()> {
+        check_profiles(&self.profiles)?;
+        for rule in &self.schedule {
+            self.profile(&rule.profile)?;
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize each of `profiles` into a [`Profile`], so a misspelled key or a mistyped value is
+/// reported when they are loaded instead of when one is first applied. A part meant only for
+/// composing may leave `resolution` to the profiles it is composed with.
+pub fn check_profiles(profiles: &BTreeMap<String, toml::Table>) -> Result<()> {
+    for (name, table) in profiles {
+        let mut part = table.clone();
+        part.entry("resolution")
+            .or_insert_with(|| toml::Value::String("1920x1080".to_string()));
+        Profile::deserialize(toml::Value::Table(part))
+            .with_context(|| format!("Invalid profile '{}'", name))?;
+    }
+    Ok(())
+}
+
+/// Deep-merge `overlay` into `base`
+fn merge(base: &mut toml::Table, overlay: &toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl Profile {
+    pub fn setting(&self) -> AmVideoSetting {
+        let mut setting = AmVideoSetting::new(
+            self.mode,
+            self.resolution,
+            self.secondary_resolution.unwrap_or(self.resolution),
+        );
+        setting.use_segatiming = u32::from(self.segatiming);
+
+        setting
+    }
+
+    /// Refresh rates and rotations for builds taking the version 2 setting layout. The first
+    /// display takes `refresh_rate` and the primary role's `displays` entry, the second
+    /// `secondary` and its entry, or the only secondary role entry.
+    pub fn setting_extras(&self) -> AmVideoSettingExtras {
+        let only = |role| {
+            let mut displays = self
+                .displays
+                .values()
+                .filter(|display| display.role == role);
+            match (displays.next(), displays.next()) {
+                (Some(display), None) => Some(display),
+                _ => None,
+            }
+        };
+        let primary = only(DisplayRole::Primary);
+        let secondary = self
+            .secondary
+            .as_ref()
+            .and_then(|secondary| secondary.display.as_ref())
+            .and_then(|name| self.displays.get(name))
+            .or_else(|| only(DisplayRole::Secondary));
+
+        let refresh_rate_1 = self
+            .refresh_rate
+            .or_else(|| primary.and_then(|display| display.refresh))
+            .unwrap_or(0);
+        let rotation_2 = self
+            .secondary
+            .as_ref()
+            .and_then(|secondary| secondary.rotation)
+            .or_else(|| secondary.and_then(|display| display.rotation))
+            .unwrap_or(0);
+        let (refresh_rate_2, rotation_2) = match self.mode {
+            AmVideoMode::Single => (0, 0),
+            // The second display shows the same mode
+            AmVideoMode::CloneVideoMode => (refresh_rate_1, rotation_2),
+            AmVideoMode::DualVideoMode => (
+                secondary.and_then(|display| display.refresh).unwrap_or(0),
+                rotation_2,
+            ),
+        };
+
+        AmVideoSettingExtras {
+            refresh_rate_1,
+            refresh_rate_2,
+            rotation_1: primary.and_then(|display| display.rotation).unwrap_or(0),
+            rotation_2,
+        }
+    }
+
+    /// Resolution the primary display should end up at: `resolution`, or with `match_aspect` the
+    /// closest mode of the panel's aspect ratio
+    pub fn fitted_resolution(&self) -> AmVideoResolution {
+        if !self.match_aspect {
+            return self.resolution;
+        }
+        let panel = match aspect::native_resolution(None) {
+            Ok(panel) => panel,
+            Err(_) => return self.resolution,
+        };
+        if Ratio::from(&self.resolution).matches(Ratio::from(&panel)) {
+            return self.resolution;
+        }
+
+        aspect::nearest_same_aspect(&self.resolution, &panel, &display::supported_modes(None))
+            .unwrap_or(self.resolution)
+    }
+
+    /// The `displays` map as one layout, if there is one, with the roles checked against each
+    /// other and the positions
+    pub fn display_layout(&self) -> Result<Option<Layout>> {
+        if self.displays.is_empty() {
+            return Ok(None);
+        }
+
+        let primaries = self
+            .displays
+            .values()
+            .filter(|display| display.role == DisplayRole::Primary)
+            .count();
+        if primaries > 1 {
+            return Err(anyhow!("Only one display can have the primary role"));
+        }
+
+        let outputs = self
+            .displays
+            .iter()
+            .map(|(name, display)| {
+                let position = match (display.role, display.position) {
+                    (DisplayRole::Primary, None) => Some((0, 0)),
+                    (DisplayRole::Primary, Some(position)) if position != (0, 0) => {
+                        return Err(anyhow!(
+                            "Primary display {} must be at [0, 0], not {:?}",
+                            name,
+                            position
+                        ));
+                    }
+                    (DisplayRole::Secondary, Some((0, 0))) => {
+                        return Err(anyhow!(
+                            "Display {} at [0, 0] would be the primary, give it the primary role",
+                            name
+                        ));
+                    }
+                    (_, position) => position,
+                };
+
+                Ok(OutputLayout {
+                    display: name.clone(),
+                    enabled: display.role != DisplayRole::Disabled,
+                    resolution: display.resolution,
+                    refresh: display.refresh,
+                    position,
+                    rotation: display.rotation,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Layout { outputs }))
+    }
+}
+
+/// One display of a profile's `displays` map. Anything left unset keeps its current value.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DisplayConfig {
+    /// Resolution before rotation
+    pub resolution: Option<AmVideoResolution>,
+    pub refresh: Option<u32>,
+    /// Clockwise rotation in degrees: 0, 90, 180, or 270
+    pub rotation: Option<u32>,
+    /// Top-left corner on the desktop
+    pub position: Option<(i32, i32)>,
+    #[serde(default)]
+    pub role: DisplayRole,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayRole {
+    /// The Windows primary display, at the desktop origin
+    Primary,
+    #[default]
+    Secondary,
+    /// Detached from the desktop
+    Disabled,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PrimaryConfig {
+    /// GDI device name (e.g. `\\.\DISPLAY2`) or monitor name
+    pub display: String,
+    #[serde(default)]
+    pub when: PrimaryWhen,
+}
+
+/// Placement of the marquee or sub monitor in dual mode
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SecondaryConfig {
+    /// GDI device name or monitor name, defaults to the first display that is not the primary
+    pub display: Option<String>,
+    /// Clockwise rotation in degrees: 0, 90, 180, or 270
+    pub rotation: Option<u32>,
+    /// Top-left corner on the desktop
+    pub position: Option<(i32, i32)>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CustomResolutionConfig {
+    /// Remove the created mode again when the starting settings are restored, instead of
+    /// leaving it in the driver for next time
+    #[serde(default)]
+    pub remove_on_restore: bool,
+}
+
+/// Profile applied by the daemon while the window from `from` to `to` is open
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScheduleRule {
+    pub profile: String,
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+    /// Days the window opens on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+/// Limits in seconds, 0 for none. Unset stages take `--timeout`, or their default.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TimeoutsConfig {
+    /// Each DLL call, default 60
+    pub dll: Option<u64>,
+    /// Waiting for a display to come up, default 60
+    pub ready: Option<u64>,
+    /// Polling for the applied mode to show, default 10
+    pub verify: Option<u64>,
+    /// Daemon control requests, default 30
+    pub ipc: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AudioConfig {
+    /// GDI device name or monitor name whose audio endpoint is chosen, defaults to the primary
+    /// display
+    pub display: Option<String>,
+    /// Part of the endpoint's name to match instead, for endpoints not named after the monitor
+    pub endpoint: Option<String>,
+    /// Leave the endpoint as the default on restore instead of switching back
+    #[serde(default)]
+    pub keep: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SegatoolsConfig {
+    pub path: PathBuf,
+    /// Fix mismatches instead of only warning about them
+    #[serde(default)]
+    pub write: bool,
+}
+
+/// Handling of Windows HDR, which washes out colors in SegaTiming modes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HdrPolicy {
+    /// Leave HDR on, only warning about it
+    #[default]
+    Warn,
+    /// Turn HDR off before the mode change and leave it off
+    Disable,
+    /// Turn HDR off for the mode change and back on once it is done
+    Suspend,
+}
+
+/// Whether to switch the primary display before or after the amVideo mode is applied
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrimaryWhen {
+    Before,
+    #[default]
+    After,
+}
+
+/// Panel settings sent over DDC/CI after a profile is applied, as percentages of the range each
+/// monitor reports
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DdcConfig {
+    pub brightness: Option<u8>,
+    pub contrast: Option<u8>,
+    /// GDI device name, defaults to the primary display
+    pub device: Option<String>,
+}
+
+impl PrimaryConfig {
+    /// GDI device name of the display `display` refers to
+    pub fn device(&self) -> Result<String> {
+        topology::resolve_display(&self.display).map_err(Into::into)
+    }
+}
+
+impl SecondaryConfig {
+    /// GDI device name of the secondary display
+    pub fn device(&self) -> Result<String> {
+        if let Some(display) = &self.display {
+            return topology::resolve_display(display).map_err(Into::into);
+        }
+
+        display::attached_displays()
+            .into_iter()
+            .find(|display| !display.primary)
+            .map(|display| display.name)
+            .ok_or_else(|| anyhow!("No secondary display is attached"))
+    }
+}
+
+impl ColorConfig {
+    pub fn ramp(&self) -> Result<GammaRamp> {
+        match (&self.icc, self.gamma) {
+            (Some(path), None) => {
+                let data = fs::read(path)
+                    .with_context(|| format!("Failed to read ICC profile '{}'", path.display()))?;
+                GammaRamp::from_icc(&data)
+                    .with_context(|| format!("Failed to load ICC profile '{}'", path.display()))
+            }
+            (None, Some(gamma)) if gamma > 0.0 => Ok(GammaRamp::from_gamma(gamma)),
+            (None, Some(gamma)) => Err(anyhow!("Gamma must be positive, got {}", gamma)),
+            _ => Err(anyhow!("Exactly one of 'icc' and 'gamma' must be set")),
+        }
+    }
+}
+
+/// Load a multi-display layout file, as JSON if the extension is `.json` and TOML otherwise
+pub fn load_layout(path: &Path) -> Result<Layout> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read layout '{}'", path.display()))?;
+    let is_json = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let layout = if is_json {
+        serde_json::from_str(&contents).map_err(anyhow::Error::from)
+    } else {
+        toml::from_str(&contents).map_err(anyhow::Error::from)
+    };
+
+    layout.with_context(|| format!("Failed to parse layout '{}'", path.display()))
+}
+
+/// Resolve an inline or file-based token setting. An empty token counts as none, since it would
+/// match a request that presents none.
+pub fn load_token(token: &Option<String>, token_file: &Option<PathBuf>) -> Result<Option<String>> {
+    let token = match (token, token_file) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!("Only one of 'token' and 'token_file' may be set"))
+        }
+        (Some(token), None) => Some(token.clone()),
+        (None, Some(path)) => {
+            let token = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read token file '{}'", path.display()))?;
+            Some(token.trim().to_string())
+        }
+        (None, None) => None,
+    };
+    Ok(token.filter(|token| !token.is_empty()))
+}
+
+pub fn default_path() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    Ok(exe.with_file_name(DEFAULT_CONFIG_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(contents: &str) -> Config {
+        toml::from_str(contents).unwrap()
+    }
+
+    const PROFILES: &str = r#"
+        [profiles.base]
+        resolution = "1920x1080"
+        refresh_rate = 60
+        color = { gamma = 2.2, device = '\\.\DISPLAY1' }
+
+        [profiles.tall]
+        resolution = "1080x1920"
+
+        [profiles.bright]
+        refresh_rate = 120
+        color = { gamma = 1.8 }
+    "#;
+
+    #[test]
+    fn looks_up_single_profile() {
+        let profile = config(PROFILES).profile("tall").unwrap();
+        assert_eq!(profile.resolution.to_string(), "1080x1920");
+        assert_eq!(profile.refresh_rate, None);
+    }
+
+    #[test]
+    fn later_parts_override_earlier_ones() {
+        let config = config(PROFILES);
+        let profile = config.profile("base+tall+bright").unwrap();
+        assert_eq!(profile.resolution.to_string(), "1080x1920");
+        assert_eq!(profile.refresh_rate, Some(120));
+
+        let profile = config.profile("bright+base").unwrap();
+        assert_eq!(profile.refresh_rate, Some(60));
+    }
+
+    #[test]
+    fn merges_tables_key_by_key() {
+        let color = config(PROFILES)
+            .profile("base+bright")
+            .unwrap()
+            .color
+            .unwrap();
+        assert_eq!(color.gamma, Some(1.8));
+        assert_eq!(color.device.as_deref(), Some(r"\\.\DISPLAY1"));
+    }
+
+    #[test]
+    fn rejects_missing_part() {
+        let error = config(PROFILES).profile("base+missing").unwrap_err();
+        assert!(error.to_string().contains("'missing'"));
+    }
+
+    #[test]
+    fn rejects_composition_without_resolution() {
+        assert!(config(PROFILES).profile("bright").is_err());
+    }
+
+    #[test]
+    fn accepts_partial_parts() {
+        config(PROFILES).check().unwrap();
+    }
+
+    #[test]
+    fn rejects_misspelled_key() {
+        let config = config("[profiles.a]\nresolution = \"1920x1080\"\nrefresh = 120\n");
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn rejects_mistyped_value() {
+        let config = config("[profiles.a]\nrefresh_rate = \"fast\"\n");
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn setting_extras_from_profile() {
+        let config = config(
+            r#"
+            [profiles.dual]
+            mode = "dual"
+            resolution = "1920x1080"
+            refresh_rate = 120
+            secondary = { display = "marquee", rotation = 90 }
+            displays.main = { role = "primary", rotation = 180 }
+            displays.marquee = { refresh = 60 }
+
+            [profiles.clone]
+            mode = "clone"
+            resolution = "1920x1080"
+            refresh_rate = 60
+
+            [profiles.single]
+            resolution = "1920x1080"
+            secondary = { rotation = 90 }
+            displays.a = { refresh = 75 }
+        "#,
+        );
+
+        let extras = |name| config.profile(name).unwrap().setting_extras();
+        assert_eq!(
+            extras("dual"),
+            AmVideoSettingExtras {
+                refresh_rate_1: 120,
+                refresh_rate_2: 60,
+                rotation_1: 180,
+                rotation_2: 90,
+            }
+        );
+        assert_eq!(
+            extras("clone"),
+            AmVideoSettingExtras {
+                refresh_rate_1: 60,
+                refresh_rate_2: 60,
+                ..AmVideoSettingExtras::default()
+            }
+        );
+        assert_eq!(extras("single"), AmVideoSettingExtras::default());
+    }
+
+    #[test]
+    fn empty_token_is_none() {
+        assert_eq!(load_token(&Some(String::new()), &None).unwrap(), None);
+        assert_eq!(
+            load_token(&Some("secret".to_string()), &None).unwrap(),
+            Some("secret".to_string())
+        );
+        assert_eq!(load_token(&None, &None).unwrap(), None);
+
+        let path = env::temp_dir().join(format!("amvideo-token-{}", std::process::id()));
+        fs::write(&path, " \r\n").unwrap();
+        let token = load_token(&None, &Some(path.clone()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(token.unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_schedule_that_does_not_compose() {
+        let schedule = r#"
+            [[schedule]]
+            profile = "bright"
+            from = "08:00"
+            to = "20:00"
+        "#;
+        assert!(config(&format!("{}{}", PROFILES, schedule))
+            .check()
+            .is_err());
+
+        let schedule = schedule.replace("\"bright\"", "\"base+bright\"");
+        config(&format!("{}{}", PROFILES, schedule))
+            .check()
+            .unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use amvideo::color::GammaRamp;
+use amvideo::ddc::{PhysicalMonitors, VCP_BRIGHTNESS, VCP_CONTRAST};
+use amvideo::hdr;
+use amvideo::snapshot::Snapshot;
+
+use crate::audit::{self, Event};
+use crate::config::Profile;
+use crate::revert;
+
+/// Countdown waiting for the user to keep the settings, if one is running
+static PENDING: Mutex<Option<Sender<()>>> = Mutex::new(None);
+static STDIN: Once = Once::new();
+
+/// A VCP control and its raw value on each monitor
+type VcpValues = (u8, Vec<u32>);
+
+/// What an apply of a profile may change, captured beforehand so an unconfirmed apply can be
+/// undone completely
+pub struct Rollback {
+    snapshot: Snapshot,
+    /// Displays that had HDR on
+    hdr: Vec<String>,
+    /// Ramp on the profile's gamma device, if it loads one
+    gamma: Option<(Option<String>, GammaRamp)>,
+    /// Raw VCP values on the profile's DDC/CI device, by control
+    ddc: Option<(Option<String>, Vec<VcpValues>)>,
+}
+
+impl Rollback {
+    /// Capture the display settings, and whatever else of `profile` the restore paths do not
+    /// undo. Only a failure to capture the display settings is fatal; the rest is reported and
+    /// left as the apply sets it.
+    pub fn capture(profile: &Profile) -> Result<Self> {
+        let snapshot = Snapshot::capture().context("Failed to capture the display settings")?;
+
+        let hdr = match hdr::query() {
+            Ok(states) => states
+                .into_iter()
+                .filter(|state| state.enabled)
+                .map(|state| state.device)
+                .collect(),
+            Err(e) => {
+                eprintln!("Not rolling back HDR: {}", e);
+                Vec::new()
+            }
+        };
+
+        let gamma = profile.color.as_ref().and_then(|color| {
+            let device = color.device.clone();
+            match GammaRamp::current(device.as_deref()) {
+                Ok(ramp) => Some((device, ramp)),
+                Err(e) => {
+                    eprintln!("Not rolling back the gamma ramp: {}", e);
+                    None
+                }
+            }
+        });
+
+        let ddc = profile.ddc.as_ref().and_then(|ddc| {
+            let codes = [
+                (VCP_BRIGHTNESS, ddc.brightness),
+                (VCP_CONTRAST, ddc.contrast),
+            ];
+            let result = PhysicalMonitors::open(ddc.device.as_deref()).and_then(|monitors| {
+                codes
+                    .iter()
+                    .filter(|(_, value)| value.is_some())
+                    .map(|&(code, _)| monitors.get(code).map(|values| (code, values)))
+                    .collect()
+            });
+            match result {
+                Ok(values) => Some((ddc.device.clone(), values)),
+                Err(e) => {
+                    eprintln!("Not rolling back DDC/CI: {}", e);
+                    None
+                }
+            }
+        });
+
+        Ok(Self {
+            snapshot,
+            hdr,
+            gamma,
+            ddc,
+        })
+    }
+
+    /// Put back everything captured, then undo the rest of the apply as `restore` does
+    fn restore(&self) -> Result<()> {
+        let result = self.snapshot.restore();
+        audit::record(Event::Restore {
+            snapshot: &self.snapshot,
+            ok: result.is_ok(),
+        });
+        result.context("Failed to restore the previous display settings")?;
+
+        for device in &self.hdr {
+            let on = hdr::query()
+                .map(|states| states.iter().any(|s| &s.device == device && s.enabled))
+                .unwrap_or(false);
+            if !on {
+                match hdr::set(device, true) {
+                    Ok(()) => println!("Turned HDR back on for {}", device),
+                    Err(e) => eprintln!("Failed to turn HDR back on for {}: {}", device, e),
+                }
+            }
+        }
+        if let Some((device, ramp)) = &self.gamma {
+            match ramp.apply(device.as_deref()) {
+                Ok(()) => println!("Restored the gamma ramp"),
+                Err(e) => eprintln!("Failed to restore the gamma ramp: {}", e),
+            }
+        }
+        if let Some((device, values)) = &self.ddc {
+            let result = PhysicalMonitors::open(device.as_deref()).and_then(|monitors| {
+                values
+                    .iter()
+                    .try_for_each(|(code, values)| monitors.set(*code, values))
+            });
+            match result {
+                Ok(()) => println!("Restored DDC/CI brightness and contrast"),
+                Err(e) => eprintln!("Failed to restore DDC/CI brightness and contrast: {}", e),
+            }
+        }
+
+        revert::restore_extras()
+    }
+}
+
+/// Keep the settings of the running countdown. Returns `false` if nothing is waiting.
+pub fn confirm() -> bool {
+    let pending = PENDING.lock().unwrap_or_else(|e| e.into_inner()).take();
+    pending.is_some_and(|tx| tx.send(()).is_ok())
+}
+
+/// Like the Windows "keep these display settings?" dialog: wait up to `timeout` for Enter on
+/// the console or a `confirm` control command, and undo the apply with `rollback` if neither
+/// arrives
+pub fn keep_or_revert(rollback: &Rollback, timeout: Duration) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    *PENDING.lock().unwrap_or_else(|e| e.into_inner()) = Some(tx);
+
+    // One reader for the life of the process, since a blocked read cannot be cancelled
+    STDIN.call_once(|| {
+        thread::spawn(|| {
+            for _ in io::stdin().lines().map_while(Result::ok) {
+                confirm();
+            }
+        });
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+
+        println!(
+            "Keep these display settings? Press Enter or send `confirm` within {}s to keep them",
+            remaining.as_secs_f32().ceil()
+        );
+        match rx.recv_timeout(remaining.min(Duration::from_secs(5))) {
+            Ok(()) => {
+                println!("Keeping display settings");
+                return Ok(());
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).take();
+    println!("Not confirmed, restoring the previous display settings");
+    rollback.restore()?;
+
+    Err(anyhow!(
+        "Display settings were not confirmed within {}s and have been reverted",
+        timeout.as_secs()
+    ))
+}
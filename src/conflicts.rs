@@ -0,0 +1,137 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::mem;
+
+use serde::Serialize;
+use winapi::shared::minwindef::FALSE;
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::tlhelp32::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+
+use crate::wide::from_wide;
+
+/// Software known to change display modes behind our back, by process name
+const KNOWN: &[(&str, &str, &str)] = &[
+    (
+        "nvidia share.exe",
+        "NVIDIA GeForce Experience",
+        "its game auto-optimize applies its own resolution when a game starts",
+    ),
+    (
+        "nvidia geforce experience.exe",
+        "NVIDIA GeForce Experience",
+        "its game auto-optimize applies its own resolution when a game starts",
+    ),
+    (
+        "nvidia app.exe",
+        "NVIDIA app",
+        "its game optimization applies its own resolution when a game starts",
+    ),
+    (
+        "displayfusion.exe",
+        "DisplayFusion",
+        "its monitor profiles and triggers reapply their own modes",
+    ),
+    (
+        "displayfusionservice.exe",
+        "DisplayFusion",
+        "its monitor profiles and triggers reapply their own modes",
+    ),
+    (
+        "radeonsoftware.exe",
+        "AMD Software",
+        "per-application display overrides switch modes when the game starts",
+    ),
+    (
+        "igcc.exe",
+        "Intel Graphics Command Center",
+        "per-application profiles switch modes when the game starts",
+    ),
+    (
+        "igfxem.exe",
+        "Intel Graphics hotkeys",
+        "saved display profiles can be reapplied by hotkey or on display changes",
+    ),
+    (
+        "ultramon.exe",
+        "UltraMon",
+        "its display profiles reapply their own modes",
+    ),
+    (
+        "multimonitortool.exe",
+        "MultiMonitorTool",
+        "saved configurations may be reloaded on a schedule",
+    ),
+];
+
+/// Running program that is known to fight over display modes
+#[derive(Clone, Debug, Serialize)]
+pub struct Conflict {
+    pub process: String,
+    pub pid: u32,
+    pub product: &'static str,
+    /// How it interferes
+    pub reason: &'static str,
+}
+
+struct Snapshot(winapi::um::winnt::HANDLE);
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Scan the running processes for display-management software that may revert a mode after we
+/// apply it
+pub fn running() -> io::Result<Vec<Conflict>> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    let snapshot = Snapshot(snapshot);
+
+    let mut entry: PROCESSENTRY32W = unsafe { mem::zeroed() };
+    entry.dwSize = mem::size_of::<PROCESSENTRY32W>() as u32;
+
+    let mut conflicts = Vec::new();
+    let mut more = unsafe { Process32FirstW(snapshot.0, &mut entry) } != FALSE;
+    while more {
+        let length = entry
+            .szExeFile
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(entry.szExeFile.len());
+        let process = from_wide(&entry.szExeFile[..length]);
+        let lower = process.to_ascii_lowercase();
+
+        if let Some(&(_, product, reason)) = KNOWN.iter().find(|(name, _, _)| *name == lower) {
+            conflicts.push(Conflict {
+                process,
+                pid: entry.th32ProcessID,
+                product,
+                reason,
+            });
+        }
+
+        more = unsafe { Process32NextW(snapshot.0, &mut entry) } != FALSE;
+    }
+
+    Ok(conflicts)
+}
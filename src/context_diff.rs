@@ -0,0 +1,128 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::digest;
+
+static DIFF_CONTEXT: AtomicBool = AtomicBool::new(false);
+
+/// Context after the previous stage, and the stage's name
+static PREVIOUS: Mutex<Option<(&'static str, Vec<u8>)>> = Mutex::new(None);
+
+/// Contexts after the last few stages with their names, oldest first, for crash reports
+static RECENT: Mutex<VecDeque<(&'static str, Vec<u8>)>> = Mutex::new(VecDeque::new());
+const RECENT_LEN: usize = 8;
+
+/// Bytes that changed together, at consecutive offsets
+#[derive(Debug, PartialEq, Eq)]
+pub struct Change {
+    pub offset: usize,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+/// Print how the context changed between lifecycle stages from now on, as requested with
+/// `--diff-context`
+pub fn enable() {
+    DIFF_CONTEXT.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    DIFF_CONTEXT.load(Ordering::Relaxed)
+}
+
+/// Contexts recorded after the last few stages, oldest first
+pub fn recent() -> Vec<(&'static str, Vec<u8>)> {
+    RECENT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Runs of bytes that differ between `before` and `after`
+pub fn changes(before: &[u8], after: &[u8]) -> Vec<Change> {
+    let mut changes: Vec<Change> = Vec::new();
+    for (offset, (&old, &new)) in before.iter().zip(after).enumerate() {
+        if old == new {
+            continue;
+        }
+        match changes.last_mut() {
+            Some(change) if change.offset + change.old.len() == offset => {
+                change.old.push(old);
+                change.new.push(new);
+            }
+            _ => changes.push(Change {
+                offset,
+                old: vec![old],
+                new: vec![new],
+            }),
+        }
+    }
+
+    changes
+}
+
+/// Print each change as its offset range with the old and new bytes
+pub fn print(changes: &[Change]) {
+    for change in changes {
+        let end = change.offset + change.old.len() - 1;
+        let range = if end == change.offset {
+            format!("{:#06x}", change.offset)
+        } else {
+            format!("{:#06x}..{:#06x}", change.offset, end)
+        };
+        println!(
+            "  {:<14} {} -> {}",
+            range,
+            digest::hex(&change.old),
+            digest::hex(&change.new)
+        );
+    }
+}
+
+/// Record the context as it is after `stage`, printing what changed since the stage before when
+/// `--diff-context` is on. The first stage of a session is printed against an all-zero context.
+pub fn stage(stage: &'static str, context: &[u8]) {
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_LEN {
+        recent.pop_front();
+    }
+    recent.push_back((stage, context.to_vec()));
+    drop(recent);
+
+    if !enabled() {
+        return;
+    }
+
+    let mut previous = PREVIOUS.lock().unwrap_or_else(|e| e.into_inner());
+    let (from, before) = previous
+        .take()
+        .unwrap_or_else(|| ("load", vec![0; context.len()]));
+    let changes = changes(&before, context);
+    if changes.is_empty() {
+        println!("Context unchanged from {} to {}", from, stage);
+    } else {
+        println!("Context changes from {} to {}:", from, stage);
+        print(&changes);
+    }
+
+    *previous = Some((stage, context.to_vec()));
+}
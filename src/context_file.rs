@@ -0,0 +1,56 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+
+/// Files the context is saved to and resumed from, as given with `--save-context` and
+/// `--load-context`
+struct ContextFiles {
+    save: Option<PathBuf>,
+    load: Option<PathBuf>,
+}
+
+static CONTEXT_FILES: OnceLock<ContextFiles> = OnceLock::new();
+
+/// Save the context before every close, and resume from a saved one instead of opening
+pub fn enable(save: Option<PathBuf>, load: Option<PathBuf>) {
+    let _ = CONTEXT_FILES.set(ContextFiles { save, load });
+}
+
+/// Saved context to resume instead of calling `amDllVideoOpen`
+pub fn saved() -> Result<Option<Vec<u8>>> {
+    match CONTEXT_FILES.get().and_then(|files| files.load.as_deref()) {
+        Some(path) => fs::read(path)
+            .map(Some)
+            .with_context(|| format!("Failed to read context '{}'", path.display())),
+        None => Ok(None),
+    }
+}
+
+/// Write the context out if `--save-context` was given
+pub fn save(context: &[u8]) -> Result<()> {
+    if let Some(path) = CONTEXT_FILES.get().and_then(|files| files.save.as_deref()) {
+        fs::write(path, context)
+            .with_context(|| format!("Failed to save context '{}'", path.display()))?;
+        println!("Saved context to {}", path.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,592 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::windows::io::FromRawHandle;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW};
+use winapi::um::winbase::{
+    LocalFree, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+    PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+use winapi::um::winnt::PSECURITY_DESCRIPTOR;
+
+use amvideo::wide::to_wide;
+
+use crate::daemon::{Daemon, Status};
+use crate::protocol;
+use crate::schedule::ScheduleAction;
+use crate::timeout::{self, Stage};
+use crate::virtual_display::VirtualDisplayAction;
+
+const PIPE_BUFFER_SIZE: u32 = 4096;
+/// Longest request line read before giving up on the connection, far more than any command needs
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// Longest a TCP client may stay silent when the `ipc` timeout is off
+const TCP_IDLE_LIMIT: Duration = Duration::from_secs(300);
+
+/// One request per line, answered by one `Response` line
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Request {
+    /// Highest protocol version the client speaks, absent for version 1 clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Subcommand)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlCommand {
+    /// Report the active profile and the outcome of the last apply
+    Status,
+    /// Apply a profile from the daemon's config
+    Apply { profile: String },
+    /// Restore the display settings captured when the daemon started
+    Restore,
+    /// Keep the settings of an apply waiting on `confirm_within`
+    Confirm,
+    /// Agree on a protocol version and list the commands the daemon accepts from this client
+    Hello,
+    /// Add or remove the configured virtual monitor
+    VirtualDisplay {
+        #[command(subcommand)]
+        action: VirtualDisplayAction,
+    },
+    /// Hold or resume switching profiles by the config's schedule
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+}
+
+/// Command names as used in `allow` lists
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControlCommandName {
+    Status,
+    Apply,
+    Restore,
+    Confirm,
+    Hello,
+    VirtualDisplay,
+    Schedule,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    /// Protocol version agreed on, for clients that sent one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Commands this client may send, in reply to `hello`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<ControlCommandName>>,
+}
+
+/// Authentication and authorization settings for one transport
+pub struct Policy {
+    pub token: Option<String>,
+    pub allow: Vec<ControlCommandName>,
+    /// Clients with their own tokens, matched before `token`
+    pub clients: Vec<Client>,
+}
+
+/// Client allowed its own set of commands
+pub struct Client {
+    pub name: String,
+    pub token: String,
+    pub allow: Vec<ControlCommandName>,
+}
+
+#[derive(Args)]
+pub struct ControlOpts {
+    /// Connect to a daemon's TCP control surface
+    #[arg(long, value_name = "ADDR", conflicts_with = "pipe")]
+    tcp: Option<String>,
+
+    /// Connect to a daemon's named pipe [default: amvideo]
+    #[arg(long, value_name = "NAME")]
+    pipe: Option<String>,
+
+    /// Pre-shared token to present to the daemon
+    #[arg(long, env = "AMVIDEO_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: ControlCommand,
+}
+
+impl ControlCommand {
+    pub const fn name(&self) -> ControlCommandName {
+        match self {
+            Self::Status => ControlCommandName::Status,
+            Self::Apply { .. } => ControlCommandName::Apply,
+            Self::Restore => ControlCommandName::Restore,
+            Self::Confirm => ControlCommandName::Confirm,
+            Self::Hello => ControlCommandName::Hello,
+            Self::VirtualDisplay { .. } => ControlCommandName::VirtualDisplay,
+            Self::Schedule { .. } => ControlCommandName::Schedule,
+        }
+    }
+}
+
+impl fmt::Display for ControlCommandName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Status => "status",
+            Self::Apply => "apply",
+            Self::Restore => "restore",
+            Self::Confirm => "confirm",
+            Self::Hello => "hello",
+            Self::VirtualDisplay => "virtual-display",
+            Self::Schedule => "schedule",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Response {
+    fn error(message: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(message),
+            ..Self::default()
+        }
+    }
+}
+
+impl Policy {
+    /// Commands the sender of `request` may send, if it may send this one
+    fn authorize(&self, request: &Request) -> Result<&[ControlCommandName], String> {
+        // A request without a token never matches one, even should an empty token slip through
+        let matches = |expected: &str| {
+            request.token.as_deref().is_some_and(|presented| {
+                constant_time_eq(expected.as_bytes(), presented.as_bytes())
+            })
+        };
+        let client = self.clients.iter().find(|client| matches(&client.token));
+
+        let allow = match (client, &self.token) {
+            (Some(client), _) => &client.allow,
+            (None, Some(expected)) if !matches(expected) => {
+                return Err("Invalid token".to_string());
+            }
+            (None, _) => &self.allow,
+        };
+
+        // Anyone holding a token may ask what they are allowed to do
+        let name = request.command.name();
+        if name != ControlCommandName::Hello && !allow.contains(&name) {
+            return Err(match client {
+                Some(client) => format!("Command '{}' is not allowed for '{}'", name, client.name),
+                None => format!("Command '{}' is not allowed", name),
+            });
+        }
+
+        Ok(allow)
+    }
+}
+
+/// Compare tokens without leaking the length of the matching prefix through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Answer requests on `stream` until the client disconnects
+fn serve<S>(daemon: &Daemon, policy: &Policy, stream: S) -> io::Result<()>
+where
+    S: io::Read + Write + Copy,
+{
+    let mut writer = stream;
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        // Bounded, so a peer cannot exhaust memory with one endless line before being checked
+        let mut line = Vec::new();
+        let read = (&mut reader)
+            .take(MAX_REQUEST_LEN as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        if line.len() > MAX_REQUEST_LEN {
+            eprintln!(
+                "Rejected control request longer than {} bytes",
+                MAX_REQUEST_LEN
+            );
+            let response = Response {
+                version: Some(protocol::VERSION),
+                ..Response::error(format!(
+                    "Request longer than {} bytes, closing the connection",
+                    MAX_REQUEST_LEN
+                ))
+            };
+            return write_response(&mut writer, &response);
+        }
+        let line = String::from_utf8_lossy(&line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let authorized = policy.authorize(&request);
+                let mut response = match &authorized {
+                    Ok(_) => daemon.handle(&request.command),
+                    Err(message) => {
+                        eprintln!("Rejected control request: {}", message);
+                        Response::error(message.clone())
+                    }
+                };
+                // Version 1 clients get exactly the responses they always did
+                if request.version.is_some() {
+                    response.version = Some(protocol::negotiate(request.version));
+                }
+                if let (true, ControlCommandName::Hello, Ok(allow)) =
+                    (response.ok, request.command.name(), authorized)
+                {
+                    let mut capabilities = allow.to_vec();
+                    capabilities.push(ControlCommandName::Hello);
+                    response.capabilities = Some(capabilities);
+                }
+                response
+            }
+            // Includes commands from newer clients this daemon does not know
+            Err(e) => Response {
+                version: Some(protocol::VERSION),
+                ..Response::error(format!("Malformed request: {}", e))
+            },
+        };
+
+        write_response(&mut writer, &response)?;
+    }
+
+    Ok(())
+}
+
+fn write_response<W: Write>(writer: &mut W, response: &Response) -> io::Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+/// Serve the TCP control surface on a background thread
+pub fn spawn_tcp(daemon: Arc<Daemon>, listener: TcpListener, policy: Policy) {
+    let policy = Arc::new(policy);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+
+            let daemon = daemon.clone();
+            let policy = policy.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                let result = stream
+                    .set_read_timeout(timeout::limit(Stage::Ipc).or(Some(TCP_IDLE_LIMIT)))
+                    .and_then(|()| serve(&daemon, &policy, &stream));
+                if let Err(e) = result {
+                    eprintln!("Control connection {:?} failed: {}", peer, e);
+                }
+            });
+        }
+    });
+}
+
+/// Serve the named pipe control surface on a background thread
+pub fn spawn_pipe(daemon: Arc<Daemon>, name: &str, sddl: &str, policy: Policy) -> Result<()> {
+    let path = to_wide(&pipe_path(name));
+    let sddl = to_wide(sddl);
+    let policy = Arc::new(policy);
+
+    // Fail early on a bad descriptor instead of on the first connection
+    SecurityDescriptor::from_sddl(&sddl).context("Invalid control pipe SDDL")?;
+
+    thread::spawn(move || loop {
+        let pipe = match SecurityDescriptor::from_sddl(&sddl)
+            .and_then(|descriptor| create_pipe_instance(&path, &descriptor))
+        {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                eprintln!("Failed to create control pipe: {}", e);
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+
+        let daemon = daemon.clone();
+        let policy = policy.clone();
+        thread::spawn(move || {
+            let result = serve(&daemon, &policy, &pipe);
+            // Make sure the client has read the last response before the handle goes away
+            let _ = pipe.sync_all();
+            if let Err(e) = result {
+                eprintln!("Control pipe connection failed: {}", e);
+            }
+        });
+    });
+
+    Ok(())
+}
+
+fn pipe_path(name: &str) -> String {
+    format!(r"\\.\pipe\{}", name)
+}
+
+/// Create a pipe instance and block until a client connects to it
+fn create_pipe_instance(path: &[u16], descriptor: &SecurityDescriptor) -> io::Result<File> {
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor.0,
+        bInheritHandle: FALSE,
+    };
+
+    let handle = unsafe {
+        CreateNamedPipeW(
+            path.as_ptr(),
+            PIPE_ACCESS_DUPLEX,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            PIPE_BUFFER_SIZE,
+            PIPE_BUFFER_SIZE,
+            0,
+            &mut attributes,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    let pipe = unsafe { File::from_raw_handle(handle as _) };
+
+    if unsafe { ConnectNamedPipe(handle, ptr::null_mut()) } == 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+            return Err(e);
+        }
+    }
+
+    Ok(pipe)
+}
+
+/// Security descriptor allocated by `ConvertStringSecurityDescriptorToSecurityDescriptorW`
+struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &[u16]) -> io::Result<Self> {
+        let mut descriptor = ptr::null_mut();
+        let result = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1.into(),
+                &mut descriptor,
+                ptr::null_mut(),
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self(descriptor))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0) };
+    }
+}
+
+/// `amvideo control`: send one command to a running daemon and print the response
+pub fn run_client(opts: &ControlOpts) -> Result<()> {
+    let response = timeout::watch(Stage::Ipc, "control request", || match &opts.tcp {
+        Some(addr) => {
+            let stream = TcpStream::connect(addr)
+                .with_context(|| format!("Failed to connect to '{}'", addr))?;
+            negotiate_and_send(&stream, opts)
+        }
+        None => {
+            let path = pipe_path(opts.pipe.as_deref().unwrap_or("amvideo"));
+            let pipe = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to connect to '{}'", path))?;
+            negotiate_and_send(&pipe, opts)
+        }
+    })?;
+
+    if let Some(status) = &response.status {
+        println!("{}", serde_json::to_string_pretty(status)?);
+    }
+    if let (Some(version), Some(capabilities)) = (response.version, &response.capabilities) {
+        let names: Vec<String> = capabilities.iter().map(ToString::to_string).collect();
+        println!(
+            "Protocol version {}, accepts: {}",
+            version,
+            names.join(", ")
+        );
+    }
+    match response.error {
+        Some(error) if !response.ok => Err(anyhow!("Daemon refused request: {}", error)),
+        _ => Ok(()),
+    }
+}
+
+/// Check with `hello` that the daemon accepts the command before sending it. Daemons from before
+/// versioning do not know `hello`, and get the command as a version 1 request instead.
+fn negotiate_and_send<S>(stream: S, opts: &ControlOpts) -> Result<Response>
+where
+    S: io::Read + Write + Copy,
+{
+    let request = |command: ControlCommand| Request {
+        version: Some(protocol::VERSION),
+        token: opts.token.clone(),
+        command,
+    };
+
+    let hello = exchange(stream, &request(ControlCommand::Hello))?;
+    if let ControlCommand::Hello = opts.command {
+        return Ok(hello);
+    }
+    let version = match (hello.ok, hello.version, &hello.capabilities) {
+        (true, Some(version), Some(capabilities)) => {
+            let name = opts.command.name();
+            if !capabilities.contains(&name) {
+                return Err(anyhow!(
+                    "Daemon does not accept '{}' from this client",
+                    name
+                ));
+            }
+            Some(version)
+        }
+        _ => None,
+    };
+
+    exchange(
+        stream,
+        &Request {
+            version,
+            ..request(opts.command.clone())
+        },
+    )
+}
+
+fn exchange<S>(stream: S, request: &Request) -> Result<Response>
+where
+    S: io::Read + Write + Copy,
+{
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+
+    let mut writer = stream;
+    writer.write_all(&line)?;
+    writer.flush()?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    serde_json::from_str(&response).context("Malformed response from daemon")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(token: Option<&str>) -> Request {
+        Request {
+            version: None,
+            token: token.map(str::to_string),
+            command: ControlCommand::Apply {
+                profile: "a".to_string(),
+            },
+        }
+    }
+
+    fn policy(token: Option<&str>, clients: Vec<Client>) -> Policy {
+        Policy {
+            token: token.map(str::to_string),
+            allow: vec![ControlCommandName::Apply],
+            clients,
+        }
+    }
+
+    #[test]
+    fn shared_token_must_match() {
+        let policy = policy(Some("secret"), Vec::new());
+        assert!(policy.authorize(&request(Some("secret"))).is_ok());
+        assert!(policy.authorize(&request(Some("wrong"))).is_err());
+        assert!(policy.authorize(&request(Some(""))).is_err());
+        assert!(policy.authorize(&request(None)).is_err());
+    }
+
+    #[test]
+    fn missing_token_never_matches() {
+        // Tokens are never empty once loaded, but a request without one must not match if so
+        let empty = Client {
+            name: "cab".to_string(),
+            token: String::new(),
+            allow: vec![ControlCommandName::Apply],
+        };
+        assert!(policy(Some(""), vec![empty])
+            .authorize(&request(None))
+            .is_err());
+    }
+
+    #[test]
+    fn client_tokens_take_their_own_allow_list() {
+        let client = Client {
+            name: "status-board".to_string(),
+            token: "board".to_string(),
+            allow: vec![ControlCommandName::Status],
+        };
+        let policy = policy(Some("secret"), vec![client]);
+        assert!(policy.authorize(&request(Some("board"))).is_err());
+        assert!(policy.authorize(&request(Some("secret"))).is_ok());
+    }
+
+    #[test]
+    fn no_token_allows_unauthenticated_transport() {
+        assert!(policy(None, Vec::new()).authorize(&request(None)).is_ok());
+    }
+}
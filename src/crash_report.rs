@@ -0,0 +1,154 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use winapi::shared::ntdef::LONG;
+use winapi::um::errhandlingapi::SetUnhandledExceptionFilter;
+use winapi::um::winnt::EXCEPTION_POINTERS;
+
+use amvideo::minidump;
+
+use crate::context_diff;
+use crate::version;
+use crate::zip::Archive;
+
+/// Folder next to the executable crash reports are written to
+const DIR_NAME: &str = "crash-reports";
+/// Audit log lines kept in a report
+const LOG_TAIL: usize = 200;
+/// Let the exception go on to Windows Error Reporting and the debugger
+const EXCEPTION_CONTINUE_SEARCH: LONG = 0;
+
+static AUDIT_LOG: OnceLock<Option<PathBuf>> = OnceLock::new();
+/// Only the first failure of a process is reported, a panic while reporting must not recurse
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Which minidump a report gets
+pub enum Dump {
+    /// None, e.g. for a crashed broker, whose process is already gone
+    Skip,
+    /// This process as it is now
+    Here,
+    /// This process at the exception being handled
+    Exception(*mut EXCEPTION_POINTERS),
+}
+
+/// Write a crash report on any unhandled exception from now on, such as a fault inside the DLL,
+/// taking the log tail from `audit_log`
+pub fn install(audit_log: Option<PathBuf>) {
+    let _ = AUDIT_LOG.set(audit_log);
+    unsafe { SetUnhandledExceptionFilter(Some(on_exception)) };
+}
+
+/// Gather everything known about an unexpected failure into a timestamped zip in `crash-reports`
+/// next to the executable and print its path. A report that cannot be written is only reported,
+/// the failure itself matters more.
+pub fn report(kind: &'static str, message: &str, dump: Dump) {
+    if REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    match write(kind, message, dump) {
+        Ok(path) => eprintln!(
+            "Crash report written to {}, attach it when reporting the problem",
+            path.display()
+        ),
+        Err(e) => eprintln!("Failed to write a crash report: {:#}", e),
+    }
+}
+
+fn write(kind: &'static str, message: &str, dump: Dump) -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    let dir = exe.with_file_name(DIR_NAME);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let name = format!("amvideo-crash-{}-{}", timestamp, process::id());
+
+    let mut archive = Archive::default();
+    let crash = serde_json::json!({
+        "kind": kind,
+        "message": message,
+        "timestamp": timestamp,
+        "pid": process::id(),
+        "args": env::args().collect::<Vec<_>>(),
+    });
+    archive.add("crash.json", serde_json::to_vec_pretty(&crash)?);
+    archive.add(
+        "environment.json",
+        serde_json::to_vec_pretty(&version::report(true))?,
+    );
+
+    if let Some(path) = AUDIT_LOG.get().and_then(Option::as_ref) {
+        match log_tail(path) {
+            Ok(tail) => archive.add("audit-tail.jsonl", tail),
+            Err(e) => eprintln!("Leaving the audit log out of the crash report: {}", e),
+        }
+    }
+    for (index, (stage, context)) in context_diff::recent().into_iter().enumerate() {
+        archive.add(&format!("context-{}-{}.bin", index + 1, stage), context);
+    }
+
+    let exception = match dump {
+        Dump::Skip => None,
+        Dump::Here => Some(None),
+        Dump::Exception(pointers) => Some(Some(pointers)),
+    };
+    if let Some(exception) = exception {
+        let path = dir.join(format!("{}.dmp", name));
+        match unsafe { minidump::write(&path, exception) }.and_then(|()| fs::read(&path)) {
+            Ok(data) => archive.add("amvideo.dmp", data),
+            Err(e) => eprintln!("Leaving the minidump out of the crash report: {}", e),
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    let path = dir.join(format!("{}.zip", name));
+    fs::write(&path, archive.to_bytes())
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(path)
+}
+
+/// The last lines of the log at `path`
+pub fn log_tail(path: &Path) -> io::Result<Vec<u8>> {
+    let log = fs::read_to_string(path)?;
+    let lines: Vec<&str> = log.lines().collect();
+    Ok(lines[lines.len().saturating_sub(LOG_TAIL)..]
+        .join("\n")
+        .into_bytes())
+}
+
+unsafe extern "system" fn on_exception(info: *mut EXCEPTION_POINTERS) -> LONG {
+    let record = &*(*info).ExceptionRecord;
+    let message = format!(
+        "Unhandled exception {:#010x} at {:p}",
+        record.ExceptionCode, record.ExceptionAddress
+    );
+    eprintln!("{}", message);
+    report("exception", &message, Dump::Exception(info));
+    EXCEPTION_CONTINUE_SEARCH
+}
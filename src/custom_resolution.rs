@@ -0,0 +1,111 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+
+use amvideo::display;
+use amvideo::nvapi::NvApi;
+
+use crate::config::Profile;
+use crate::state::{self, CustomResolution};
+
+/// Refresh rate a custom resolution is created at when the profile leaves it to the DLL
+const DEFAULT_REFRESH: u32 = 60;
+
+/// Create the profile's resolution on the primary display through NVAPI if the profile asks for
+/// it and the driver does not list the mode, as amVideoNvidia does for SegaTiming modes
+pub fn ensure(profile: &Profile) -> Result<()> {
+    let config = match &profile.custom_resolution {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let device = display::attached_displays()
+        .into_iter()
+        .find(|display| display.primary)
+        .ok_or_else(|| anyhow!("No primary display to create a custom resolution on"))?
+        .name;
+
+    let refresh = profile.refresh_rate.unwrap_or(DEFAULT_REFRESH);
+    let exists = display::supported_modes(Some(&device)).iter().any(|mode| {
+        mode.width == u32::from(profile.resolution.width)
+            && mode.height == u32::from(profile.resolution.height)
+            && profile
+                .refresh_rate
+                .is_none_or(|rate| mode.frequency == rate)
+    });
+    if exists {
+        return Ok(());
+    }
+
+    NvApi::load()
+        .and_then(|nvapi| nvapi.add_custom_resolution(&device, &profile.resolution, refresh))
+        .with_context(|| {
+            format!(
+                "Failed to create custom resolution {} @ {} Hz on {}",
+                profile.resolution, refresh, device
+            )
+        })?;
+    println!(
+        "Created custom resolution {} @ {} Hz on {}",
+        profile.resolution, refresh, device
+    );
+
+    if config.remove_on_restore {
+        state::add_custom_resolution(CustomResolution {
+            display: device,
+            resolution: profile.resolution,
+            refresh,
+        })
+        .context("Failed to record the custom resolution to remove on restore")?;
+    }
+
+    Ok(())
+}
+
+/// Remove the custom resolutions created by earlier applies with `remove_on_restore`, once the
+/// starting settings are back. A failure only leaves the mode behind, so it is reported.
+pub fn remove_created() {
+    let created = match state::take_custom_resolutions() {
+        Ok(created) => created,
+        Err(e) => {
+            eprintln!("Failed to read the custom resolutions to remove: {:#}", e);
+            return;
+        }
+    };
+    if created.is_empty() {
+        return;
+    }
+
+    let nvapi = match NvApi::load() {
+        Ok(nvapi) => nvapi,
+        Err(e) => {
+            eprintln!("Failed to remove custom resolutions: {}", e);
+            return;
+        }
+    };
+    for custom in created {
+        match nvapi.remove_custom_resolution(&custom.display, &custom.resolution, custom.refresh) {
+            Ok(()) => println!(
+                "Removed custom resolution {} @ {} Hz from {}",
+                custom.resolution, custom.refresh, custom.display
+            ),
+            Err(e) => eprintln!(
+                "Failed to remove custom resolution {} @ {} Hz from {}: {}",
+                custom.resolution, custom.refresh, custom.display, e
+            ),
+        }
+    }
+}
@@ -0,0 +1,671 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use amvideo::display;
+use amvideo::snapshot::Snapshot;
+use amvideo::AmVideoCrateError;
+
+use crate::audit::{self, Event};
+use crate::config::{self, Config, ControlClientConfig, Profile};
+use crate::confirm;
+use crate::control::{self, Client, ControlCommand, Policy, Response};
+use crate::headless;
+use crate::monitor::{self, DisplayEvent};
+use crate::mqtt::Publisher;
+use crate::notify;
+use crate::pull::{self, Profiles};
+use crate::revert;
+use crate::schedule::{self, Hold, Moment, ScheduleAction};
+use crate::state as applied;
+use crate::timeout;
+use crate::timing;
+use crate::virtual_display::{self, VirtualDisplayAction};
+use crate::{apply_profile_with, GlobalOpts, Switch};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// Time to let the display come back after a resume or wake before reapplying
+const RESUME_SETTLE: Duration = Duration::from_secs(3);
+
+#[derive(Args)]
+pub struct DaemonOpts {
+    /// Profile to apply when the daemon starts
+    #[arg(short, long)]
+    profile: Option<String>,
+
+    /// Do not subscribe to WMI monitor and display driver events
+    #[arg(long)]
+    no_wmi: bool,
+
+    /// Only log display events instead of reapplying the active profile
+    #[arg(long)]
+    no_reapply: bool,
+
+    /// Do not reload profiles when the config file changes
+    #[arg(long)]
+    no_watch: bool,
+
+    /// Reapply the active profile when a config reload changes it
+    #[arg(long, conflicts_with = "no_watch")]
+    reapply_on_change: bool,
+
+    /// Add the configured virtual monitor before applying the startup profile
+    #[arg(long)]
+    virtual_display: bool,
+
+    /// Raise a desktop notification when a reapply succeeds or any apply fails
+    #[arg(long)]
+    notify: bool,
+
+    /// Time to gather further display events into one before acting on them, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    debounce_ms: u64,
+
+    /// Minimum time between two reapplies, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    min_reapply_interval: u64,
+}
+
+/// Long-running owner of the display state, driven by the control surfaces
+pub struct Daemon {
+    /// Profiles are reloaded on the fly; control surfaces keep the settings they started with
+    config: RwLock<Config>,
+    snapshot: Snapshot,
+    state: Mutex<Status>,
+    /// Where apply events and status are published, if the config names a broker
+    telemetry: Option<Publisher>,
+    /// Config file the daemon was started with, reread when a pulled set arrives
+    path: PathBuf,
+    /// Profiles pulled from the central endpoint, which take precedence over the config file's
+    pulled: RwLock<Profiles>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Status {
+    /// Profile most recently applied successfully
+    pub profile: Option<String>,
+    pub last_apply: Option<ApplyOutcome>,
+    /// Virtual monitor the daemon added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtual_display: Option<String>,
+    /// Profile the schedule calls for now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduled: Option<String>,
+    /// Set while the schedule is not followed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule_hold: Option<Hold>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApplyOutcome {
+    pub profile: String,
+    pub timestamp_ms: u128,
+    pub ok: bool,
+    /// Set when there was no display to verify the apply against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn run(global: &GlobalOpts, opts: &DaemonOpts) -> Result<()> {
+    let path = match &global.config {
+        Some(path) => path.clone(),
+        None => config::default_path()?,
+    };
+    let mut config = Config::load(Some(&path))?;
+    let control = config.control.clone();
+    let telemetry = config.mqtt.clone().map(Publisher::new);
+    let pull_config = config.pull.clone();
+    let (pulled, latest) = pull_config
+        .as_ref()
+        .and_then(pull::cached)
+        .unwrap_or_default();
+    config.profiles.extend(pulled.clone());
+    let snapshot =
+        Snapshot::capture().context("Failed to capture the starting display settings")?;
+    let daemon = Arc::new(Daemon {
+        config: RwLock::new(config),
+        snapshot,
+        state: Mutex::new(Status::default()),
+        telemetry,
+        path: path.clone(),
+        pulled: RwLock::new(pulled),
+    });
+
+    if opts.notify {
+        notify::enable();
+    }
+    if opts.virtual_display {
+        daemon.virtual_display(VirtualDisplayAction::Create)?;
+    }
+    if let Some(profile) = &opts.profile {
+        if let Err(e) = daemon.apply(profile) {
+            notify::failure(&format!("Applying '{}'", profile), &e);
+            notify::disable();
+            return Err(e);
+        }
+    } else {
+        daemon.resume();
+    }
+    // Connects, so the cab shows up as online even before its first apply
+    if let Some(telemetry) = &daemon.telemetry {
+        telemetry.publish("status", &daemon.status(), true);
+    }
+
+    if !opts.no_watch {
+        spawn_watcher(daemon.clone(), path, opts.reapply_on_change);
+    }
+    if let Some(pull_config) = pull_config {
+        pull::spawn(daemon.clone(), pull_config, latest, opts.reapply_on_change)?;
+    }
+    schedule::spawn(daemon.clone());
+
+    let mut surfaces = 0;
+    if let Some(tcp) = &control.tcp {
+        let token = config::load_token(&tcp.token, &tcp.token_file)?;
+        if token.is_none() && (tcp.token.is_some() || tcp.token_file.is_some()) {
+            return Err(anyhow!(
+                "The TCP control surface's 'token' or 'token_file' is empty"
+            ));
+        }
+        let clients = control_clients(&tcp.clients)?;
+        // Without a shared token, only the clients' own tokens get anywhere
+        let allow = match &token {
+            Some(_) => tcp.allow.clone(),
+            None if !clients.is_empty() => Vec::new(),
+            None => {
+                return Err(anyhow!(
+                    "The TCP control surface requires a 'token', 'token_file', or 'clients'"
+                ))
+            }
+        };
+        let listener = TcpListener::bind(tcp.listen)
+            .with_context(|| format!("Failed to listen on {}", tcp.listen))?;
+        let policy = Policy {
+            token,
+            allow,
+            clients,
+        };
+
+        control::spawn_tcp(daemon.clone(), listener, policy);
+        println!("Control surface listening on tcp://{}", tcp.listen);
+        surfaces += 1;
+    }
+    if let Some(pipe) = &control.pipe {
+        let policy = Policy {
+            token: config::load_token(&pipe.token, &pipe.token_file)?,
+            allow: pipe.allow.clone(),
+            clients: control_clients(&pipe.clients)?,
+        };
+
+        control::spawn_pipe(daemon.clone(), &pipe.name, &pipe.sddl, policy)?;
+        println!("Control surface listening on \\\\.\\pipe\\{}", pipe.name);
+        surfaces += 1;
+    }
+    if surfaces == 0 {
+        eprintln!("No control surface configured, the daemon can only be stopped");
+    }
+
+    let (events_tx, events) = mpsc::channel();
+    monitor::spawn_window(events_tx.clone());
+    if !opts.no_wmi {
+        monitor::spawn_wmi(events_tx);
+    }
+
+    let debounce = Duration::from_millis(opts.debounce_ms);
+    let min_interval = Duration::from_secs(opts.min_reapply_interval);
+    let mut last_reapply: Option<Instant> = None;
+    while let Ok(event) = events.recv() {
+        println!("Display event: {}", event);
+        if opts.no_reapply {
+            continue;
+        }
+
+        // A flaky cable sends storms of connect and disconnect events, which are handled as one
+        let mut after_power_cycle = event.after_power_cycle() | coalesce(&events, debounce);
+        if after_power_cycle {
+            // Give the driver time to bring the display back, folding in the display events
+            // and duplicate resume notifications the wake-up sends meanwhile
+            coalesce(&events, RESUME_SETTLE);
+        }
+        if let Some(wait) = last_reapply.and_then(|last| min_interval.checked_sub(last.elapsed())) {
+            println!("Holding off the reapply for {:.1}s", wait.as_secs_f64());
+            after_power_cycle |= coalesce(&events, wait);
+        }
+
+        // A wake may have slept through a scheduled switch, which applies the profile anyway
+        let result = if after_power_cycle {
+            match daemon.follow_schedule(true) {
+                Ok(None) => daemon.reapply(),
+                result => result,
+            }
+        } else {
+            daemon.reapply_if_needed()
+        };
+        if let Ok(Some(_)) | Err(_) = result {
+            last_reapply = Some(Instant::now());
+        }
+        match result {
+            Ok(Some(profile)) => notify::success(&format!("Reapplied '{}'", profile)),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Failed to reapply profile: {:#}", e);
+                notify::failure("Reapplying the profile", &e);
+            }
+        }
+    }
+
+    notify::disable();
+    if let Some(telemetry) = &daemon.telemetry {
+        telemetry.disconnect();
+    }
+    Ok(())
+}
+
+impl Daemon {
+    /// Execute a control command on behalf of a client
+    pub fn handle(&self, command: &ControlCommand) -> Response {
+        let result = match command {
+            ControlCommand::Status => Ok(()),
+            ControlCommand::Apply { profile } => self
+                .apply(profile)
+                .inspect(|()| self.hold_schedule())
+                .inspect_err(|e| {
+                    notify::failure(&format!("Applying '{}'", profile), e);
+                }),
+            ControlCommand::Restore => self.restore().inspect(|()| self.hold_schedule()),
+            // Must not take the state lock, which the apply waiting for it holds
+            ControlCommand::Confirm if confirm::confirm() => Ok(()),
+            ControlCommand::Confirm => {
+                Err(anyhow!("No display change is waiting for confirmation"))
+            }
+            // Answered by the transport, which knows the client's version and policy
+            ControlCommand::Hello => Ok(()),
+            ControlCommand::VirtualDisplay { action } => self.virtual_display(*action),
+            ControlCommand::Schedule { action } => self.schedule(*action),
+        };
+
+        match result {
+            Ok(()) => Response {
+                ok: true,
+                status: Some(self.status()),
+                ..Response::default()
+            },
+            Err(e) => Response {
+                ok: false,
+                error: Some(format!("{:#}", e)),
+                status: Some(self.status()),
+                ..Response::default()
+            },
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.lock().clone()
+    }
+
+    /// Apply `name` from the config. Holding the state lock serializes every DLL call, and once
+    /// one has hung, [`timeout::abandonable`] refuses the rest.
+    pub fn apply(&self, name: &str) -> Result<()> {
+        self.apply_with(name, Switch::IfNeeded)
+    }
+
+    fn apply_with(&self, name: &str, switch: Switch) -> Result<()> {
+        let mut state = self.lock();
+        // A hung DLL call is left behind on its thread instead of taking the service down
+        let (result, duration) = timing::time(|| {
+            self.profile(name).and_then(|profile| {
+                timeout::abandonable(move || apply_profile_with(&profile, switch))
+            })
+        });
+        applied::record(Some(name), &result, duration);
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        state.last_apply = Some(ApplyOutcome {
+            profile: name.to_string(),
+            timestamp_ms,
+            ok: result.is_ok(),
+            status: headless::enabled()
+                .filter(|_| result.is_ok())
+                .map(|_| headless::STATUS.to_string()),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+        if result.is_ok() {
+            state.profile = Some(name.to_string());
+        }
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.publish("events", &state.last_apply, false);
+            telemetry.publish("status", &*state, true);
+        }
+
+        result
+    }
+
+    /// Take the profile the last run applied as the active one, reapplying it only if the display
+    /// no longer runs it
+    fn resume(&self) {
+        let name = match applied::load() {
+            Ok(applied::State {
+                last:
+                    Some(applied::Applied {
+                        profile: Some(name),
+                        ..
+                    }),
+                ..
+            }) => name,
+            Ok(_) => return,
+            Err(e) => {
+                eprintln!("Not resuming the last profile: {:#}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.profile(&name) {
+            eprintln!("Not resuming '{}': {:#}", name, e);
+            return;
+        }
+
+        println!("Resuming '{}' from the last run", name);
+        self.lock().profile = Some(name);
+        if let Err(e) = self.reapply_if_needed() {
+            eprintln!("Failed to reapply profile: {:#}", e);
+        }
+    }
+
+    /// Add or remove the configured virtual monitor, keeping track of the one it added
+    pub fn virtual_display(&self, action: VirtualDisplayAction) -> Result<()> {
+        let mut state = self.lock();
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        let driver = virtual_display::configured(&config)?;
+
+        match action {
+            VirtualDisplayAction::Create => {
+                if let Some(device) = &state.virtual_display {
+                    return Err(anyhow!("Virtual display {} already exists", device));
+                }
+                let device = virtual_display::create(driver)?;
+                println!("Created virtual display {}", device);
+                state.virtual_display = Some(device);
+            }
+            VirtualDisplayAction::Destroy => {
+                virtual_display::destroy(driver)?;
+                println!("Removed the virtual display");
+                state.virtual_display = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the profile the schedule calls for if it just changed, or after a wake, which may
+    /// have slept through a change. Returns the profile applied.
+    pub fn follow_schedule(&self, woke: bool) -> Result<Option<String>> {
+        let due = {
+            let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+            schedule::due(&config.schedule, Moment::now()).map(str::to_string)
+        };
+
+        let mut state = self.lock();
+        let changed = state.scheduled != due;
+        if changed {
+            state.scheduled = due.clone();
+            if state.schedule_hold == Some(Hold::UntilNext) {
+                state.schedule_hold = None;
+            }
+        }
+        let due = match due {
+            Some(due) if (changed || woke) && state.schedule_hold.is_none() => due,
+            _ => return Ok(None),
+        };
+        if state.profile.as_ref() == Some(&due) {
+            return Ok(None);
+        }
+        drop(state);
+
+        println!("Switching to '{}' on schedule", due);
+        // The driver may have dropped the mode while keeping the resolution, as for `reapply`
+        let switch = if woke {
+            Switch::Always
+        } else {
+            Switch::IfNeeded
+        };
+        self.apply_with(&due, switch)?;
+        Ok(Some(due))
+    }
+
+    /// Hold or resume the schedule on behalf of a control client
+    fn schedule(&self, action: ScheduleAction) -> Result<()> {
+        match action {
+            ScheduleAction::Hold => {
+                self.lock().schedule_hold = Some(Hold::Indefinite);
+                println!("Holding the schedule");
+            }
+            ScheduleAction::Resume => {
+                let mut state = self.lock();
+                state.schedule_hold = None;
+                // Forgetting the due profile makes the next check apply it
+                state.scheduled = None;
+                drop(state);
+                println!("Resuming the schedule");
+                self.follow_schedule(false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a profile applied or restored by hand until the schedule next calls for another
+    fn hold_schedule(&self) {
+        let mut state = self.lock();
+        let scheduling = !self
+            .config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .schedule
+            .is_empty();
+        if scheduling && state.schedule_hold.is_none() {
+            state.schedule_hold = Some(Hold::UntilNext);
+        }
+    }
+
+    /// Reapply the active profile unconditionally, e.g. after a power cycle, returning the
+    /// profile reapplied
+    pub fn reapply(&self) -> Result<Option<String>> {
+        match self.status().profile {
+            Some(profile) => {
+                println!("Reapplying '{}'", profile);
+                self.apply_with(&profile, Switch::Always)?;
+                Ok(Some(profile))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reapply the active profile if the primary display no longer runs at its resolution,
+    /// returning the profile if it was reapplied
+    pub fn reapply_if_needed(&self) -> Result<Option<String>> {
+        let profile = match self.status().profile {
+            Some(profile) if headless::enabled().is_none() => profile,
+            _ => return Ok(None),
+        };
+        let expected = self.profile(&profile)?;
+
+        match display::verify_mode(&expected.fitted_resolution(), expected.refresh_rate) {
+            Ok(_) => Ok(None),
+            Err(AmVideoCrateError::Verify { actual, .. })
+            | Err(AmVideoCrateError::VerifyRefresh { actual, .. }) => {
+                println!("Display changed to {}, reapplying '{}'", actual, profile);
+                self.apply(&profile)?;
+                Ok(Some(profile))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn restore(&self) -> Result<()> {
+        let mut state = self.lock();
+
+        let result = self.snapshot.restore();
+        audit::record(Event::Restore {
+            snapshot: &self.snapshot,
+            ok: result.is_ok(),
+        });
+        result.context("Failed to restore the starting display settings")?;
+        revert::restore_extras()?;
+
+        state.profile = None;
+        applied::clear();
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.publish("status", &*state, true);
+        }
+        Ok(())
+    }
+
+    /// Replace the config with `config` overlaid with the pulled profiles, reapplying the active
+    /// profile if asked to and its definition changed
+    pub fn reload(&self, mut config: Config, reapply: bool) -> Result<()> {
+        let pulled = self
+            .pulled
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        config.profiles.extend(pulled);
+        config.check()?;
+
+        let active = self.status().profile;
+        if let Some(name) = &active {
+            config
+                .profile(name)
+                .with_context(|| format!("The active profile '{}' no longer loads", name))?;
+        }
+        let changed = active.as_ref().is_some_and(|name| {
+            let old = self
+                .profile(name)
+                .ok()
+                .map(|p| serde_json::to_value(p).ok());
+            let new = config
+                .profile(name)
+                .ok()
+                .map(|p| serde_json::to_value(p).ok());
+            old != new
+        });
+
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+        println!("Reloaded config");
+
+        match active {
+            Some(name) if changed && reapply => {
+                println!("Profile '{}' changed, reapplying", name);
+                self.apply(&name)
+            }
+            Some(name) if changed => {
+                println!(
+                    "Profile '{}' changed, it takes effect on the next apply",
+                    name
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the pulled profiles with `profiles` and reload the config file on top of them
+    pub fn pull(&self, profiles: Profiles, reapply: bool) -> Result<()> {
+        *self.pulled.write().unwrap_or_else(|e| e.into_inner()) = profiles;
+        self.reload(Config::load(Some(&self.path))?, reapply)
+    }
+
+    /// Copy of the profile `name` from the current config
+    fn profile(&self, name: &str) -> Result<Profile> {
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        config.profile(name)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Status> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Log the display events that arrive within `window`, returning whether any called for a
+/// reapply regardless of the mode
+fn coalesce(events: &Receiver<DisplayEvent>, window: Duration) -> bool {
+    let deadline = Instant::now() + window;
+    let mut after_power_cycle = false;
+    while let Ok(event) = events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        println!("Display event: {}", event);
+        after_power_cycle |= event.after_power_cycle();
+    }
+    after_power_cycle
+}
+
+/// Control clients with their tokens loaded
+fn control_clients(configs: &[ControlClientConfig]) -> Result<Vec<Client>> {
+    configs
+        .iter()
+        .map(|client| {
+            let token =
+                config::load_token(&client.token, &client.token_file)?.ok_or_else(|| {
+                    anyhow!(
+                        "Control client '{}' requires a 'token' or 'token_file'",
+                        client.name
+                    )
+                })?;
+            Ok(Client {
+                name: client.name.clone(),
+                token,
+                allow: client.allow.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Poll the config file for changes on a background thread and hand each version that parses
+/// to the daemon. A config that fails to parse is reported and the previous one kept.
+fn spawn_watcher(daemon: Arc<Daemon>, path: PathBuf, reapply: bool) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    thread::spawn(move || {
+        let mut last = modified(&path);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+
+            let current = modified(&path);
+            if current == last {
+                continue;
+            }
+            last = current;
+
+            let result =
+                Config::load(Some(&path)).and_then(|config| daemon.reload(config, reapply));
+            if let Err(e) = result {
+                eprintln!("Failed to reload config: {:#}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,141 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::mem;
+use std::ptr;
+
+use winapi::um::lowlevelmonitorconfigurationapi::{GetVCPFeatureAndVCPFeatureReply, SetVCPFeature};
+use winapi::um::physicalmonitorenumerationapi::{
+    DestroyPhysicalMonitors, GetNumberOfPhysicalMonitorsFromHMONITOR,
+    GetPhysicalMonitorsFromHMONITOR, PHYSICAL_MONITOR,
+};
+
+use crate::display;
+use crate::error::Result;
+
+/// MCCS luminance control
+pub const VCP_BRIGHTNESS: u8 = 0x10;
+/// MCCS contrast control
+pub const VCP_CONTRAST: u8 = 0x12;
+
+/// Physical monitors behind one display, reachable over DDC/CI. Handles are released on drop.
+pub struct PhysicalMonitors(Vec<PHYSICAL_MONITOR>);
+
+impl PhysicalMonitors {
+    /// Open the monitors driven by `device`, or by the primary display if `None`
+    pub fn open(device: Option<&str>) -> Result<Self> {
+        let monitor = display::monitor_handle(device)?;
+
+        let mut count = 0;
+        if unsafe { GetNumberOfPhysicalMonitorsFromHMONITOR(monitor, &mut count) } == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut monitors: Vec<PHYSICAL_MONITOR> = vec![unsafe { mem::zeroed() }; count as usize];
+        if unsafe { GetPhysicalMonitorsFromHMONITOR(monitor, count, monitors.as_mut_ptr()) } == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self(monitors))
+    }
+
+    /// Whether any of the monitors answers a DDC/CI brightness query. Dummy plugs and monitors
+    /// with DDC/CI turned off in their menu do not.
+    pub fn respond(&self) -> bool {
+        self.0.iter().any(|monitor| {
+            let (mut current, mut maximum) = (0, 0);
+            unsafe {
+                GetVCPFeatureAndVCPFeatureReply(
+                    monitor.hPhysicalMonitor,
+                    VCP_BRIGHTNESS,
+                    ptr::null_mut(),
+                    &mut current,
+                    &mut maximum,
+                ) != 0
+            }
+        })
+    }
+
+    /// Raw value of a VCP control on each monitor, in order
+    pub fn get(&self, code: u8) -> Result<Vec<u32>> {
+        let mut values = Vec::with_capacity(self.0.len());
+        for monitor in &self.0 {
+            let (mut current, mut maximum) = (0, 0);
+            let result = unsafe {
+                GetVCPFeatureAndVCPFeatureReply(
+                    monitor.hPhysicalMonitor,
+                    code,
+                    ptr::null_mut(),
+                    &mut current,
+                    &mut maximum,
+                )
+            };
+            if result == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            values.push(current);
+        }
+
+        Ok(values)
+    }
+
+    /// Put back raw values read by [`get`](Self::get), one per monitor
+    pub fn set(&self, code: u8, values: &[u32]) -> Result<()> {
+        for (monitor, &value) in self.0.iter().zip(values) {
+            if unsafe { SetVCPFeature(monitor.hPhysicalMonitor, code, value) } == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set a VCP control on every monitor to `percent` of the range it reports
+    pub fn set_percent(&self, code: u8, percent: u8) -> Result<()> {
+        for monitor in &self.0 {
+            let handle = monitor.hPhysicalMonitor;
+
+            let mut current = 0;
+            let mut maximum = 0;
+            let result = unsafe {
+                GetVCPFeatureAndVCPFeatureReply(
+                    handle,
+                    code,
+                    ptr::null_mut(),
+                    &mut current,
+                    &mut maximum,
+                )
+            };
+            if result == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let value = maximum * u32::from(percent.min(100)) / 100;
+            if unsafe { SetVCPFeature(handle, code, value) } == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PhysicalMonitors {
+    fn drop(&mut self) {
+        unsafe { DestroyPhysicalMonitors(self.0.len() as u32, self.0.as_mut_ptr()) };
+    }
+}
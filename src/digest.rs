@@ -0,0 +1,346 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::mem;
+use std::ptr;
+
+use winapi::shared::bcrypt::{
+    BCryptCloseAlgorithmProvider, BCryptCreateHash, BCryptDestroyHash, BCryptDestroyKey,
+    BCryptFinishHash, BCryptGenRandom, BCryptHashData, BCryptImportKeyPair,
+    BCryptOpenAlgorithmProvider, BCryptVerifySignature, BCRYPT_ALG_HANDLE,
+    BCRYPT_ALG_HANDLE_HMAC_FLAG, BCRYPT_ECCKEY_BLOB, BCRYPT_ECCPUBLIC_BLOB,
+    BCRYPT_ECDSA_P256_ALGORITHM, BCRYPT_ECDSA_PUBLIC_P256_MAGIC, BCRYPT_HASH_HANDLE,
+    BCRYPT_KEY_HANDLE, BCRYPT_SHA256_ALGORITHM, BCRYPT_SHA512_ALGORITHM,
+    BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+};
+use winapi::shared::ntdef::NTSTATUS;
+use winapi::shared::ntstatus::STATUS_INVALID_SIGNATURE;
+
+use amvideo::wide::to_wide;
+
+/// SHA-256 of `data`
+pub fn sha256(data: &[u8]) -> io::Result<[u8; 32]> {
+    hash(BCRYPT_SHA256_ALGORITHM, None, data)
+}
+
+/// SHA-512 of `data`, as Ed25519 hashes with
+pub fn sha512(data: &[u8]) -> io::Result<[u8; 64]> {
+    hash(BCRYPT_SHA512_ALGORITHM, None, data)
+}
+
+/// HMAC-SHA256 of `data` keyed with `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> io::Result<[u8; 32]> {
+    hash(BCRYPT_SHA256_ALGORITHM, Some(key), data)
+}
+
+/// Bytes from the system RNG, for generating keys
+pub fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    check("BCryptGenRandom", unsafe {
+        BCryptGenRandom(
+            ptr::null_mut(),
+            bytes.as_mut_ptr(),
+            N as u32,
+            BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+        )
+    })?;
+    Ok(bytes)
+}
+
+/// Lowercase hex encoding, as digests are written in manifests
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of a hex string, in either case
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` alone would also take a sign, as in "+1"
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Check an ECDSA P-256 `signature` (`r` then `s`) of `digest` against the public key point
+/// `public_key` (`x` then `y`)
+pub fn verify_p256(
+    public_key: &[u8; 64],
+    digest: &[u8; 32],
+    signature: &[u8; 64],
+) -> io::Result<bool> {
+    let algorithm_id = to_wide(BCRYPT_ECDSA_P256_ALGORITHM);
+    let blob_type = to_wide(BCRYPT_ECCPUBLIC_BLOB);
+
+    let header = BCRYPT_ECCKEY_BLOB {
+        dwMagic: BCRYPT_ECDSA_PUBLIC_P256_MAGIC,
+        cbKey: 32,
+    };
+    let mut blob = Vec::with_capacity(mem::size_of::<BCRYPT_ECCKEY_BLOB>() + public_key.len());
+    blob.extend_from_slice(&header.dwMagic.to_le_bytes());
+    blob.extend_from_slice(&header.cbKey.to_le_bytes());
+    blob.extend_from_slice(public_key);
+
+    let mut algorithm: BCRYPT_ALG_HANDLE = ptr::null_mut();
+    check("BCryptOpenAlgorithmProvider", unsafe {
+        BCryptOpenAlgorithmProvider(&mut algorithm, algorithm_id.as_ptr(), ptr::null(), 0)
+    })?;
+
+    let mut key: BCRYPT_KEY_HANDLE = ptr::null_mut();
+    let result = check("BCryptImportKeyPair", unsafe {
+        BCryptImportKeyPair(
+            algorithm,
+            ptr::null_mut(),
+            blob_type.as_ptr(),
+            &mut key,
+            blob.as_mut_ptr(),
+            blob.len() as u32,
+            0,
+        )
+    })
+    .and_then(|()| {
+        let status = unsafe {
+            BCryptVerifySignature(
+                key,
+                ptr::null_mut(),
+                digest.as_ptr() as *mut u8,
+                digest.len() as u32,
+                signature.as_ptr() as *mut u8,
+                signature.len() as u32,
+                0,
+            )
+        };
+        unsafe { BCryptDestroyKey(key) };
+        match status {
+            STATUS_INVALID_SIGNATURE => Ok(false),
+            status => check("BCryptVerifySignature", status).map(|()| true),
+        }
+    });
+    unsafe { BCryptCloseAlgorithmProvider(algorithm, 0) };
+
+    result
+}
+
+fn check(function: &str, status: NTSTATUS) -> io::Result<()> {
+    if status < 0 {
+        Err(io::Error::other(format!(
+            "{} failed: NTSTATUS {:#010x}",
+            function, status
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn hash<const N: usize>(algorithm: &str, key: Option<&[u8]>, data: &[u8]) -> io::Result<[u8; N]> {
+    let algorithm_id = to_wide(algorithm);
+    let flags = if key.is_some() {
+        BCRYPT_ALG_HANDLE_HMAC_FLAG
+    } else {
+        0
+    };
+
+    let mut algorithm: BCRYPT_ALG_HANDLE = ptr::null_mut();
+    check("BCryptOpenAlgorithmProvider", unsafe {
+        BCryptOpenAlgorithmProvider(&mut algorithm, algorithm_id.as_ptr(), ptr::null(), flags)
+    })?;
+
+    let mut digest = [0; N];
+    let mut handle: BCRYPT_HASH_HANDLE = ptr::null_mut();
+    let (secret, secret_len) = key.map_or((ptr::null_mut(), 0), |key| {
+        (key.as_ptr() as *mut u8, key.len() as u32)
+    });
+    let result = check("BCryptCreateHash", unsafe {
+        BCryptCreateHash(
+            algorithm,
+            &mut handle,
+            ptr::null_mut(),
+            0,
+            secret,
+            secret_len,
+            0,
+        )
+    })
+    .and_then(|()| {
+        let result = check("BCryptHashData", unsafe {
+            BCryptHashData(handle, data.as_ptr() as *mut u8, data.len() as u32, 0)
+        })
+        .and_then(|()| {
+            check("BCryptFinishHash", unsafe {
+                BCryptFinishHash(handle, digest.as_mut_ptr(), digest.len() as u32, 0)
+            })
+        });
+        unsafe { BCryptDestroyHash(handle) };
+        result
+    });
+    unsafe { BCryptCloseAlgorithmProvider(algorithm, 0) };
+
+    result.map(|()| digest)
+}
+
+/// The point (`x` then `y`) of a P-256 public key given as the raw point, the uncompressed point
+/// with its `04` prefix, or a DER `SubjectPublicKeyInfo`, which ends with the uncompressed point
+pub fn public_key(hex: &str) -> io::Result<[u8; 64]> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let bytes = unhex(hex.trim()).ok_or_else(|| invalid("Public key is not hex"))?;
+    let point = match bytes.len() {
+        64 => &bytes[..],
+        65 if bytes[0] == 4 => &bytes[1..],
+        91 if bytes[26] == 4 => &bytes[27..],
+        _ => return Err(invalid("Public key is not an ECDSA P-256 key")),
+    };
+
+    let mut key = [0; 64];
+    key.copy_from_slice(point);
+    Ok(key)
+}
+
+/// `r` then `s` of an ECDSA P-256 signature encoded as a DER `SEQUENCE` of two `INTEGER`s
+pub fn der_signature(der: &[u8]) -> Option<[u8; 64]> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    if tag != 0x30 || len as usize != rest.len() {
+        return None;
+    }
+
+    let mut signature = [0; 64];
+    for half in signature.chunks_mut(32) {
+        let (&tag, after) = rest.split_first()?;
+        let (&len, after) = after.split_first()?;
+        if tag != 0x02 || after.len() < len as usize {
+            return None;
+        }
+        let (integer, after) = after.split_at(len as usize);
+        // DER prefixes a zero byte to integers with the top bit set
+        let integer = match integer.iter().position(|&b| b != 0) {
+            Some(start) => &integer[start..],
+            None => &[],
+        };
+        if integer.len() > 32 {
+            return None;
+        }
+        half[32 - integer.len()..].copy_from_slice(integer);
+        rest = after;
+    }
+
+    if rest.is_empty() {
+        Some(signature)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// DER `SubjectPublicKeyInfo` header of a P-256 key, up to the uncompressed point
+    const SPKI_PREFIX: &str = "3059301306072a8648ce3d020106082a8648ce3d030107034200";
+
+    fn point() -> String {
+        (0..64u8).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn unhex_accepts_either_case() {
+        assert_eq!(unhex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(unhex(""), Some(Vec::new()));
+        assert_eq!(unhex(&hex(&[1, 0xab, 0xcd])), Some(vec![1, 0xab, 0xcd]));
+    }
+
+    #[test]
+    fn unhex_rejects_odd_length_and_non_hex() {
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+        assert_eq!(unhex("+1"), None);
+        assert_eq!(unhex("éé"), None);
+    }
+
+    #[test]
+    fn public_key_forms() {
+        let expected: Vec<u8> = (0..64).collect();
+        for key in [
+            point(),
+            format!("04{}", point()),
+            format!("{}04{}", SPKI_PREFIX, point()),
+            format!("  {}\n", point().to_uppercase()),
+        ] {
+            assert_eq!(public_key(&key).unwrap().to_vec(), expected, "{}", key);
+        }
+    }
+
+    #[test]
+    fn public_key_rejects_other_keys() {
+        // Compressed point, uncompressed point without its prefix, and not hex at all
+        assert!(public_key(&format!("02{}", &point()[..64])).is_err());
+        assert!(public_key(&format!("05{}", point())).is_err());
+        assert!(public_key(&format!("{}05{}", SPKI_PREFIX, point())).is_err());
+        assert!(public_key("not a key").is_err());
+    }
+
+    #[test]
+    fn der_signature_pads_and_strips_integers() {
+        let r: Vec<u8> = (1..=32).collect();
+        let mut s = vec![0x80];
+        s.extend(1..32);
+
+        // `s` has its top bit set, so DER gives it a leading zero
+        let mut der = vec![0x30, 2 + 32 + 2 + 33, 0x02, 32];
+        der.extend(&r);
+        der.extend([0x02, 33, 0x00]);
+        der.extend(&s);
+
+        let signature = der_signature(&der).unwrap();
+        assert_eq!(signature[..32], r[..]);
+        assert_eq!(signature[32..], s[..]);
+
+        // Short integers are right-aligned
+        let signature = der_signature(&[0x30, 6, 0x02, 1, 0x05, 0x02, 1, 0x07]).unwrap();
+        assert_eq!(signature[31], 5);
+        assert_eq!(signature[63], 7);
+        assert!(signature[..31]
+            .iter()
+            .chain(&signature[32..63])
+            .all(|&b| b == 0));
+    }
+
+    #[test]
+    fn der_signature_rejects_malformed() {
+        let valid = [0x30, 6, 0x02, 1, 0x05, 0x02, 1, 0x07];
+        assert!(der_signature(&valid).is_some());
+
+        // Wrong outer tag, wrong outer length, wrong integer tag
+        assert!(der_signature(&[0x31, 6, 0x02, 1, 0x05, 0x02, 1, 0x07]).is_none());
+        assert!(der_signature(&[0x30, 7, 0x02, 1, 0x05, 0x02, 1, 0x07]).is_none());
+        assert!(der_signature(&[0x30, 6, 0x04, 1, 0x05, 0x02, 1, 0x07]).is_none());
+        // Trailing bytes after `s`
+        assert!(der_signature(&[0x30, 7, 0x02, 1, 0x05, 0x02, 1, 0x07, 0x00]).is_none());
+        // An integer longer than the data left
+        assert!(der_signature(&[0x30, 6, 0x02, 1, 0x05, 0x02, 2, 0x07]).is_none());
+        // An integer too large for P-256
+        let mut der = vec![0x30, 2 + 33 + 3, 0x02, 33, 0x01];
+        der.extend([0xff; 32]);
+        der.extend([0x02, 1, 0x07]);
+        assert!(der_signature(&der).is_none());
+
+        for len in 0..valid.len() {
+            assert!(der_signature(&valid[..len]).is_none(), "{} bytes", len);
+        }
+    }
+}
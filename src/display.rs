@@ -0,0 +1,429 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, TRUE};
+use winapi::shared::windef::{HDC, HMONITOR, LPRECT};
+use winapi::shared::winerror::FAILED;
+use winapi::um::shellapi::{SHQueryUserNotificationState, QUNS_RUNNING_D3D_FULL_SCREEN};
+use winapi::um::shellscalingapi::{
+    GetDpiForMonitor, SetProcessDpiAwareness, MDT_EFFECTIVE_DPI, PROCESS_PER_MONITOR_DPI_AWARE,
+};
+use winapi::um::wingdi::{
+    DEVMODEW, DISPLAY_DEVICEW, DISPLAY_DEVICE_ATTACHED_TO_DESKTOP, DISPLAY_DEVICE_PRIMARY_DEVICE,
+    DM_DISPLAYFREQUENCY,
+};
+use winapi::um::winuser::{
+    ChangeDisplaySettingsExW, EnumDisplayDevicesW, EnumDisplayMonitors, EnumDisplaySettingsW,
+    GetForegroundWindow, GetMonitorInfoW, GetWindowTextW, MonitorFromWindow, CDS_NORESET,
+    CDS_UPDATEREGISTRY, DISP_CHANGE_SUCCESSFUL, ENUM_CURRENT_SETTINGS, MONITORINFOEXW,
+    MONITORINFOF_PRIMARY, MONITOR_DEFAULTTOPRIMARY,
+};
+
+use crate::error::{AmVideoCrateError, Result};
+use crate::setting::AmVideoResolution;
+use crate::wide::{from_wide, to_wide};
+
+/// Mode currently active on a display as reported by Windows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub frequency: u32,
+}
+
+/// Display attached to the desktop
+#[derive(Clone, Debug, Serialize)]
+pub struct DisplayDevice {
+    /// GDI device name, e.g. `\\.\DISPLAY1`
+    pub name: String,
+    /// Adapter description, e.g. `NVIDIA GeForce GTX 1050`
+    pub description: String,
+    pub primary: bool,
+}
+
+/// Direct3D application running in exclusive fullscreen
+#[derive(Clone, Debug, Serialize)]
+pub struct FullscreenApp {
+    /// Title of the application's foreground window
+    pub title: String,
+    /// GDI device name of the display it owns
+    pub device: String,
+}
+
+/// Full set of GDI settings for one display, enough to put it back the way it was
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplaySettings {
+    pub device: String,
+    pub width: u32,
+    pub height: u32,
+    pub frequency: u32,
+    pub bits_per_pixel: u32,
+    pub position: (i32, i32),
+    pub orientation: u32,
+}
+
+impl DisplayMode {
+    pub fn matches(&self, resolution: &AmVideoResolution) -> bool {
+        self.width == u32::from(resolution.width) && self.height == u32::from(resolution.height)
+    }
+
+    /// Whether the mode runs at `refresh` Hz, allowing for Windows reporting 119.88 Hz and the
+    /// like as 119
+    pub fn runs_at(&self, refresh: u32) -> bool {
+        self.frequency.abs_diff(refresh) <= 1
+    }
+}
+
+impl fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} @ {} Hz", self.width, self.height, self.frequency)
+    }
+}
+
+impl DisplaySettings {
+    pub fn mode(&self) -> DisplayMode {
+        DisplayMode {
+            width: self.width,
+            height: self.height,
+            frequency: self.frequency,
+        }
+    }
+}
+
+/// Query the current `DEVMODEW` of `device`, or of the primary display if `None`
+pub(crate) fn current_dev_mode(device: Option<&str>) -> io::Result<DEVMODEW> {
+    let mut dev_mode: DEVMODEW = unsafe { mem::zeroed() };
+    dev_mode.dmSize = mem::size_of::<DEVMODEW>() as u16;
+
+    let device = device.map(to_wide);
+    let device_ptr = device.as_ref().map_or(ptr::null(), |name| name.as_ptr());
+
+    let result = unsafe { EnumDisplaySettingsW(device_ptr, ENUM_CURRENT_SETTINGS, &mut dev_mode) };
+    if result == 0 {
+        return Err(io::Error::other("Failed to query current display settings"));
+    }
+
+    Ok(dev_mode)
+}
+
+/// Stage a mode change for `device` in the registry without applying it yet
+pub(crate) fn stage(device: &str, dev_mode: &mut DEVMODEW, flags: DWORD) -> Result<()> {
+    let name = to_wide(device);
+    let result = unsafe {
+        ChangeDisplaySettingsExW(
+            name.as_ptr(),
+            dev_mode,
+            ptr::null_mut(),
+            CDS_UPDATEREGISTRY | CDS_NORESET | flags,
+            ptr::null_mut(),
+        )
+    };
+    if result != DISP_CHANGE_SUCCESSFUL {
+        return Err(AmVideoCrateError::DisplayChange {
+            device: device.to_string(),
+            code: result,
+        });
+    }
+
+    Ok(())
+}
+
+/// Apply every staged mode change in one go
+pub(crate) fn commit() -> Result<()> {
+    let result = unsafe {
+        ChangeDisplaySettingsExW(
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if result != DISP_CHANGE_SUCCESSFUL {
+        return Err(AmVideoCrateError::DisplayChange {
+            device: "all displays".to_string(),
+            code: result,
+        });
+    }
+
+    Ok(())
+}
+
+/// Query the mode currently active on the primary display
+pub fn current_mode() -> io::Result<DisplayMode> {
+    let dev_mode = current_dev_mode(None)?;
+
+    Ok(DisplayMode {
+        width: dev_mode.dmPelsWidth,
+        height: dev_mode.dmPelsHeight,
+        frequency: dev_mode.dmDisplayFrequency,
+    })
+}
+
+/// Every mode the driver offers on `device`, or on the primary display if `None`. Windows leaves
+/// out modes the panel's EDID does not list or the link cannot carry.
+pub fn supported_modes(device: Option<&str>) -> Vec<DisplayMode> {
+    let device = device.map(to_wide);
+    let device_ptr = device.as_ref().map_or(ptr::null(), |name| name.as_ptr());
+
+    let mut modes = Vec::new();
+    for index in 0.. {
+        let mut dev_mode: DEVMODEW = unsafe { mem::zeroed() };
+        dev_mode.dmSize = mem::size_of::<DEVMODEW>() as u16;
+
+        if unsafe { EnumDisplaySettingsW(device_ptr, index, &mut dev_mode) } == 0 {
+            break;
+        }
+
+        // Listed once per color depth and scaling option
+        let mode = DisplayMode {
+            width: dev_mode.dmPelsWidth,
+            height: dev_mode.dmPelsHeight,
+            frequency: dev_mode.dmDisplayFrequency,
+        };
+        if !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    }
+
+    modes
+}
+
+/// Estimated pixel clock in MHz of a `width`x`height` mode at `refresh` Hz with CVT reduced
+/// blanking: 160 pixels of horizontal blanking and 460 µs of vertical blanking per frame
+pub fn pixel_clock_mhz(width: u32, height: u32, refresh: u32) -> f64 {
+    let frame = 1.0 / f64::from(refresh);
+    let lines = f64::from(height) * frame / (frame - 460e-6);
+
+    f64::from(width + 160) * lines * f64::from(refresh) / 1e6
+}
+
+/// Switch the primary display to `frequency` Hz, keeping its resolution
+pub fn set_refresh(frequency: u32) -> Result<()> {
+    let device = attached_displays()
+        .into_iter()
+        .find(|display| display.primary)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No primary display"))?;
+
+    let mut dev_mode = current_dev_mode(Some(&device.name))?;
+    dev_mode.dmFields = DM_DISPLAYFREQUENCY;
+    dev_mode.dmDisplayFrequency = frequency;
+    stage(&device.name, &mut dev_mode, 0)?;
+    commit()
+}
+
+/// Query the full settings currently active on `device`
+pub fn current_settings(device: &str) -> io::Result<DisplaySettings> {
+    let dev_mode = current_dev_mode(Some(device))?;
+    let position = unsafe { dev_mode.u1.s2().dmPosition };
+    let orientation = unsafe { dev_mode.u1.s2().dmDisplayOrientation };
+
+    Ok(DisplaySettings {
+        device: device.to_string(),
+        width: dev_mode.dmPelsWidth,
+        height: dev_mode.dmPelsHeight,
+        frequency: dev_mode.dmDisplayFrequency,
+        bits_per_pixel: dev_mode.dmBitsPerPel,
+        position: (position.x, position.y),
+        orientation,
+    })
+}
+
+/// Enumerate the displays currently attached to the desktop
+pub fn attached_displays() -> Vec<DisplayDevice> {
+    let mut displays = Vec::new();
+
+    for index in 0.. {
+        let mut device: DISPLAY_DEVICEW = unsafe { mem::zeroed() };
+        device.cb = mem::size_of::<DISPLAY_DEVICEW>() as u32;
+
+        if unsafe { EnumDisplayDevicesW(ptr::null(), index, &mut device, 0) } == 0 {
+            break;
+        }
+        if device.StateFlags & DISPLAY_DEVICE_ATTACHED_TO_DESKTOP == 0 {
+            continue;
+        }
+
+        displays.push(DisplayDevice {
+            name: from_wide(&device.DeviceName),
+            description: from_wide(&device.DeviceString),
+            primary: device.StateFlags & DISPLAY_DEVICE_PRIMARY_DEVICE != 0,
+        });
+    }
+
+    displays
+}
+
+/// Adapter Windows drives displays with while no GPU driver is loaded
+const FALLBACK_ADAPTER: &str = "Microsoft Basic Display Adapter";
+
+/// Poll until a display (or the display named `device`) is attached to the desktop, reports a
+/// mode, and is driven by its GPU driver rather than Windows' fallback adapter, which drives the
+/// displays while the real driver is still loading at boot. Gives up after `timeout`.
+pub fn wait_for_display(device: Option<&str>, timeout: Duration) -> Result<DisplayDevice> {
+    let start = Instant::now();
+
+    loop {
+        let found = attached_displays().into_iter().find(|display| {
+            device.is_none_or(|name| display.name.eq_ignore_ascii_case(name))
+                && !display.description.eq_ignore_ascii_case(FALLBACK_ADAPTER)
+                && current_dev_mode(Some(&display.name)).is_ok()
+        });
+        if let Some(display) = found {
+            return Ok(display);
+        }
+
+        if start.elapsed() >= timeout {
+            let what = device.unwrap_or("any display");
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out after {:?} waiting for {}", timeout, what),
+            )
+            .into());
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+unsafe extern "system" fn collect_monitor(
+    monitor: HMONITOR,
+    _hdc: HDC,
+    _rect: LPRECT,
+    data: LPARAM,
+) -> BOOL {
+    let monitors = &mut *(data as *mut Vec<HMONITOR>);
+    monitors.push(monitor);
+    TRUE
+}
+
+/// Find the `HMONITOR` of the display named `device`, or of the primary display if `None`
+pub(crate) fn monitor_handle(device: Option<&str>) -> io::Result<HMONITOR> {
+    let mut monitors: Vec<HMONITOR> = Vec::new();
+    let result = unsafe {
+        EnumDisplayMonitors(
+            ptr::null_mut(),
+            ptr::null(),
+            Some(collect_monitor),
+            &mut monitors as *mut _ as LPARAM,
+        )
+    };
+    if result == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    monitors
+        .into_iter()
+        .find(|&monitor| {
+            let mut info: MONITORINFOEXW = unsafe { mem::zeroed() };
+            info.cbSize = mem::size_of::<MONITORINFOEXW>() as u32;
+            if unsafe { GetMonitorInfoW(monitor, &mut info as *mut _ as *mut _) } == 0 {
+                return false;
+            }
+
+            match device {
+                Some(device) => from_wide(&info.szDevice).eq_ignore_ascii_case(device),
+                None => info.dwFlags & MONITORINFOF_PRIMARY != 0,
+            }
+        })
+        .ok_or_else(|| {
+            let what = device.unwrap_or("the primary display");
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No monitor found for {}", what),
+            )
+        })
+}
+
+/// Find an exclusive fullscreen Direct3D application in the foreground. Changing modes under one
+/// black-screens the game and sometimes the driver.
+pub fn exclusive_fullscreen_app() -> io::Result<Option<FullscreenApp>> {
+    let mut state = 0;
+    let hr = unsafe { SHQueryUserNotificationState(&mut state) };
+    if FAILED(hr) {
+        return Err(io::Error::from_raw_os_error(hr));
+    }
+    if state != QUNS_RUNNING_D3D_FULL_SCREEN {
+        return Ok(None);
+    }
+
+    let window = unsafe { GetForegroundWindow() };
+    let mut title = [0; 256];
+    let length = unsafe { GetWindowTextW(window, title.as_mut_ptr(), title.len() as i32) };
+    let title = String::from_utf16_lossy(&title[..length.max(0) as usize]);
+
+    let mut info: MONITORINFOEXW = unsafe { mem::zeroed() };
+    info.cbSize = mem::size_of::<MONITORINFOEXW>() as u32;
+    let monitor = unsafe { MonitorFromWindow(window, MONITOR_DEFAULTTOPRIMARY) };
+    let device = if unsafe { GetMonitorInfoW(monitor, &mut info as *mut _ as *mut _) } != 0 {
+        from_wide(&info.szDevice)
+    } else {
+        "an unknown display".to_string()
+    };
+
+    Ok(Some(FullscreenApp { title, device }))
+}
+
+/// Windows scaling factor of `device` in percent, 100 meaning unscaled. Makes the process
+/// per-monitor DPI aware, since Windows reports 96 DPI everywhere to unaware processes.
+pub fn scale_percent(device: &str) -> Result<u32> {
+    // Fails harmlessly if the awareness was already set, e.g. by a manifest
+    unsafe { SetProcessDpiAwareness(PROCESS_PER_MONITOR_DPI_AWARE) };
+
+    let monitor = monitor_handle(Some(device))?;
+    let mut dpi_x = 0;
+    let mut dpi_y = 0;
+    let hr = unsafe { GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y) };
+    if FAILED(hr) {
+        return Err(io::Error::from_raw_os_error(hr).into());
+    }
+
+    Ok(dpi_x * 100 / 96)
+}
+
+/// Check the primary display is running at `expected`, returning the active mode if so
+pub fn verify(expected: &AmVideoResolution) -> Result<DisplayMode> {
+    let actual = current_mode()?;
+
+    if actual.matches(expected) {
+        Ok(actual)
+    } else {
+        Err(AmVideoCrateError::Verify {
+            expected: *expected,
+            actual,
+        })
+    }
+}
+
+/// Check the primary display is running at `expected`, and at `refresh` Hz if given
+pub fn verify_mode(expected: &AmVideoResolution, refresh: Option<u32>) -> Result<DisplayMode> {
+    let actual = verify(expected)?;
+
+    match refresh {
+        Some(refresh) if !actual.runs_at(refresh) => Err(AmVideoCrateError::VerifyRefresh {
+            expected: refresh,
+            actual,
+        }),
+        _ => Ok(actual),
+    }
+}
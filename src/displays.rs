@@ -0,0 +1,164 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
+
+use amvideo::display;
+use amvideo::layout::Layout;
+use amvideo::topology::Topology;
+
+use crate::{GlobalOpts, OutputFormat};
+
+#[derive(Args)]
+pub struct DisplaysOpts {
+    #[command(subcommand)]
+    command: Option<DisplaysCommand>,
+}
+
+#[derive(Subcommand)]
+enum DisplaysCommand {
+    /// Capture the current layout of every display as a file `apply-layout` accepts
+    Export {
+        #[arg(long, value_enum, default_value_t = LayoutFormat::Toml)]
+        format: LayoutFormat,
+
+        /// Where to write the layout [default: standard output]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LayoutFormat {
+    Json,
+    Toml,
+}
+
+pub fn run(global: &GlobalOpts, opts: &DisplaysOpts) -> Result<()> {
+    match &opts.command {
+        None => show(global),
+        Some(DisplaysCommand::Export { format, out }) => export(*format, out.as_ref()),
+    }
+}
+
+fn export(format: LayoutFormat, out: Option<&PathBuf>) -> Result<()> {
+    let layout = Layout::capture().context("Failed to capture display layout")?;
+    let contents = match format {
+        LayoutFormat::Json => serde_json::to_string_pretty(&layout)? + "\n",
+        LayoutFormat::Toml => toml::to_string(&layout)?,
+    };
+
+    match out {
+        Some(path) => fs::write(path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{}", contents),
+    };
+
+    Ok(())
+}
+
+fn show(global: &GlobalOpts) -> Result<()> {
+    let mut topology = Topology::query().context("Failed to query display topology")?;
+    topology.detect_dummies();
+
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&topology)?),
+        OutputFormat::Text => print_text(&topology),
+    };
+
+    Ok(())
+}
+
+fn print_text(topology: &Topology) {
+    for adapter in &topology.adapters {
+        println!(
+            "Adapter {}: {} [{:04x}:{:04x}] LUID {}",
+            adapter.index, adapter.description, adapter.vendor_id, adapter.device_id, adapter.luid
+        );
+    }
+
+    for display in &topology.displays {
+        println!();
+        println!(
+            "{}{}",
+            display.device,
+            if display.primary { " (primary)" } else { "" }
+        );
+
+        match (&display.adapter, display.output) {
+            (Some(adapter), Some(output)) => {
+                println!("  Adapter {} output {}", adapter.index, output)
+            }
+            _ => println!("  Not enumerated by DXGI"),
+        };
+        if let Some((left, top, right, bottom)) = display.desktop {
+            println!(
+                "  Desktop {}x{} at ({}, {}), rotated {} degrees",
+                right - left,
+                bottom - top,
+                left,
+                top,
+                display.rotation
+            );
+        }
+        if display.is_spanned() {
+            println!(
+                "  Spans {} physical monitors (Surround or Eyefinity)",
+                display.physical_monitors
+            );
+        }
+        for target in &display.targets {
+            println!(
+                "  Source {} -> target {} via {} #{}: {}",
+                target.source_id,
+                target.target_id,
+                target.connector,
+                target.connector_instance,
+                if target.monitor_name.is_empty() {
+                    "unknown monitor"
+                } else {
+                    &target.monitor_name
+                }
+            );
+            if let Some(link) = target.link {
+                // Frequencies of 0 and 1 stand for the hardware default
+                let mode = display::current_settings(&display.device)
+                    .ok()
+                    .filter(|mode| mode.frequency > 1);
+                println!(
+                    "    Link: {}, up to {} MHz of pixel clock ({:.1} Gbit/s){}",
+                    link,
+                    link.max_pixel_clock_mhz(),
+                    link.max_bandwidth_gbps(),
+                    mode.map_or(String::new(), |mode| format!(
+                        ", the current mode needs about {:.0} MHz",
+                        display::pixel_clock_mhz(mode.width, mode.height, mode.frequency)
+                    ))
+                );
+            }
+            // A dummy plug lets dual mode find a second display that nobody can see
+            if !target.dummy_signs.is_empty() {
+                println!(
+                    "    Looks like a dummy plug or phantom display: {}",
+                    target.dummy_signs.join(", ")
+                );
+            }
+        }
+    }
+}
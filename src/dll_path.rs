@@ -0,0 +1,298 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::os::windows::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::accctrl::SE_FILE_OBJECT;
+use winapi::um::aclapi::GetNamedSecurityInfoW;
+use winapi::um::libloaderapi::{SetDefaultDllDirectories, LOAD_LIBRARY_SEARCH_DEFAULT_DIRS};
+use winapi::um::processenv::SearchPathW;
+use winapi::um::securitybaseapi::{CreateWellKnownSid, EqualSid, GetAce};
+use winapi::um::sysinfoapi::GetSystemDirectoryW;
+use winapi::um::winbase::LocalFree;
+use winapi::um::winnt::{
+    WinAuthenticatedUserSid, WinBuiltinUsersSid, WinWorldSid, ACCESS_ALLOWED_ACE,
+    ACCESS_ALLOWED_ACE_TYPE, ACE_HEADER, DACL_SECURITY_INFORMATION, DELETE, FILE_APPEND_DATA,
+    FILE_DELETE_CHILD, FILE_WRITE_DATA, GENERIC_ALL, GENERIC_WRITE, INHERIT_ONLY_ACE, PACL,
+    PSECURITY_DESCRIPTOR, PSID, SECURITY_MAX_SID_SIZE, WELL_KNOWN_SID_TYPE, WRITE_DAC, WRITE_OWNER,
+};
+
+use crate::wide::to_wide;
+
+/// Rights that let a principal put a different DLL in place, on a file or on its directory
+const WRITE_RIGHTS: DWORD = FILE_WRITE_DATA
+    | FILE_APPEND_DATA
+    | FILE_DELETE_CHILD
+    | DELETE
+    | WRITE_DAC
+    | WRITE_OWNER
+    | GENERIC_WRITE
+    | GENERIC_ALL;
+
+/// Groups any local user is a member of
+const BROAD_GROUPS: &[(WELL_KNOWN_SID_TYPE, &str)] = &[
+    (WinWorldSid, "Everyone"),
+    (WinAuthenticatedUserSid, "Authenticated Users"),
+    (WinBuiltinUsersSid, "Users"),
+];
+
+/// Where a DLL name was found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// A path was given rather than a bare name
+    Explicit,
+    /// Next to the executable
+    ApplicationDirectory,
+    /// The system directory
+    System,
+    /// Only on the legacy search path: the current directory or `PATH`, where anyone who can
+    /// write to one of those directories can plant a DLL of the same name
+    SearchPath,
+}
+
+#[derive(Debug)]
+pub struct Resolved {
+    pub path: PathBuf,
+    pub origin: Origin,
+}
+
+/// Drop the current directory and `PATH` from the directories `LoadLibrary` searches for this
+/// process, leaving the application directory and the system directory
+pub fn harden() -> io::Result<()> {
+    if unsafe { SetDefaultDllDirectories(LOAD_LIBRARY_SEARCH_DEFAULT_DIRS) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Find the file `LoadLibrary` would load for `name`, looking for a bare name in the safe
+/// directories before the legacy search path
+pub fn resolve(name: &OsStr) -> io::Result<Resolved> {
+    // `LoadLibrary` appends `.dll` to a name without an extension
+    let mut file_name = PathBuf::from(name);
+    if file_name.extension().is_none() {
+        file_name.set_extension("dll");
+    }
+
+    let path = Path::new(name);
+    if path.components().count() > 1 {
+        let path = std::path::absolute(&file_name)?;
+        if !path.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' does not exist", path.display()),
+            ));
+        }
+        return Ok(Resolved {
+            path,
+            origin: Origin::Explicit,
+        });
+    }
+
+    let name = file_name.as_os_str();
+    let candidates = [
+        (application_directory(), Origin::ApplicationDirectory),
+        (system_directory(), Origin::System),
+    ];
+    for (dir, origin) in candidates {
+        if let Some(path) = dir.map(|dir| dir.join(name)).filter(|path| path.is_file()) {
+            return Ok(Resolved { path, origin });
+        }
+    }
+
+    match search_path(name) {
+        Some(path) => Ok(Resolved {
+            path,
+            origin: Origin::SearchPath,
+        }),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("'{}' is not on the DLL search path", name.to_string_lossy()),
+        )),
+    }
+}
+
+/// Refuse a DLL that another user could have planted or replaced: one only found on the legacy
+/// search path, or in a file or directory a broad group may write to
+pub fn check(resolved: &Resolved) -> io::Result<()> {
+    if resolved.origin == Origin::SearchPath {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} was only found through the current directory or PATH; install it next to \
+                 the executable or in the system directory, or configure its full path",
+                resolved.path.display()
+            ),
+        ));
+    }
+
+    let dir = resolved.path.parent().unwrap_or(&resolved.path);
+    for path in [dir, resolved.path.as_path()] {
+        let writer = writable_by_anyone(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to read the permissions of {}: {}",
+                    path.display(),
+                    e
+                ),
+            )
+        })?;
+        if let Some(group) = writer {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is writable by {}", path.display(), group),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// [`resolve`] `name` and [`check`] the file found, for loaders without a way to override the
+/// check
+pub fn resolve_checked(name: &OsStr) -> io::Result<Resolved> {
+    let resolved = resolve(name)?;
+    check(&resolved)?;
+    Ok(resolved)
+}
+
+/// The broad group, such as Everyone, that may modify `path`, if any. Only ACEs that apply to
+/// `path` itself are considered.
+pub fn writable_by_anyone(path: &Path) -> io::Result<Option<&'static str>> {
+    let name = to_wide(path);
+    let mut dacl: PACL = ptr::null_mut();
+    let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+    let status = unsafe {
+        GetNamedSecurityInfoW(
+            name.as_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut dacl,
+            ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(status as i32));
+    }
+
+    let result = unsafe { broad_writer(dacl) };
+    unsafe { LocalFree(descriptor) };
+    result
+}
+
+/// # Safety
+///
+/// `dacl` must be null or point to a valid ACL.
+unsafe fn broad_writer(dacl: PACL) -> io::Result<Option<&'static str>> {
+    // No DACL at all grants everyone full access
+    if dacl.is_null() {
+        return Ok(Some("Everyone"));
+    }
+
+    let mut groups = Vec::new();
+    for &(kind, label) in BROAD_GROUPS {
+        let mut sid = [0u8; SECURITY_MAX_SID_SIZE];
+        let mut size = sid.len() as DWORD;
+        if CreateWellKnownSid(kind, ptr::null_mut(), sid.as_mut_ptr() as PSID, &mut size) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        groups.push((sid, label));
+    }
+
+    for index in 0..(*dacl).AceCount as DWORD {
+        let mut ace = ptr::null_mut();
+        if GetAce(dacl, index, &mut ace) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let header = &*(ace as *const ACE_HEADER);
+        if header.AceType != ACCESS_ALLOWED_ACE_TYPE || header.AceFlags & INHERIT_ONLY_ACE != 0 {
+            continue;
+        }
+
+        let ace = &*(ace as *const ACCESS_ALLOWED_ACE);
+        if ace.Mask & WRITE_RIGHTS == 0 {
+            continue;
+        }
+        let sid = &ace.SidStart as *const DWORD as PSID;
+        for (group, label) in &mut groups {
+            if EqualSid(sid, group.as_mut_ptr() as PSID) != 0 {
+                return Ok(Some(label));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn application_directory() -> Option<PathBuf> {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+}
+
+fn system_directory() -> Option<PathBuf> {
+    let mut buffer = [0u16; 260];
+    let len = unsafe { GetSystemDirectoryW(buffer.as_mut_ptr(), buffer.len() as u32) } as usize;
+    if len == 0 || len > buffer.len() {
+        return None;
+    }
+    Some(PathBuf::from(OsString::from_wide(&buffer[..len])))
+}
+
+/// Where the legacy search order, with the current directory and `PATH`, finds `name`
+fn search_path(name: &OsStr) -> Option<PathBuf> {
+    let name = to_wide(name);
+    let mut buffer = [0u16; 1024];
+    let len = unsafe {
+        SearchPathW(
+            ptr::null(),
+            name.as_ptr(),
+            ptr::null(),
+            buffer.len() as u32,
+            buffer.as_mut_ptr(),
+            ptr::null_mut(),
+        )
+    } as usize;
+    if len == 0 || len > buffer.len() {
+        return None;
+    }
+    Some(PathBuf::from(OsString::from_wide(&buffer[..len])))
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let origin = match self {
+            Self::Explicit => "configured path",
+            Self::ApplicationDirectory => "application directory",
+            Self::System => "system directory",
+            Self::SearchPath => "search path",
+        };
+        f.write_str(origin)
+    }
+}
@@ -0,0 +1,357 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use amvideo::conflicts;
+use amvideo::display;
+use amvideo::dll_path;
+use amvideo::hdr;
+use amvideo::hooks;
+use amvideo::nvapi::NvApi;
+use amvideo::platform;
+use amvideo::topology::Topology;
+use amvideo::{dll_name, AmVideo, AmVideoCrateError};
+
+use crate::export_map;
+use crate::{GlobalOpts, OutputFormat};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// Outcome of one diagnostic
+#[derive(Debug, Serialize)]
+pub struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new<N: Into<String>, D: Into<String>>(name: N, status: Status, detail: D) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+pub fn run(global: &GlobalOpts) -> Result<()> {
+    let checks = checks();
+
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&checks)?),
+        OutputFormat::Text => {
+            for check in &checks {
+                let status = match check.status {
+                    Status::Ok => "ok",
+                    Status::Warn => "WARN",
+                    Status::Fail => "FAIL",
+                };
+                println!("[{:>4}] {}: {}", status, check.name, check.detail);
+            }
+        }
+    };
+
+    match checks.iter().filter(|c| c.status == Status::Fail).count() {
+        0 => Ok(()),
+        failed => Err(anyhow!("{} of {} checks failed", failed, checks.len())),
+    }
+}
+
+/// Every diagnostic, in the order they are printed
+pub fn checks() -> Vec<Check> {
+    let mut checks = vec![check_platform(), check_elevation(), check_dll()];
+    checks.extend(check_scaling());
+    checks.push(check_spanning());
+    checks.push(check_dummies());
+    checks.push(check_hdr());
+    checks.push(check_frame_lock());
+    checks.push(check_hooks());
+    checks.push(check_conflicts());
+    checks
+}
+
+/// What kind of machine this is, and what that classification was based on
+fn check_platform() -> Check {
+    let detection = platform::detect();
+    let warnings: Vec<String> = detection
+        .platform
+        .warning()
+        .map(str::to_string)
+        .into_iter()
+        .chain(detection.environment_warning())
+        .collect();
+    let status = if warnings.is_empty() {
+        Status::Ok
+    } else {
+        Status::Warn
+    };
+    let mut detail = format!("{} ({})", detection.platform, detection.evidence.join(", "));
+    for warning in &warnings {
+        detail.push_str(&format!("; {}", warning));
+    }
+
+    Check::new("platform", status, detail)
+}
+
+/// Whether the operations that need administrator rights will run or be skipped
+fn check_elevation() -> Check {
+    match platform::is_elevated() {
+        Ok(true) => Check::new("elevation", Status::Ok, "running as administrator"),
+        Ok(false) => Check::new(
+            "elevation",
+            Status::Warn,
+            "not running as administrator, so G-SYNC mode changes and writes to protected \
+             segatools.ini files will be skipped",
+        ),
+        Err(e) => Check::new("elevation", Status::Warn, format!("Failed to check: {}", e)),
+    }
+}
+
+/// The configured DLL can be found, loaded, and exports everything
+fn check_dll() -> Check {
+    let name = match dll_name() {
+        Ok(name) => name,
+        Err(e) => {
+            return Check::new(
+                "amVideo DLL",
+                Status::Fail,
+                format!("{:#}", anyhow::Error::from(e)),
+            )
+        }
+    };
+
+    let loaded = dll_path::resolve_checked(&name)
+        .map_err(AmVideoCrateError::from)
+        .and_then(|resolved| AmVideo::with_exports(resolved.path, &export_map::get()));
+    match loaded {
+        Ok(_) => Check::new(
+            "amVideo DLL",
+            Status::Ok,
+            format!("{} loads with all exports", name.to_string_lossy()),
+        ),
+        Err(e) => Check::new(
+            "amVideo DLL",
+            Status::Fail,
+            format!("{:#}", anyhow::Error::from(e)),
+        ),
+    }
+}
+
+/// Other hook frameworks loaded into this process, or inline hooks on the DLL's exports
+fn check_hooks() -> Check {
+    let mut found: Vec<String> = match hooks::loaded_hooks() {
+        Ok(hooks) => hooks
+            .into_iter()
+            .map(|hook| format!("{} from {} ({})", hook.module, hook.framework, hook.reason))
+            .collect(),
+        Err(e) => return Check::new("hooks", Status::Warn, format!("Failed to check: {}", e)),
+    };
+    if let Ok(amvideo) = dll_name().and_then(|name| {
+        let resolved = dll_path::resolve_checked(&name)?;
+        AmVideo::with_exports(resolved.path, &export_map::get())
+    }) {
+        found.extend(
+            amvideo
+                .hooked_exports()
+                .into_iter()
+                .map(|name| format!("{} starts with a jump", name)),
+        );
+    }
+
+    if found.is_empty() {
+        Check::new("hooks", Status::Ok, "No other hook framework found")
+    } else {
+        Check::new("hooks", Status::Warn, found.join(", "))
+    }
+}
+
+/// Display-management software that reverts modes, the usual cause of "my resolution keeps
+/// reverting"
+fn check_conflicts() -> Check {
+    match conflicts::running() {
+        Ok(found) if found.is_empty() => Check::new(
+            "Conflicting software",
+            Status::Ok,
+            "No known display-management software running",
+        ),
+        Ok(found) => Check::new(
+            "Conflicting software",
+            Status::Warn,
+            found
+                .iter()
+                .map(|c| format!("{} ({}): {}", c.product, c.process, c.reason))
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        Err(e) => Check::new(
+            "Conflicting software",
+            Status::Warn,
+            format!("Failed to check: {}", e),
+        ),
+    }
+}
+
+/// Windows scaling other than 100% breaks touch alignment and letterboxing in most titles
+fn check_scaling() -> Vec<Check> {
+    display::attached_displays()
+        .into_iter()
+        .map(|device| {
+            let name = format!("{} scaling", device.name);
+            match display::scale_percent(&device.name) {
+                Ok(100) => Check::new(name, Status::Ok, "100%"),
+                Ok(scale) => Check::new(
+                    name,
+                    Status::Warn,
+                    format!(
+                        "{}%, which breaks touch alignment and letterboxing; set it to 100% in \
+                         Settings > System > Display",
+                        scale
+                    ),
+                ),
+                Err(e) => Check::new(name, Status::Warn, format!("Unknown: {}", e)),
+            }
+        })
+        .collect()
+}
+
+/// HDR washes out the colors of SegaTiming modes
+fn check_hdr() -> Check {
+    let states = match hdr::query() {
+        Ok(states) => states,
+        Err(e) => return Check::new("HDR", Status::Warn, format!("Unknown: {}", e)),
+    };
+
+    let enabled: Vec<_> = states
+        .iter()
+        .filter(|state| state.enabled)
+        .map(|state| state.device.as_str())
+        .collect();
+    if enabled.is_empty() {
+        Check::new("HDR", Status::Ok, "Off on every display")
+    } else {
+        Check::new(
+            "HDR",
+            Status::Warn,
+            format!(
+                "On for {}, which washes out SegaTiming modes; set `hdr = \"disable\"` in the \
+                 profile or turn it off in Settings > System > Display",
+                enabled.join(", ")
+            ),
+        )
+    }
+}
+
+/// Surround and Eyefinity groups hide the physical monitors from the DLL
+fn check_spanning() -> Check {
+    let topology = match Topology::query() {
+        Ok(topology) => topology,
+        Err(e) => return Check::new("Spanning", Status::Warn, format!("Unknown: {}", e)),
+    };
+
+    let spanned: Vec<_> = topology
+        .displays
+        .iter()
+        .filter(|display| display.is_spanned())
+        .map(|display| display.device.as_str())
+        .collect();
+    if spanned.is_empty() {
+        Check::new("Spanning", Status::Ok, "No Surround or Eyefinity groups")
+    } else {
+        Check::new(
+            "Spanning",
+            Status::Fail,
+            format!("{} span several monitors", spanned.join(", ")),
+        )
+    }
+}
+
+/// Dummy plugs and phantom displays, which satisfy dual mode without a second panel to see it on
+fn check_dummies() -> Check {
+    let mut topology = match Topology::query() {
+        Ok(topology) => topology,
+        Err(e) => return Check::new("Dummy displays", Status::Warn, format!("Unknown: {}", e)),
+    };
+    topology.detect_dummies();
+
+    let dummies: Vec<String> = topology
+        .displays
+        .iter()
+        .flat_map(|display| {
+            display
+                .targets
+                .iter()
+                .filter(|target| !target.dummy_signs.is_empty())
+                .map(move |target| {
+                    format!("{} ({})", display.device, target.dummy_signs.join(", "))
+                })
+        })
+        .collect();
+    if dummies.is_empty() {
+        Check::new(
+            "Dummy displays",
+            Status::Ok,
+            "Every display looks like a real monitor",
+        )
+    } else {
+        Check::new(
+            "Dummy displays",
+            Status::Warn,
+            format!("{} look like dummy plugs", dummies.join(", ")),
+        )
+    }
+}
+
+/// Linked multi-cabinet setups rely on Quadro Sync frame lock
+fn check_frame_lock() -> Check {
+    let devices = match NvApi::load().and_then(|nvapi| nvapi.frame_lock_status()) {
+        Ok(devices) => devices,
+        Err(e) => return Check::new("Frame lock", Status::Ok, format!("Not available: {}", e)),
+    };
+    if devices.is_empty() {
+        return Check::new("Frame lock", Status::Ok, "No Quadro Sync boards");
+    }
+
+    let mut status = Status::Ok;
+    let mut details = Vec::new();
+    for device in &devices {
+        for gpu in &device.gpus {
+            let state = match (gpu.signal_available, gpu.synced) {
+                (false, _) => "no sync signal",
+                (true, false) => "not locked",
+                (true, true) => "locked",
+            };
+            if !gpu.synced {
+                status = Status::Warn;
+            }
+            details.push(format!("board {} GPU {}: {}", device.index, gpu.gpu, state));
+        }
+        if device.gpus.is_empty() {
+            status = Status::Warn;
+            details.push(format!("board {}: no GPUs attached", device.index));
+        }
+    }
+
+    Check::new("Frame lock", status, details.join(", "))
+}
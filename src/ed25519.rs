@@ -0,0 +1,498 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+
+use crate::digest::sha512;
+
+// Field elements mod 2^255 - 19 as sixteen 16-bit limbs, following TweetNaCl. Nothing here
+// handles secrets in a way that needs to be constant time beyond what TweetNaCl does.
+type Gf = [i64; 16];
+type Point = [Gf; 4];
+
+const GF0: Gf = [0; 16];
+const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const D: Gf = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7,
+    0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+const D2: Gf = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e,
+    0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+const X: Gf = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4,
+    0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const Y: Gf = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666,
+];
+const I: Gf = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d,
+    0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+/// Order of the base point, little endian
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// Public key of the 32-byte private key `seed`
+pub fn public_key(seed: &[u8; 32]) -> io::Result<[u8; 32]> {
+    let (scalar, _) = expand(seed)?;
+    Ok(pack(&scalarbase(&scalar)))
+}
+
+/// Signature of `message` (`R` then `s`) by the private key `seed`
+pub fn sign(seed: &[u8; 32], message: &[u8]) -> io::Result<[u8; 64]> {
+    let (scalar, prefix) = expand(seed)?;
+    let public = pack(&scalarbase(&scalar));
+
+    let r = reduce(&sha512(&[&prefix[..], message].concat())?);
+    let big_r = pack(&scalarbase(&r));
+    let h = reduce(&sha512(&[&big_r[..], &public[..], message].concat())?);
+
+    let mut x = [0i64; 64];
+    for (x, &r) in x.iter_mut().zip(&r) {
+        *x = r as i64;
+    }
+    for (i, &h) in h.iter().enumerate() {
+        for (j, &a) in scalar.iter().enumerate() {
+            x[i + j] += h as i64 * a as i64;
+        }
+    }
+
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&mod_l(&mut x));
+    Ok(signature)
+}
+
+/// Check the Ed25519 `signature` of `message` against `public_key`
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> io::Result<bool> {
+    let mut s = [0; 32];
+    s.copy_from_slice(&signature[32..]);
+    // Reject `s` at or above the group order, which would make signatures malleable
+    if !less_than_l(&s) {
+        return Ok(false);
+    }
+    let negated = match unpack_negated(public_key) {
+        Some(point) => point,
+        None => return Ok(false),
+    };
+
+    let h = reduce(&sha512(
+        &[&signature[..32], &public_key[..], message].concat(),
+    )?);
+    let mut p = scalarmult(&negated, &h);
+    add_point(&mut p, &scalarbase(&s));
+
+    Ok(pack(&p)[..] == signature[..32])
+}
+
+/// Clamped scalar and nonce prefix of `seed`
+fn expand(seed: &[u8; 32]) -> io::Result<([u8; 32], [u8; 32])> {
+    let d = sha512(seed)?;
+    let mut scalar = [0; 32];
+    let mut prefix = [0; 32];
+    scalar.copy_from_slice(&d[..32]);
+    prefix.copy_from_slice(&d[32..]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    Ok((scalar, prefix))
+}
+
+fn less_than_l(s: &[u8; 32]) -> bool {
+    for (&s, &l) in s.iter().zip(&L).rev() {
+        match (s as i64).cmp(&l) {
+            std::cmp::Ordering::Less => return true,
+            std::cmp::Ordering::Greater => return false,
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+    false
+}
+
+/// `x` mod `L`, consuming `x`
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut r = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+/// A 64-byte hash reduced mod `L`
+fn reduce(hash: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (x, &b) in x.iter_mut().zip(hash.iter()) {
+        *x = b as i64;
+    }
+    mod_l(&mut x)
+}
+
+fn carry(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// Swap `p` and `q` if `b` is 1
+fn select(p: &mut Gf, q: &mut Gf, b: i64) {
+    let c = !(b - 1);
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        let t = c & (*p ^ *q);
+        *p ^= t;
+        *q ^= t;
+    }
+}
+
+fn pack_gf(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+
+    let mut m = GF0;
+    for _ in 0..2 {
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        select(&mut t, &mut m, 1 - b);
+    }
+
+    let mut o = [0; 32];
+    for (o, t) in o.chunks_mut(2).zip(&t) {
+        o[0] = *t as u8;
+        o[1] = (*t >> 8) as u8;
+    }
+    o
+}
+
+fn unpack_gf(n: &[u8; 32]) -> Gf {
+    let mut o = GF0;
+    for (o, n) in o.iter_mut().zip(n.chunks(2)) {
+        *o = n[0] as i64 + ((n[1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn parity(a: &Gf) -> u8 {
+    pack_gf(a)[0] & 1
+}
+
+fn add(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn sub(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o = GF0;
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+fn square(a: &Gf) -> Gf {
+    mul(a, a)
+}
+
+fn invert(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=253).rev() {
+        c = square(&c);
+        if a != 2 && a != 4 {
+            c = mul(&c, i);
+        }
+    }
+    c
+}
+
+/// `i` to the power of (p - 5) / 8, for square roots
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=250).rev() {
+        c = square(&c);
+        if a != 1 {
+            c = mul(&c, i);
+        }
+    }
+    c
+}
+
+fn add_point(p: &mut Point, q: &Point) {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add(&p[0], &p[1]), &add(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = add(&d, &d);
+    let e = sub(&b, &a);
+    let f = sub(&d, &c);
+    let g = add(&d, &c);
+    let h = add(&b, &a);
+
+    p[0] = mul(&e, &f);
+    p[1] = mul(&h, &g);
+    p[2] = mul(&g, &f);
+    p[3] = mul(&e, &h);
+}
+
+fn swap_points(p: &mut Point, q: &mut Point, b: i64) {
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        select(p, q, b);
+    }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = invert(&p[2]);
+    let tx = mul(&p[0], &zi);
+    let ty = mul(&p[1], &zi);
+    let mut r = pack_gf(&ty);
+    r[31] ^= parity(&tx) << 7;
+    r
+}
+
+fn scalarmult(q: &Point, s: &[u8; 32]) -> Point {
+    let mut p = [GF0, GF1, GF1, GF0];
+    let mut q = *q;
+    for i in (0..256).rev() {
+        let b = ((s[i / 8] >> (i & 7)) & 1) as i64;
+        swap_points(&mut p, &mut q, b);
+        add_point(&mut q, &p);
+        let doubled = p;
+        add_point(&mut p, &doubled);
+        swap_points(&mut p, &mut q, b);
+    }
+    p
+}
+
+fn scalarbase(s: &[u8; 32]) -> Point {
+    scalarmult(&[X, Y, GF1, mul(&X, &Y)], s)
+}
+
+/// The negation of the point encoded as `p`, which verification adds to `[s]B`
+fn unpack_negated(p: &[u8; 32]) -> Option<Point> {
+    let mut r = [GF0, unpack_gf(p), GF1, GF0];
+
+    let mut num = square(&r[1]);
+    let mut den = mul(&num, &D);
+    num = sub(&num, &r[2]);
+    den = add(&r[2], &den);
+
+    let den2 = square(&den);
+    let den4 = square(&den2);
+    let den6 = mul(&den4, &den2);
+    let mut t = mul(&den6, &num);
+    t = mul(&t, &den);
+
+    t = pow2523(&t);
+    t = mul(&t, &num);
+    t = mul(&t, &den);
+    t = mul(&t, &den);
+    r[0] = mul(&t, &den);
+
+    let check = mul(&square(&r[0]), &den);
+    if pack_gf(&check) != pack_gf(&num) {
+        r[0] = mul(&r[0], &I);
+    }
+    let check = mul(&square(&r[0]), &den);
+    if pack_gf(&check) != pack_gf(&num) {
+        return None;
+    }
+
+    if parity(&r[0]) == p[31] >> 7 {
+        r[0] = sub(&GF0, &r[0]);
+    }
+    r[3] = mul(&r[0], &r[1]);
+    Some(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::digest::unhex;
+
+    /// Seed, public key, message, and signature of the RFC 8032 section 7.1 test vectors
+    const VECTORS: [(&str, &str, &str, &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bac\
+             c61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e\
+             458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290\
+             ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    struct Vector {
+        seed: [u8; 32],
+        public: [u8; 32],
+        message: Vec<u8>,
+        signature: [u8; 64],
+    }
+
+    fn vectors() -> Vec<Vector> {
+        VECTORS
+            .iter()
+            .map(|(seed, public, message, signature)| Vector {
+                seed: unhex(seed).unwrap().try_into().unwrap(),
+                public: unhex(public).unwrap().try_into().unwrap(),
+                message: unhex(message).unwrap(),
+                signature: unhex(signature).unwrap().try_into().unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rfc8032_vectors() {
+        for vector in vectors() {
+            assert_eq!(public_key(&vector.seed).unwrap(), vector.public);
+            assert_eq!(
+                sign(&vector.seed, &vector.message).unwrap()[..],
+                vector.signature[..]
+            );
+            assert!(verify(&vector.public, &vector.message, &vector.signature).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_a_flipped_bit() {
+        for vector in vectors() {
+            // A different bit of every byte, to keep the test quick
+            for byte in 0..64 {
+                let mut signature = vector.signature;
+                signature[byte] ^= 1 << (byte % 8);
+                assert!(!verify(&vector.public, &vector.message, &signature).unwrap());
+            }
+
+            let mut message = vector.message.clone();
+            match message.first_mut() {
+                Some(byte) => *byte ^= 1,
+                None => message.push(0),
+            }
+            assert!(!verify(&vector.public, &message, &vector.signature).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_a_wrong_key() {
+        let vectors = vectors();
+        for (vector, other) in vectors.iter().zip(vectors.iter().cycle().skip(1)) {
+            assert!(!verify(&other.public, &vector.message, &vector.signature).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_a_non_canonical_s() {
+        for vector in vectors() {
+            // `s + L` is the same scalar mod L, so only the range check can refuse it
+            let mut signature = vector.signature;
+            let mut carry = 0;
+            for (byte, &l) in signature[32..].iter_mut().zip(L.iter()) {
+                let sum = i64::from(*byte) + l + carry;
+                *byte = sum as u8;
+                carry = sum >> 8;
+            }
+            assert_eq!(carry, 0);
+            assert!(!verify(&vector.public, &vector.message, &signature).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_a_key_off_the_curve() {
+        let vector = &vectors()[0];
+        // y = 2 has no x on the curve
+        let mut public = [0; 32];
+        public[0] = 2;
+        assert!(!verify(&public, &vector.message, &vector.signature).unwrap());
+    }
+}
@@ -0,0 +1,218 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+
+use winreg::enums::{RegType, HKEY_LOCAL_MACHINE, KEY_ALL_ACCESS};
+use winreg::{RegKey, RegValue};
+
+use crate::error::{AmVideoCrateError, Result};
+
+/// Length of the base block and of every extension block
+pub const BLOCK_SIZE: usize = 128;
+
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+/// Subkey of a monitor's `Device Parameters` the graphics driver reads in place of the EDID the
+/// monitor reports, one binary value per block named by its index
+const OVERRIDE_KEY: &str = "EDID_OVERRIDE";
+
+/// Words dummy plugs and virtual monitors put in their monitor name
+const DUMMY_NAMES: &[&str] = &["dummy", "headless", "ghost", "virtual", "fake"];
+
+/// Identification a base EDID block carries
+#[derive(Clone, Debug, Default)]
+pub struct Info {
+    /// Three-letter PNP manufacturer ID, e.g. `DEL`
+    pub manufacturer: String,
+    pub product: u16,
+    pub serial: u32,
+    /// Monitor name descriptor
+    pub name: Option<String>,
+    /// Serial number descriptor
+    pub serial_text: Option<String>,
+    /// Physical size in centimeters, zero when not given
+    pub width_cm: u8,
+    pub height_cm: u8,
+}
+
+impl Info {
+    /// Parse the base block of `data`, if it starts with the EDID header
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < BLOCK_SIZE || data[..HEADER.len()] != HEADER {
+            return None;
+        }
+
+        let id = u16::from_be_bytes([data[8], data[9]]);
+        let manufacturer = [10, 5, 0]
+            .iter()
+            .map(|shift| char::from(b'@' + ((id >> shift) & 0x1f) as u8))
+            .collect();
+        let mut info = Self {
+            manufacturer,
+            product: u16::from_le_bytes([data[10], data[11]]),
+            serial: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            width_cm: data[21],
+            height_cm: data[22],
+            ..Default::default()
+        };
+
+        // Display descriptors start with a zero pixel clock, their tag is the fourth byte
+        for descriptor in data[54..126].chunks(18) {
+            if descriptor[..2] != [0, 0] {
+                continue;
+            }
+            let text = String::from_utf8_lossy(&descriptor[5..])
+                .split('\n')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            match descriptor[3] {
+                0xfc => info.name = Some(text),
+                0xff => info.serial_text = Some(text),
+                _ => {}
+            }
+        }
+
+        Some(info)
+    }
+
+    /// Whether the monitor name is one dummy plugs and virtual monitors use
+    pub fn has_dummy_name(&self) -> bool {
+        self.name.as_deref().is_some_and(|name| {
+            let name = name.to_ascii_lowercase();
+            DUMMY_NAMES.iter().any(|dummy| name.contains(dummy))
+        })
+    }
+}
+
+/// The EDID the monitor a `monitorDevicePath` names last reported, as Windows stored it
+pub fn read(monitor_device_path: &str) -> Result<Vec<u8>> {
+    let key = device_parameters_key(monitor_device_path)?;
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(&key)
+        .and_then(|parameters| parameters.get_raw_value("EDID"))
+        .map(|value| value.bytes)
+        .map_err(|source| AmVideoCrateError::Registry {
+            path: format!("{}\\EDID", key),
+            source,
+        })
+}
+
+/// Check that `data` is a whole EDID: the header, a valid checksum on every block, and as many
+/// extension blocks as the base block announces
+pub fn validate(data: &[u8]) -> io::Result<()> {
+    let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+
+    if data.len() < BLOCK_SIZE || !data.len().is_multiple_of(BLOCK_SIZE) {
+        return invalid(format!(
+            "EDID must be a whole number of {}-byte blocks, got {} bytes",
+            BLOCK_SIZE,
+            data.len()
+        ));
+    }
+    if data[..HEADER.len()] != HEADER {
+        return invalid("EDID does not start with the EDID header".to_string());
+    }
+    let extensions = usize::from(data[126]);
+    if data.len() != (extensions + 1) * BLOCK_SIZE {
+        return invalid(format!(
+            "EDID announces {} extension blocks but holds {}",
+            extensions,
+            data.len() / BLOCK_SIZE - 1
+        ));
+    }
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let sum = block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0 {
+            return invalid(format!("EDID block {} has a bad checksum", index));
+        }
+    }
+
+    Ok(())
+}
+
+/// `Device Parameters` key, relative to `HKLM`, of the monitor a `monitorDevicePath` such as
+/// `\\?\DISPLAY#DEL4082#5&2a3b4c5d&0&UID4353#{e6f07b5f-...}` names
+pub fn device_parameters_key(monitor_device_path: &str) -> io::Result<String> {
+    let parts: Vec<&str> = monitor_device_path
+        .trim_start_matches(r"\\?\")
+        .split('#')
+        .collect();
+    match parts.as_slice() {
+        [class, model, instance, _interface] => Ok(format!(
+            "SYSTEM\\CurrentControlSet\\Enum\\{}\\{}\\{}\\Device Parameters",
+            class, model, instance
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unrecognized monitor device path '{}'", monitor_device_path),
+        )),
+    }
+}
+
+/// Make the driver use `data` as the monitor's EDID from its next restart on, replacing any
+/// override already installed. Needs administrator rights.
+pub fn install_override(monitor_device_path: &str, data: &[u8]) -> Result<()> {
+    validate(data)?;
+    let key = device_parameters_key(monitor_device_path)?;
+    let path = format!("{}\\{}", key, OVERRIDE_KEY);
+    let registry = |source| AmVideoCrateError::Registry {
+        path: path.clone(),
+        source,
+    };
+
+    let parameters = open(&key)?;
+    if let Err(e) = parameters.delete_subkey_all(OVERRIDE_KEY) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(registry(e));
+        }
+    }
+    let (overrides, _) = parameters.create_subkey(OVERRIDE_KEY).map_err(registry)?;
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let value = RegValue {
+            bytes: block.to_vec(),
+            vtype: RegType::REG_BINARY,
+        };
+        overrides
+            .set_raw_value(index.to_string(), &value)
+            .map_err(registry)?;
+    }
+
+    Ok(())
+}
+
+/// Remove the monitor's EDID override, returning whether there was one
+pub fn remove_override(monitor_device_path: &str) -> Result<bool> {
+    let key = device_parameters_key(monitor_device_path)?;
+    match open(&key)?.delete_subkey_all(OVERRIDE_KEY) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(source) => Err(AmVideoCrateError::Registry {
+            path: format!("{}\\{}", key, OVERRIDE_KEY),
+            source,
+        }),
+    }
+}
+
+fn open(key: &str) -> Result<RegKey> {
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey_with_flags(key, KEY_ALL_ACCESS)
+        .map_err(|source| AmVideoCrateError::Registry {
+            path: key.to_string(),
+            source,
+        })
+}
@@ -0,0 +1,137 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+
+use amvideo::edid;
+use amvideo::topology::{Target, Topology};
+
+use crate::elevation;
+use crate::{prompt, GlobalOpts};
+
+#[derive(Args)]
+pub struct EdidOpts {
+    #[command(subcommand)]
+    action: EdidAction,
+}
+
+#[derive(Subcommand)]
+enum EdidAction {
+    /// Make the driver use an EDID binary in place of the one the monitor reports
+    Install {
+        /// GDI device name (e.g. `\\.\DISPLAY2`) or monitor name
+        display: String,
+        /// EDID binary, e.g. one saved by CRU or a panel vendor
+        file: PathBuf,
+    },
+    /// Go back to the EDID the monitor reports
+    Remove {
+        /// GDI device name (e.g. `\\.\DISPLAY2`) or monitor name
+        display: String,
+    },
+}
+
+pub fn run(global: &GlobalOpts, opts: &EdidOpts) -> Result<()> {
+    if !elevation::elevated() {
+        return Err(anyhow!(
+            "Changing EDID overrides needs administrator rights, run amvideo as administrator"
+        ));
+    }
+
+    match &opts.action {
+        EdidAction::Install { display, file } => {
+            let data = fs::read(file)
+                .with_context(|| format!("Failed to read EDID '{}'", file.display()))?;
+            edid::validate(&data)
+                .with_context(|| format!("'{}' is not a valid EDID", file.display()))?;
+            let target = monitor(display)?;
+            prompt(
+                global,
+                &format!(
+                    "About to override the EDID of {} with '{}' ({} blocks)",
+                    describe(&target),
+                    file.display(),
+                    data.len() / edid::BLOCK_SIZE
+                ),
+            )?;
+
+            edid::install_override(&target.monitor_device_path, &data)?;
+            println!("Installed the EDID override for {}", describe(&target));
+        }
+        EdidAction::Remove { display } => {
+            let target = monitor(display)?;
+            if !edid::remove_override(&target.monitor_device_path)? {
+                println!("{} has no EDID override", describe(&target));
+                return Ok(());
+            }
+            println!("Removed the EDID override for {}", describe(&target));
+        }
+    }
+
+    // The driver reads the EDID when the monitor is enumerated
+    println!("Restart the graphics driver or reboot for the change to take effect");
+    Ok(())
+}
+
+/// The one monitor `selector` names, by GDI device name or monitor name
+fn monitor(selector: &str) -> Result<Target> {
+    let topology = Topology::query()?;
+    let mut targets = Vec::new();
+    for route in topology.displays {
+        if route.device.eq_ignore_ascii_case(selector) {
+            targets.extend(route.targets);
+        } else {
+            targets.extend(
+                route
+                    .targets
+                    .into_iter()
+                    .filter(|target| target.monitor_name.eq_ignore_ascii_case(selector)),
+            );
+        }
+    }
+
+    match targets.len() {
+        0 => Err(anyhow!("No monitor matches '{}'", selector)),
+        1 => Ok(targets.remove(0)),
+        count => Err(anyhow!(
+            "'{}' matches {} monitors, name one of them: {}",
+            selector,
+            count,
+            targets
+                .iter()
+                .map(|target| target.monitor_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn describe(target: &Target) -> String {
+    match target.monitor_name.as_str() {
+        "" => format!(
+            "the monitor on {} {}",
+            target.connector, target.connector_instance
+        ),
+        name => format!(
+            "{} on {} {}",
+            name, target.connector, target.connector_instance
+        ),
+    }
+}
@@ -0,0 +1,58 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::OnceLock;
+
+use amvideo::platform;
+
+use crate::audit::{self, Event};
+
+static ELEVATED: OnceLock<bool> = OnceLock::new();
+
+/// Whether amvideo runs as administrator. If that cannot be told, operations are attempted and
+/// left to report their own errors.
+pub fn elevated() -> bool {
+    *ELEVATED.get_or_init(|| platform::is_elevated().unwrap_or(true))
+}
+
+/// Operations left out of one run for lack of administrator rights, reported together at the end
+#[derive(Debug, Default)]
+pub struct Skipped(Vec<&'static str>);
+
+impl Skipped {
+    /// Whether `operation` can go ahead, noting it as skipped if it needs elevation amvideo does
+    /// not have
+    pub fn allow(&mut self, operation: &'static str) -> bool {
+        if elevated() {
+            return true;
+        }
+
+        eprintln!("Skipping {}: needs administrator rights", operation);
+        audit::record(Event::Skip { operation });
+        self.0.push(operation);
+        false
+    }
+
+    pub fn report(&self) {
+        if !self.0.is_empty() {
+            eprintln!(
+                "Warning: Skipped without administrator rights: {}. Run amvideo as administrator \
+                 to apply them.",
+                self.0.join(", ")
+            );
+        }
+    }
+}
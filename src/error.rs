@@ -0,0 +1,108 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::display::DisplayMode;
+use crate::setting::AmVideoResolution;
+
+pub type Result<T, E = AmVideoCrateError> = std::result::Result<T, E>;
+
+/// Failure classes surfaced by the library
+#[derive(Debug, Error)]
+pub enum AmVideoCrateError {
+    /// Reading the amVideo configuration from the registry failed
+    #[error("Failed to read '{path}' from the registry")]
+    Registry {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// `LoadLibraryW` failed for the amVideo DLL
+    #[error("Failed to load '{name}'")]
+    Load {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The DLL is missing one or more of the expected exports
+    #[error("Failed to find functions: {}", .functions.join(", "))]
+    Resolve { functions: Vec<String> },
+
+    /// Patch targets found neither by symbol, in the build database, nor by signature
+    #[error("Failed to locate {} in this amVideo build", .targets.join(", "))]
+    Unresolved { targets: Vec<&'static str> },
+
+    /// A call needs the context in a lifecycle state it is not in, see `ArcAmVideo`
+    #[error("amVideo is not {expected}")]
+    WrongState { expected: &'static str },
+
+    /// An amVideo export returned a non-zero status
+    #[error("amVideo function failed: {code} ({code:#x})")]
+    DllCall { code: usize },
+
+    /// The display did not end up in the requested mode
+    #[error("Requested {expected}, display reports {actual}")]
+    Verify {
+        expected: AmVideoResolution,
+        actual: DisplayMode,
+    },
+
+    /// The display ended up at the requested resolution but another refresh rate
+    #[error("Requested {expected} Hz, display reports {actual}")]
+    VerifyRefresh { expected: u32, actual: DisplayMode },
+
+    /// `ChangeDisplaySettingsExW` rejected a mode change
+    #[error("Failed to change display settings for {device}: DISP_CHANGE code {code}")]
+    DisplayChange { device: String, code: i32 },
+
+    /// An NVAPI call returned a non-zero `NvAPI_Status`
+    #[error("{function} failed: NvAPI status {status}")]
+    NvApi { function: &'static str, status: i32 },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl AmVideoCrateError {
+    /// Status returned by the DLL, if this is a `DllCall` failure
+    pub const fn code(&self) -> Option<usize> {
+        match self {
+            Self::DllCall { code } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Stable name for the kind of failure, for tooling that classifies errors
+    pub const fn class(&self) -> &'static str {
+        match self {
+            Self::Registry { .. } => "registry",
+            Self::Load { .. } => "load",
+            Self::Resolve { .. } => "resolve",
+            Self::Unresolved { .. } => "unresolved",
+            Self::WrongState { .. } => "wrong-state",
+            Self::DllCall { .. } => "dll-call",
+            Self::Verify { .. } | Self::VerifyRefresh { .. } => "verify",
+            Self::DisplayChange { .. } => "display-change",
+            Self::NvApi { .. } => "nvapi",
+            Self::Io(_) => "io",
+        }
+    }
+}
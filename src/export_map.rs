@@ -0,0 +1,31 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::OnceLock;
+
+use amvideo::amlib::ExportMap;
+
+static EXPORT_MAP: OnceLock<ExportMap> = OnceLock::new();
+
+/// Find the DLL's exports where the config's `[exports]` table says they are from now on
+pub fn enable(map: ExportMap) {
+    let _ = EXPORT_MAP.set(map);
+}
+
+/// Configured export locations, the usual ordinals unless `enable` was called
+pub fn get() -> ExportMap {
+    EXPORT_MAP.get().cloned().unwrap_or_default()
+}
@@ -0,0 +1,99 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+
+use serde::Serialize;
+
+use amvideo::AmVideoCrateError;
+
+use crate::boot;
+use crate::broker::Crashed;
+use crate::codes;
+
+/// A failed command, as printed on stderr with `--output json`
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    /// Kind of failure, e.g. `dll-call` or `load`, or `other` for anything unclassified
+    pub class: &'static str,
+    /// DLL status, exception, Win32 error, or other numeric code behind the failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<i64>,
+    /// Known meaning of the DLL status, from `error-codes.toml`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meaning: Option<String>,
+    pub message: String,
+    /// Underlying causes, outermost first
+    pub context: Vec<String>,
+}
+
+impl Failure {
+    pub fn new(error: &anyhow::Error) -> Self {
+        let (class, code) = classify(error);
+
+        Self {
+            class,
+            code,
+            meaning: codes::dll_code(error)
+                .and_then(codes::lookup)
+                .map(|known| known.meaning),
+            message: error.to_string(),
+            context: error.chain().skip(1).map(ToString::to_string).collect(),
+        }
+    }
+}
+
+/// Class and code of the outermost amVideo or broker error in the chain, falling back to the boot
+/// stage and then to any OS error
+fn classify(error: &anyhow::Error) -> (&'static str, Option<i64>) {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<AmVideoCrateError>() {
+            let code = match e {
+                AmVideoCrateError::DllCall { code } => Some(*code as i64),
+                AmVideoCrateError::DisplayChange { code, .. } => Some(i64::from(*code)),
+                AmVideoCrateError::NvApi { status, .. } => Some(i64::from(*status)),
+                AmVideoCrateError::Registry { source, .. }
+                | AmVideoCrateError::Load { source, .. }
+                | AmVideoCrateError::Io(source) => source.raw_os_error().map(i64::from),
+                _ => None,
+            };
+            return (e.class(), code);
+        }
+        if let Some(e) = cause.downcast_ref::<Crashed>() {
+            return ("crash", e.status().map(i64::from));
+        }
+    }
+
+    if let Some(failed) = error.downcast_ref::<boot::Failed>() {
+        return (failed.class(), Some(i64::from(failed.exit_code())));
+    }
+
+    match error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+    {
+        Some(e) => ("io", e.raw_os_error().map(i64::from)),
+        None => ("other", None),
+    }
+}
+
+/// Print the failure as one line of JSON on stderr
+pub fn report(error: &anyhow::Error) {
+    match serde_json::to_string(&Failure::new(error)) {
+        Ok(line) => eprintln!("{}", line),
+        Err(_) => eprintln!("Error: {:?}", error),
+    }
+}
@@ -0,0 +1,50 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+use crate::audit::{self, Event};
+
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+/// Let every safety check through from now on, as requested with `--force`
+pub fn enable() {
+    FORCE.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--force` was given
+pub fn enabled() -> bool {
+    FORCE.load(Ordering::Relaxed)
+}
+
+/// Pass on the outcome of the safety check `check`, or log and ignore its failure under
+/// `--force`, so every bypass leaves a trace
+pub fn guard(check: &'static str, result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if enabled() => {
+            let reason = format!("{:#}", e);
+            eprintln!(
+                "Ignoring failed {} check because of --force: {}",
+                check, reason
+            );
+            audit::record(Event::Bypass { check, reason });
+            Ok(())
+        }
+        result => result,
+    }
+}
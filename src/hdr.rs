@@ -0,0 +1,69 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+
+use serde::Serialize;
+
+use crate::ccd;
+
+/// HDR state of one active display
+#[derive(Clone, Debug, Serialize)]
+pub struct HdrState {
+    /// GDI device name of the source, e.g. `\\.\DISPLAY1`
+    pub device: String,
+    pub supported: bool,
+    pub enabled: bool,
+}
+
+/// HDR state of every active display
+pub fn query() -> io::Result<Vec<HdrState>> {
+    let (paths, _) = ccd::active_paths()?;
+
+    paths
+        .iter()
+        .map(|path| {
+            let (supported, enabled) = ccd::advanced_color(path)?;
+            Ok(HdrState {
+                device: ccd::source_name(path)?,
+                supported,
+                enabled,
+            })
+        })
+        .collect()
+}
+
+/// Turn HDR on or off on the display named `device`
+pub fn set(device: &str, enabled: bool) -> io::Result<()> {
+    let (paths, _) = ccd::active_paths()?;
+
+    let mut found = false;
+    for path in &paths {
+        if ccd::source_name(path)?.eq_ignore_ascii_case(device) {
+            ccd::set_advanced_color(path, enabled)?;
+            found = true;
+        }
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No active display named '{}'", device),
+        ))
+    }
+}
@@ -0,0 +1,87 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use amvideo::{AmVideoSetting, AmVideoSettingExtras, VbiosVersion};
+
+use crate::broker::Session;
+
+/// Outcome reported for an apply that had no display to verify against
+pub const STATUS: &str = "applied-unverified (headless)";
+
+/// Where amVideo calls go while running without a display
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Make the calls against the configured DLL as usual
+    Dll,
+    /// Answer every call successfully without loading a DLL
+    Mock,
+}
+
+static HEADLESS: OnceLock<Backend> = OnceLock::new();
+
+/// Run without a display from now on, as requested with `--headless`: every step that reads or
+/// changes display settings is skipped, and so is verification
+pub fn enable(backend: Backend) {
+    let _ = HEADLESS.set(backend);
+}
+
+pub fn enabled() -> Option<Backend> {
+    HEADLESS.get().copied()
+}
+
+/// Backend that accepts every call, for benches with neither a display nor the DLL's GPU
+pub struct Mock {
+    context_version: u32,
+}
+
+impl Mock {
+    pub fn new(context_version: Option<u32>) -> Self {
+        Self {
+            context_version: context_version.unwrap_or(1),
+        }
+    }
+}
+
+impl Session for Mock {
+    fn open(&mut self) -> Result<u32> {
+        Ok(self.context_version)
+    }
+
+    fn vbios_version(&mut self, _buffer: u32) -> Result<VbiosVersion> {
+        Ok(VbiosVersion {
+            version: "(headless mock)".to_string(),
+            raw: Vec::new(),
+            truncated: false,
+        })
+    }
+
+    fn set_resolution(
+        &mut self,
+        _setting: &AmVideoSetting,
+        _extras: &AmVideoSettingExtras,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
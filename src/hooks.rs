@@ -0,0 +1,111 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use serde::Serialize;
+use winapi::shared::minwindef::{DWORD, HMODULE};
+use winapi::um::libloaderapi::GetProcAddress;
+use winapi::um::processthreadsapi::GetCurrentProcess;
+use winapi::um::psapi::{EnumProcessModules, GetModuleBaseNameW};
+
+use crate::wide::from_wide;
+
+/// Exports of the segatools IO API, which only segatools' IO and hook DLLs provide
+const SEGATOOLS_EXPORTS: &[&str] = &[
+    "aime_io_get_api_version",
+    "chuni_io_get_api_version",
+    "mai2_io_get_api_version",
+    "mu3_io_get_api_version",
+];
+
+/// Module names injected by spice-style launchers
+const SPICE_MODULES: &[&str] = &["spice.dll", "spice64.dll", "spicetools.dll"];
+
+/// Hook framework found loaded in this process
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadedHook {
+    pub module: String,
+    pub framework: &'static str,
+    /// What gave the module away
+    pub reason: String,
+}
+
+fn modules() -> io::Result<Vec<HMODULE>> {
+    let process = unsafe { GetCurrentProcess() };
+    let mut modules: Vec<HMODULE> = vec![ptr::null_mut(); 256];
+
+    loop {
+        let size = (modules.len() * mem::size_of::<HMODULE>()) as DWORD;
+        let mut needed = 0;
+        if unsafe { EnumProcessModules(process, modules.as_mut_ptr(), size, &mut needed) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let count = needed as usize / mem::size_of::<HMODULE>();
+        if needed <= size {
+            modules.truncate(count);
+            return Ok(modules);
+        }
+        modules.resize(count, ptr::null_mut());
+    }
+}
+
+/// Find segatools and spice-style hook DLLs loaded into this process, e.g. when amvideo is
+/// started through segatools' `inject.exe`. Their hooks see amVideo calls before the DLL does.
+pub fn loaded_hooks() -> io::Result<Vec<LoadedHook>> {
+    let mut hooks = Vec::new();
+
+    for module in modules()? {
+        let mut name = [0; 260];
+        let length =
+            unsafe { GetModuleBaseNameW(GetCurrentProcess(), module, name.as_mut_ptr(), 260) };
+        let name = from_wide(&name[..length as usize]);
+        let lower = name.to_ascii_lowercase();
+
+        if SPICE_MODULES.contains(&lower.as_str()) {
+            hooks.push(LoadedHook {
+                module: name,
+                framework: "spice",
+                reason: "known module name".to_string(),
+            });
+            continue;
+        }
+
+        let export = SEGATOOLS_EXPORTS.iter().find(|export| {
+            let export = CString::new(**export).unwrap_or_default();
+            !unsafe { GetProcAddress(module, export.as_ptr()) }.is_null()
+        });
+        if let Some(export) = export {
+            hooks.push(LoadedHook {
+                module: name,
+                framework: "segatools",
+                reason: format!("exports {}", export),
+            });
+        } else if lower.ends_with("hook.dll") {
+            hooks.push(LoadedHook {
+                module: name,
+                framework: "segatools",
+                reason: "hook DLL naming".to_string(),
+            });
+        }
+    }
+
+    Ok(hooks)
+}
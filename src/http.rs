@@ -0,0 +1,190 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::ptr;
+
+use anyhow::{Context, Result};
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
+use winapi::um::wininet::{
+    HttpQueryInfoW, InternetCloseHandle, InternetOpenUrlW, InternetOpenW, InternetReadFile,
+    HINTERNET, HTTP_QUERY_ETAG, HTTP_QUERY_FLAG_NUMBER, HTTP_QUERY_STATUS_CODE,
+    HTTP_STATUS_NOT_MODIFIED, HTTP_STATUS_OK, INTERNET_FLAG_NO_CACHE_WRITE, INTERNET_FLAG_RELOAD,
+    INTERNET_OPEN_TYPE_PRECONFIG,
+};
+
+use amvideo::wide::to_wide;
+
+/// WinINet handle, closed on drop
+struct Internet(HINTERNET);
+
+impl Drop for Internet {
+    fn drop(&mut self) {
+        unsafe { InternetCloseHandle(self.0) };
+    }
+}
+
+/// Result of a conditional fetch
+pub enum Fetched {
+    /// The resource changed, with its new entity tag if the server sent one
+    Changed { data: Vec<u8>, etag: Option<String> },
+    /// The server answered 304 for the entity tag sent
+    NotModified,
+}
+
+/// Contents of `url`, fetched through WinINet, or read from disk for a local path or `file://`
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    match fetch_if_changed(url, None)? {
+        Fetched::Changed { data, .. } => Ok(data),
+        Fetched::NotModified => Err(anyhow!("Failed to fetch '{}': HTTP status 304", url)),
+    }
+}
+
+/// Contents of `url` unless it still matches `etag`. Local paths are always read.
+pub fn fetch_if_changed(url: &str, etag: Option<&str>) -> Result<Fetched> {
+    if let Some(path) = local_path(url) {
+        let data =
+            fs::read(&path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+        return Ok(Fetched::Changed { data, etag: None });
+    }
+
+    let agent = to_wide(concat!("amvideo/", env!("CARGO_PKG_VERSION")));
+    let internet = unsafe {
+        InternetOpenW(
+            agent.as_ptr(),
+            INTERNET_OPEN_TYPE_PRECONFIG,
+            ptr::null(),
+            ptr::null(),
+            0,
+        )
+    };
+    if internet.is_null() {
+        return Err(io::Error::last_os_error()).context("InternetOpenW failed");
+    }
+    let internet = Internet(internet);
+
+    let wide_url = to_wide(url);
+    let headers = etag.map(|etag| format!("If-None-Match: {}\r\n", etag));
+    // `to_wide` terminates the string, which WinINet takes as "length -1"
+    let wide_headers = headers.as_deref().map(to_wide);
+    let (headers_ptr, headers_len) = match &wide_headers {
+        Some(headers) => (headers.as_ptr(), !0),
+        None => (ptr::null(), 0),
+    };
+    let request = unsafe {
+        InternetOpenUrlW(
+            internet.0,
+            wide_url.as_ptr(),
+            headers_ptr,
+            headers_len,
+            INTERNET_FLAG_RELOAD | INTERNET_FLAG_NO_CACHE_WRITE,
+            0,
+        )
+    };
+    if request.is_null() {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to fetch '{}'", url));
+    }
+    let request = Internet(request);
+
+    let mut new_etag = None;
+    if url.starts_with("http") {
+        let mut status: DWORD = 0;
+        let mut length = mem::size_of::<DWORD>() as DWORD;
+        let queried = unsafe {
+            HttpQueryInfoW(
+                request.0,
+                HTTP_QUERY_STATUS_CODE | HTTP_QUERY_FLAG_NUMBER,
+                &mut status as *mut DWORD as *mut _,
+                &mut length,
+                ptr::null_mut(),
+            )
+        };
+        if queried != 0 && status == HTTP_STATUS_NOT_MODIFIED && etag.is_some() {
+            return Ok(Fetched::NotModified);
+        }
+        if queried != 0 && status != HTTP_STATUS_OK {
+            return Err(anyhow!("Failed to fetch '{}': HTTP status {}", url, status));
+        }
+
+        new_etag = query_string(&request, HTTP_QUERY_ETAG);
+    }
+
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 0x10000];
+    loop {
+        let mut read: DWORD = 0;
+        let ok = unsafe {
+            InternetReadFile(
+                request.0,
+                chunk.as_mut_ptr() as *mut _,
+                chunk.len() as DWORD,
+                &mut read,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to read '{}'", url));
+        }
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&chunk[..read as usize]);
+    }
+
+    Ok(Fetched::Changed {
+        data,
+        etag: new_etag,
+    })
+}
+
+/// A response header as a string, `None` if the server did not send it
+fn query_string(request: &Internet, info: DWORD) -> Option<String> {
+    let mut buffer = vec![0u16; 256];
+    loop {
+        let mut length = (buffer.len() * 2) as DWORD;
+        let queried = unsafe {
+            HttpQueryInfoW(
+                request.0,
+                info,
+                buffer.as_mut_ptr() as *mut _,
+                &mut length,
+                ptr::null_mut(),
+            )
+        };
+        if queried != 0 {
+            return Some(String::from_utf16_lossy(&buffer[..length as usize / 2]));
+        }
+        if io::Error::last_os_error().raw_os_error() != Some(ERROR_INSUFFICIENT_BUFFER as i32) {
+            return None;
+        }
+        // `length` now holds the size needed in bytes, including the terminator
+        buffer.resize(length as usize / 2 + 1, 0);
+    }
+}
+
+/// The path `url` names if it is a local path or `file://` URL rather than a remote resource
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    match url.strip_prefix("file://") {
+        Some(path) => Some(PathBuf::from(path.trim_start_matches('/'))),
+        None if !url.contains("://") => Some(PathBuf::from(url)),
+        None => None,
+    }
+}
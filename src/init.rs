@@ -0,0 +1,216 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+
+use amvideo::display;
+use amvideo::platform;
+use amvideo::topology::Topology;
+
+use crate::config;
+use crate::migrate;
+use crate::{prompt, GlobalOpts};
+
+/// Game executables recognized by `config init`, with the profile name and title to use
+const TITLES: &[(&str, &str, &str)] = &[
+    ("chusanApp.exe", "chunithm", "CHUNITHM NEW"),
+    ("chuniApp.exe", "chunithm", "CHUNITHM"),
+    ("mu3.exe", "ongeki", "O.N.G.E.K.I."),
+    ("Sinmai.exe", "maimai", "maimai DX"),
+];
+
+const NVIDIA_VENDOR_ID: u32 = 0x10de;
+
+#[derive(Args)]
+pub struct ConfigOpts {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Write a commented starter config with a profile guessed from this machine
+    Init {
+        /// Game directory to look for a known title in [default: the current directory]
+        #[arg(long, value_name = "DIR")]
+        game_dir: Option<PathBuf>,
+    },
+    /// Update a config of an older format version in place, keeping the original as a backup
+    Migrate,
+}
+
+pub fn run(global: &GlobalOpts, opts: &ConfigOpts) -> Result<()> {
+    match &opts.command {
+        ConfigCommand::Init { game_dir } => init(global, game_dir.as_deref()),
+        ConfigCommand::Migrate => migrate(global),
+    }
+}
+
+fn migrate(global: &GlobalOpts) -> Result<()> {
+    let path = match &global.config {
+        Some(path) => path.clone(),
+        None => config::default_path()?,
+    };
+    if migrate::migrate_file(&path)?.is_none() {
+        println!(
+            "Config '{}' is already version {}",
+            path.display(),
+            migrate::VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Executable, profile name, and title of the first known game found in `dir` or its `bin`
+fn detect_title(dir: &Path) -> Option<(&'static str, &'static str, &'static str)> {
+    TITLES
+        .iter()
+        .copied()
+        .find(|(exe, _, _)| dir.join(exe).is_file() || dir.join("bin").join(exe).is_file())
+}
+
+fn init(global: &GlobalOpts, game_dir: Option<&Path>) -> Result<()> {
+    let path = match &global.config {
+        Some(path) => path.clone(),
+        None => config::default_path()?,
+    };
+    let game_dir = match game_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => env::current_dir()?,
+    };
+
+    let topology = Topology::query().context("Failed to query display topology")?;
+    let displays = display::attached_displays();
+    let settings: Vec<_> = displays
+        .iter()
+        .filter_map(|device| display::current_settings(&device.name).ok())
+        .collect();
+    let title = detect_title(&game_dir);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Starter config written by `amvideo config init`. The profile below is"
+    )?;
+    writeln!(
+        out,
+        "# a best guess from the current display settings; check it before use."
+    )?;
+    writeln!(out, "#")?;
+    writeln!(out, "# Platform: {}", platform::detect().platform)?;
+    for adapter in &topology.adapters {
+        writeln!(out, "# GPU: {}", adapter.description)?;
+    }
+    for (device, settings) in displays.iter().zip(&settings) {
+        writeln!(
+            out,
+            "# Display: {} at {}{}",
+            device.name,
+            settings.mode(),
+            if device.primary { ", primary" } else { "" }
+        )?;
+    }
+    let name = match title {
+        Some((exe, name, title)) => {
+            writeln!(out, "# Game: {} ({})", title, exe)?;
+            name
+        }
+        None => {
+            writeln!(out, "# Game: none recognized in '{}'", game_dir.display())?;
+            "default"
+        }
+    };
+    writeln!(out)?;
+    writeln!(out, "version = {}", migrate::VERSION)?;
+    writeln!(out)?;
+
+    let primary = displays
+        .iter()
+        .zip(&settings)
+        .find(|(device, _)| device.primary)
+        .map(|(_, settings)| settings);
+    let secondary = displays
+        .iter()
+        .zip(&settings)
+        .find(|(device, _)| !device.primary)
+        .map(|(_, settings)| settings);
+
+    writeln!(out, "[profiles.{}]", name)?;
+    match secondary {
+        Some(secondary) => {
+            writeln!(out, "mode = \"dual\"          # single, clone, or dual")?;
+            write_resolution(&mut out, "resolution", primary)?;
+            write_resolution(&mut out, "secondary_resolution", Some(secondary))?;
+        }
+        None => {
+            writeln!(out, "mode = \"single\"        # single, clone, or dual")?;
+            write_resolution(&mut out, "resolution", primary)?;
+        }
+    }
+    writeln!(out, "# segatiming = true")?;
+    if topology
+        .adapters
+        .iter()
+        .any(|adapter| adapter.vendor_id == NVIDIA_VENDOR_ID)
+    {
+        writeln!(
+            out,
+            "vrr = \"off\"             # G-SYNC judders with SegaTiming's fixed-rate modes"
+        )?;
+    }
+    writeln!(
+        out,
+        "# confirm_within = 15    # revert unless confirmed within this many seconds"
+    )?;
+    if topology.displays.iter().any(|display| display.is_spanned()) {
+        writeln!(
+            out,
+            "# allow_spanning = true  # a Surround/Eyefinity group is active, see `amvideo doctor`"
+        )?;
+    }
+
+    if path.exists() {
+        prompt(
+            global,
+            &format!(
+                "About to overwrite {} with a starter config",
+                path.display()
+            ),
+        )?;
+    }
+    fs::write(&path, &out).with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote starter config to {}", path.display());
+
+    Ok(())
+}
+
+fn write_resolution(
+    out: &mut String,
+    key: &str,
+    settings: Option<&display::DisplaySettings>,
+) -> fmt::Result {
+    match settings {
+        Some(settings) => writeln!(out, "{} = \"{}x{}\"", key, settings.width, settings.height),
+        // Still a valid config, just possibly not the right mode
+        None => writeln!(out, "{} = \"1920x1080\"", key),
+    }
+}
@@ -0,0 +1,178 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+
+use amvideo::pe::{DataFile, Import};
+use amvideo::{dll_name, platform};
+
+use crate::digest;
+use crate::stub::EXPORTS;
+use crate::version::file_version;
+use crate::{GlobalOpts, OutputFormat};
+
+#[derive(Args)]
+pub struct InspectOpts {
+    /// DLL to inspect [default: the configured amVideo DLL]
+    dll: Option<PathBuf>,
+
+    /// Also list every imported module and function, which shows the vendor and driver
+    /// interfaces (NVAPI, ADL, SetupAPI) a build depends on
+    #[arg(long)]
+    imports: bool,
+}
+
+/// What can be learned about a DLL from its file alone
+#[derive(Debug, Serialize)]
+pub struct Report {
+    path: PathBuf,
+    machine: String,
+    sha256: String,
+    file_version: Option<String>,
+    banner: Option<String>,
+    /// Whether the banner is in the build database
+    known_build: bool,
+    exports: Vec<Export>,
+    /// amVideo exports found neither by name nor by their usual ordinal
+    missing: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imports: Option<Vec<Import>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Export {
+    ordinal: u16,
+    name: Option<String>,
+}
+
+/// `amvideo inspect`: report on a DLL without loading it for execution, so DLLs of unknown
+/// provenance can be triaged safely
+pub fn run(global: &GlobalOpts, opts: &InspectOpts) -> Result<()> {
+    let report = report(opts.dll.as_deref(), opts.imports)?;
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Text => print_text(&report),
+    };
+
+    Ok(())
+}
+
+/// Report on the DLL at `dll`, or the configured one, listing its imports too if `imports`
+pub fn report(dll: Option<&Path>, imports: bool) -> Result<Report> {
+    let name: OsString = match dll {
+        Some(path) => path.into(),
+        None => match (dll_name(), platform::detect().platform.default_dll_name()) {
+            (Ok(name), _) => name,
+            (Err(_), Some(default)) => default.into(),
+            (Err(e), None) => return Err(e.into()),
+        },
+    };
+    let dll = DataFile::open(&name)
+        .with_context(|| format!("Failed to find '{}'", name.to_string_lossy()))?;
+    let exports = dll
+        .exports()
+        .ok_or_else(|| anyhow!("{} is not a valid DLL", dll.path.display()))?;
+
+    let missing = EXPORTS
+        .iter()
+        .zip(1..)
+        .filter(|&(&export, ordinal)| {
+            !exports
+                .iter()
+                .any(|(o, name)| *o == ordinal || name.as_deref() == Some(export))
+        })
+        .map(|(&export, _)| export)
+        .collect();
+
+    let imports = if imports {
+        Some(
+            dll.imports()
+                .ok_or_else(|| anyhow!("{} has a corrupt import table", dll.path.display()))?,
+        )
+    } else {
+        None
+    };
+
+    let report = Report {
+        machine: match dll.machine() {
+            Some(0x014c) => "x86".to_string(),
+            Some(0x8664) => "x64".to_string(),
+            Some(machine) => format!("{:#06x}", machine),
+            None => "unknown".to_string(),
+        },
+        sha256: digest::hex(&digest::sha256(&dll.data)?),
+        file_version: file_version(&dll.path),
+        banner: dll.banner(),
+        known_build: dll.build().is_some(),
+        exports: exports
+            .into_iter()
+            .map(|(ordinal, name)| Export { ordinal, name })
+            .collect(),
+        missing,
+        imports,
+        path: dll.path,
+    };
+
+    Ok(report)
+}
+
+fn print_text(report: &Report) {
+    println!("{}", report.path.display());
+    println!("  Machine: {}", report.machine);
+    println!("  SHA-256: {}", report.sha256);
+    println!(
+        "  File version: {}",
+        report.file_version.as_deref().unwrap_or("none")
+    );
+    println!(
+        "  Build: {}{}",
+        report.banner.as_deref().unwrap_or("unknown"),
+        if report.known_build { " (known)" } else { "" }
+    );
+    println!("  Exports:");
+    for export in &report.exports {
+        println!(
+            "    {:>4} {}",
+            export.ordinal,
+            export.name.as_deref().unwrap_or("(by ordinal only)")
+        );
+    }
+    if !report.missing.is_empty() {
+        println!("  Missing amVideo exports: {}", report.missing.join(", "));
+    }
+    if let Some(imports) = &report.imports {
+        println!("  Imports:");
+        for import in imports {
+            println!(
+                "    {}{}",
+                import.module,
+                if import.delay_load {
+                    " (delay-loaded)"
+                } else {
+                    ""
+                }
+            );
+            for function in &import.functions {
+                println!("      {}", function);
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+
+use amvideo::power::PowerSettings;
+
+/// The user's settings from before kiosk mode, kept next to the executable until restored
+const SAVED_NAME: &str = "amvideo-power.json";
+
+#[derive(Args)]
+pub struct KioskOpts {
+    #[command(subcommand)]
+    action: KioskAction,
+}
+
+#[derive(Subcommand)]
+enum KioskAction {
+    /// Keep the display from sleeping, blanking, or turning off at the lock screen
+    On,
+    /// Put back the power and screensaver settings from before `on`
+    Off,
+    /// Show the current settings and whether kiosk mode is on
+    Status,
+}
+
+pub fn run(opts: &KioskOpts) -> Result<()> {
+    match opts.action {
+        KioskAction::On => enable(),
+        KioskAction::Off => {
+            if !restore()? {
+                println!("Kiosk mode is not on");
+            }
+            Ok(())
+        }
+        KioskAction::Status => {
+            let current = PowerSettings::current()?;
+            println!("{}", serde_json::to_string_pretty(&current)?);
+            match saved()? {
+                Some(_) => println!("Kiosk mode is on"),
+                None => println!("Kiosk mode is off"),
+            }
+            Ok(())
+        }
+    }
+}
+
+fn saved_path() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    Ok(exe.with_file_name(SAVED_NAME))
+}
+
+fn saved() -> Result<Option<PowerSettings>> {
+    let path = saved_path()?;
+    match fs::read_to_string(&path) {
+        Ok(json) => Ok(Some(serde_json::from_str(&json).with_context(|| {
+            format!("Failed to parse saved settings '{}'", path.display())
+        })?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    }
+}
+
+/// Turn off display sleep and the screensaver, saving the user's settings first unless kiosk
+/// mode is already on, so switching profiles never overwrites the originals
+pub fn enable() -> Result<()> {
+    if saved()?.is_none() {
+        let path = saved_path()?;
+        let current = PowerSettings::current().context("Failed to read the power settings")?;
+        fs::write(&path, serde_json::to_string_pretty(&current)?).with_context(|| {
+            format!("Failed to save the power settings to '{}'", path.display())
+        })?;
+    }
+
+    PowerSettings::KIOSK
+        .apply()
+        .context("Failed to apply the kiosk power settings")?;
+    println!("Kiosk mode on: display sleep, lock screen timeout, and screensaver disabled");
+
+    Ok(())
+}
+
+/// Put back the settings saved by `enable`, returning whether kiosk mode was on
+pub fn restore() -> Result<bool> {
+    let settings = match saved()? {
+        Some(settings) => settings,
+        None => return Ok(false),
+    };
+
+    settings
+        .apply()
+        .context("Failed to restore the power settings")?;
+    fs::remove_file(saved_path()?).context("Failed to remove the saved power settings")?;
+    println!("Kiosk mode off: power settings restored");
+
+    Ok(true)
+}
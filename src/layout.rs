@@ -0,0 +1,218 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io;
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+use winapi::um::wingdi::{
+    DEVMODEW, DMDO_180, DMDO_270, DMDO_90, DMDO_DEFAULT, DM_DISPLAYFREQUENCY,
+    DM_DISPLAYORIENTATION, DM_PELSHEIGHT, DM_PELSWIDTH, DM_POSITION,
+};
+use winapi::um::winuser::CDS_SET_PRIMARY;
+
+use crate::display;
+use crate::error::Result;
+use crate::setting::AmVideoResolution;
+use crate::topology;
+
+/// Desired state of every output in a multi-display setup, applied in one mode change
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Layout {
+    #[serde(default, rename = "output")]
+    pub outputs: Vec<OutputLayout>,
+}
+
+/// One output of a [`Layout`]. Anything left unset keeps its current value.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OutputLayout {
+    /// GDI device name (e.g. `\\.\DISPLAY1`) or monitor name
+    pub display: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Resolution before rotation, width and height are swapped for 90 and 270 degrees
+    pub resolution: Option<AmVideoResolution>,
+    pub refresh: Option<u32>,
+    /// Top-left corner on the desktop. `[0, 0]` makes the output the primary display.
+    pub position: Option<(i32, i32)>,
+    /// Clockwise rotation in degrees: 0, 90, 180, or 270
+    pub rotation: Option<u32>,
+}
+
+const fn default_enabled() -> bool {
+    true
+}
+
+/// `DMDO_*` orientation for a clockwise rotation in degrees
+fn orientation(degrees: u32) -> io::Result<u32> {
+    match degrees {
+        0 => Ok(DMDO_DEFAULT),
+        90 => Ok(DMDO_90),
+        180 => Ok(DMDO_180),
+        270 => Ok(DMDO_270),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Rotation must be 0, 90, 180, or 270 degrees, got {}",
+                degrees
+            ),
+        )),
+    }
+}
+
+const fn is_portrait(orientation: u32) -> bool {
+    orientation == DMDO_90 || orientation == DMDO_270
+}
+
+/// Clockwise rotation in degrees for a `DMDO_*` orientation
+const fn degrees(orientation: u32) -> u32 {
+    match orientation {
+        DMDO_90 => 90,
+        DMDO_180 => 180,
+        DMDO_270 => 270,
+        _ => 0,
+    }
+}
+
+impl fmt::Display for OutputLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.display)?;
+        if !self.enabled {
+            return write!(f, " disabled");
+        }
+        if let Some(resolution) = self.resolution {
+            write!(f, " {}", resolution)?;
+        }
+        if let Some(refresh) = self.refresh {
+            write!(f, " @ {} Hz", refresh)?;
+        }
+        if let Some((x, y)) = self.position {
+            write!(f, " at ({}, {})", x, y)?;
+        }
+        if let Some(rotation) = self.rotation {
+            write!(f, " rotated {} degrees", rotation)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Layout {
+    /// Describe every display currently attached to the desktop
+    pub fn capture() -> Result<Self> {
+        let outputs = display::attached_displays()
+            .iter()
+            .map(|device| {
+                let settings = display::current_settings(&device.name)?;
+                let (mut width, mut height) = (settings.width, settings.height);
+                if is_portrait(settings.orientation) {
+                    mem::swap(&mut width, &mut height);
+                }
+
+                Ok(OutputLayout {
+                    display: device.name.clone(),
+                    enabled: true,
+                    resolution: Some(AmVideoResolution::new(width as u16, height as u16)),
+                    refresh: Some(settings.frequency),
+                    position: Some(settings.position),
+                    rotation: Some(degrees(settings.orientation)),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { outputs })
+    }
+
+    /// Stage every output, then commit them in one mode change
+    pub fn apply(&self) -> Result<()> {
+        for output in &self.outputs {
+            let device = topology::resolve_display(&output.display)?;
+            let (mut dev_mode, flags) = output.dev_mode(&device)?;
+            display::stage(&device, &mut dev_mode, flags)?;
+        }
+
+        display::commit()
+    }
+}
+
+impl OutputLayout {
+    /// Mode to stage for `device` and the extra `ChangeDisplaySettingsExW` flags it needs
+    fn dev_mode(&self, device: &str) -> Result<(DEVMODEW, u32)> {
+        // Detached displays have no current mode to start from
+        let mut dev_mode = display::current_dev_mode(Some(device)).unwrap_or_else(|_| {
+            let mut dev_mode: DEVMODEW = unsafe { mem::zeroed() };
+            dev_mode.dmSize = mem::size_of::<DEVMODEW>() as u16;
+            dev_mode
+        });
+
+        if !self.enabled {
+            // A zero-sized mode detaches the display from the desktop
+            dev_mode.dmPelsWidth = 0;
+            dev_mode.dmPelsHeight = 0;
+            unsafe {
+                let s2 = dev_mode.u1.s2_mut();
+                s2.dmPosition.x = 0;
+                s2.dmPosition.y = 0;
+            }
+            dev_mode.dmFields = DM_PELSWIDTH | DM_PELSHEIGHT | DM_POSITION;
+            return Ok((dev_mode, 0));
+        }
+
+        let current_orientation = unsafe { dev_mode.u1.s2().dmDisplayOrientation };
+        let orientation = match self.rotation {
+            Some(degrees) => orientation(degrees)?,
+            None => current_orientation,
+        };
+
+        // Work in unrotated dimensions so a rotation change alone still swaps them
+        let (mut width, mut height) = (dev_mode.dmPelsWidth, dev_mode.dmPelsHeight);
+        if is_portrait(current_orientation) {
+            mem::swap(&mut width, &mut height);
+        }
+        if let Some(resolution) = self.resolution {
+            width = u32::from(resolution.width);
+            height = u32::from(resolution.height);
+        }
+        if is_portrait(orientation) {
+            mem::swap(&mut width, &mut height);
+        }
+
+        dev_mode.dmPelsWidth = width;
+        dev_mode.dmPelsHeight = height;
+        dev_mode.dmFields = DM_PELSWIDTH | DM_PELSHEIGHT | DM_DISPLAYORIENTATION | DM_POSITION;
+        unsafe { dev_mode.u1.s2_mut().dmDisplayOrientation = orientation };
+
+        if let Some(refresh) = self.refresh {
+            dev_mode.dmDisplayFrequency = refresh;
+            dev_mode.dmFields |= DM_DISPLAYFREQUENCY;
+        }
+
+        let mut flags = 0;
+        if let Some((x, y)) = self.position {
+            unsafe {
+                let s2 = dev_mode.u1.s2_mut();
+                s2.dmPosition.x = x;
+                s2.dmPosition.y = y;
+            }
+            if (x, y) == (0, 0) {
+                flags |= CDS_SET_PRIMARY;
+            }
+        }
+
+        Ok((dev_mode, flags))
+    }
+}
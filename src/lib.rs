@@ -0,0 +1,63 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#[macro_use(assert_impl_all, const_assert_eq)]
+extern crate static_assertions;
+
+pub mod amlib;
+pub mod aspect;
+pub mod audio;
+pub mod builds;
+mod ccd;
+pub mod color;
+pub mod com;
+pub mod conflicts;
+pub mod ddc;
+pub mod display;
+pub mod dll_path;
+pub mod edid;
+mod error;
+pub mod hdr;
+pub mod hooks;
+pub mod layout;
+pub mod library_handle;
+pub mod minidump;
+pub mod nvapi;
+pub mod pe;
+pub mod platform;
+pub mod power;
+mod registry;
+pub mod screenshot;
+mod setting;
+mod shared;
+pub mod snapshot;
+pub mod symbols;
+pub mod topology;
+mod video;
+pub mod wide;
+
+pub use crate::error::{AmVideoCrateError, Result};
+pub use crate::registry::{dll_name, AM_VIDEO_KEY};
+pub use crate::setting::{
+    setting_size, AmVideoMode, AmVideoResolution, AmVideoSetting, AmVideoSettingExtras,
+    ParseResolutionError,
+};
+pub use crate::shared::{global, ArcAmVideo};
+pub use crate::video::{
+    close_open_contexts, close_thread_contexts, AmDllVideoClose, AmDllVideoGetVBiosVersion,
+    AmDllVideoOpen, AmDllVideoSetResolution, AmVideo, AmVideoContext, Closed, LifecycleError, Open,
+    RawAmVideoExports, State, VbiosVersion, AM_VIDEO_CONTEXT_DATA_SIZE, DEFAULT_VBIOS_BUFFER,
+};
@@ -0,0 +1,110 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use amvideo::amlib::{AmLibrary, Library, LIBRARIES};
+
+use crate::{GlobalOpts, OutputFormat};
+
+/// What one AM library's registry key and DLL show
+#[derive(Debug, Serialize)]
+struct Report {
+    key: String,
+    dll: Option<String>,
+    path: Option<String>,
+    banner: Option<String>,
+    /// Exports in the DLL file's export table, named where the table names them
+    exports: Vec<String>,
+    error: Option<String>,
+}
+
+/// `amvideo libraries`: report on every AM library of the video and monitor stack configured
+/// next to amVideo
+pub fn run(global: &GlobalOpts) -> Result<()> {
+    let reports: Vec<Report> = LIBRARIES.iter().map(|library| report(library)).collect();
+
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&reports)?),
+        OutputFormat::Text => {
+            for report in &reports {
+                println!("HKLM\\{}", report.key);
+                if let Some(dll) = &report.dll {
+                    println!("  DLL: {}", dll);
+                }
+                if let Some(path) = &report.path {
+                    println!("  Loaded from: {}", path);
+                }
+                if let Some(banner) = &report.banner {
+                    println!("  Build: {}", banner);
+                }
+                if !report.exports.is_empty() {
+                    println!("  Exports: {}", report.exports.join(", "));
+                }
+                if let Some(error) = &report.error {
+                    println!("  {}", error);
+                }
+            }
+        }
+    };
+
+    Ok(())
+}
+
+fn report(library: &'static Library) -> Report {
+    let mut report = Report {
+        key: library.key_path(),
+        dll: None,
+        path: None,
+        banner: None,
+        exports: Vec::new(),
+        error: None,
+    };
+
+    let name = match library.dll_name() {
+        Ok(name) => name,
+        Err(e) => {
+            report.error = Some(format!("{:#}", anyhow::Error::from(e)));
+            return report;
+        }
+    };
+    report.dll = Some(name.to_string_lossy().into_owned());
+
+    let loaded = match AmLibrary::open_named(library, &name) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            report.error = Some(format!("{:#}", anyhow::Error::from(e)));
+            return report;
+        }
+    };
+    report.path = loaded.path().ok().map(|path| path.display().to_string());
+    report.banner = loaded.banner();
+    match loaded.exports() {
+        Ok(exports) => {
+            report.exports = exports
+                .into_iter()
+                .map(|(ordinal, name)| match name {
+                    Some(name) => format!("{} (#{})", name, ordinal),
+                    None => format!("#{}", ordinal),
+                })
+                .collect()
+        }
+        Err(e) => report.error = Some(format!("Failed to read the export table: {}", e)),
+    };
+
+    report
+}
@@ -15,12 +15,15 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::error::Error;
+use std::ffi::{CString, OsString};
 use std::fmt;
 use std::io;
 use std::ops::Deref;
+use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
 
 use winapi::shared::minwindef::{FARPROC, HMODULE};
-use winapi::um::libloaderapi::{FreeLibrary, GetProcAddress};
+use winapi::um::libloaderapi::{FreeLibrary, GetModuleFileNameW, GetProcAddress};
 
 /// RAII guard around a dynamically loaded module
 #[repr(transparent)]
@@ -39,6 +42,48 @@ impl LibraryHandle {
         Self { handle }
     }
 
+    /// Path the module was loaded from
+    pub fn path(&self) -> io::Result<PathBuf> {
+        let mut path = [0; 1024];
+        let length =
+            unsafe { GetModuleFileNameW(self.handle, path.as_mut_ptr(), path.len() as u32) };
+        if length == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(PathBuf::from(OsString::from_wide(&path[..length as usize])))
+    }
+
+    /// Look up an export by name
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer must be transmuted to the export's real signature before it is called.
+    pub unsafe fn get_func_named<'a>(
+        &self,
+        name: &'a str,
+    ) -> Result<FARPROC, FunctionGetError<'a>> {
+        let c_name = CString::new(name).map_err(|e| FunctionGetError {
+            name,
+            source: io::Error::new(io::ErrorKind::InvalidInput, e),
+        })?;
+        let func = GetProcAddress(self.handle, c_name.as_ptr());
+
+        if !func.is_null() {
+            Ok(func)
+        } else {
+            Err(FunctionGetError {
+                name,
+                source: io::Error::last_os_error(),
+            })
+        }
+    }
+
+    /// Look up an export by ordinal, using `name` only for error reporting
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer must be transmuted to the export's real signature before it is called.
     pub unsafe fn get_func_named_ordinal<'a>(
         &self,
         name: &'a str,
@@ -57,6 +102,10 @@ impl LibraryHandle {
     }
 }
 
+// Module handles are process-wide and `FreeLibrary`/`GetProcAddress` may be called from any thread
+unsafe impl Send for LibraryHandle {}
+unsafe impl Sync for LibraryHandle {}
+
 impl fmt::Debug for LibraryHandle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&self.handle, f)
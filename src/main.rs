@@ -16,286 +16,1335 @@
 
 #[macro_use(anyhow)]
 extern crate anyhow;
-#[macro_use(const_assert_eq)]
-extern crate static_assertions;
-
-use std::error::Error as StdError;
-use std::ffi::{OsStr, OsString};
-use std::fmt;
-use std::io::Error;
-use std::mem;
-use std::os::windows::ffi::OsStrExt;
-use std::str;
-
-use anyhow::{Context, Result};
-use winapi::um::libloaderapi::LoadLibraryW;
-use winreg::enums::HKEY_LOCAL_MACHINE;
-use winreg::RegKey;
-
-mod library_handle;
-
-use crate::library_handle::LibraryHandle;
-
-const AM_VIDEO_CONTEXT_DATA_SIZE: usize = 0x400 - mem::size_of::<u32>();
-
-#[repr(C)]
-struct AmVideoContext {
-    version: u32,
-    data: [u8; AM_VIDEO_CONTEXT_DATA_SIZE],
-}
-
-#[derive(Debug)]
-#[repr(C)]
-struct AmVideoSetting {
-    version: u32,
-    use_segatiming: u32,
-    mode: AmVideoMode,
-    resolution_1: AmVideoResolution,
-    resolution_2: AmVideoResolution,
-}
-
-#[allow(unused)]
-#[derive(Debug)]
-#[repr(u32)]
-enum AmVideoMode {
-    /// Single display mode using `resolution_1`
-    Single = 0,
-    /// Single or dual display mode using `resolution_1` for both displays. Does not fail if a
-    /// second display is not connected.
-    CloneVideoMode = 1,
-    /// Dual display mode using both `resolution_1` and `resolution_2`
-    DualVideoMode = 4,
-}
-
-#[derive(Debug)]
-#[repr(C)]
-struct AmVideoResolution {
-    width: u16,
-    height: u16,
-}
-
-impl Default for AmVideoResolution {
-    fn default() -> Self {
-        Self {
-            width: 0,
-            height: 0,
+
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+use amvideo::aspect::{self, Fit, Ratio};
+use amvideo::conflicts;
+use amvideo::ddc::{PhysicalMonitors, VCP_BRIGHTNESS, VCP_CONTRAST};
+use amvideo::display;
+use amvideo::dll_path;
+use amvideo::hdr;
+use amvideo::hooks;
+use amvideo::layout::Layout;
+use amvideo::platform;
+use amvideo::snapshot::Snapshot;
+use amvideo::topology::{self, Topology};
+use amvideo::{
+    dll_name, AmVideo, AmVideoCrateError, AmVideoMode, AmVideoResolution, AmVideoSetting,
+    AmVideoSettingExtras, Closed, DEFAULT_VBIOS_BUFFER,
+};
+
+mod audio_endpoint;
+mod audit;
+mod boot;
+mod broker;
+mod bundle;
+mod call;
+mod codes;
+mod completions;
+mod config;
+mod confirm;
+mod context_diff;
+mod context_file;
+mod control;
+mod crash_report;
+mod custom_resolution;
+mod daemon;
+mod digest;
+mod displays;
+mod doctor;
+mod ed25519;
+mod edid_override;
+mod elevation;
+mod export_map;
+mod failure;
+mod force;
+mod headless;
+mod http;
+mod init;
+mod inspect;
+mod kiosk;
+mod libraries;
+mod migrate;
+mod monitor;
+mod mqtt;
+mod notify;
+mod png;
+mod probe;
+mod protocol;
+mod pull;
+mod ready;
+mod repl;
+mod revert;
+mod safe_mode;
+mod scenario;
+mod schedule;
+mod segatools;
+mod selftest;
+mod state;
+mod steps;
+mod stress;
+mod stub;
+mod supervise;
+mod support_bundle;
+mod timeout;
+mod timing;
+mod trace;
+mod update;
+mod verbose;
+mod version;
+mod virtual_display;
+mod vrr;
+mod zip;
+
+use crate::audit::{Event, Export};
+use crate::boot::BootOpts;
+use crate::broker::{Broker, Crashed, Local, Session};
+use crate::bundle::ProfileOpts;
+use crate::call::CallOpts;
+use crate::completions::Shell;
+use crate::config::{Config, HdrPolicy, PrimaryWhen, Profile};
+use crate::control::ControlOpts;
+use crate::daemon::DaemonOpts;
+use crate::displays::DisplaysOpts;
+use crate::edid_override::EdidOpts;
+use crate::elevation::Skipped;
+use crate::headless::Mock;
+use crate::init::ConfigOpts;
+use crate::inspect::InspectOpts;
+use crate::kiosk::KioskOpts;
+use crate::probe::ProbeOpts;
+use crate::repl::ReplOpts;
+use crate::scenario::ScenarioOpts;
+use crate::state::StatusOpts;
+use crate::steps::Failures;
+use crate::stress::StressOpts;
+use crate::stub::GenStubOpts;
+use crate::supervise::RunOpts;
+use crate::support_bundle::SupportBundleOpts;
+use crate::timeout::Stage;
+use crate::trace::{ReplayOpts, Tracer};
+use crate::update::SelfUpdateOpts;
+use crate::virtual_display::VirtualDisplayOpts;
+
+/// Set monitor resolutions with amVideo on SEGA's Nu and ALLS platforms
+#[derive(Parser)]
+#[command(version)]
+struct Opts {
+    #[command(flatten)]
+    global: GlobalOpts,
+
+    /// Profile to apply instead of 1920x1080 in single mode, several joined with `+` to compose
+    /// them, e.g. `alls-ux+chunithm`
+    #[arg(short, long)]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args)]
+struct GlobalOpts {
+    /// Append a JSON record of every DLL and display operation to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Profile config file [default: amvideo.toml next to the executable]
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Apply changes without asking for confirmation first
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Go ahead even if a safety check fails, logging each check bypassed
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Go on with the remaining steps of an apply after one fails, and report every failure at the
+    /// end, instead of stopping at the first
+    #[arg(long, global = true)]
+    continue_on_error: bool,
+
+    /// Use only the documented DLL interface, refusing raw calls, the self-test, and resuming a
+    /// saved context
+    #[arg(long, global = true)]
+    safe_mode: bool,
+
+    /// Print extra detail, such as the raw VBIOS version bytes and, for `version`, the DLL,
+    /// driver, and OS versions
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Load the DLL into a separate broker process, so a crash in it is reported and retried
+    /// instead of taking amvideo down
+    #[arg(long, global = true)]
+    isolate: bool,
+
+    /// Before opening the DLL, wait up to SECONDS (the `ready` timeout if not given) for a display
+    /// to be attached and driven by the GPU driver, for boot-time races on slow hardware
+    #[arg(long, global = true, value_name = "SECONDS", num_args = 0..=1)]
+    wait_for_display: Option<Option<u64>>,
+
+    /// Give up on any DLL call, display wait, verification, or control request still going after
+    /// SECONDS, 0 for never. The config's `[timeouts]` override it per stage.
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Display `--wait-for-display` waits for instead of any, e.g. `\\.\DISPLAY2`
+    #[arg(
+        long,
+        global = true,
+        value_name = "DEVICE",
+        requires = "wait_for_display"
+    )]
+    wait_device: Option<String>,
+
+    /// Record the requested amVideo calls and the environment to a capture file for a bug report,
+    /// without calling the DLL or changing any display settings
+    #[arg(long, global = true, value_name = "PATH")]
+    trace: Option<PathBuf>,
+
+    /// Run without any display attached: skip every display check, adjustment, and verification,
+    /// and make the DLL calls anyway (`dll`, the default) or answer them with a mock (`mock`)
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "BACKEND",
+        num_args = 0..=1,
+        default_missing_value = "dll",
+        conflicts_with = "wait_for_display"
+    )]
+    headless: Option<headless::Backend>,
+
+    /// After applying and verifying, capture the primary display to a PNG at PATH
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with_all = ["headless", "trace"]
+    )]
+    screenshot: Option<PathBuf>,
+
+    /// Print the context bytes each DLL call changed: after open, the VBIOS read, setting the
+    /// resolution, and closing
+    #[arg(long, global = true, conflicts_with = "isolate")]
+    diff_context: bool,
+
+    /// Write the 0x400-byte context to PATH before the DLL closes it
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "isolate")]
+    save_context: Option<PathBuf>,
+
+    /// Resume the context saved to PATH by `--save-context` instead of calling `amDllVideoOpen`
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "isolate")]
+    load_context: Option<PathBuf>,
+
+    /// Format for reports printed by `displays` and `doctor`, and for errors on stderr
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Repeatedly open amVideo, apply alternating resolutions, verify, and close
+    Stress(StressOpts),
+    /// Run declarative scenario files
+    Scenario(ScenarioOpts),
+    /// Stay resident and accept commands on the configured control surfaces
+    Daemon(DaemonOpts),
+    /// Apply a profile, run a game under it, and restore the displays when it exits, keeping
+    /// the crash context if it crashes
+    Run(RunOpts),
+    /// Wait for the display and driver to come up, apply a profile, and verify it, exiting with
+    /// a status code per failed stage for a boot-time scheduled task
+    Boot(BootOpts),
+    /// Send a command to a running daemon
+    Control(ControlOpts),
+    /// Show which adapter, output, and connector drive each attached display
+    Displays(DisplaysOpts),
+    /// Apply a layout file, such as one written by `displays export`
+    ApplyLayout {
+        /// TOML layout, or JSON if the extension is `.json`
+        layout: PathBuf,
+    },
+    /// Manage the profile config file
+    Config(ConfigOpts),
+    /// Share profiles between cabs as bundles with their layout and calibration files
+    Profile(ProfileOpts),
+    /// Check the DLL, display scaling, and topology for common problems
+    Doctor,
+    /// Report a DLL's machine, hash, versions, and exports without running any of its code
+    Inspect(InspectOpts),
+    /// Disable display sleep and the screensaver for a cabinet, or put the user's settings back
+    Kiosk(KioskOpts),
+    /// Report the DLL, build, and exports of every AM library in the video and monitor stack:
+    /// amVideo, amOsinfo, and amMonitor
+    Libraries,
+    /// Load and open the DLL and read the VBIOS version, never setting a resolution, for a
+    /// capability report that is safe on a cabinet in service
+    Probe(ProbeOpts),
+    /// Check our struct layouts against the loaded build with canary-filled buffers, without
+    /// setting a resolution
+    Selftest,
+    /// Show the last profile applied and the mode it verified at, without loading the DLL
+    Status(StatusOpts),
+    /// Put back the display settings from before the latest apply, from any earlier run
+    Revert,
+    /// Collect the doctor checks, versions, DLL details, redacted config, state, display
+    /// settings, and audit log tail into a zip for an issue or the game's support
+    SupportBundle(SupportBundleOpts),
+    /// Print the tool version, and with --verbose the DLL, driver, and OS versions too
+    Version,
+    /// Call any export with hand-built arguments against the open context, for reverse
+    /// engineering undocumented exports
+    #[command(hide = true)]
+    Call(CallOpts),
+    /// Load a DLL and explore it interactively: list exports, open, call, and diff the context
+    Repl(ReplOpts),
+    /// Make DLL calls sent on stdin, for `--isolate`
+    #[command(hide = true)]
+    Broker,
+    /// Print a completion script for the given shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Write a standalone amVideo stub DLL whose exports return configured values
+    GenStub(GenStubOpts),
+    /// Make the calls recorded in a `--trace` capture against a DLL, such as a stub
+    Replay(ReplayOpts),
+    /// Replace this executable with a newer signed release
+    SelfUpdate(SelfUpdateOpts),
+    /// Add or remove a virtual monitor through the configured driver
+    VirtualDisplay(VirtualDisplayOpts),
+    /// Install or remove a registry EDID override for a monitor, for panels with a broken EDID
+    /// and dummy plugs
+    Edid(EdidOpts),
+}
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+
+    // Nothing this process loads should come from the current directory or PATH
+    if let Err(e) = dll_path::harden() {
+        eprintln!("Warning: Failed to restrict the DLL search path: {}", e);
+    }
+
+    // Unwinding drops, and so closes, the panicking thread's handles, but an abort skips that.
+    // Close early either way so the driver is left in a sane state even if the drop panics too.
+    // A handle that outlives a caught panic refuses every call on the context closed under it.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if cfg!(panic = "abort") {
+            amvideo::close_open_contexts();
+        } else {
+            amvideo::close_thread_contexts();
         }
+        crash_report::report("panic", &info.to_string(), crash_report::Dump::Here);
+    }));
+    crash_report::install(opts.global.audit_log.clone());
+
+    if let Some(path) = &opts.global.audit_log {
+        audit::init(path)?;
+    }
+    if opts.global.force {
+        force::enable();
+    }
+    if opts.global.safe_mode {
+        safe_mode::enable();
+    }
+    if opts.global.continue_on_error {
+        steps::enable();
+    }
+    if opts.global.verbose {
+        verbose::enable();
+    }
+    if opts.global.isolate {
+        broker::enable();
+    }
+    if let Some(seconds) = opts.global.timeout {
+        timeout::set(seconds);
+    }
+    if let Some(seconds) = opts.global.wait_for_display {
+        ready::enable(
+            opts.global.wait_device.clone(),
+            seconds.map(Duration::from_secs),
+        );
+    }
+    if let Some(path) = &opts.global.trace {
+        trace::enable(path)?;
+    }
+    if let Some(backend) = opts.global.headless {
+        headless::enable(backend);
+    }
+    if opts.global.diff_context {
+        context_diff::enable();
     }
+    if opts.global.load_context.is_some() {
+        safe_mode::check("Resuming a saved context")?;
+    }
+    if opts.global.save_context.is_some() || opts.global.load_context.is_some() {
+        context_file::enable(
+            opts.global.save_context.clone(),
+            opts.global.load_context.clone(),
+        );
+    }
+    // Commands that need the config report a missing or broken one themselves
+    if let Some(exports) = Config::load(opts.global.config.as_deref())
+        .ok()
+        .and_then(|config| config.exports)
+    {
+        export_map::enable(exports);
+    }
+    audit::record(Event::SessionStart {
+        version: env!("CARGO_PKG_VERSION"),
+        args: env::args().collect(),
+    });
+
+    let result = match opts.command {
+        Some(Command::Stress(stress_opts)) => stress::run(&stress_opts),
+        Some(Command::Scenario(scenario_opts)) => scenario::run(&opts.global, &scenario_opts),
+        Some(Command::Daemon(daemon_opts)) => daemon::run(&opts.global, &daemon_opts),
+        Some(Command::Boot(boot_opts)) => boot::run(&opts.global, &boot_opts),
+        Some(Command::Control(control_opts)) => control::run_client(&control_opts),
+        Some(Command::Displays(displays_opts)) => displays::run(&opts.global, &displays_opts),
+        Some(Command::ApplyLayout { layout }) => run_apply_layout(&opts.global, &layout),
+        Some(Command::Config(config_opts)) => init::run(&opts.global, &config_opts),
+        Some(Command::Profile(profile_opts)) => bundle::run(&opts.global, &profile_opts),
+        Some(Command::Run(run_opts)) => supervise::run(&opts.global, &run_opts),
+        Some(Command::Doctor) => doctor::run(&opts.global),
+        Some(Command::Inspect(inspect_opts)) => inspect::run(&opts.global, &inspect_opts),
+        Some(Command::Call(call_opts)) => call::run(&call_opts),
+        Some(Command::Kiosk(kiosk_opts)) => kiosk::run(&kiosk_opts),
+        Some(Command::Repl(repl_opts)) => repl::run(&repl_opts),
+        Some(Command::Selftest) => selftest::run(&opts.global),
+        Some(Command::Status(status_opts)) => state::run(&opts.global, &status_opts),
+        Some(Command::Revert) => revert::run(&opts.global),
+        Some(Command::SupportBundle(bundle_opts)) => {
+            support_bundle::run(&opts.global, &bundle_opts)
+        }
+        Some(Command::Libraries) => libraries::run(&opts.global),
+        Some(Command::Probe(probe_opts)) => probe::run(&opts.global, &probe_opts),
+        Some(Command::Version) => version::run(&opts.global),
+        Some(Command::Broker) => broker::serve(),
+        Some(Command::Completions { shell }) => {
+            print!("{}", completions::generate(shell, Opts::command()));
+            Ok(())
+        }
+        Some(Command::GenStub(stub_opts)) => stub::run(&opts.global, &stub_opts),
+        Some(Command::Replay(replay_opts)) => trace::run(&replay_opts),
+        Some(Command::SelfUpdate(update_opts)) => update::run(&opts.global, &update_opts),
+        Some(Command::Edid(edid_opts)) => edid_override::run(&opts.global, &edid_opts),
+        Some(Command::VirtualDisplay(virtual_opts)) => {
+            virtual_display::run(&opts.global, &virtual_opts)
+        }
+        None => apply(&opts.global, opts.profile.as_deref()),
+    };
+
+    audit::record(Event::SessionEnd {
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+    });
+
+    // Boot failures exit with a code per stage so the task scheduler history tells them apart
+    if let Err(e) = &result {
+        if e.chain().any(|cause| cause.is::<Crashed>()) {
+            crash_report::report("dll-fault", &format!("{:#}", e), crash_report::Dump::Skip);
+        }
+        let failed = e.downcast_ref::<boot::Failed>();
+        if let OutputFormat::Json = opts.global.output {
+            failure::report(e);
+            process::exit(failed.map_or(1, |failed| failed.exit_code()));
+        }
+        if let Some(failed) = failed {
+            eprintln!("Error: {:?}", e);
+            if let Some(explanation) = codes::explain(e) {
+                eprintln!("{}", explanation);
+            }
+            process::exit(failed.exit_code());
+        }
+        if let Some(explanation) = codes::explain(e) {
+            eprintln!("Error: {:?}", e);
+            eprintln!("{}", explanation);
+            process::exit(1);
+        }
+    }
+
+    result
+}
+
+/// Name of the configured amVideo DLL, warning first about anything that will keep it from
+/// working on this machine
+fn dll_to_load() -> Result<OsString> {
+    let detection = platform::detect();
+    if let Some(warning) = detection.platform.warning() {
+        eprintln!("Warning: {}", warning);
+    }
+    if !detection.nvidia {
+        eprintln!("Warning: No NVIDIA GPU found, which amVideoNvidia requires");
+    }
+    if let Some(warning) = detection.environment_warning() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    let name = match (dll_name(), detection.platform.default_dll_name()) {
+        (Ok(name), _) => name,
+        (Err(e), Some(default)) => {
+            eprintln!("{}, falling back to {}", e, default);
+            default.into()
+        }
+        (Err(e), None) => return Err(e.into()),
+    };
+
+    let resolved = dll_path::resolve(&name)
+        .with_context(|| format!("Failed to find {}", name.to_string_lossy()))?;
+    force::guard("DLL path", dll_path::check(&resolved).map_err(Into::into))?;
+    eprintln!(
+        "Resolved {} to {} ({})",
+        name.to_string_lossy(),
+        resolved.path.display(),
+        resolved.origin
+    );
+
+    Ok(resolved.path.into_os_string())
 }
 
-// Ensure structure sizes are correct
-const_assert_eq!(mem::size_of::<AmVideoContext>(), 0x400);
-const_assert_eq!(mem::size_of::<AmVideoSetting>(), 0x14);
+/// Load the configured amVideo DLL and report where its exports were found
+fn load() -> Result<AmVideo<Closed>> {
+    let name = dll_to_load()?;
+    let amvideo = AmVideo::with_exports(&name, &export_map::get())?;
+    warn_hooks(&amvideo);
+    warn_conflicts();
 
-type AmDllVideoOpen = unsafe extern "C" fn(ctx: *mut AmVideoContext) -> usize;
-type AmDllVideoClose = unsafe extern "C" fn(ctx: *mut AmVideoContext) -> usize;
-type AmDllVideoSetResolution =
-    unsafe extern "C" fn(ctx: *mut AmVideoContext, setting: *const AmVideoSetting) -> usize;
-type AmDllVideoGetVBiosVersion =
-    unsafe extern "C" fn(ctx: *mut AmVideoContext, dst: *mut u8, size: u32) -> usize;
+    let exports: Vec<(&str, usize)> = amvideo
+        .exports()
+        .iter()
+        .map(|&(name, func)| (name, func as usize))
+        .collect();
+    report_loaded(&name, **amvideo.library() as usize, &exports);
 
-struct AmVideo {
-    lib: LibraryHandle,
-    video_open: AmDllVideoOpen,
-    video_close: AmDllVideoClose,
-    video_set_resolution: AmDllVideoSetResolution,
-    video_get_v_bios_version: AmDllVideoGetVBiosVersion,
-    ctx: AmVideoContext,
+    Ok(amvideo)
 }
 
-#[derive(Debug)]
-struct AmVideoError(usize);
+/// Print and record where the DLL and its exports were loaded
+fn report_loaded(name: &OsString, base: usize, exports: &[(&str, usize)]) {
+    println!("Opened amVideo.dll @ {:#x}", base);
+    for (name, address) in exports {
+        println!("Loaded {} @ {:#x}", name, address);
+    }
 
-impl AmVideo {
-    fn new<T: AsRef<OsStr>>(name: T) -> Result<Self> {
-        let name = name.as_ref();
-        let lib = unsafe {
-            let name: Vec<u16> = name.encode_wide().collect();
-            LoadLibraryW(name.as_ptr())
-        };
-        if lib.is_null() {
-            let e = Error::last_os_error();
-            let name = name.to_string_lossy();
-            let name = name.trim_end_matches('\0');
-            return Err(e).with_context(|| format!("Failed to load '{}'", name));
+    audit::record(Event::DllResolved {
+        name: &name.to_string_lossy(),
+        base,
+        exports: exports
+            .iter()
+            .map(|&(name, address)| Export {
+                name: name.to_string(),
+                address,
+            })
+            .collect(),
+    });
+}
+
+/// Warn about running software that may put its own mode back after ours is applied
+fn warn_conflicts() {
+    match conflicts::running() {
+        Ok(found) => {
+            for conflict in found {
+                eprintln!(
+                    "Warning: {} is running ({}), {}; close it or turn that off if the \
+                     resolution keeps reverting",
+                    conflict.product, conflict.process, conflict.reason
+                );
+            }
         }
-        let lib = LibraryHandle::new(lib);
-
-        println!("Opened amVideo.dll @ {:?}", lib);
-
-        // get functions
-        let video_open: AmDllVideoOpen;
-        let video_close: AmDllVideoClose;
-        let video_set_resolution: AmDllVideoSetResolution;
-        let video_get_v_bios_version: AmDllVideoGetVBiosVersion;
-        unsafe {
-            let am_dll_video_open = lib.get_func_named_ordinal("amDllVideoOpen", 1);
-            let am_dll_video_close = lib.get_func_named_ordinal("amDllVideoClose", 2);
-            let am_dll_video_set_resolution =
-                lib.get_func_named_ordinal("amDllVideoSetResolution", 3);
-            let am_dll_video_get_vbios_version =
-                lib.get_func_named_ordinal("amDllVideoGetVBiosVersion", 4);
-
-            let results = vec![
-                &am_dll_video_open,
-                &am_dll_video_close,
-                &am_dll_video_set_resolution,
-                &am_dll_video_get_vbios_version,
-            ];
-            let bad_funcs: Vec<_> = results
-                .into_iter()
-                .flat_map(|result| match result {
-                    Ok(_) => None,
-                    Err(e) => Some(e),
-                })
-                .map(|e| e.name())
-                .collect();
-
-            if !bad_funcs.is_empty() {
-                return Err(anyhow!(
-                    "Failed to find functions: {}",
-                    bad_funcs.join(", ")
-                ));
+        Err(e) => eprintln!("Skipping conflicting software check: {}", e),
+    }
+}
+
+/// Show what is about to change and ask before going ahead, unless `--yes` was passed
+fn prompt(global: &GlobalOpts, summary: &str) -> Result<()> {
+    println!("{}", summary);
+    if global.yes {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!("Not running interactively, pass --yes to go ahead"));
+    }
+
+    print!("Continue? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(anyhow!("Cancelled")),
+    }
+}
+
+/// Warn when another hook framework already sits between us and the DLL, since its hooks also
+/// see every call and may rewrite the requested mode
+fn warn_hooks(amvideo: &AmVideo<Closed>) {
+    match hooks::loaded_hooks() {
+        Ok(hooks) => {
+            for hook in hooks {
+                eprintln!(
+                    "Warning: {} from {} is loaded ({}), amVideo calls may be hooked twice",
+                    hook.module, hook.framework, hook.reason
+                );
             }
+        }
+        Err(e) => eprintln!("Skipping hook framework check: {}", e),
+    }
+
+    let hooked = amvideo.hooked_exports();
+    if !hooked.is_empty() {
+        eprintln!(
+            "Warning: {} already start with a jump, another hook may be installed",
+            hooked.join(", ")
+        );
+    }
+}
 
-            video_open = mem::transmute(am_dll_video_open?);
-            video_close = mem::transmute(am_dll_video_close?);
-            video_set_resolution = mem::transmute(am_dll_video_set_resolution?);
-            video_get_v_bios_version = mem::transmute(am_dll_video_get_vbios_version?);
+fn apply(global: &GlobalOpts, profile: Option<&str>) -> Result<()> {
+    match profile {
+        Some(name) => apply_named(global, name)?,
+        None => apply_default(global)?,
+    }
 
-            println!("Loaded amDllVideoOpen @ {:?}", video_open);
-            println!("Loaded amDllVideoClose @ {:?}", video_close);
-            println!(
-                "Loaded amDllVideoSetResolution @ {:?}",
-                video_set_resolution
-            );
-            println!(
-                "Loaded amDllVideoGetVBiosVersion @ {:?}",
-                video_get_v_bios_version
-            );
+    if headless::enabled().is_some() {
+        println!("Done: {}", headless::STATUS);
+    } else {
+        println!("Done");
+    }
+    // The apply worked, a missing screenshot only means less to look at remotely
+    if let Some(path) = &global.screenshot {
+        if let Err(e) = png::save_screenshot(path) {
+            eprintln!("{:#}", e);
         }
+    }
 
-        let ctx = AmVideoContext {
-            version: 1,
-            data: [0; AM_VIDEO_CONTEXT_DATA_SIZE],
-        };
+    Ok(())
+}
+
+/// Apply the profile `name` from the config
+fn apply_named(global: &GlobalOpts, name: &str) -> Result<()> {
+    let config = Config::load(global.config.as_deref())?;
+    let profile = config.profile(name)?;
+
+    prompt(
+        global,
+        &format!(
+            "About to apply '{}', {} in {:?} mode, through amVideo",
+            name, profile.resolution, profile.mode
+        ),
+    )?;
+    let (result, duration) = timing::time(|| apply_profile(&profile));
+    state::record(Some(name), &result, duration);
+    result
+}
+
+/// Apply 1920x1080 in single mode with SegaTiming
+fn apply_default(global: &GlobalOpts) -> Result<()> {
+    let setting = AmVideoSetting::new(
+        AmVideoMode::Single,
+        AmVideoResolution::new(1920, 1080),
+        AmVideoResolution::new(1920, 1080),
+    );
+
+    if headless::enabled().is_none() {
+        force::guard("fullscreen", check_fullscreen())?;
+
+        if let Some(mode) = already_running(&setting, None, Switch::IfNeeded) {
+            println!("Already applied: running {}", mode);
+            state::record(None, &Ok::<_, Error>(()), Duration::ZERO);
+            return Ok(());
+        }
+    }
+
+    // The DLL applies whatever it is given, without checking the panel supports it
+    prompt(
+        global,
+        &format!(
+            "About to set {} in single mode with SegaTiming through amVideo",
+            setting.resolution_1
+        ),
+    )?;
+    if headless::enabled().is_none() {
+        state::stash();
+    }
+    let (result, duration) = timing::time(|| apply_setting(&setting, None));
+    state::record(None, &result, duration);
+    result
+}
+
+/// Whether an apply may leave out the mode switch when the displays already run the mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Switch {
+    IfNeeded,
+    /// Switch anyway, e.g. after a power cycle, when the driver may have dropped SegaTiming
+    /// while reporting the same mode
+    Always,
+}
+
+/// Apply a profile's setting, then restore the display state the mode switch resets
+fn apply_profile(profile: &Profile) -> Result<()> {
+    apply_profile_with(profile, Switch::IfNeeded)
+}
 
-        Ok(Self {
-            lib,
-            video_open,
-            video_close,
-            video_set_resolution,
-            video_get_v_bios_version,
-            ctx,
-        })
-    }
-
-    /// Enable amVideo's built-in error logging
-    ///
-    /// Offsets are for "amVideoNvidia Build:Jan 30 2015 18:51:29 ($Rev: 4624 $)"
-    #[allow(dead_code)]
-    fn enable_logging(&mut self) {
-        unsafe {
-            // Use `#[repr(transparent)]` here
-            let amvideo_ptr = *self.lib as *mut u8;
-
-            // Compute memory locations
-            let validate_log_level = amvideo_ptr.add(0x505D4) as *mut u32;
-            let log_level = amvideo_ptr.add(0x505D8) as *mut u32;
-
-            *validate_log_level = 1;
-            *log_level = 1;
+/// [`apply_profile`], switching the mode even if already active with [`Switch::Always`]
+fn apply_profile_with(profile: &Profile, switch: Switch) -> Result<()> {
+    // The capture holds the whole profile, so the steps around the DLL calls need no recording
+    if trace::enabled() {
+        trace::record_profile(profile);
+        return apply_setting(&profile.setting(), Some(profile));
+    }
+
+    // There is no display to check, adjust, or restore anything on
+    if headless::enabled().is_some() {
+        return apply_setting(&profile.setting(), Some(profile));
+    }
+
+    check_aspect(profile);
+    check_link(profile);
+    let fitted = profile.fitted_resolution();
+    let adjusted;
+    let profile = if fitted == profile.resolution {
+        profile
+    } else {
+        println!(
+            "Using {} instead of {} to match the panel's aspect ratio",
+            fitted, profile.resolution
+        );
+        adjusted = Profile {
+            resolution: fitted,
+            secondary_resolution: Some(profile.secondary_resolution.unwrap_or(profile.resolution)),
+            ..profile.clone()
         };
+        &adjusted
+    };
+
+    force::guard("spanning", check_spanning(profile))?;
+    force::guard("refresh rate", check_refresh(profile))?;
+    force::guard("fullscreen", check_fullscreen())?;
+
+    state::stash();
+    let rollback = match profile.confirm_within {
+        Some(_) => Some(confirm::Rollback::capture(profile)?),
+        None => None,
+    };
+
+    let mut skipped = Skipped::default();
+    let mut failures = Failures::default();
+
+    // Switched before the mode change so the new mode never runs with VRR active
+    if let Some(mode) = profile.vrr.filter(|_| skipped.allow("G-SYNC mode")) {
+        failures.run("G-SYNC mode", || vrr::switch(mode))?;
     }
 
-    fn open(&mut self) -> Result<(), AmVideoError> {
-        let result = unsafe { (self.video_open)(&mut self.ctx) };
-        if result == 0 {
+    failures.run("primary display", || {
+        switch_primary(profile, PrimaryWhen::Before)
+    })?;
+    let setting = profile.setting();
+    // Switching to the mode already active only blanks the screen for a moment
+    if let Some(mode) = already_running(&setting, profile.refresh_rate, switch) {
+        println!("Already applied: running {}", mode);
+        audit::record(Event::Skip {
+            operation: "mode switch",
+        });
+    } else {
+        failures.run("mode switch", || {
+            custom_resolution::ensure(profile)?;
+            let suspended = prepare_hdr(profile.hdr)?;
+            let result = apply_setting(&setting, Some(profile));
+            for device in &suspended {
+                match hdr::set(device, true) {
+                    Ok(()) => println!("Turned HDR back on for {}", device),
+                    Err(e) => eprintln!("Failed to turn HDR back on for {}: {}", device, e),
+                }
+            }
+            result?;
+            if let Some(refresh) = profile.refresh_rate {
+                apply_refresh(refresh)?;
+            }
+            verify_applied(&profile.resolution, profile.refresh_rate)
+        })?;
+    }
+    if let Some(scaling) = profile.scaling {
+        failures.run("GPU scaling", || {
+            topology::set_scaling(None, scaling).context("Failed to set GPU scaling")?;
+            println!("Set GPU scaling to {:?}", scaling);
             Ok(())
+        })?;
+    }
+    if let Some(secondary) = &profile.secondary {
+        if profile.mode == AmVideoMode::DualVideoMode {
+            failures.run("secondary display", || {
+                let device = secondary.device()?;
+                topology::place(&device, secondary.position, secondary.rotation)
+                    .with_context(|| format!("Failed to place secondary display {}", device))?;
+                println!("Placed secondary display {}", device);
+                Ok(())
+            })?;
         } else {
-            Err(AmVideoError(result))
+            eprintln!("Ignoring secondary display placement outside dual mode");
         }
     }
+    if let Some(path) = &profile.layout {
+        failures.run("layout", || apply_layout(path))?;
+    }
+    if let Some(layout) = profile.display_layout()? {
+        failures.run("displays", || apply_displays(&layout))?;
+    }
+    failures.run("primary display", || {
+        switch_primary(profile, PrimaryWhen::After)
+    })?;
+    if let Some(segatools) = &profile.segatools {
+        failures.run("segatools.ini", || segatools::sync(segatools, &mut skipped))?;
+    }
 
-    fn set_resolution(&mut self, setting: &AmVideoSetting) -> Result<(), AmVideoError> {
-        let result = unsafe { (self.video_set_resolution)(&mut self.ctx, setting) };
-        if result == 0 {
+    if let Some(color) = &profile.color {
+        failures.run("gamma ramp", || {
+            color
+                .ramp()?
+                .apply(color.device.as_deref())
+                .context("Failed to load gamma ramp")?;
+            println!("Loaded gamma ramp");
             Ok(())
-        } else {
-            Err(AmVideoError(result))
+        })?;
+    }
+
+    if let Some(ddc) = &profile.ddc {
+        failures.run("DDC/CI", || {
+            let monitors =
+                PhysicalMonitors::open(ddc.device.as_deref()).context("Failed to open monitor")?;
+            if let Some(brightness) = ddc.brightness {
+                monitors
+                    .set_percent(VCP_BRIGHTNESS, brightness)
+                    .context("Failed to set brightness")?;
+                println!("Set brightness to {}%", brightness);
+            }
+            if let Some(contrast) = ddc.contrast {
+                monitors
+                    .set_percent(VCP_CONTRAST, contrast)
+                    .context("Failed to set contrast")?;
+                println!("Set contrast to {}%", contrast);
+            }
+            Ok(())
+        })?;
+    }
+    failures.run("audio endpoint", || audio_endpoint::switch(profile))?;
+
+    if let (Some(rollback), Some(seconds)) = (&rollback, profile.confirm_within) {
+        confirm::keep_or_revert(rollback, Duration::from_secs(seconds))?;
+    }
+
+    if profile.kiosk {
+        failures.run("kiosk mode", kiosk::enable)?;
+    }
+
+    skipped.report();
+
+    failures.finish()
+}
+
+/// Warn about displays running with HDR on, or turn it off as `policy` asks, returning the
+/// displays to turn it back on for afterwards
+fn prepare_hdr(policy: HdrPolicy) -> Result<Vec<String>> {
+    let enabled: Vec<String> = match hdr::query() {
+        Ok(states) => states
+            .into_iter()
+            .filter(|state| state.enabled)
+            .map(|state| state.device)
+            .collect(),
+        Err(e) => {
+            eprintln!("Skipping HDR check: {}", e);
+            return Ok(Vec::new());
+        }
+    };
+
+    for device in &enabled {
+        if policy == HdrPolicy::Warn {
+            eprintln!(
+                "Warning: HDR is on for {}; SegaTiming modes and HDR interact badly and colors \
+                 will look washed out. Set `hdr = \"disable\"` in the profile to turn it off.",
+                device
+            );
+            continue;
         }
+        hdr::set(device, false)
+            .with_context(|| format!("Failed to turn HDR off for {}", device))?;
+        println!("Turned HDR off for {}", device);
+    }
+
+    Ok(match policy {
+        HdrPolicy::Suspend => enabled,
+        _ => Vec::new(),
+    })
+}
+
+/// `amvideo apply-layout`: confirm the layout's changes, then apply them
+fn run_apply_layout(global: &GlobalOpts, path: &Path) -> Result<()> {
+    let layout = config::load_layout(path)?;
+    let mut summary = format!("About to apply layout '{}':", path.display());
+    for output in &layout.outputs {
+        summary.push_str(&format!("\n  {}", output));
+    }
+    prompt(global, &summary)?;
+
+    state::stash();
+    layout
+        .apply()
+        .with_context(|| format!("Failed to apply layout '{}'", path.display()))?;
+    println!("Applied layout '{}'", path.display());
+
+    Ok(())
+}
+
+fn apply_layout(path: &Path) -> Result<()> {
+    config::load_layout(path)?
+        .apply()
+        .with_context(|| format!("Failed to apply layout '{}'", path.display()))?;
+    println!("Applied layout '{}'", path.display());
+
+    Ok(())
+}
+
+/// Apply a profile's `displays` map in one mode change, putting every display back if it fails
+fn apply_displays(layout: &Layout) -> Result<()> {
+    let snapshot = Snapshot::capture().context("Failed to capture the display settings")?;
+    if let Err(e) = layout.apply() {
+        let restored = snapshot.restore();
+        audit::record(Event::Restore {
+            snapshot: &snapshot,
+            ok: restored.is_ok(),
+        });
+        let e = Error::from(e);
+        return Err(match restored {
+            Ok(()) => e.context("Failed to apply the display settings, rolled them back"),
+            Err(restore) => e.context(format!(
+                "Failed to apply the display settings, and to roll them back: {}",
+                restore
+            )),
+        });
+    }
+    for output in &layout.outputs {
+        println!("Applied {}", output);
+    }
+
+    Ok(())
+}
+
+/// Make the profile's primary display the Windows primary, if it asks for that at `when`
+fn switch_primary(profile: &Profile, when: PrimaryWhen) -> Result<()> {
+    let primary = match &profile.primary {
+        Some(primary) if primary.when == when => primary,
+        _ => return Ok(()),
+    };
+
+    let device = primary.device()?;
+    topology::set_primary(&device)
+        .with_context(|| format!("Failed to make {} the primary display", device))?;
+    println!("Made {} the primary display", device);
+
+    Ok(())
+}
+
+/// Warn when the profile's resolution has another aspect ratio than the primary panel, along with
+/// the bars it shows with once scaled to keep its shape
+fn check_aspect(profile: &Profile) {
+    let panel = match aspect::native_resolution(None) {
+        Ok(panel) => panel,
+        Err(e) => {
+            eprintln!("Skipping aspect ratio check: {}", e);
+            return;
+        }
+    };
+    let (requested, expected) = (Ratio::from(&profile.resolution), Ratio::from(&panel));
+    if requested.matches(expected) {
+        return;
+    }
+
+    let fit = Fit::of(&profile.resolution, &panel);
+    let bars = if fit.pillarbox > 0 {
+        format!("{}-pixel bars left and right", fit.pillarbox)
+    } else {
+        format!("{}-pixel bars above and below", fit.letterbox)
+    };
+    eprintln!(
+        "{} is {} but the panel is {} ({}); scaled to keep its shape it fills {}x{} with {}",
+        profile.resolution, requested, expected, panel, fit.width, fit.height, bars
+    );
+    if !profile.match_aspect {
+        eprintln!(
+            "Set `scaling = \"aspect-ratio\"` to keep that shape, or `match_aspect = true` to use \
+             the panel's closest {} mode",
+            expected
+        );
+    }
+}
+
+/// Warn when a display's mode likely needs more bandwidth than its link carries, at the profile's
+/// refresh rate or 60 Hz
+fn check_link(profile: &Profile) {
+    let topology = match Topology::query() {
+        Ok(topology) => topology,
+        Err(e) => {
+            eprintln!("Skipping link bandwidth check: {}", e);
+            return;
+        }
+    };
+    let refresh = profile.refresh_rate.unwrap_or(60);
+
+    let primary = topology.displays.iter().find(|display| display.primary);
+    let mut checks = vec![(primary, profile.resolution, profile.link)];
+    if profile.mode == AmVideoMode::DualVideoMode {
+        let secondary = topology.displays.iter().find(|display| !display.primary);
+        let resolution = profile.secondary_resolution.unwrap_or(profile.resolution);
+        checks.push((secondary, resolution, profile.secondary_link));
     }
 
-    fn get_vbios_version(&mut self) -> Result<String> {
-        let mut data = [0; 255];
-        let result = unsafe {
-            (self.video_get_v_bios_version)(&mut self.ctx, data.as_mut_ptr(), data.len() as u32)
+    for (display, resolution, configured) in checks {
+        let display = match display {
+            Some(display) => display,
+            None => continue,
         };
-        if result != 0 {
-            return Err(AmVideoError(result).into());
+        let link = configured.or_else(|| display.targets.first().and_then(|target| target.link));
+        let link = match link {
+            Some(link) => link,
+            None => continue,
+        };
+        let clock = display::pixel_clock_mhz(
+            u32::from(resolution.width),
+            u32::from(resolution.height),
+            refresh,
+        );
+        if clock > f64::from(link.max_pixel_clock_mhz()) {
+            eprintln!(
+                "Warning: {} @ {} Hz needs about {:.0} MHz of pixel clock, more than the {} MHz \
+                 {} to {} carries; use a faster cable, adapter, or port if the mode does not show",
+                resolution,
+                refresh,
+                clock,
+                link.max_pixel_clock_mhz(),
+                link,
+                display.device
+            );
         }
+    }
+}
+
+/// Refuse a refresh rate the primary display does not offer at the profile's resolution
+fn check_refresh(profile: &Profile) -> Result<()> {
+    let refresh = match profile.refresh_rate {
+        Some(refresh) => refresh,
+        None => return Ok(()),
+    };
+    let resolution = profile.resolution;
+
+    // A missing mode is created before the switch
+    if profile.custom_resolution.is_some() {
+        return Ok(());
+    }
 
-        let data = data.split(|&c| c == 0).nth(0).unwrap_or(&data);
-        let version =
-            str::from_utf8(data).context("Failed to interpret VBIOS version string as UTF-8")?;
-        Ok(version.to_string())
+    let modes = display::supported_modes(None);
+    if modes
+        .iter()
+        .any(|mode| mode.matches(&resolution) && mode.runs_at(refresh))
+    {
+        return Ok(());
     }
+
+    let mut offered: Vec<_> = modes
+        .iter()
+        .filter(|mode| mode.matches(&resolution))
+        .map(|mode| mode.frequency)
+        .collect();
+    offered.sort_unstable();
+    offered.dedup();
+    Err(anyhow!(
+        "The primary display does not offer {} at {} Hz, only at {:?} Hz; the panel or the link \
+         cannot carry it",
+        resolution,
+        refresh,
+        offered
+    ))
 }
 
-impl Drop for AmVideo {
-    fn drop(&mut self) {
-        let result = unsafe { (self.video_close)(&mut self.ctx) };
-        if result != 0 {
-            eprintln!("Failed to close amVideo: {}", result);
+/// The primary display's mode if the displays already run `setting` (and `refresh`), so the
+/// mode switch can be left out. Always `None` under `--force` or with [`Switch::Always`].
+fn already_running(
+    setting: &AmVideoSetting,
+    refresh: Option<u32>,
+    switch: Switch,
+) -> Option<display::DisplayMode> {
+    if switch == Switch::Always || force::enabled() {
+        return None;
+    }
+
+    let mode = display::verify_mode(&setting.resolution_1, refresh).ok()?;
+    if setting.mode == AmVideoMode::DualVideoMode {
+        let secondary = display::attached_displays()
+            .into_iter()
+            .filter(|device| !device.primary)
+            .any(|device| {
+                display::current_settings(&device.name)
+                    .is_ok_and(|settings| settings.mode().matches(&setting.resolution_2))
+            });
+        if !secondary {
+            return None;
         }
     }
+
+    Some(mode)
 }
 
-impl fmt::Display for AmVideoError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "amVideo function failed: {}", self.0)
+/// Ask Windows for `refresh` Hz if the DLL left the primary display at another rate
+fn apply_refresh(refresh: u32) -> Result<()> {
+    let mode = display::current_mode()?;
+    if !mode.runs_at(refresh) {
+        display::set_refresh(refresh)
+            .with_context(|| format!("Failed to switch from {} to {} Hz", mode, refresh))?;
     }
+
+    Ok(())
 }
 
-impl StdError for AmVideoError {}
+/// Wait for the primary display to show `resolution`, and `refresh` Hz if given, recording the
+/// check in the audit log
+fn verify_applied(resolution: &AmVideoResolution, refresh: Option<u32>) -> Result<()> {
+    let result = timeout::poll(timeout::limit(Stage::Verify), || {
+        display::verify_mode(resolution, refresh)
+    });
+    audit::record_verify(resolution, &result);
+    println!("Running at {}", result?);
 
-fn main() -> Result<()> {
-    let name: OsString = RegKey::predef(HKEY_LOCAL_MACHINE)
-        .open_subkey("System\\Sega\\SystemProperty\\amVideo")
-        .context("Failed to open 'System\\Sega\\SystemProperty\\amVideo'")?
-        .get_value("name")
-        .context("Failed to get amVideo 'name'")?;
-    let mut amvideo = AmVideo::new(name)?;
-    //amvideo.enable_logging();
-    amvideo.open()?;
+    Ok(())
+}
+
+/// Refuse to apply over a Surround or Eyefinity group, where the DLL's display 1 and 2 are not
+/// the physical monitors, unless the profile opts in
+fn check_spanning(profile: &Profile) -> Result<()> {
+    let topology = match Topology::query() {
+        Ok(topology) => topology,
+        Err(e) => {
+            eprintln!("Skipping spanning check: {}", e);
+            return Ok(());
+        }
+    };
+
+    let spanned: Vec<_> = topology
+        .displays
+        .iter()
+        .filter(|display| display.is_spanned())
+        .collect();
+    if spanned.is_empty() {
+        return Ok(());
+    }
+
+    for display in &spanned {
+        eprintln!(
+            "{} spans {} monitors{}; amVideo will treat the group as a single display",
+            display.device,
+            display.physical_monitors,
+            display
+                .desktop
+                .map_or(String::new(), |(left, top, right, bottom)| format!(
+                    " as {}x{}",
+                    right - left,
+                    bottom - top
+                )),
+        );
+    }
+    eprintln!(
+        "Disable NVIDIA Surround or AMD Eyefinity in the driver control panel, or set \
+         `allow_spanning = true` in the profile or pass --force to apply anyway"
+    );
+
+    if profile.allow_spanning {
+        Ok(())
+    } else {
+        Err(anyhow!("Refusing to apply over a spanned display group"))
+    }
+}
+
+/// Refuse to change modes under a game running in exclusive fullscreen. The daemon retries on
+/// the next display event, by which point the game has usually exited.
+fn check_fullscreen() -> Result<()> {
+    match display::exclusive_fullscreen_app() {
+        Ok(None) => Ok(()),
+        Ok(Some(app)) => Err(anyhow!(
+            "'{}' is running in exclusive fullscreen on {}, deferring the mode change until it \
+             exits",
+            app.title,
+            app.device
+        )),
+        Err(e) => {
+            eprintln!("Skipping fullscreen check: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Load the DLL, open it with the DLL options of `profile` if given, apply `setting`, and close
+/// it again. Under `--isolate` the DLL runs in a broker process, and a crash is retried once in a
+/// fresh one. Under `--trace` the calls are only recorded.
+fn apply_setting(setting: &AmVideoSetting, profile: Option<&Profile>) -> Result<()> {
+    let context_version = profile.and_then(|profile| profile.context_version);
+
+    ready::wait()?;
+
+    if let Some(headless::Backend::Mock) = headless::enabled() {
+        let mut mock = Mock::new(context_version);
+        return drive(&mut mock, context_version.unwrap_or(1), setting, profile);
+    }
+
+    if trace::enabled() {
+        let name = dll_to_load()?;
+        let mut tracer = Tracer::load(&name.to_string_lossy(), context_version);
+        return drive(&mut tracer, context_version.unwrap_or(1), setting, profile);
+    }
+
+    if !broker::enabled() {
+        let mut amvideo = load()?;
+        if let Some(version) = context_version {
+            amvideo.set_context_version(version);
+        }
+        let requested = amvideo.context_version();
+        //amvideo.enable_logging();
+        return drive(&mut Local::Closed(amvideo), requested, setting, profile);
+    }
+
+    let name = dll_to_load()?;
+    let mut retried = false;
+    loop {
+        let (mut broker, loaded) = timeout::watch(Stage::Dll, "broker start", || {
+            Broker::spawn(&name, context_version)
+        })?;
+        let exports: Vec<(&str, usize)> = loaded
+            .exports
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+            .collect();
+        report_loaded(&name, loaded.base, &exports);
+
+        match drive(&mut broker, loaded.context_version, setting, profile) {
+            Err(e) if !retried && e.downcast_ref::<Crashed>().is_some() => {
+                eprintln!("{:#}, retrying in a fresh broker", e);
+                retried = true;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Open, report the VBIOS version, apply `setting`, and close, recording each call
+fn drive(
+    session: &mut dyn Session,
+    requested: u32,
+    setting: &AmVideoSetting,
+    profile: Option<&Profile>,
+) -> Result<()> {
+    let (result, duration) = timing::time(|| session.open());
+    audit::record(Event::Open {
+        outcome: audit_outcome(&result),
+        duration_us: timing::micros(duration),
+    });
+    timing::report("amDllVideoOpen", duration);
+    let version = result?;
+    if version != requested {
+        println!(
+            "Opened with context version {} instead of {}",
+            version, requested
+        );
+    }
 
     // Get VBIOS version
-    match amvideo
-        .get_vbios_version()
-        .context("Failed to get VBIOS version")
-    {
-        Ok(vbios_version) => println!("VBIOS Version: {}", vbios_version),
+    let buffer = profile
+        .and_then(|profile| profile.vbios_buffer)
+        .unwrap_or(DEFAULT_VBIOS_BUFFER);
+    let (result, duration) = timing::time(|| session.vbios_version(buffer));
+    audit::record(Event::VbiosVersion {
+        outcome: audit_outcome(&result),
+        version: result.as_ref().ok().map(|vbios| vbios.version.as_str()),
+        duration_us: timing::micros(duration),
+    });
+    timing::report("amDllVideoGetVBiosVersion", duration);
+    match result.context("Failed to get VBIOS version") {
+        Ok(vbios) => {
+            println!("VBIOS Version: {}", vbios.version);
+            state::note_vbios(&vbios.version);
+            if vbios.truncated {
+                eprintln!("Warning: VBIOS version did not fit the largest buffer and is truncated");
+            }
+            if verbose::enabled() {
+                println!("VBIOS Version bytes: {:02x?}", vbios.raw);
+            }
+        }
+        // A crashed broker cannot go on to set the resolution
+        Err(e) if e.downcast_ref::<Crashed>().is_some() => {
+            let _ = close(session);
+            return Err(e);
+        }
         Err(e) => eprintln!("{:?}", e),
     };
 
     // Set resolution
-    let resolution = AmVideoSetting {
-        version: 1,
-        use_segatiming: 1,
-        mode: AmVideoMode::Single,
-        resolution_1: AmVideoResolution {
-            width: 1920,
-            height: 1080,
-        },
-        resolution_2: AmVideoResolution {
-            width: 1920,
-            height: 1080,
-        },
-    };
-    println!("Attempting to set resolution: {:#?}", resolution);
-    amvideo.set_resolution(&resolution)?;
+    println!("Attempting to set resolution: {:#?}", setting);
+    let extras = profile.map_or_else(AmVideoSettingExtras::default, Profile::setting_extras);
+    let (result, duration) = timing::time(|| session.set_resolution(setting, &extras));
+    audit::record(Event::SetResolution {
+        setting,
+        outcome: audit_outcome(&result),
+        duration_us: timing::micros(duration),
+    });
+    timing::report("amDllVideoSetResolution", duration);
 
-    println!("Done");
+    // Closed even after a failure, so the audit log never shows a context left open
+    let closed = close(session);
+    result?;
+    closed
+}
 
-    Ok(())
+/// Close the session, recording the call
+fn close(session: &mut dyn Session) -> Result<()> {
+    let (result, duration) = timing::time(|| session.close());
+    audit::record(Event::Close {
+        outcome: audit_outcome(&result),
+        duration_us: timing::micros(duration),
+    });
+    timing::report("amDllVideoClose", duration);
+    result
+}
+
+/// Outcome of a session call for the audit log, with the DLL status and the whole error chain
+fn audit_outcome<T>(result: &Result<T>) -> audit::Outcome {
+    match result {
+        Ok(_) => audit::outcome(None),
+        Err(e) => audit::Outcome {
+            code: e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<AmVideoCrateError>())
+                .and_then(AmVideoCrateError::code),
+            error: Some(format!("{:#}", e)),
+        },
+    }
 }
@@ -0,0 +1,296 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use anyhow::{Context, Result};
+
+/// Version of the config format this build writes and understands, stamped as the top-level
+/// `version` key
+pub const VERSION: u32 = 2;
+
+/// Step from each version to the next, the first taking version 1 to 2
+const MIGRATIONS: &[fn(&mut toml::Table)] = &[from_v1];
+
+/// Parse `contents` of the config at `path`, bringing a config of an older version up to date
+/// in memory. The file is left alone, since most commands only read it and several may load it
+/// at once; `config migrate` rewrites it. A config written by a newer build is refused rather
+/// than half understood.
+pub fn load(path: &Path, contents: &str) -> Result<toml::Table> {
+    let (table, from) = upgrade(path, contents)?;
+    if let Some(version) = from {
+        // Reported on stderr to keep JSON reports parseable, and once for every load
+        static NOTED: Once = Once::new();
+        NOTED.call_once(|| {
+            eprintln!(
+                "Note: Config '{}' is version {}, run `amvideo config migrate` to update it to \
+                 version {}",
+                path.display(),
+                version,
+                VERSION
+            )
+        });
+    }
+    Ok(table)
+}
+
+/// Bring the config file at `path` up to date on disk, keeping the original next to it as a
+/// backup. Returns the version it was migrated from, or `None` if it was already current.
+pub fn migrate_file(path: &Path) -> Result<Option<u32>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config '{}'", path.display()))?;
+    let (migrated, from) = upgrade(path, &contents)?;
+    let version = match from {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+
+    let original: toml::Table = toml::from_str(&contents)?;
+    let backup = save(path, &contents, &original, &migrated, version)?;
+    println!(
+        "Migrated config '{}' from version {} to {}, the original is kept as '{}'",
+        path.display(),
+        version,
+        VERSION,
+        backup.display()
+    );
+    Ok(Some(version))
+}
+
+/// Parse and migrate `contents`, along with the version it was migrated from, `None` if it was
+/// already current
+fn upgrade(path: &Path, contents: &str) -> Result<(toml::Table, Option<u32>)> {
+    let mut table: toml::Table = toml::from_str(contents)
+        .with_context(|| format!("Failed to parse config '{}'", path.display()))?;
+    let version = version(&table)
+        .with_context(|| format!("Invalid version in config '{}'", path.display()))?;
+    if version > VERSION {
+        return Err(anyhow!(
+            "Config '{}' is version {}, but this build only understands up to version {}; \
+             update amvideo",
+            path.display(),
+            version,
+            VERSION
+        ));
+    }
+    if version == VERSION {
+        return Ok((table, None));
+    }
+
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(&mut table);
+    }
+    table.insert("version".to_string(), toml::Value::Integer(VERSION.into()));
+    Ok((table, Some(version)))
+}
+
+/// Version 1 configs predate the `version` key, and need nothing else
+fn from_v1(_table: &mut toml::Table) {}
+
+/// Version a config was written in, 1 for one without a `version` key
+fn version(table: &toml::Table) -> Result<u32> {
+    match table.get("version") {
+        None => Ok(1),
+        Some(toml::Value::Integer(version)) if *version >= 1 => {
+            u32::try_from(*version).map_err(|_| anyhow!("Version {} is out of range", version))
+        }
+        Some(value) => Err(anyhow!("Expected a positive integer, got {}", value)),
+    }
+}
+
+/// Back up the config and write the migrated one over it, returning the backup's path
+fn save(
+    path: &Path,
+    contents: &str,
+    original: &toml::Table,
+    migrated: &toml::Table,
+    version: u32,
+) -> Result<PathBuf> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(backup);
+    // A backup from an earlier attempt is the file as the user last wrote it
+    if !backup.exists() {
+        fs::write(&backup, contents)
+            .with_context(|| format!("Failed to write '{}'", backup.display()))?;
+    }
+
+    fs::write(path, rewrite(contents, original, migrated)?)
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(backup)
+}
+
+/// The migrated config as text. When only the version changed, the stamp is edited into the
+/// original text to keep its comments; otherwise the config is written out afresh.
+fn rewrite(contents: &str, original: &toml::Table, migrated: &toml::Table) -> Result<String> {
+    let without_version = |table: &toml::Table| {
+        let mut table = table.clone();
+        table.remove("version");
+        table
+    };
+    if without_version(original) != without_version(migrated) {
+        return Ok(format!(
+            "# Migrated to version {} by amvideo, which leaves out comments\n\n{}",
+            VERSION,
+            toml::to_string_pretty(migrated)?
+        ));
+    }
+
+    let stamp = format!("version = {}", VERSION);
+    let mut lines: Vec<&str> = contents.lines().collect();
+    let tables = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    let existing = lines[..tables].iter().position(|line| {
+        line.split_once('=')
+            .is_some_and(|(key, _)| key.trim() == "version")
+    });
+    match existing {
+        Some(index) => lines[index] = &stamp,
+        None => {
+            // Top-level keys must come before the first table, after any leading comments
+            let index = lines
+                .iter()
+                .position(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+                .unwrap_or(lines.len());
+            lines.insert(index, &stamp);
+            lines.insert(index + 1, "");
+        }
+    }
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn table(contents: &str) -> toml::Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    /// Fresh directory for a test's config files
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("amvideo-migrate-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_version() {
+        assert_eq!(version(&table("")).unwrap(), 1);
+        assert_eq!(version(&table("version = 2")).unwrap(), 2);
+        assert!(version(&table("version = 0")).is_err());
+        assert!(version(&table("version = -1")).is_err());
+        assert!(version(&table("version = \"2\"")).is_err());
+        assert!(version(&table("version = 4294967296")).is_err());
+    }
+
+    #[test]
+    fn loads_current_version_unchanged() {
+        let contents = "version = 2\n\n[profiles.a]\nresolution = \"1920x1080\"\n";
+        let path = Path::new("does-not-exist.toml");
+        assert_eq!(load(path, contents).unwrap(), table(contents));
+    }
+
+    #[test]
+    fn refuses_newer_version() {
+        let error = load(Path::new("config.toml"), "version = 3").unwrap_err();
+        assert!(error.to_string().contains("version 3"));
+    }
+
+    #[test]
+    fn loads_v1_without_writing() {
+        let dir = scratch("load");
+        let path = dir.join("config.toml");
+        let contents = "[profiles.a]\nresolution = \"1920x1080\"\n";
+        fs::write(&path, contents).unwrap();
+
+        let migrated = load(&path, contents).unwrap();
+        assert_eq!(migrated.get("version"), Some(&toml::Value::Integer(2)));
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        assert!(!dir.join("config.toml.v1.bak").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_v1_file_with_backup() {
+        let dir = scratch("v1");
+        let path = dir.join("config.toml");
+        let contents = "# Cab config\n\n[profiles.a]\nresolution = \"1920x1080\"\n";
+        fs::write(&path, contents).unwrap();
+
+        assert_eq!(migrate_file(&path).unwrap(), Some(1));
+        assert_eq!(
+            fs::read_to_string(dir.join("config.toml.v1.bak")).unwrap(),
+            contents
+        );
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("# Cab config\n\nversion = 2\n"));
+        assert_eq!(table(&written), load(&path, contents).unwrap());
+
+        // Already current, so nothing more to do
+        assert_eq!(migrate_file(&path).unwrap(), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stamps_after_leading_comments() {
+        let contents = "# One\n# Two\n\nname = \"cab\"\n\n[profiles.a]\n";
+        let original = table(contents);
+        let mut migrated = original.clone();
+        migrated.insert("version".to_string(), toml::Value::Integer(2));
+        assert_eq!(
+            rewrite(contents, &original, &migrated).unwrap(),
+            "# One\n# Two\n\nversion = 2\n\nname = \"cab\"\n\n[profiles.a]\n"
+        );
+    }
+
+    #[test]
+    fn replaces_existing_stamp() {
+        let contents = "version = 1\n\n[profiles.a]\nversion = 7\n";
+        let original = table(contents);
+        let mut migrated = original.clone();
+        migrated.insert("version".to_string(), toml::Value::Integer(2));
+        assert_eq!(
+            rewrite(contents, &original, &migrated).unwrap(),
+            "version = 2\n\n[profiles.a]\nversion = 7\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_changed_config() {
+        let original = table("[profiles.a]\n");
+        let mut migrated = table("[profiles.b]\n");
+        migrated.insert("version".to_string(), toml::Value::Integer(2));
+        let text = rewrite("[profiles.a]\n", &original, &migrated).unwrap();
+        assert!(text.starts_with("# Migrated to version 2"));
+        assert_eq!(table(&text), migrated);
+    }
+}
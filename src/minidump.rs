@@ -0,0 +1,82 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+
+use winapi::shared::minwindef::{BOOL, DWORD};
+use winapi::um::processthreadsapi::{GetCurrentProcess, GetCurrentProcessId, GetCurrentThreadId};
+use winapi::um::winnt::{EXCEPTION_POINTERS, HANDLE};
+
+/// `MiniDumpWithDataSegs`: the stacks plus the modules' global data, a few MB for amvideo
+const MINIDUMP_WITH_DATA_SEGS: u32 = 0x1;
+/// `MiniDumpWithHandleData`
+const MINIDUMP_WITH_HANDLE_DATA: u32 = 0x4;
+
+/// `MINIDUMP_EXCEPTION_INFORMATION`, which dbghelp.h packs to 4 bytes
+#[repr(C, packed(4))]
+struct ExceptionInformation {
+    thread_id: DWORD,
+    exception_pointers: *mut EXCEPTION_POINTERS,
+    client_pointers: BOOL,
+}
+
+// Not declared by winapi 0.3
+#[link(name = "dbghelp")]
+extern "system" {
+    fn MiniDumpWriteDump(
+        process: HANDLE,
+        process_id: DWORD,
+        file: HANDLE,
+        dump_type: u32,
+        exception_param: *const ExceptionInformation,
+        user_stream_param: *const u8,
+        callback_param: *const u8,
+    ) -> BOOL;
+}
+
+/// Write a minidump of this process to `path`, with the faulting thread's context if
+/// `exception` is given, as a debugger or WinDbg opens it
+///
+/// # Safety
+///
+/// `exception` must come from the exception filter or handler currently running.
+pub unsafe fn write(path: &Path, exception: Option<*mut EXCEPTION_POINTERS>) -> io::Result<()> {
+    let file = File::create(path)?;
+    let info = exception.map(|exception_pointers| ExceptionInformation {
+        thread_id: GetCurrentThreadId(),
+        exception_pointers,
+        client_pointers: 0,
+    });
+
+    let ok = MiniDumpWriteDump(
+        GetCurrentProcess(),
+        GetCurrentProcessId(),
+        file.as_raw_handle() as HANDLE,
+        MINIDUMP_WITH_DATA_SEGS | MINIDUMP_WITH_HANDLE_DATA,
+        info.as_ref().map_or(ptr::null(), |info| info as *const _),
+        ptr::null(),
+        ptr::null(),
+    );
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
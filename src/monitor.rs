@@ -0,0 +1,351 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::Cell;
+use std::fmt;
+use std::ptr;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use anyhow::{Context, Result};
+use winapi::shared::guiddef::IsEqualGUID;
+use winapi::shared::minwindef::{HIWORD, LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::rpcdce::RPC_C_IMP_LEVEL_IMPERSONATE;
+use winapi::shared::rpcdce::{RPC_C_AUTHN_LEVEL_CALL, RPC_C_AUTHN_WINNT, RPC_C_AUTHZ_NONE};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::FAILED;
+use winapi::shared::wtypesbase::CLSCTX_INPROC_SERVER;
+use winapi::um::combaseapi::{CoCreateInstance, CoSetProxyBlanket};
+use winapi::um::dbt::DBT_DEVNODES_CHANGED;
+use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::objidlbase::EOAC_NONE;
+use winapi::um::unknwnbase::IUnknown;
+use winapi::um::wbemcli::{
+    IEnumWbemClassObject, IWbemClassObject, IWbemLocator, IWbemServices, WbemLocator,
+    WBEM_FLAG_FORWARD_ONLY, WBEM_FLAG_RETURN_IMMEDIATELY, WBEM_INFINITE,
+};
+use winapi::um::winnt::GUID_CONSOLE_DISPLAY_STATE;
+use winapi::um::winuser::{
+    CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassExW,
+    RegisterPowerSettingNotification, TranslateMessage, DEVICE_NOTIFY_WINDOW_HANDLE, MSG,
+    PBT_APMRESUMEAUTOMATIC, PBT_APMRESUMESUSPEND, PBT_POWERSETTINGCHANGE, POWERBROADCAST_SETTING,
+    WM_DEVICECHANGE, WM_DISPLAYCHANGE, WM_POWERBROADCAST, WNDCLASSEXW, WS_OVERLAPPED,
+};
+use winapi::{Class, Interface};
+
+use amvideo::com::{self, Bstr, ComPtr};
+use amvideo::wide::to_wide;
+
+/// WMI queries for changes that can invalidate the applied mode
+const WMI_QUERIES: &[(&str, &str)] = &[
+    (
+        "monitor",
+        "SELECT * FROM __InstanceOperationEvent WITHIN 2 \
+         WHERE TargetInstance ISA 'Win32_DesktopMonitor'",
+    ),
+    (
+        "display driver",
+        "SELECT * FROM __InstanceOperationEvent WITHIN 2 \
+         WHERE TargetInstance ISA 'Win32_PnPEntity' \
+         AND (TargetInstance.PNPClass = 'Display' OR TargetInstance.PNPClass = 'Monitor')",
+    ),
+];
+
+/// WMI query for resume from sleep (`EventType` 7) and automatic resume (18), which unlike
+/// `WM_POWERBROADCAST` also reaches a service
+const WMI_RESUME_QUERY: &str =
+    "SELECT * FROM Win32_PowerManagementEvent WHERE EventType = 7 OR EventType = 18";
+
+/// `GUID_CONSOLE_DISPLAY_STATE` values
+const DISPLAY_OFF: u8 = 0;
+const DISPLAY_ON: u8 = 1;
+
+/// Something happened that may have changed the display mode
+#[derive(Debug)]
+pub enum DisplayEvent {
+    /// `WM_DISPLAYCHANGE` with the new primary resolution
+    DisplayChange { width: u16, height: u16 },
+    /// `WM_DEVICECHANGE` reporting the device tree changed
+    DevNodesChanged,
+    /// WMI event, e.g. `__InstanceCreationEvent` from the monitor query
+    Wmi { source: &'static str, class: String },
+    /// The system resumed from sleep or hibernation
+    Resume { source: &'static str },
+    /// The console display came back on after being powered off
+    DisplayOn,
+}
+
+impl DisplayEvent {
+    /// Drivers commonly drop the SegaTiming mode across a power cycle while reporting the same
+    /// resolution, so these call for a reapply even if the mode still looks right
+    pub const fn after_power_cycle(&self) -> bool {
+        matches!(self, Self::Resume { .. } | Self::DisplayOn)
+    }
+}
+
+impl fmt::Display for DisplayEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DisplayChange { width, height } => {
+                write!(f, "WM_DISPLAYCHANGE ({}x{})", width, height)
+            }
+            Self::DevNodesChanged => write!(f, "WM_DEVICECHANGE (DBT_DEVNODES_CHANGED)"),
+            Self::Wmi { source, class } => write!(f, "WMI {} event ({})", source, class),
+            Self::Resume { source } => write!(f, "resume from sleep ({})", source),
+            Self::DisplayOn => write!(f, "display powered on"),
+        }
+    }
+}
+
+thread_local! {
+    static WINDOW_EVENTS: std::cell::RefCell<Option<Sender<DisplayEvent>>> =
+        const { std::cell::RefCell::new(None) };
+    /// Last console display state, so only a change from off to on is reported
+    static DISPLAY_STATE: Cell<u8> = const { Cell::new(DISPLAY_ON) };
+}
+
+/// Listen for display broadcasts with a hidden top-level window. Message-only windows do not
+/// receive broadcasts, so this has to be a real (never shown) window.
+pub fn spawn_window(events: Sender<DisplayEvent>) {
+    thread::spawn(move || {
+        WINDOW_EVENTS.with(|cell| *cell.borrow_mut() = Some(events));
+
+        if let Err(e) = run_window() {
+            eprintln!("Display change window failed: {:#}", e);
+        }
+    });
+}
+
+fn run_window() -> Result<()> {
+    let class_name = to_wide("amVideoDisplayMonitor");
+
+    unsafe {
+        let instance = GetModuleHandleW(ptr::null());
+        let class = WNDCLASSEXW {
+            cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: class_name.as_ptr(),
+            ..std::mem::zeroed()
+        };
+        if RegisterClassExW(&class) == 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to register window class");
+        }
+
+        let hwnd = CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            class_name.as_ptr(),
+            WS_OVERLAPPED,
+            0,
+            0,
+            0,
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            instance,
+            ptr::null_mut(),
+        );
+        if hwnd.is_null() {
+            return Err(std::io::Error::last_os_error()).context("Failed to create window");
+        }
+
+        // Display power changes are only sent to windows that ask for them
+        let notify = RegisterPowerSettingNotification(
+            hwnd as *mut _,
+            &GUID_CONSOLE_DISPLAY_STATE,
+            DEVICE_NOTIFY_WINDOW_HANDLE,
+        );
+        if notify.is_null() {
+            eprintln!(
+                "Failed to register for display power events: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        let mut msg: MSG = std::mem::zeroed();
+        while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+    }
+
+    Ok(())
+}
+
+unsafe extern "system" fn window_proc(
+    hwnd: HWND,
+    msg: UINT,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    let event = match msg {
+        WM_DISPLAYCHANGE => Some(DisplayEvent::DisplayChange {
+            width: LOWORD(lparam as u32),
+            height: HIWORD(lparam as u32),
+        }),
+        WM_DEVICECHANGE if wparam == DBT_DEVNODES_CHANGED => Some(DisplayEvent::DevNodesChanged),
+        WM_POWERBROADCAST if wparam == PBT_APMRESUMEAUTOMATIC || wparam == PBT_APMRESUMESUSPEND => {
+            Some(DisplayEvent::Resume {
+                source: "WM_POWERBROADCAST",
+            })
+        }
+        WM_POWERBROADCAST if wparam == PBT_POWERSETTINGCHANGE => {
+            display_power_event(&*(lparam as *const POWERBROADCAST_SETTING))
+        }
+        _ => None,
+    };
+
+    if let Some(event) = event {
+        WINDOW_EVENTS.with(|cell| {
+            if let Some(events) = &*cell.borrow() {
+                let _ = events.send(event);
+            }
+        });
+    }
+
+    DefWindowProcW(hwnd, msg, wparam, lparam)
+}
+
+/// `DisplayOn` if a `GUID_CONSOLE_DISPLAY_STATE` change turned the display back on. The current
+/// state is also sent on registration, which is not a change.
+fn display_power_event(setting: &POWERBROADCAST_SETTING) -> Option<DisplayEvent> {
+    if !IsEqualGUID(&setting.PowerSetting, &GUID_CONSOLE_DISPLAY_STATE) || setting.DataLength < 1 {
+        return None;
+    }
+
+    let state = setting.Data[0];
+    let previous = DISPLAY_STATE.with(|cell| cell.replace(state));
+    if previous == DISPLAY_OFF && state == DISPLAY_ON {
+        Some(DisplayEvent::DisplayOn)
+    } else {
+        None
+    }
+}
+
+/// Subscribe to the WMI monitor and display driver queries, one thread per query. Unlike window
+/// messages these also arrive in a service's non-interactive session.
+pub fn spawn_wmi(events: Sender<DisplayEvent>) {
+    for &(source, query) in WMI_QUERIES {
+        let events = events.clone();
+
+        thread::spawn(move || {
+            let result = run_wmi_query(query, &events, |class| DisplayEvent::Wmi { source, class });
+            if let Err(e) = result {
+                eprintln!("WMI {} subscription failed: {:#}", source, e);
+            }
+        });
+    }
+
+    thread::spawn(move || {
+        let result = run_wmi_query(WMI_RESUME_QUERY, &events, |_| DisplayEvent::Resume {
+            source: "WMI",
+        });
+        if let Err(e) = result {
+            eprintln!("WMI resume subscription failed: {:#}", e);
+        }
+    });
+}
+
+/// Send the event `event` makes of the class of each object `query` returns
+fn run_wmi_query(
+    query: &str,
+    events: &Sender<DisplayEvent>,
+    event: impl Fn(String) -> DisplayEvent,
+) -> Result<()> {
+    com::init_mta().context("Failed to initialize COM")?;
+
+    let services = connect_wmi()?;
+    let language = Bstr::new("WQL");
+    let query = Bstr::new(query);
+
+    let enumerator = unsafe {
+        let mut enumerator: *mut IEnumWbemClassObject = ptr::null_mut();
+        com::check(services.ExecNotificationQuery(
+            language.as_raw(),
+            query.as_raw(),
+            (WBEM_FLAG_RETURN_IMMEDIATELY | WBEM_FLAG_FORWARD_ONLY) as i32,
+            ptr::null_mut(),
+            &mut enumerator,
+        ))
+        .context("Failed to subscribe to WMI events")?;
+        ComPtr::from_raw(enumerator)
+    };
+
+    loop {
+        let mut object: *mut IWbemClassObject = ptr::null_mut();
+        let mut returned = 0;
+
+        let hr = unsafe { enumerator.Next(WBEM_INFINITE as i32, 1, &mut object, &mut returned) };
+        if FAILED(hr) {
+            com::check(hr).context("Failed to receive WMI event")?;
+        }
+        if returned == 0 || object.is_null() {
+            continue;
+        }
+
+        let object = unsafe { ComPtr::from_raw(object) };
+        let class = com::wmi_string(&object, "__CLASS").unwrap_or_default();
+        if events.send(event(class)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Connect to `ROOT\CIMV2` on the local machine
+pub fn connect_wmi() -> Result<ComPtr<IWbemServices>> {
+    unsafe {
+        let mut locator: *mut IWbemLocator = ptr::null_mut();
+        com::check(CoCreateInstance(
+            &WbemLocator::uuidof(),
+            ptr::null_mut(),
+            CLSCTX_INPROC_SERVER,
+            &IWbemLocator::uuidof(),
+            &mut locator as *mut _ as *mut _,
+        ))
+        .context("Failed to create WbemLocator")?;
+        let locator = ComPtr::from_raw(locator);
+
+        let namespace = Bstr::new("ROOT\\CIMV2");
+        let mut services: *mut IWbemServices = ptr::null_mut();
+        com::check(locator.ConnectServer(
+            namespace.as_raw(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut services,
+        ))
+        .context("Failed to connect to ROOT\\CIMV2")?;
+        let services = ComPtr::from_raw(services);
+
+        com::check(CoSetProxyBlanket(
+            services.as_raw() as *mut IUnknown,
+            RPC_C_AUTHN_WINNT,
+            RPC_C_AUTHZ_NONE,
+            ptr::null_mut(),
+            RPC_C_AUTHN_LEVEL_CALL,
+            RPC_C_IMP_LEVEL_IMPERSONATE,
+            ptr::null_mut(),
+            EOAC_NONE,
+        ))
+        .context("Failed to set WMI proxy security")?;
+
+        Ok(services)
+    }
+}
@@ -0,0 +1,219 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::config::{self, MqttConfig};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const DISCONNECT: u8 = 0xE0;
+/// RETAIN flag of a PUBLISH, so late subscribers still see the latest status
+const RETAIN: u8 = 0x01;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Retained on `<topic>/health` and sent as the will, so the broker marks the cab offline if
+/// the daemon goes away without saying so
+#[derive(Serialize)]
+struct Health {
+    online: bool,
+    version: &'static str,
+}
+
+/// MQTT 3.1.1 publisher with QoS 0, all the telemetry needs, so no client library is pulled in.
+/// The connection is made on the first publish and again after any failure.
+pub struct Publisher {
+    config: MqttConfig,
+    topic: String,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl Publisher {
+    pub fn new(config: MqttConfig) -> Self {
+        let cabinet = config
+            .cabinet
+            .clone()
+            .or_else(|| env::var("COMPUTERNAME").ok())
+            .unwrap_or_else(|| "cabinet".to_string());
+        let topic = format!("{}/{}", config.topic_prefix.trim_end_matches('/'), cabinet);
+
+        Self {
+            config,
+            topic,
+            stream: Mutex::new(None),
+        }
+    }
+
+    /// Publish `payload` as JSON to `<topic>/<suffix>`, reporting rather than failing on errors
+    /// since telemetry must never hold up an apply
+    pub fn publish<T: Serialize>(&self, suffix: &str, payload: &T, retain: bool) {
+        if let Err(e) = self.try_publish(suffix, payload, retain) {
+            eprintln!(
+                "Failed to publish to MQTT broker {}: {:#}",
+                self.config.broker, e
+            );
+        }
+    }
+
+    fn try_publish<T: Serialize>(&self, suffix: &str, payload: &T, retain: bool) -> Result<()> {
+        let topic = format!("{}/{}", self.topic, suffix);
+        let packet = publish(&topic, &serde_json::to_vec(payload)?, retain);
+
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(connected) = stream.as_mut() {
+            if connected.write_all(&packet).is_ok() {
+                return Ok(());
+            }
+        }
+
+        // Not connected yet, or the broker dropped us
+        let mut connected = self.connect()?;
+        connected.write_all(&packet)?;
+        *stream = Some(connected);
+
+        Ok(())
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.config.broker)
+            .with_context(|| format!("Failed to connect to {}", self.config.broker))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let will = serde_json::to_vec(&Health {
+            online: false,
+            version: env!("CARGO_PKG_VERSION"),
+        })?;
+        let password = config::load_token(&self.config.password, &self.config.password_file)?;
+        stream.write_all(&connect(
+            &self.topic.replace('/', "-"),
+            &format!("{}/health", self.topic),
+            &will,
+            self.config.username.as_deref(),
+            password.as_deref(),
+        ))?;
+
+        let mut ack = [0; 4];
+        stream.read_exact(&mut ack)?;
+        if ack[0] != CONNACK || ack[3] != 0 {
+            return Err(anyhow!(
+                "Broker refused the connection with return code {}",
+                ack[3]
+            ));
+        }
+
+        let online = serde_json::to_vec(&Health {
+            online: true,
+            version: env!("CARGO_PKG_VERSION"),
+        })?;
+        stream.write_all(&publish(&format!("{}/health", self.topic), &online, true))?;
+
+        Ok(stream)
+    }
+
+    /// Mark the cab offline and close the connection on a clean shutdown
+    pub fn disconnect(&self) {
+        self.publish(
+            "health",
+            &Health {
+                online: false,
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            true,
+        );
+        if let Some(mut stream) = self.stream.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = stream.write_all(&[DISCONNECT, 0]);
+        }
+    }
+}
+
+fn connect(
+    client_id: &str,
+    will_topic: &str,
+    will: &[u8],
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, b"MQTT");
+    body.push(4); // protocol level 3.1.1
+
+    // Clean session, retained will at QoS 0
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&0u16.to_be_bytes()); // no keep-alive
+
+    put_str(&mut body, client_id.as_bytes());
+    put_str(&mut body, will_topic.as_bytes());
+    put_str(&mut body, will);
+    if let Some(username) = username {
+        put_str(&mut body, username.as_bytes());
+    }
+    if let Some(password) = password {
+        put_str(&mut body, password.as_bytes());
+    }
+
+    packet(CONNECT, &body)
+}
+
+fn publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+
+    packet(PUBLISH | if retain { RETAIN } else { 0 }, &body)
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+
+    // Remaining length, seven bits at a time
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+
+    out.extend_from_slice(body);
+    out
+}
+
+fn put_str(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
@@ -0,0 +1,159 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::mpsc;
+use std::sync::OnceLock;
+use std::thread;
+
+use winapi::shared::windef::HWND;
+use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::shellapi::{
+    Shell_NotifyIconW, NIF_ICON, NIF_INFO, NIF_TIP, NIIF_ERROR, NIIF_INFO, NIM_ADD, NIM_DELETE,
+    NIM_MODIFY, NOTIFYICONDATAW,
+};
+use winapi::um::winuser::{
+    CreateWindowExW, DispatchMessageW, GetMessageW, LoadIconW, TranslateMessage, IDI_APPLICATION,
+    MSG, WS_OVERLAPPED,
+};
+
+use amvideo::wide::to_wide;
+
+use crate::failure::Failure;
+
+const TITLE: &str = "amvideo";
+
+/// Hidden window the notification icon belongs to, as an address so it can be shared
+static WINDOW: OnceLock<usize> = OnceLock::new();
+
+/// Show apply results as desktop notifications from now on, as requested with `--notify`.
+/// Windows 10 and later show them as toasts.
+pub fn enable() {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let hwnd = match create_window() {
+            Ok(hwnd) => hwnd,
+            Err(e) => {
+                eprintln!("Failed to set up notifications: {}", e);
+                return;
+            }
+        };
+        let _ = tx.send(hwnd as usize);
+
+        // The icon's window must keep answering messages while it exists
+        unsafe {
+            let mut msg: MSG = mem::zeroed();
+            while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+    });
+
+    if let Ok(hwnd) = rx.recv() {
+        let mut data = icon(hwnd as HWND);
+        if unsafe { Shell_NotifyIconW(NIM_ADD, &mut data) } == 0 {
+            eprintln!("Failed to add the notification icon");
+            return;
+        }
+        let _ = WINDOW.set(hwnd);
+    }
+}
+
+/// Remove the notification icon, which would otherwise linger in the tray after exit
+pub fn disable() {
+    if let Some(&hwnd) = WINDOW.get() {
+        let mut data = icon(hwnd as HWND);
+        unsafe { Shell_NotifyIconW(NIM_DELETE, &mut data) };
+    }
+}
+
+/// Notify that a profile was applied or reapplied
+pub fn success(message: &str) {
+    show(message, NIIF_INFO);
+}
+
+/// Notify of a failure, leading with its class so it can be looked up without the logs
+pub fn failure(action: &str, error: &anyhow::Error) {
+    let failure = Failure::new(error);
+    show(
+        &format!("{} failed ({}): {}", action, failure.class, failure.message),
+        NIIF_ERROR,
+    );
+}
+
+fn show(message: &str, kind: u32) {
+    let hwnd = match WINDOW.get() {
+        Some(&hwnd) => hwnd as HWND,
+        None => return,
+    };
+
+    let mut data = icon(hwnd);
+    data.uFlags |= NIF_INFO;
+    data.dwInfoFlags = kind;
+    copy(&mut data.szInfoTitle, TITLE);
+    copy(&mut data.szInfo, message);
+    if unsafe { Shell_NotifyIconW(NIM_MODIFY, &mut data) } == 0 {
+        eprintln!("Failed to show a notification: {}", message);
+    }
+}
+
+fn icon(hwnd: HWND) -> NOTIFYICONDATAW {
+    let mut data: NOTIFYICONDATAW = unsafe { mem::zeroed() };
+    data.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
+    data.hWnd = hwnd;
+    data.uID = 1;
+    data.uFlags = NIF_ICON | NIF_TIP;
+    data.hIcon = unsafe { LoadIconW(ptr::null_mut(), IDI_APPLICATION) };
+    copy(&mut data.szTip, TITLE);
+    data
+}
+
+/// Copy `text` into a fixed-size wide string field, truncated to fit with its terminator
+fn copy(field: &mut [u16], text: &str) {
+    let wide = to_wide(text);
+    let length = wide.len().min(field.len() - 1);
+    field[..length].copy_from_slice(&wide[..length]);
+    field[length] = 0;
+}
+
+fn create_window() -> io::Result<HWND> {
+    // The predefined static class needs no registration, and the window is never shown
+    let class = to_wide("STATIC");
+    let hwnd = unsafe {
+        CreateWindowExW(
+            0,
+            class.as_ptr(),
+            class.as_ptr(),
+            WS_OVERLAPPED,
+            0,
+            0,
+            0,
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            GetModuleHandleW(ptr::null()),
+            ptr::null_mut(),
+        )
+    };
+    if hwnd.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(hwnd)
+}
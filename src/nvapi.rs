@@ -0,0 +1,601 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ffi::{c_void, CString};
+use std::io::Error;
+use std::mem;
+use std::ptr;
+
+use serde::{Deserialize, Serialize};
+use winapi::shared::minwindef::FARPROC;
+use winapi::um::libloaderapi::LoadLibraryW;
+
+use crate::error::{AmVideoCrateError, Result};
+use crate::library_handle::LibraryHandle;
+use crate::setting::AmVideoResolution;
+use crate::wide::to_wide;
+
+#[cfg(target_pointer_width = "64")]
+const NVAPI_DLL: &str = "nvapi64.dll";
+#[cfg(target_pointer_width = "32")]
+const NVAPI_DLL: &str = "nvapi.dll";
+
+// Interface IDs from `nvapi_interface.h`
+const NVAPI_INITIALIZE: u32 = 0x0150_E828;
+const NVAPI_DRS_CREATE_SESSION: u32 = 0x0694_D52E;
+const NVAPI_DRS_DESTROY_SESSION: u32 = 0xDAD9_CFF8;
+const NVAPI_DRS_LOAD_SETTINGS: u32 = 0x375D_BD6B;
+const NVAPI_DRS_SAVE_SETTINGS: u32 = 0xFCBC_7E14;
+const NVAPI_DRS_GET_BASE_PROFILE: u32 = 0xDA84_66A0;
+const NVAPI_DRS_SET_SETTING: u32 = 0x577D_D202;
+const NVAPI_DRS_GET_SETTING: u32 = 0x73BF_8338;
+const NVAPI_DRS_DELETE_PROFILE_SETTING: u32 = 0xE4A2_6362;
+const NVAPI_ENUM_PHYSICAL_GPUS: u32 = 0xE5AC_921F;
+const NVAPI_GSYNC_ENUM_SYNC_DEVICES: u32 = 0xD963_9601;
+const NVAPI_GSYNC_GET_SYNC_STATUS: u32 = 0xF1F5_B434;
+const NVAPI_DISP_GET_DISPLAY_ID_BY_DISPLAY_NAME: u32 = 0xAE45_7190;
+const NVAPI_DISP_TRY_CUSTOM_DISPLAY: u32 = 0x1F7D_B630;
+const NVAPI_DISP_SAVE_CUSTOM_DISPLAY: u32 = 0x4988_2876;
+const NVAPI_DISP_DELETE_CUSTOM_DISPLAY: u32 = 0x552E_5B9B;
+
+const NVAPI_MAX_PHYSICAL_GPUS: usize = 64;
+const NVAPI_MAX_GSYNC_DEVICES: usize = 4;
+/// `NVAPI_NVIDIA_DEVICE_NOT_FOUND`, returned when there is nothing to enumerate
+const NVAPI_NVIDIA_DEVICE_NOT_FOUND: NvStatus = -6;
+/// `NVAPI_SETTING_NOT_FOUND`, returned for a setting left at the driver default
+const NVAPI_SETTING_NOT_FOUND: NvStatus = -160;
+
+/// `VRR_MODE_ID` from `NvApiDriverSettings.h`, the G-SYNC mode in the NVIDIA Control Panel
+const VRR_MODE_ID: u32 = 0x1194_F158;
+
+const NVDRS_DWORD_TYPE: u32 = 0;
+
+type NvStatus = i32;
+type DrsSessionHandle = *mut c_void;
+type PhysicalGpuHandle = *mut c_void;
+type GSyncDeviceHandle = *mut c_void;
+type DrsProfileHandle = *mut c_void;
+
+type DispGetDisplayIdByDisplayName =
+    unsafe extern "C" fn(name: *const i8, display_id: *mut u32) -> NvStatus;
+type DispTryCustomDisplay =
+    unsafe extern "C" fn(display_ids: *mut u32, count: u32, custom: *mut CustomDisplay) -> NvStatus;
+type DispSaveCustomDisplay = unsafe extern "C" fn(
+    display_ids: *mut u32,
+    count: u32,
+    this_output_only: u32,
+    this_monitor_only: u32,
+) -> NvStatus;
+type DispDeleteCustomDisplay =
+    unsafe extern "C" fn(display_ids: *mut u32, count: u32, custom: *mut CustomDisplay) -> NvStatus;
+
+type QueryInterface = unsafe extern "C" fn(id: u32) -> *mut c_void;
+type Initialize = unsafe extern "C" fn() -> NvStatus;
+type DrsCreateSession = unsafe extern "C" fn(session: *mut DrsSessionHandle) -> NvStatus;
+type DrsSessionFn = unsafe extern "C" fn(session: DrsSessionHandle) -> NvStatus;
+type DrsGetBaseProfile =
+    unsafe extern "C" fn(session: DrsSessionHandle, profile: *mut DrsProfileHandle) -> NvStatus;
+type DrsSetSetting = unsafe extern "C" fn(
+    session: DrsSessionHandle,
+    profile: DrsProfileHandle,
+    setting: *mut DrsSetting,
+) -> NvStatus;
+type DrsGetSetting = unsafe extern "C" fn(
+    session: DrsSessionHandle,
+    profile: DrsProfileHandle,
+    setting_id: u32,
+    setting: *mut DrsSetting,
+) -> NvStatus;
+type DrsDeleteProfileSetting = unsafe extern "C" fn(
+    session: DrsSessionHandle,
+    profile: DrsProfileHandle,
+    setting_id: u32,
+) -> NvStatus;
+
+type EnumPhysicalGpus = unsafe extern "C" fn(
+    gpus: *mut [PhysicalGpuHandle; NVAPI_MAX_PHYSICAL_GPUS],
+    count: *mut u32,
+) -> NvStatus;
+type GSyncEnumSyncDevices = unsafe extern "C" fn(
+    devices: *mut [GSyncDeviceHandle; NVAPI_MAX_GSYNC_DEVICES],
+    count: *mut u32,
+) -> NvStatus;
+type GSyncGetSyncStatus = unsafe extern "C" fn(
+    device: GSyncDeviceHandle,
+    gpu: PhysicalGpuHandle,
+    status: *mut GSyncStatus,
+) -> NvStatus;
+
+/// `NV_GSYNC_STATUS`
+#[repr(C)]
+#[derive(Default)]
+struct GSyncStatus {
+    version: u32,
+    is_synced: u32,
+    is_stereo_synced: u32,
+    is_sync_signal_available: u32,
+}
+
+/// `NVDRS_SETTING_V1`. Only DWORD settings are read and written, so the value unions are opaque.
+#[repr(C)]
+struct DrsSetting {
+    version: u32,
+    setting_name: [u16; 2048],
+    setting_id: u32,
+    setting_type: u32,
+    setting_location: u32,
+    is_current_predefined: u32,
+    is_predefined_valid: u32,
+    predefined_value: [u32; 1025],
+    current_value: [u32; 1025],
+}
+
+/// `NV_TIMINGEXT`
+#[repr(C)]
+struct TimingExt {
+    flag: u32,
+    rr: u16,
+    rr_x1k: u32,
+    aspect: u32,
+    rep: u16,
+    status: u32,
+    name: [u8; 40],
+}
+
+/// `NV_TIMING`. Polarities are 0 for positive and 1 for negative, the pixel clock is in 10 kHz.
+#[repr(C)]
+struct Timing {
+    h_visible: u16,
+    h_border: u16,
+    h_front_porch: u16,
+    h_sync_width: u16,
+    h_total: u16,
+    h_sync_pol: u8,
+    v_visible: u16,
+    v_border: u16,
+    v_front_porch: u16,
+    v_sync_width: u16,
+    v_total: u16,
+    v_sync_pol: u8,
+    interlaced: u16,
+    pclk: u32,
+    etc: TimingExt,
+}
+
+/// `NV_CUSTOM_DISPLAY`
+#[repr(C)]
+struct CustomDisplay {
+    version: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+    color_format: u32,
+    src_partition: [f32; 4],
+    x_ratio: f32,
+    y_ratio: f32,
+    timing: Timing,
+    hw_mode_set_only: u32,
+}
+
+// Ensure structure sizes are correct
+const_assert_eq!(mem::size_of::<DrsSetting>(), 0x3020);
+const_assert_eq!(mem::size_of::<Timing>(), 0x60);
+const_assert_eq!(mem::size_of::<CustomDisplay>(), 0x90);
+
+/// Driver-wide variable refresh rate behaviour
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VrrMode {
+    Off,
+    Fullscreen,
+    FullscreenAndWindowed,
+}
+
+/// Frame lock state of one GPU attached to a Quadro Sync board
+#[derive(Clone, Debug, Serialize)]
+pub struct GpuSyncStatus {
+    /// Index in `NvAPI_EnumPhysicalGPUs` order
+    pub gpu: usize,
+    pub synced: bool,
+    pub stereo_synced: bool,
+    pub signal_available: bool,
+}
+
+/// Quadro Sync board and the GPUs it frame locks
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncDeviceStatus {
+    pub index: usize,
+    pub gpus: Vec<GpuSyncStatus>,
+}
+
+/// Loaded and initialized NVIDIA driver API
+pub struct NvApi {
+    _lib: LibraryHandle,
+    query_interface: QueryInterface,
+}
+
+/// Driver settings session, destroyed on drop
+struct DrsSession<'a> {
+    nvapi: &'a NvApi,
+    handle: DrsSessionHandle,
+}
+
+fn check(function: &'static str, status: NvStatus) -> Result<()> {
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(AmVideoCrateError::NvApi { function, status })
+    }
+}
+
+impl NvApi {
+    /// Load the NVAPI DLL installed with the NVIDIA driver and initialize it
+    pub fn load() -> Result<Self> {
+        let lib = unsafe {
+            let name = to_wide(NVAPI_DLL);
+            LoadLibraryW(name.as_ptr())
+        };
+        if lib.is_null() {
+            return Err(AmVideoCrateError::Load {
+                name: NVAPI_DLL.to_string(),
+                source: Error::last_os_error(),
+            });
+        }
+        let lib = LibraryHandle::new(lib);
+
+        let query_interface = unsafe {
+            let func = lib.get_func_named("nvapi_QueryInterface").map_err(|e| {
+                AmVideoCrateError::Resolve {
+                    functions: vec![e.name().to_string()],
+                }
+            })?;
+            mem::transmute::<FARPROC, QueryInterface>(func)
+        };
+
+        let nvapi = Self {
+            _lib: lib,
+            query_interface,
+        };
+        let initialize: Initialize =
+            unsafe { nvapi.function("NvAPI_Initialize", NVAPI_INITIALIZE)? };
+        check("NvAPI_Initialize", unsafe { initialize() })?;
+
+        Ok(nvapi)
+    }
+
+    /// Resolve an interface by ID
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the function pointer type of the interface `id` names.
+    unsafe fn function<T>(&self, name: &'static str, id: u32) -> Result<T> {
+        let func = (self.query_interface)(id);
+        if func.is_null() {
+            return Err(AmVideoCrateError::Resolve {
+                functions: vec![name.to_string()],
+            });
+        }
+        Ok(mem::transmute_copy::<*mut c_void, T>(&func))
+    }
+
+    fn session(&self) -> Result<DrsSession<'_>> {
+        let create: DrsCreateSession =
+            unsafe { self.function("NvAPI_DRS_CreateSession", NVAPI_DRS_CREATE_SESSION)? };
+        let load: DrsSessionFn =
+            unsafe { self.function("NvAPI_DRS_LoadSettings", NVAPI_DRS_LOAD_SETTINGS)? };
+
+        let mut handle = ptr::null_mut();
+        check("NvAPI_DRS_CreateSession", unsafe { create(&mut handle) })?;
+        let session = DrsSession {
+            nvapi: self,
+            handle,
+        };
+        check("NvAPI_DRS_LoadSettings", unsafe { load(handle) })?;
+
+        Ok(session)
+    }
+
+    fn base_profile(&self, session: &DrsSession<'_>) -> Result<DrsProfileHandle> {
+        let get_base_profile: DrsGetBaseProfile =
+            unsafe { self.function("NvAPI_DRS_GetBaseProfile", NVAPI_DRS_GET_BASE_PROFILE)? };
+
+        let mut profile = ptr::null_mut();
+        check("NvAPI_DRS_GetBaseProfile", unsafe {
+            get_base_profile(session.handle, &mut profile)
+        })?;
+        Ok(profile)
+    }
+
+    /// DWORD setting on the global driver profile, `None` if it is left at the driver default
+    pub fn global_setting(&self, id: u32) -> Result<Option<u32>> {
+        let session = self.session()?;
+        let profile = self.base_profile(&session)?;
+        let get_setting: DrsGetSetting =
+            unsafe { self.function("NvAPI_DRS_GetSetting", NVAPI_DRS_GET_SETTING)? };
+
+        let mut setting: Box<DrsSetting> = Box::new(unsafe { mem::zeroed() });
+        setting.version = mem::size_of::<DrsSetting>() as u32 | (1 << 16);
+        match unsafe { get_setting(session.handle, profile, id, &mut *setting) } {
+            NVAPI_SETTING_NOT_FOUND => Ok(None),
+            status => {
+                check("NvAPI_DRS_GetSetting", status).map(|()| Some(setting.current_value[0]))
+            }
+        }
+    }
+
+    /// Set a DWORD setting on the global driver profile and save it
+    pub fn set_global_setting(&self, id: u32, value: u32) -> Result<()> {
+        let session = self.session()?;
+        let profile = self.base_profile(&session)?;
+        let set_setting: DrsSetSetting =
+            unsafe { self.function("NvAPI_DRS_SetSetting", NVAPI_DRS_SET_SETTING)? };
+        let save: DrsSessionFn =
+            unsafe { self.function("NvAPI_DRS_SaveSettings", NVAPI_DRS_SAVE_SETTINGS)? };
+
+        // Boxed, it is too large to comfortably live on the stack
+        let mut setting: Box<DrsSetting> = Box::new(unsafe { mem::zeroed() });
+        setting.version = mem::size_of::<DrsSetting>() as u32 | (1 << 16);
+        setting.setting_id = id;
+        setting.setting_type = NVDRS_DWORD_TYPE;
+        setting.current_value[0] = value;
+        check("NvAPI_DRS_SetSetting", unsafe {
+            set_setting(session.handle, profile, &mut *setting)
+        })?;
+
+        check("NvAPI_DRS_SaveSettings", unsafe { save(session.handle) })
+    }
+
+    /// Put a setting on the global driver profile back to the driver default and save it
+    pub fn reset_global_setting(&self, id: u32) -> Result<()> {
+        let session = self.session()?;
+        let profile = self.base_profile(&session)?;
+        let delete: DrsDeleteProfileSetting = unsafe {
+            self.function(
+                "NvAPI_DRS_DeleteProfileSetting",
+                NVAPI_DRS_DELETE_PROFILE_SETTING,
+            )?
+        };
+        let save: DrsSessionFn =
+            unsafe { self.function("NvAPI_DRS_SaveSettings", NVAPI_DRS_SAVE_SETTINGS)? };
+
+        match unsafe { delete(session.handle, profile, id) } {
+            NVAPI_SETTING_NOT_FOUND => return Ok(()),
+            status => check("NvAPI_DRS_DeleteProfileSetting", status)?,
+        }
+        check("NvAPI_DRS_SaveSettings", unsafe { save(session.handle) })
+    }
+
+    /// Raw driver-wide G-SYNC mode, `None` if it is left at the driver default. Kept raw so a
+    /// value this crate has no `VrrMode` for can still be put back.
+    pub fn vrr_mode_value(&self) -> Result<Option<u32>> {
+        self.global_setting(VRR_MODE_ID)
+    }
+
+    /// Put back a G-SYNC mode read by `vrr_mode_value`
+    pub fn restore_vrr_mode(&self, value: Option<u32>) -> Result<()> {
+        match value {
+            Some(value) => self.set_global_setting(VRR_MODE_ID, value),
+            None => self.reset_global_setting(VRR_MODE_ID),
+        }
+    }
+
+    /// Set the driver-wide G-SYNC mode. NVIDIA does not expose a per-display switch, so this
+    /// affects every G-SYNC capable display.
+    pub fn set_vrr_mode(&self, mode: VrrMode) -> Result<()> {
+        let value = match mode {
+            VrrMode::Off => 0,
+            VrrMode::Fullscreen => 1,
+            VrrMode::FullscreenAndWindowed => 2,
+        };
+        self.set_global_setting(VRR_MODE_ID, value)
+    }
+}
+
+impl NvApi {
+    /// Frame lock status of every Quadro Sync board, empty if there are none
+    pub fn frame_lock_status(&self) -> Result<Vec<SyncDeviceStatus>> {
+        let enum_gpus: EnumPhysicalGpus =
+            unsafe { self.function("NvAPI_EnumPhysicalGPUs", NVAPI_ENUM_PHYSICAL_GPUS)? };
+        let enum_devices: GSyncEnumSyncDevices =
+            unsafe { self.function("NvAPI_GSync_EnumSyncDevices", NVAPI_GSYNC_ENUM_SYNC_DEVICES)? };
+        let get_status: GSyncGetSyncStatus =
+            unsafe { self.function("NvAPI_GSync_GetSyncStatus", NVAPI_GSYNC_GET_SYNC_STATUS)? };
+
+        let mut devices = [ptr::null_mut(); NVAPI_MAX_GSYNC_DEVICES];
+        let mut device_count = 0;
+        match unsafe { enum_devices(&mut devices, &mut device_count) } {
+            NVAPI_NVIDIA_DEVICE_NOT_FOUND => return Ok(Vec::new()),
+            status => check("NvAPI_GSync_EnumSyncDevices", status)?,
+        };
+
+        let mut gpus = [ptr::null_mut(); NVAPI_MAX_PHYSICAL_GPUS];
+        let mut gpu_count = 0;
+        check("NvAPI_EnumPhysicalGPUs", unsafe {
+            enum_gpus(&mut gpus, &mut gpu_count)
+        })?;
+
+        let devices = devices.iter().take(device_count as usize).enumerate();
+        Ok(devices
+            .map(|(index, &device)| SyncDeviceStatus {
+                index,
+                // GPUs that are not cabled to this board fail the query
+                gpus: gpus
+                    .iter()
+                    .take(gpu_count as usize)
+                    .enumerate()
+                    .filter_map(|(gpu, &handle)| {
+                        let mut status = GSyncStatus {
+                            version: mem::size_of::<GSyncStatus>() as u32 | (1 << 16),
+                            ..Default::default()
+                        };
+                        match unsafe { get_status(device, handle, &mut status) } {
+                            0 => Some(GpuSyncStatus {
+                                gpu,
+                                synced: status.is_synced != 0,
+                                stereo_synced: status.is_stereo_synced != 0,
+                                signal_available: status.is_sync_signal_available != 0,
+                            }),
+                            _ => None,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+}
+
+impl NvApi {
+    /// Add `resolution` at `refresh` Hz with CVT reduced blanking timing to the modes of
+    /// `display`, a GDI device name, and switch to it. The driver keeps the mode across reboots.
+    pub fn add_custom_resolution(
+        &self,
+        display: &str,
+        resolution: &AmVideoResolution,
+        refresh: u32,
+    ) -> Result<()> {
+        let try_custom: DispTryCustomDisplay =
+            unsafe { self.function("NvAPI_DISP_TryCustomDisplay", NVAPI_DISP_TRY_CUSTOM_DISPLAY)? };
+        let save_custom: DispSaveCustomDisplay = unsafe {
+            self.function(
+                "NvAPI_DISP_SaveCustomDisplay",
+                NVAPI_DISP_SAVE_CUSTOM_DISPLAY,
+            )?
+        };
+
+        let mut display_id = self.display_id(display)?;
+        let mut custom = custom_display(resolution, refresh);
+        check("NvAPI_DISP_TryCustomDisplay", unsafe {
+            try_custom(&mut display_id, 1, &mut custom)
+        })?;
+        check("NvAPI_DISP_SaveCustomDisplay", unsafe {
+            save_custom(&mut display_id, 1, 1, 1)
+        })
+    }
+
+    /// Remove a mode added with [`add_custom_resolution`](Self::add_custom_resolution)
+    pub fn remove_custom_resolution(
+        &self,
+        display: &str,
+        resolution: &AmVideoResolution,
+        refresh: u32,
+    ) -> Result<()> {
+        let delete_custom: DispDeleteCustomDisplay = unsafe {
+            self.function(
+                "NvAPI_DISP_DeleteCustomDisplay",
+                NVAPI_DISP_DELETE_CUSTOM_DISPLAY,
+            )?
+        };
+
+        let mut display_id = self.display_id(display)?;
+        let mut custom = custom_display(resolution, refresh);
+        check("NvAPI_DISP_DeleteCustomDisplay", unsafe {
+            delete_custom(&mut display_id, 1, &mut custom)
+        })
+    }
+
+    /// NVAPI display ID of a GDI device name such as `\\.\DISPLAY1`
+    fn display_id(&self, display: &str) -> Result<u32> {
+        let get_display_id: DispGetDisplayIdByDisplayName = unsafe {
+            self.function(
+                "NvAPI_DISP_GetDisplayIdByDisplayName",
+                NVAPI_DISP_GET_DISPLAY_ID_BY_DISPLAY_NAME,
+            )?
+        };
+
+        // A name with a NUL in it cannot name a display, let the driver say so
+        let name = CString::new(display).unwrap_or_default();
+        let mut display_id = 0;
+        check("NvAPI_DISP_GetDisplayIdByDisplayName", unsafe {
+            get_display_id(name.as_ptr(), &mut display_id)
+        })?;
+
+        Ok(display_id)
+    }
+}
+
+/// Full-screen custom display of `resolution` at `refresh` Hz
+fn custom_display(resolution: &AmVideoResolution, refresh: u32) -> CustomDisplay {
+    let mut custom: CustomDisplay = unsafe { mem::zeroed() };
+    custom.version = mem::size_of::<CustomDisplay>() as u32 | (1 << 16);
+    custom.width = u32::from(resolution.width);
+    custom.height = u32::from(resolution.height);
+    custom.depth = 32;
+    custom.src_partition = [0.0, 0.0, 1.0, 1.0];
+    custom.x_ratio = 1.0;
+    custom.y_ratio = 1.0;
+    reduced_blanking(&mut custom.timing, resolution, refresh);
+
+    custom
+}
+
+/// Fill in VESA CVT reduced blanking (version 1) timing, which the control panel also generates
+/// for flat panels
+fn reduced_blanking(timing: &mut Timing, resolution: &AmVideoResolution, refresh: u32) {
+    const H_BLANK: u16 = 160;
+    const H_FRONT_PORCH: u16 = 48;
+    const H_SYNC: u16 = 32;
+    const MIN_V_BLANK_US: f64 = 460.0;
+    const V_FRONT_PORCH: u16 = 3;
+    const MIN_V_BACK_PORCH: u16 = 6;
+
+    // Horizontal timings are in 8-pixel character cells
+    let width = resolution.width / 8 * 8;
+    let height = resolution.height.max(1);
+    // The vertical sync width tells the aspect ratio
+    let v_sync = match u32::from(width) * 1000 / u32::from(height) {
+        1333 => 4,
+        1777 | 1778 => 5,
+        1600 => 6,
+        1250 | 1666 | 1667 => 7,
+        _ => 10,
+    };
+
+    let rate = f64::from(refresh.max(1));
+    let h_period_us = (1e6 / rate - MIN_V_BLANK_US) / f64::from(height);
+    let v_blank =
+        ((MIN_V_BLANK_US / h_period_us) as u16 + 1).max(V_FRONT_PORCH + v_sync + MIN_V_BACK_PORCH);
+    let h_total = width + H_BLANK;
+    let v_total = height + v_blank;
+    let pixels = f64::from(h_total) * f64::from(v_total);
+    // The pixel clock steps by 0.25 MHz, or 25 of NVAPI's 10 kHz units
+    let pclk = (rate * pixels / 1e4) as u32 / 25 * 25;
+
+    timing.h_visible = width;
+    timing.h_front_porch = H_FRONT_PORCH;
+    timing.h_sync_width = H_SYNC;
+    timing.h_total = h_total;
+    timing.h_sync_pol = 0;
+    timing.v_visible = height;
+    timing.v_front_porch = V_FRONT_PORCH;
+    timing.v_sync_width = v_sync;
+    timing.v_total = v_total;
+    timing.v_sync_pol = 1;
+    timing.pclk = pclk;
+    timing.etc.rr = refresh as u16;
+    timing.etc.rr_x1k = (f64::from(pclk) * 1e7 / pixels) as u32;
+}
+
+impl Drop for DrsSession<'_> {
+    fn drop(&mut self) {
+        let destroy: Result<DrsSessionFn> = unsafe {
+            self.nvapi
+                .function("NvAPI_DRS_DestroySession", NVAPI_DRS_DESTROY_SESSION)
+        };
+        if let Ok(destroy) = destroy {
+            unsafe { destroy(self.handle) };
+        }
+    }
+}
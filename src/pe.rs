@@ -0,0 +1,470 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::{TryFrom, TryInto};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::windows::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use serde::Serialize;
+use winapi::um::processenv::SearchPathW;
+
+use crate::builds::{self, Build};
+use crate::wide::to_wide;
+
+/// Ordinal and name, if any, of each export of a DLL
+pub type Exports = Vec<(u16, Option<String>)>;
+
+/// Module a DLL imports from, and the functions it takes from it
+#[derive(Debug, Serialize)]
+pub struct Import {
+    pub module: String,
+    /// Resolved on first call rather than at load time
+    pub delay_load: bool,
+    /// Function names, or `#ordinal` for imports by ordinal
+    pub functions: Vec<String>,
+}
+
+/// DLL read from disk as plain data. Inspecting it this way never maps it for execution, so
+/// `DllMain` and anything else in a DLL of unknown provenance never runs.
+#[derive(Debug)]
+pub struct DataFile {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+/// Little-endian field of a PE image
+fn field<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    data.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    field(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    field(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    field(data, offset).map(u64::from_le_bytes)
+}
+
+impl DataFile {
+    /// Find `name` the way `LoadLibrary` would, in the application directory, the system
+    /// directories, and `PATH`, and read it
+    pub fn open<T: AsRef<OsStr>>(name: T) -> io::Result<Self> {
+        let name = to_wide(name.as_ref());
+        let extension = to_wide(".dll");
+        let mut path = [0; 1024];
+        let length = unsafe {
+            SearchPathW(
+                ptr::null(),
+                name.as_ptr(),
+                extension.as_ptr(),
+                path.len() as u32,
+                path.as_mut_ptr(),
+                ptr::null_mut(),
+            )
+        };
+        if length == 0 || length as usize > path.len() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Self::read(PathBuf::from(OsString::from_wide(&path[..length as usize])))
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let data = fs::read(&path)?;
+        Ok(Self { path, data })
+    }
+
+    /// Offset of the PE header, if this is a PE image at all
+    fn pe(&self) -> Option<usize> {
+        let pe = u32_at(&self.data, 0x3c)? as usize;
+        (&field::<4>(&self.data, pe)? == b"PE\0\0").then_some(pe)
+    }
+
+    /// `IMAGE_FILE_HEADER::Machine`
+    pub fn machine(&self) -> Option<u16> {
+        u16_at(&self.data, self.pe()? + 4)
+    }
+
+    /// Whether this is a 64-bit (PE32+) image
+    fn is_64_bit(&self) -> Option<bool> {
+        match u16_at(&self.data, self.pe()? + 24)? {
+            0x010b => Some(false),
+            0x020b => Some(true),
+            _ => None,
+        }
+    }
+
+    /// RVA of data directory `index`, or `None` for an image without one
+    fn directory(&self, index: usize) -> Option<u32> {
+        let optional_header = self.pe()? + 24;
+        let directories = if self.is_64_bit()? {
+            optional_header + 112
+        } else {
+            optional_header + 96
+        };
+        u32_at(&self.data, directories + 8 * index).filter(|&rva| rva != 0)
+    }
+
+    /// Address the image prefers to be loaded at, which absolute addresses in it assume
+    pub fn image_base(&self) -> Option<u64> {
+        let optional_header = self.pe()? + 24;
+        if self.is_64_bit()? {
+            u64_at(&self.data, optional_header + 24)
+        } else {
+            u32_at(&self.data, optional_header + 28).map(u64::from)
+        }
+    }
+
+    /// `(virtual address, size, file offset)` of each section
+    fn sections(&self) -> Option<Vec<(u32, u32, u32)>> {
+        let data = &self.data;
+        let pe = self.pe()?;
+        let section_count = usize::from(u16_at(data, pe + 6)?);
+        let sections = pe + 24 + usize::from(u16_at(data, pe + 20)?);
+        (0..section_count)
+            .map(|i| {
+                let section = sections + i * 40;
+                let virtual_address = u32_at(data, section + 12)?;
+                let size = u32_at(data, section + 16)?.max(u32_at(data, section + 8)?);
+                let raw = u32_at(data, section + 20)?;
+                Some((virtual_address, size, raw))
+            })
+            .collect()
+    }
+
+    /// File offset of `rva`, from the section that contains it
+    fn offset(&self, rva: u32) -> Option<usize> {
+        self.sections()?
+            .into_iter()
+            .find(|&(virtual_address, size, _)| {
+                rva >= virtual_address && rva - virtual_address < size
+            })
+            .and_then(|(virtual_address, _, raw)| raw.checked_add(rva - virtual_address))
+            .map(|offset| offset as usize)
+    }
+
+    /// RVA the byte at file offset `offset` is mapped to
+    pub fn rva(&self, offset: usize) -> Option<u32> {
+        let offset = u32::try_from(offset).ok()?;
+        self.sections()?
+            .into_iter()
+            .find(|&(_, size, raw)| raw != 0 && offset >= raw && offset - raw < size)
+            .and_then(|(virtual_address, _, raw)| virtual_address.checked_add(offset - raw))
+    }
+
+    /// NUL-terminated string at `rva`
+    fn string(&self, rva: u32) -> Option<String> {
+        let start = self.offset(rva)?;
+        let end = start + self.data.get(start..)?.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&self.data[start..end]).into_owned())
+    }
+
+    /// Exports in ordinal order, or `None` if this is not a valid PE image
+    pub fn exports(&self) -> Option<Exports> {
+        let data = &self.data;
+        let export_rva = match self.directory(0) {
+            Some(rva) => rva,
+            None => return self.pe().map(|_| Vec::new()),
+        };
+
+        let directory = self.offset(export_rva)?;
+        let base = u32_at(data, directory + 16)?;
+        let function_count = u32_at(data, directory + 20)?;
+        let name_count = u32_at(data, directory + 24)?;
+        let functions = self.offset(u32_at(data, directory + 28)?)?;
+        let names = self.offset(u32_at(data, directory + 32)?)?;
+        let ordinals = self.offset(u32_at(data, directory + 36)?)?;
+
+        let mut exports = Vec::new();
+        for index in 0..function_count {
+            if u32_at(data, functions + 4 * index as usize)? == 0 {
+                continue;
+            }
+            let name = (0..name_count as usize)
+                .find(|&i| u16_at(data, ordinals + 2 * i).map(u32::from) == Some(index))
+                .and_then(|i| self.string(u32_at(data, names + 4 * i)?));
+            exports.push((u16::try_from(base.checked_add(index)?).ok()?, name));
+        }
+
+        Some(exports)
+    }
+
+    /// Imported modules and functions, both regular and delay-loaded, or `None` if this is not a
+    /// valid PE image. Functions the DLL finds with `GetProcAddress`, like most of NVAPI, only
+    /// show up as the import of `GetProcAddress` itself.
+    pub fn imports(&self) -> Option<Vec<Import>> {
+        self.pe()?;
+        let data = &self.data;
+        let mut imports = Vec::new();
+
+        if let Some(rva) = self.directory(1) {
+            let mut descriptor = self.offset(rva)?;
+            loop {
+                let name = u32_at(data, descriptor + 12)?;
+                if name == 0 {
+                    break;
+                }
+                // Binders overwrite FirstThunk, so prefer OriginalFirstThunk when there is one
+                let thunks = match u32_at(data, descriptor)? {
+                    0 => u32_at(data, descriptor + 16)?,
+                    original => original,
+                };
+                imports.push(Import {
+                    module: self.string(name)?,
+                    delay_load: false,
+                    functions: self.thunks(thunks)?,
+                });
+                descriptor += 20;
+            }
+        }
+
+        if let Some(rva) = self.directory(13) {
+            let mut descriptor = self.offset(rva)?;
+            loop {
+                let attributes = u32_at(data, descriptor)?;
+                let name = u32_at(data, descriptor + 4)?;
+                if name == 0 {
+                    break;
+                }
+                // Descriptors without the RVA attribute hold VAs, from toolchains older than
+                // any amVideo build
+                if attributes & 1 != 0 {
+                    imports.push(Import {
+                        module: self.string(name)?,
+                        delay_load: true,
+                        functions: self.thunks(u32_at(data, descriptor + 16)?)?,
+                    });
+                }
+                descriptor += 32;
+            }
+        }
+
+        Some(imports)
+    }
+
+    /// Function names in the import lookup table at `rva`
+    fn thunks(&self, rva: u32) -> Option<Vec<String>> {
+        let data = &self.data;
+        let is_64_bit = self.is_64_bit()?;
+        let mut thunk = self.offset(rva)?;
+        let mut functions = Vec::new();
+        loop {
+            let (value, by_ordinal) = if is_64_bit {
+                let value = u64_at(data, thunk)?;
+                (value, value & (1 << 63) != 0)
+            } else {
+                let value = u64::from(u32_at(data, thunk)?);
+                (value, value & (1 << 31) != 0)
+            };
+            if value == 0 {
+                break;
+            }
+
+            functions.push(if by_ordinal {
+                format!("#{}", value & 0xffff)
+            } else {
+                // Skip the two-byte hint in front of the name
+                self.string(u32::try_from(value & 0x7fff_ffff).ok()? + 2)?
+            });
+            thunk += if is_64_bit { 8 } else { 4 };
+        }
+
+        Some(functions)
+    }
+
+    /// Build banner compiled into the DLL
+    pub fn banner(&self) -> Option<String> {
+        builds::banner(&self.data)
+    }
+
+    /// Build database entry matching the banner
+    pub fn build(&self) -> Option<&'static Build> {
+        builds::find(&self.banner()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RVA and file offset of the one section
+    const SECTION_RVA: u32 = 0x1000;
+    const SECTION_RAW: usize = 0x200;
+
+    fn put(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// PE32+ DLL exporting `amDllVideoOpen` at ordinal 1 and an unnamed function at ordinal 2,
+    /// importing `GetProcAddress` by name and ordinal 5 from `KERNEL32.dll`
+    fn image() -> Vec<u8> {
+        let mut data = Vec::new();
+        put(&mut data, 0, b"MZ");
+        put(&mut data, 0x3c, &0x40u32.to_le_bytes());
+        put(&mut data, 0x40, b"PE\0\0");
+        put(&mut data, 0x44, &0x8664u16.to_le_bytes());
+        put(&mut data, 0x46, &1u16.to_le_bytes());
+        put(&mut data, 0x54, &240u16.to_le_bytes());
+
+        let optional = 0x58;
+        put(&mut data, optional, &0x020bu16.to_le_bytes());
+        put(&mut data, optional + 24, &0x1_8000_0000u64.to_le_bytes());
+
+        // Everything lives in the section, at these offsets into it
+        let (exports, functions, names, ordinals) = (0x00, 0x40, 0x50, 0x58);
+        let (export_name, imports, lookup, hint_name, module) = (0x60, 0x80, 0xc0, 0xe0, 0x100);
+        let rva = |offset: u32| SECTION_RVA + offset;
+
+        put(&mut data, optional + 112, &rva(exports).to_le_bytes());
+        put(&mut data, optional + 116, &40u32.to_le_bytes());
+        put(&mut data, optional + 120, &rva(imports).to_le_bytes());
+        put(&mut data, optional + 124, &40u32.to_le_bytes());
+
+        let section = optional + 240;
+        put(&mut data, section, b".rdata\0\0");
+        put(&mut data, section + 8, &0x200u32.to_le_bytes());
+        put(&mut data, section + 12, &SECTION_RVA.to_le_bytes());
+        put(&mut data, section + 16, &0x200u32.to_le_bytes());
+        put(&mut data, section + 20, &(SECTION_RAW as u32).to_le_bytes());
+
+        let at = |offset: u32| SECTION_RAW + offset as usize;
+        put(&mut data, at(exports) + 16, &1u32.to_le_bytes());
+        put(&mut data, at(exports) + 20, &2u32.to_le_bytes());
+        put(&mut data, at(exports) + 24, &1u32.to_le_bytes());
+        put(&mut data, at(exports) + 28, &rva(functions).to_le_bytes());
+        put(&mut data, at(exports) + 32, &rva(names).to_le_bytes());
+        put(&mut data, at(exports) + 36, &rva(ordinals).to_le_bytes());
+        put(&mut data, at(functions), &0x1100u32.to_le_bytes());
+        put(&mut data, at(functions) + 4, &0x1104u32.to_le_bytes());
+        put(&mut data, at(names), &rva(export_name).to_le_bytes());
+        put(&mut data, at(ordinals), &0u16.to_le_bytes());
+        put(&mut data, at(export_name), b"amDllVideoOpen\0");
+
+        put(&mut data, at(imports), &rva(lookup).to_le_bytes());
+        put(&mut data, at(imports) + 12, &rva(module).to_le_bytes());
+        put(&mut data, at(imports) + 16, &rva(lookup).to_le_bytes());
+        put(
+            &mut data,
+            at(lookup),
+            &u64::from(rva(hint_name)).to_le_bytes(),
+        );
+        put(&mut data, at(lookup) + 8, &((1u64 << 63) | 5).to_le_bytes());
+        put(&mut data, at(hint_name) + 2, b"GetProcAddress\0");
+        put(&mut data, at(module), b"KERNEL32.dll\0");
+
+        data.resize(SECTION_RAW + 0x200, 0);
+        data
+    }
+
+    fn file(data: Vec<u8>) -> DataFile {
+        DataFile {
+            path: PathBuf::from("test.dll"),
+            data,
+        }
+    }
+
+    #[test]
+    fn reads_headers() {
+        let dll = file(image());
+        assert_eq!(dll.machine(), Some(0x8664));
+        assert_eq!(dll.image_base(), Some(0x1_8000_0000));
+        assert_eq!(dll.rva(SECTION_RAW + 0x10), Some(SECTION_RVA + 0x10));
+        assert_eq!(dll.rva(0x10), None);
+    }
+
+    #[test]
+    fn reads_exports() {
+        assert_eq!(
+            file(image()).exports(),
+            Some(vec![(1, Some("amDllVideoOpen".to_string())), (2, None)])
+        );
+    }
+
+    #[test]
+    fn reads_imports() {
+        let imports = file(image()).imports().unwrap();
+        assert_eq!(imports.len(), 1);
+        assert_eq!(imports[0].module, "KERNEL32.dll");
+        assert!(!imports[0].delay_load);
+        assert_eq!(imports[0].functions, ["GetProcAddress", "#5"]);
+    }
+
+    #[test]
+    fn rejects_non_pe() {
+        let dll = file(b"MZ not a portable executable".to_vec());
+        assert_eq!(dll.machine(), None);
+        assert_eq!(dll.exports(), None);
+        assert!(dll.imports().is_none());
+
+        let mut data = image();
+        data[0x40] = b'X';
+        assert_eq!(file(data).exports(), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_fields() {
+        let section = 0x148;
+
+        // Section ending past the top of the address space
+        let mut data = image();
+        put(&mut data, section + 8, &u32::MAX.to_le_bytes());
+        put(&mut data, section + 12, &0xffff_f000u32.to_le_bytes());
+        let dll = file(data);
+        assert_eq!(dll.exports(), None);
+        assert_eq!(dll.rva(SECTION_RAW + 0x10), Some(0xffff_f010));
+        assert_eq!(dll.rva(SECTION_RAW + 0x1000), None);
+
+        // Raw data ending past the top of the file offsets
+        let mut data = image();
+        put(&mut data, section + 20, &0xffff_ff00u32.to_le_bytes());
+        let dll = file(data);
+        assert_eq!(dll.exports(), None);
+        assert_eq!(dll.rva(0xffff_ff80), Some(SECTION_RVA + 0x80));
+
+        // Ordinals past `u32::MAX`, after skipping the unused first function
+        let mut data = image();
+        put(&mut data, SECTION_RAW + 16, &u32::MAX.to_le_bytes());
+        put(&mut data, SECTION_RAW + 0x40, &0u32.to_le_bytes());
+        assert_eq!(file(data).exports(), None);
+    }
+
+    #[test]
+    fn survives_truncation() {
+        let data = image();
+        for len in 0..data.len() {
+            let dll = file(data[..len].to_vec());
+            // Only checks nothing panics or reads out of bounds
+            let _ = (
+                dll.machine(),
+                dll.image_base(),
+                dll.exports(),
+                dll.imports(),
+            );
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use serde::Serialize;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+use winapi::um::securitybaseapi::GetTokenInformation;
+use winapi::um::winnt::{TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+use winapi::um::winuser::{GetSystemMetrics, SM_REMOTESESSION};
+use winreg::enums::HKEY_LOCAL_MACHINE;
+use winreg::RegKey;
+
+use crate::registry::AM_VIDEO_KEY;
+use crate::topology::Topology;
+
+const SYSTEM_PROPERTY_KEY: &str = "System\\Sega\\SystemProperty";
+const AMDAEMON_SERVICE_KEY: &str = "SYSTEM\\CurrentControlSet\\Services\\amdaemon";
+const WINDOWS_VERSION_KEY: &str = "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion";
+const BIOS_KEY: &str = "HARDWARE\\DESCRIPTION\\System\\BIOS";
+
+/// Hypervisors, by a string they leave in the BIOS vendor and model or the emulated GPU's name.
+/// Hyper-V reports itself as a Microsoft Corporation "Virtual Machine".
+const HYPERVISORS: &[(&str, &str)] = &[
+    ("VMware", "VMware"),
+    ("VirtualBox", "VirtualBox"),
+    ("innotek", "VirtualBox"),
+    ("Hyper-V", "Hyper-V"),
+    ("Virtual Machine", "Hyper-V"),
+    ("QEMU", "QEMU"),
+    ("KVM", "KVM"),
+    ("Parallels", "Parallels"),
+    ("Xen", "Xen"),
+];
+
+const NVIDIA_VENDOR_ID: u32 = 0x10de;
+
+/// Kind of machine amvideo is running on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Platform {
+    /// Windows Embedded Standard 2009
+    RingEdge,
+    /// Windows Embedded Standard 7
+    Nu,
+    /// Windows 10 IoT Enterprise
+    Alls,
+    /// Anything without the SEGA system software, e.g. a development PC
+    GenericPc,
+}
+
+/// Platform classification and the evidence it was based on
+#[derive(Clone, Debug, Serialize)]
+pub struct Detection {
+    pub platform: Platform,
+    pub evidence: Vec<String>,
+    /// Whether an NVIDIA GPU is present, which the `amVideoNvidia` builds require
+    pub nvidia: bool,
+    /// Hypervisor amvideo runs under, if any
+    pub virtual_machine: Option<&'static str>,
+    /// Whether this is a Remote Desktop session, whose virtual display ignores mode switches
+    pub remote_session: bool,
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::RingEdge => "RingEdge",
+            Self::Nu => "Nu",
+            Self::Alls => "ALLS",
+            Self::GenericPc => "generic PC",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Platform {
+    /// DLL to load when the registry names none, which only happens off SEGA hardware
+    pub const fn default_dll_name(self) -> Option<&'static str> {
+        match self {
+            Self::GenericPc => Some("amVideo.dll"),
+            _ => None,
+        }
+    }
+
+    /// Caveat worth printing before driving amVideo on this platform
+    pub const fn warning(self) -> Option<&'static str> {
+        match self {
+            Self::RingEdge => {
+                Some("amvideo targets Nu and ALLS; RingEdge may not have a compatible amVideo DLL")
+            }
+            Self::GenericPc => Some(
+                "Not running on SEGA hardware; amVideo only drives the GPUs SEGA shipped it for",
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl Detection {
+    /// Caveat about running in a VM or over Remote Desktop, where amVideo cannot behave like it
+    /// does on a cabinet
+    pub fn environment_warning(&self) -> Option<String> {
+        let environment = match (self.virtual_machine, self.remote_session) {
+            (Some(hypervisor), _) => format!("a {} virtual machine", hypervisor),
+            (None, true) => "a Remote Desktop session".to_string(),
+            (None, false) => return None,
+        };
+
+        Some(format!(
+            "Running in {}, where amVideo and mode switching do not behave like on a cabinet; \
+             `amvideo gen-stub` writes a stub DLL that emulates amVideo instead",
+            environment
+        ))
+    }
+}
+
+/// Hypervisor named by the BIOS strings or one of the GPU names in `gpus`
+fn virtual_machine(gpus: &[String]) -> Option<&'static str> {
+    let mut haystack: Vec<String> = gpus.to_vec();
+    if let Ok(bios) = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey(BIOS_KEY) {
+        for value in &["SystemManufacturer", "SystemProductName", "BIOSVendor"] {
+            if let Ok(value) = bios.get_value::<String, _>(value) {
+                haystack.push(value);
+            }
+        }
+    }
+
+    HYPERVISORS
+        .iter()
+        .find(|(needle, _)| haystack.iter().any(|s| s.contains(needle)))
+        .map(|&(_, hypervisor)| hypervisor)
+}
+
+/// Whether this process runs with administrator rights, past UAC
+pub fn is_elevated() -> io::Result<bool> {
+    let mut token = ptr::null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+    let mut length = 0;
+    let result = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut TOKEN_ELEVATION as *mut _,
+            mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut length,
+        )
+    };
+    let error = io::Error::last_os_error();
+    unsafe { CloseHandle(token) };
+    if result == 0 {
+        return Err(error);
+    }
+
+    Ok(elevation.TokenIsElevated != 0)
+}
+
+/// Windows version as `(major, minor)`, from the registry since `GetVersionEx` lies to
+/// unmanifested programs
+fn windows_version() -> Option<(u32, u32)> {
+    let key = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(WINDOWS_VERSION_KEY)
+        .ok()?;
+
+    // Only Windows 10 and later have the numeric values
+    if let Ok(major) = key.get_value::<u32, _>("CurrentMajorVersionNumber") {
+        let minor = key.get_value("CurrentMinorVersionNumber").unwrap_or(0);
+        return Some((major, minor));
+    }
+
+    let version: String = key.get_value("CurrentVersion").ok()?;
+    let (major, minor) = version.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Windows edition, release, and build, e.g. `Windows 10 IoT Enterprise LTSC 2019 1809 (build
+/// 17763.1432)`
+pub fn windows_build() -> Option<String> {
+    let key = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(WINDOWS_VERSION_KEY)
+        .ok()?;
+    let product: String = key.get_value("ProductName").ok()?;
+    let release: Option<String> = key
+        .get_value("DisplayVersion")
+        .or_else(|_| key.get_value("ReleaseId"))
+        .ok();
+    let build: String = key.get_value("CurrentBuild").ok()?;
+    let revision: Option<u32> = key.get_value("UBR").ok();
+
+    let mut description = product;
+    if let Some(release) = release {
+        description.push_str(&format!(" {}", release));
+    }
+    description.push_str(&format!(" (build {}", build));
+    if let Some(revision) = revision {
+        description.push_str(&format!(".{}", revision));
+    }
+    description.push(')');
+
+    Some(description)
+}
+
+/// Classify the machine from the SEGA registry keys, the amdaemon service, the Windows release
+/// each platform ships with, and the GPUs present
+pub fn detect() -> Detection {
+    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+    let mut evidence = Vec::new();
+
+    let sega = hklm.open_subkey(SYSTEM_PROPERTY_KEY).is_ok();
+    if sega {
+        evidence.push(format!("HKLM\\{} exists", SYSTEM_PROPERTY_KEY));
+    }
+    if hklm.open_subkey(AM_VIDEO_KEY).is_ok() {
+        evidence.push(format!("HKLM\\{} exists", AM_VIDEO_KEY));
+    }
+    let amdaemon = hklm.open_subkey(AMDAEMON_SERVICE_KEY).is_ok();
+    if amdaemon {
+        evidence.push("amdaemon service is installed".to_string());
+    }
+
+    let version = windows_version();
+    if let Some((major, minor)) = version {
+        evidence.push(format!("Windows {}.{}", major, minor));
+    }
+
+    let mut gpus = Vec::new();
+    let nvidia = Topology::query()
+        .map(|topology| {
+            for adapter in &topology.adapters {
+                evidence.push(format!(
+                    "GPU {:04x}:{:04x} {}",
+                    adapter.vendor_id, adapter.device_id, adapter.description
+                ));
+                gpus.push(adapter.description.clone());
+            }
+            topology
+                .adapters
+                .iter()
+                .any(|adapter| adapter.vendor_id == NVIDIA_VENDOR_ID)
+        })
+        .unwrap_or(false);
+
+    let virtual_machine = virtual_machine(&gpus);
+    if let Some(hypervisor) = virtual_machine {
+        evidence.push(format!("{} virtual machine", hypervisor));
+    }
+    let remote_session = unsafe { GetSystemMetrics(SM_REMOTESESSION) } != 0;
+    if remote_session {
+        evidence.push("Remote Desktop session".to_string());
+    }
+
+    let platform = match version {
+        _ if !sega && !amdaemon => Platform::GenericPc,
+        Some((5, _)) => Platform::RingEdge,
+        Some((6, _)) => Platform::Nu,
+        Some((10, _)) => Platform::Alls,
+        // SEGA software on an unexpected Windows release is most likely a PC set up by hand
+        _ => Platform::GenericPc,
+    };
+
+    Detection {
+        platform,
+        evidence,
+        nvidia,
+        virtual_machine,
+        remote_session,
+    }
+}
@@ -0,0 +1,201 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use amvideo::screenshot::{self, Image};
+
+use crate::zip::crc32;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Largest stored deflate block
+const MAX_BLOCK: usize = 0xFFFF;
+
+/// Encode an image as an RGBA PNG with stored (uncompressed) deflate blocks. Screenshots are
+/// written once per apply, so the size is not worth a compression dependency.
+pub fn encode(image: &Image) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&image.width.to_be_bytes());
+    header.extend_from_slice(&image.height.to_be_bytes());
+    // 8 bits per channel, RGBA, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    chunk(&mut out, b"IHDR", &header);
+
+    // Each row starts with its filter type, 0 for none
+    let stride = image.width as usize * 4;
+    let mut raw = Vec::with_capacity((stride + 1) * image.height as usize);
+    for row in image.rgba.chunks_exact(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut out, b"IEND", &[]);
+
+    out
+}
+
+/// Capture the primary display to a PNG at `path`, for confirming an apply remotely
+pub fn save_screenshot(path: &Path) -> Result<()> {
+    let image = screenshot::capture(None).context("Failed to capture the primary display")?;
+    fs::write(path, encode(&image))
+        .with_context(|| format!("Failed to write screenshot '{}'", path.display()))?;
+    println!(
+        "Saved a {}x{} screenshot to {}",
+        image.width,
+        image.height,
+        path.display()
+    );
+
+    Ok(())
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window and no preset dictionary
+    let mut out = vec![0x78, 0x01];
+
+    let blocks: Vec<&[u8]> = data.chunks(MAX_BLOCK).collect();
+    if blocks.is_empty() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i + 1 == blocks.len();
+        out.push(last as u8);
+        let length = block.len() as u16;
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn be_u32(data: &[u8]) -> u32 {
+        u32::from_be_bytes(data[..4].try_into().unwrap())
+    }
+
+    /// Kind and data of each chunk, checking each one's CRC on the way
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = be_u32(rest) as usize;
+            let body = &rest[4..8 + len];
+            assert_eq!(be_u32(&rest[8 + len..]), crc32(body));
+            chunks.push((body[..4].try_into().unwrap(), body[4..].to_vec()));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    /// Undo `zlib_stored`, checking the block framing and the checksum
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[..2], &[0x78, 0x01]);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        let mut out = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] & 1 != 0;
+            assert_eq!(zlib[pos] & !1, 0, "not a stored block");
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]);
+            let nlen = u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]);
+            assert_eq!(nlen, !len);
+            out.extend_from_slice(&zlib[pos + 5..pos + 5 + len as usize]);
+            pos += 5 + len as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(be_u32(&zlib[pos..]), adler32(&out));
+        assert_eq!(zlib.len(), pos + 4);
+        out
+    }
+
+    #[test]
+    fn adler32_check_value() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn encodes_header_and_filtered_rows() {
+        let image = Image {
+            width: 2,
+            height: 3,
+            rgba: (0..24).collect(),
+        };
+        let chunks = chunks(&encode(&image));
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 3, 8, 6, 0, 0, 0]);
+        let raw = inflate_stored(&chunks[1].1);
+        let mut expected = Vec::new();
+        for row in image.rgba.chunks(8) {
+            expected.push(0);
+            expected.extend_from_slice(row);
+        }
+        assert_eq!(raw, expected);
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn splits_large_images_into_blocks() {
+        let image = Image {
+            width: 300,
+            height: 200,
+            rgba: (0..300 * 200 * 4).map(|i| i as u8).collect(),
+        };
+        let chunks = chunks(&encode(&image));
+        let raw = inflate_stored(&chunks[1].1);
+        assert_eq!(raw.len(), (300 * 4 + 1) * 200);
+        assert!(raw.len() > MAX_BLOCK);
+    }
+
+    #[test]
+    fn zlib_stored_empty() {
+        assert!(inflate_stored(&zlib_stored(&[])).is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::ptr;
+
+use serde::{Deserialize, Serialize};
+use winapi::ctypes::c_void;
+use winapi::shared::guiddef::GUID;
+use winapi::shared::minwindef::{BOOL, DWORD, FALSE};
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::powersetting::{
+    PowerGetActiveScheme, PowerSetActiveScheme, PowerWriteACValueIndex, PowerWriteDCValueIndex,
+};
+use winapi::um::powrprof::{PowerReadACValueIndex, PowerReadDCValueIndex};
+use winapi::um::winbase::LocalFree;
+use winapi::um::winnt::{GUID_VIDEO_POWERDOWN_TIMEOUT, GUID_VIDEO_SUBGROUP};
+use winapi::um::winuser::{
+    SystemParametersInfoW, SPIF_SENDCHANGE, SPIF_UPDATEINIFILE, SPI_GETSCREENSAVEACTIVE,
+    SPI_SETSCREENSAVEACTIVE,
+};
+
+use crate::error::Result;
+
+/// `VIDEOCONLOCK`, how long the display stays on at the lock screen. winapi does not name it.
+const GUID_VIDEO_CONSOLE_LOCK_TIMEOUT: GUID = GUID {
+    Data1: 0x8ec4_b3a5,
+    Data2: 0x6868,
+    Data3: 0x48c2,
+    Data4: [0xbe, 0x75, 0x4f, 0x30, 0x44, 0xbe, 0x88, 0xa7],
+};
+
+/// Idle time in seconds before something happens, on mains and on battery. 0 means never.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeout {
+    pub ac: u32,
+    pub dc: u32,
+}
+
+/// Power and screensaver settings that blank the display of an idle cabinet
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerSettings {
+    /// Turn the display off when idle
+    pub display_off: Timeout,
+    /// Turn the display off when idle at the lock screen
+    pub lock_display_off: Timeout,
+    pub screensaver: bool,
+}
+
+impl PowerSettings {
+    /// Display never turned off or blanked
+    pub const KIOSK: Self = Self {
+        display_off: Timeout { ac: 0, dc: 0 },
+        lock_display_off: Timeout { ac: 0, dc: 0 },
+        screensaver: false,
+    };
+
+    /// Settings of the active power scheme
+    pub fn current() -> Result<Self> {
+        let scheme = ActiveScheme::get()?;
+
+        let mut screensaver: BOOL = FALSE;
+        let result = unsafe {
+            SystemParametersInfoW(
+                SPI_GETSCREENSAVEACTIVE,
+                0,
+                &mut screensaver as *mut BOOL as *mut c_void,
+                0,
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self {
+            display_off: scheme.read(&GUID_VIDEO_POWERDOWN_TIMEOUT)?,
+            lock_display_off: scheme.read(&GUID_VIDEO_CONSOLE_LOCK_TIMEOUT)?,
+            screensaver: screensaver != FALSE,
+        })
+    }
+
+    /// Write the settings to the active power scheme and make them take effect
+    pub fn apply(&self) -> Result<()> {
+        let scheme = ActiveScheme::get()?;
+        scheme.write(&GUID_VIDEO_POWERDOWN_TIMEOUT, self.display_off)?;
+        scheme.write(&GUID_VIDEO_CONSOLE_LOCK_TIMEOUT, self.lock_display_off)?;
+        check(unsafe { PowerSetActiveScheme(ptr::null_mut(), scheme.0) })?;
+
+        let result = unsafe {
+            SystemParametersInfoW(
+                SPI_SETSCREENSAVEACTIVE,
+                u32::from(self.screensaver),
+                ptr::null_mut(),
+                SPIF_UPDATEINIFILE | SPIF_SENDCHANGE,
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+}
+
+/// GUID of the active power scheme, allocated by `PowerGetActiveScheme` and freed on drop
+struct ActiveScheme(*mut GUID);
+
+impl ActiveScheme {
+    fn get() -> io::Result<Self> {
+        let mut scheme = ptr::null_mut();
+        check(unsafe { PowerGetActiveScheme(ptr::null_mut(), &mut scheme) })?;
+        Ok(Self(scheme))
+    }
+
+    fn read(&self, setting: &GUID) -> io::Result<Timeout> {
+        let mut ac = 0;
+        let mut dc = 0;
+        check(unsafe {
+            PowerReadACValueIndex(
+                ptr::null_mut(),
+                self.0,
+                &GUID_VIDEO_SUBGROUP,
+                setting,
+                &mut ac,
+            )
+        })?;
+        check(unsafe {
+            PowerReadDCValueIndex(
+                ptr::null_mut(),
+                self.0,
+                &GUID_VIDEO_SUBGROUP,
+                setting,
+                &mut dc,
+            )
+        })?;
+
+        Ok(Timeout { ac, dc })
+    }
+
+    fn write(&self, setting: &GUID, timeout: Timeout) -> io::Result<()> {
+        check(unsafe {
+            PowerWriteACValueIndex(
+                ptr::null_mut(),
+                self.0,
+                &GUID_VIDEO_SUBGROUP,
+                setting,
+                timeout.ac,
+            )
+        })?;
+        check(unsafe {
+            PowerWriteDCValueIndex(
+                ptr::null_mut(),
+                self.0,
+                &GUID_VIDEO_SUBGROUP,
+                setting,
+                timeout.dc,
+            )
+        })
+    }
+}
+
+impl Drop for ActiveScheme {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0 as *mut c_void) };
+    }
+}
+
+fn check(result: DWORD) -> io::Result<()> {
+    if result == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(result as i32))
+    }
+}
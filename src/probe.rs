@@ -0,0 +1,239 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+use serde::Serialize;
+
+use amvideo::display::{self, DisplayMode};
+use amvideo::{AmVideo, AmVideoCrateError, DEFAULT_VBIOS_BUFFER};
+
+use crate::audit::{self, Event};
+use crate::export_map;
+use crate::timeout::{self, Stage};
+use crate::timing::{self, Millis};
+use crate::{dll_to_load, GlobalOpts, OutputFormat};
+
+#[derive(Args)]
+pub struct ProbeOpts {
+    /// Bytes to read the VBIOS version into at first, grown if the string does not fit
+    #[arg(long, default_value_t = DEFAULT_VBIOS_BUFFER)]
+    vbios_buffer: u32,
+}
+
+/// Everything learned by loading and opening the DLL, without setting a resolution
+#[derive(Debug, Serialize)]
+struct Report {
+    dll: String,
+    base: String,
+    exports: Vec<Export>,
+    /// Exports that look hooked by another framework
+    hooked: Vec<&'static str>,
+    /// Banner of the matching build database entry
+    build: Option<&'static str>,
+    setting_version: u32,
+    requested_context_version: u32,
+    /// Context version the DLL accepted, if it opened
+    context_version: Option<u32>,
+    open: Outcome,
+    vbios: Option<Vbios>,
+    close: Option<Outcome>,
+    /// Mode of the primary display, read through Windows rather than the DLL
+    display: Option<DisplayMode>,
+}
+
+#[derive(Debug, Serialize)]
+struct Export {
+    name: &'static str,
+    address: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Outcome {
+    ok: bool,
+    duration_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Vbios {
+    version: Option<String>,
+    truncated: bool,
+    duration_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn of(error: Option<&AmVideoCrateError>, duration: Duration) -> Self {
+        Self {
+            ok: error.is_none(),
+            duration_us: timing::micros(duration),
+            code: error.and_then(AmVideoCrateError::code),
+            error: error.map(ToString::to_string),
+        }
+    }
+}
+
+/// `amvideo probe`: load, open, read the VBIOS version, and close, and report what the DLL and
+/// driver support. Never sets a resolution, so it is safe on a cabinet in service.
+pub fn run(global: &GlobalOpts, opts: &ProbeOpts) -> Result<()> {
+    let name = dll_to_load()?;
+    let amvideo = AmVideo::with_exports(&name, &export_map::get())?;
+
+    let mut report = Report {
+        dll: name.to_string_lossy().into_owned(),
+        base: format!("{:#x}", **amvideo.library() as usize),
+        exports: amvideo
+            .exports()
+            .iter()
+            .map(|&(name, func)| Export {
+                name,
+                address: format!("{:#x}", func as usize),
+            })
+            .collect(),
+        hooked: amvideo.hooked_exports(),
+        build: amvideo.build().map(|build| build.banner),
+        setting_version: amvideo.setting_version(),
+        requested_context_version: amvideo.context_version(),
+        context_version: None,
+        open: Outcome::of(None, Duration::ZERO),
+        vbios: None,
+        close: None,
+        display: display::current_mode().ok(),
+    };
+
+    let (result, duration) =
+        timing::time(|| timeout::watch(Stage::Dll, "amDllVideoOpen", || amvideo.open()));
+    audit::record(Event::Open {
+        outcome: audit::outcome(result.as_ref().err().map(|e| e.error())),
+        duration_us: timing::micros(duration),
+    });
+    report.open = Outcome::of(result.as_ref().err().map(|e| e.error()), duration);
+
+    if let Ok(mut opened) = result {
+        report.context_version = Some(opened.context_version());
+
+        let (result, duration) = timing::time(|| {
+            timeout::watch(Stage::Dll, "amDllVideoGetVBiosVersion", || {
+                opened.vbios_version(opts.vbios_buffer)
+            })
+        });
+        audit::record(Event::VbiosVersion {
+            outcome: audit::outcome(result.as_ref().err()),
+            version: result.as_ref().ok().map(|vbios| vbios.version.as_str()),
+            duration_us: timing::micros(duration),
+        });
+        report.vbios = Some(match result {
+            Ok(vbios) => Vbios {
+                version: Some(vbios.version),
+                truncated: vbios.truncated,
+                duration_us: timing::micros(duration),
+                error: None,
+            },
+            Err(e) => Vbios {
+                version: None,
+                truncated: false,
+                duration_us: timing::micros(duration),
+                error: Some(e.to_string()),
+            },
+        });
+
+        let (result, duration) =
+            timing::time(|| timeout::watch(Stage::Dll, "amDllVideoClose", || opened.close()));
+        audit::record(Event::Close {
+            outcome: audit::outcome(result.as_ref().err().map(|e| e.error())),
+            duration_us: timing::micros(duration),
+        });
+        report.close = Some(Outcome::of(
+            result.as_ref().err().map(|e| e.error()),
+            duration,
+        ));
+    }
+
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Text => print_text(&report),
+    };
+
+    if report.open.ok {
+        Ok(())
+    } else {
+        Err(anyhow!("amVideo did not open"))
+    }
+}
+
+fn print_text(report: &Report) {
+    println!("{} @ {}", report.dll, report.base);
+    for export in &report.exports {
+        println!("  {} @ {}", export.name, export.address);
+    }
+    if !report.hooked.is_empty() {
+        println!("  Hooked: {}", report.hooked.join(", "));
+    }
+    println!(
+        "  Build: {}",
+        report.build.unwrap_or("not in the build database")
+    );
+    println!("  Setting version: {}", report.setting_version);
+
+    match report.context_version {
+        Some(version) => println!(
+            "  Opened with context version {} (requested {}) in {}",
+            version,
+            report.requested_context_version,
+            Millis::from_micros(report.open.duration_us)
+        ),
+        None => println!(
+            "  Open failed with context version {}: {}",
+            report.requested_context_version,
+            report.open.error.as_deref().unwrap_or("unknown error")
+        ),
+    }
+    if let Some(vbios) = &report.vbios {
+        match &vbios.version {
+            Some(version) if vbios.truncated => println!(
+                "  VBIOS: {} (truncated) in {}",
+                version,
+                Millis::from_micros(vbios.duration_us)
+            ),
+            Some(version) => println!(
+                "  VBIOS: {} in {}",
+                version,
+                Millis::from_micros(vbios.duration_us)
+            ),
+            None => println!(
+                "  VBIOS: {}",
+                vbios.error.as_deref().unwrap_or("unknown error")
+            ),
+        }
+    }
+    if let Some(close) = &report.close {
+        match &close.error {
+            Some(error) => println!("  Close failed: {}", error),
+            None => println!("  Closed in {}", Millis::from_micros(close.duration_us)),
+        }
+    }
+    match &report.display {
+        Some(mode) => println!("  Primary display: {}", mode),
+        None => println!("  Primary display: unknown"),
+    }
+}
@@ -0,0 +1,24 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/// Version of the control and broker protocols this build speaks. Version 1, the original
+/// control protocol, has no `version` field and is what unversioned peers are taken to speak.
+pub const VERSION: u32 = 2;
+
+/// Highest version both sides speak, given the one a peer offered
+pub fn negotiate(offered: Option<u32>) -> u32 {
+    offered.unwrap_or(1).min(VERSION)
+}
@@ -0,0 +1,216 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::{self, PullConfig};
+use crate::daemon::Daemon;
+use crate::digest;
+use crate::http::{self, Fetched};
+
+/// Last verified set, kept next to the executable so a cab that boots offline still has it
+const CACHE_NAME: &str = "amvideo-pulled.toml";
+
+pub type Profiles = BTreeMap<String, toml::Table>;
+
+/// The document served at `pull.url`
+#[derive(Deserialize)]
+struct ProfileSet {
+    /// Raised with every set published, so an older signed set cannot be served in place of a
+    /// newer one
+    serial: u64,
+    #[serde(default)]
+    profiles: Profiles,
+}
+
+/// What is known of the set the daemon has, for the next poll to compare against
+#[derive(Default)]
+pub struct Latest {
+    /// Entity tag the set was served with
+    etag: Option<String>,
+    serial: Option<u64>,
+}
+
+/// Profiles from the cached set and what a poll needs to know of it, if there is one and it
+/// still verifies against the key
+pub fn cached(config: &PullConfig) -> Option<(Profiles, Latest)> {
+    let result = (|| {
+        let key = digest::public_key(&config.public_key).context("Invalid pull.public_key")?;
+        let path = cache_path()?;
+        let data = match fs::read(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            data => data.with_context(|| format!("Failed to read '{}'", path.display()))?,
+        };
+        let signature = fs::read(with_extension(&path, "sig"))
+            .context("Failed to read the cached profile set's signature")?;
+        verify(&data, &signature, &key)?;
+        let etag = fs::read_to_string(with_extension(&path, "etag")).ok();
+        let set = parse(&data)?;
+        let latest = Latest {
+            etag,
+            serial: Some(set.serial),
+        };
+        Ok(Some((set.profiles, latest)))
+    })();
+
+    result.unwrap_or_else(|e: anyhow::Error| {
+        eprintln!("Ignoring the cached profile set: {:#}", e);
+        None
+    })
+}
+
+/// Poll `pull.url` every `pull.interval` seconds on a background thread, handing each newly
+/// verified set to the daemon. A set that fails to fetch, verify, or parse is reported and the
+/// previous one kept, as is one no newer than it. `latest` describes the set the daemon already
+/// has, from [`cached`].
+pub fn spawn(
+    daemon: Arc<Daemon>,
+    config: PullConfig,
+    mut latest: Latest,
+    reapply: bool,
+) -> Result<()> {
+    let key = digest::public_key(&config.public_key).context("Invalid pull.public_key")?;
+    let interval = Duration::from_secs(config.interval.max(1));
+
+    thread::spawn(move || loop {
+        match poll(&config, &key, &mut latest) {
+            Ok(Some(profiles)) => {
+                println!("Pulled {} profiles from {}", profiles.len(), config.url);
+                if let Err(e) = daemon.pull(profiles, reapply) {
+                    eprintln!("Failed to load the pulled profiles: {:#}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to pull profiles: {:#}", e),
+        }
+        thread::sleep(interval);
+    });
+
+    Ok(())
+}
+
+/// The profile set at `pull.url` if it changed since `latest`, which is updated
+fn poll(config: &PullConfig, key: &[u8; 64], latest: &mut Latest) -> Result<Option<Profiles>> {
+    let (data, new_etag) = match http::fetch_if_changed(&config.url, latest.etag.as_deref())? {
+        Fetched::Changed { data, etag } => (data, etag),
+        Fetched::NotModified => return Ok(None),
+    };
+
+    let signature_url = config
+        .signature_url
+        .clone()
+        .unwrap_or_else(|| format!("{}.sig", config.url));
+    let signature = http::fetch(&signature_url)?;
+    verify(&data, &signature, key)?;
+    let set = parse(&data)?;
+
+    // Local paths have no tag and are re-read every poll, and a server may re-tag the same set,
+    // so skip sets that did not change
+    if fs::read(cache_path()?).ok().as_deref() == Some(&data[..]) {
+        latest.etag = new_etag;
+        return Ok(None);
+    }
+    // The signature alone would let an old set be served again
+    if let Some(serial) = latest.serial.filter(|&serial| set.serial <= serial) {
+        return Err(anyhow!(
+            "Refusing profile set {}, which is not newer than set {} already pulled",
+            set.serial,
+            serial
+        ));
+    }
+
+    let path = cache_path()?;
+    fs::write(&path, &data).with_context(|| format!("Failed to write '{}'", path.display()))?;
+    fs::write(with_extension(&path, "sig"), &signature)
+        .context("Failed to cache the profile set's signature")?;
+    let etag_path = with_extension(&path, "etag");
+    match &new_etag {
+        Some(tag) => fs::write(&etag_path, tag).context("Failed to cache the entity tag")?,
+        None => {
+            let _ = fs::remove_file(&etag_path);
+        }
+    }
+    *latest = Latest {
+        etag: new_etag,
+        serial: Some(set.serial),
+    };
+
+    Ok(Some(set.profiles))
+}
+
+/// Check the DER ECDSA P-256 `signature`, raw or in hex as written by `openssl dgst -sha256
+/// -sign`, of `data` against `key`
+fn verify(data: &[u8], signature: &[u8], key: &[u8; 64]) -> Result<()> {
+    let der = std::str::from_utf8(signature)
+        .ok()
+        .and_then(|hex| digest::unhex(hex.trim()))
+        .unwrap_or_else(|| signature.to_vec());
+    let signature = digest::der_signature(&der)
+        .ok_or_else(|| anyhow!("Profile set signature is not a DER ECDSA P-256 signature"))?;
+
+    let digest = digest::sha256(data)?;
+    if !digest::verify_p256(key, &digest, &signature)? {
+        return Err(anyhow!(
+            "Profile set signature does not match the configured public key"
+        ));
+    }
+
+    Ok(())
+}
+
+fn parse(data: &[u8]) -> Result<ProfileSet> {
+    let contents = std::str::from_utf8(data).context("Profile set is not UTF-8")?;
+    let set: ProfileSet = toml::from_str(contents).context("Failed to parse the profile set")?;
+    config::check_profiles(&set.profiles)?;
+    Ok(set)
+}
+
+fn cache_path() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    Ok(exe.with_file_name(CACHE_NAME))
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    path.with_extension(format!("toml.{}", extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_serial_and_profiles() {
+        let set = parse(b"serial = 7\n\n[profiles.a]\nresolution = \"1920x1080\"\n").unwrap();
+        assert_eq!(set.serial, 7);
+        assert_eq!(set.profiles.keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn requires_serial() {
+        assert!(parse(b"[profiles.a]\nresolution = \"1920x1080\"\n").is_err());
+        assert!(parse(b"serial = -1\n").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use amvideo::display;
+
+use crate::timeout::{self, Stage};
+
+/// Display to wait for and for how long, as requested with `--wait-for-display`. Without a time
+/// the `ready` timeout applies.
+static WAIT: OnceLock<(Option<String>, Option<Duration>)> = OnceLock::new();
+
+/// Wait for a display before every open from now on, or for the display named `device`
+pub fn enable(device: Option<String>, timeout: Option<Duration>) {
+    let _ = WAIT.set((device, timeout));
+}
+
+/// Block until the display asked for is attached and driven by its GPU driver, if
+/// `--wait-for-display` was passed
+pub fn wait() -> Result<()> {
+    let (device, timeout) = match WAIT.get() {
+        Some(wait) => wait,
+        None => return Ok(()),
+    };
+
+    let timeout = timeout
+        .or_else(|| timeout::limit(Stage::Ready))
+        .unwrap_or(Duration::MAX);
+    let display = display::wait_for_display(device.as_deref(), timeout)
+        .context("Display did not become ready")?;
+    println!("Display ready: {} ({})", display.name, display.description);
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::ffi::OsString;
+
+use winreg::enums::HKEY_LOCAL_MACHINE;
+use winreg::RegKey;
+
+use crate::error::{AmVideoCrateError, Result};
+
+/// Hive the AM platform libraries are configured in, one key per library
+pub const SYSTEM_PROPERTY_KEY: &str = "System\\Sega\\SystemProperty";
+pub const AM_VIDEO_KEY: &str = "System\\Sega\\SystemProperty\\amVideo";
+
+/// Environment variable naming the amVideo DLL to use in place of the registry's, for
+/// development machines and tests without the SystemProperty keys. Loaders resolve and check it
+/// with [`dll_path`](crate::dll_path) like the registry's name.
+pub const DLL_OVERRIDE_VAR: &str = "AMVIDEO_DLL";
+
+/// Look up the amVideo DLL name configured for this machine
+pub fn dll_name() -> Result<OsString> {
+    match env::var_os(DLL_OVERRIDE_VAR) {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => library_dll_name(AM_VIDEO_KEY),
+    }
+}
+
+/// Look up the DLL name in an AM library's key
+pub fn library_dll_name(key: &str) -> Result<OsString> {
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey(key)
+        .map_err(|source| AmVideoCrateError::Registry {
+            path: key.to_string(),
+            source,
+        })?
+        .get_value("name")
+        .map_err(|source| AmVideoCrateError::Registry {
+            path: format!("{}\\name", key),
+            source,
+        })
+}
@@ -0,0 +1,347 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::slice;
+
+use anyhow::{Context, Result};
+use clap::Args;
+
+use amvideo::pe::DataFile;
+use amvideo::{AmVideo, AmVideoContext, Closed, Open, DEFAULT_VBIOS_BUFFER};
+
+use crate::call::{self, Convention};
+use crate::context_diff;
+use crate::digest;
+use crate::safe_mode;
+use crate::{dll_to_load, export_map};
+
+/// Saved between sessions next to the executable, like a shell's history
+const HISTORY_NAME: &str = "amvideo-repl.history";
+
+const HELP: &str = "\
+load [DLL]             load the configured DLL, or the one given
+exports                list the DLL's exports and their addresses
+open | close           open or close the context through the DLL
+vbios                  read the VBIOS version
+ctx                    dump the non-zero rows of the context
+snap                   remember the context for diff
+diff                   show the context bytes changed since snap
+blob [HEX]             set or show the buffer passed as `blob`
+call EXPORT [ARGS...]  call an export by ordinal (#N) or name, ARGS as for `amvideo call`
+convention NAME        cdecl, stdcall, or fastcall for later calls
+history                list earlier commands, `!N` runs one again
+help | quit";
+
+#[derive(Args)]
+pub struct ReplOpts {
+    /// DLL to load at startup [default: none until `load`]
+    dll: Option<PathBuf>,
+}
+
+/// DLL handle in whichever lifecycle state the session left it
+enum Handle {
+    Closed(AmVideo<Closed>),
+    Open(AmVideo<Open>),
+}
+
+struct Repl {
+    handle: Option<Handle>,
+    snapshot: Option<Vec<u8>>,
+    blob: Vec<u8>,
+    convention: Convention,
+    history: Vec<String>,
+}
+
+/// `amvideo repl`: load a DLL and poke at it interactively
+pub fn run(opts: &ReplOpts) -> Result<()> {
+    let mut repl = Repl {
+        handle: None,
+        snapshot: None,
+        blob: Vec::new(),
+        convention: Convention::Cdecl,
+        history: load_history(),
+    };
+    if let Some(dll) = &opts.dll {
+        repl.execute(&format!("load {}", dll.display()))?;
+    }
+
+    println!("amvideo repl, `help` lists the commands");
+    let stdin = io::stdin();
+    loop {
+        print!("amvideo> ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let mut line = line.trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(index) = line.strip_prefix('!') {
+            match index
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| repl.history.get(i.wrapping_sub(1)))
+            {
+                Some(earlier) => {
+                    line = earlier.clone();
+                    println!("{}", line);
+                }
+                None => {
+                    eprintln!("No command {} in the history", line);
+                    continue;
+                }
+            }
+        }
+        if line == "quit" || line == "exit" {
+            break;
+        }
+
+        repl.remember(&line);
+        if let Err(e) = repl.execute(&line) {
+            eprintln!("Error: {:#}", e);
+        }
+    }
+
+    repl.close()
+}
+
+impl Repl {
+    fn execute(&mut self, line: &str) -> Result<()> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["help"] => println!("{}", HELP),
+            ["history"] => {
+                for (i, line) in self.history.iter().enumerate() {
+                    println!("{:>4}  {}", i + 1, line);
+                }
+            }
+            ["load"] => self.load(dll_to_load()?.into())?,
+            // Paths may contain spaces, so the DLL is the rest of the line
+            ["load", ..] => self.load(PathBuf::from(line["load".len()..].trim()))?,
+            ["exports"] => self.exports()?,
+            ["open"] => self.open()?,
+            ["close"] => self.close()?,
+            ["vbios"] => match &mut self.handle {
+                Some(Handle::Open(opened)) => {
+                    let version = opened.vbios_version(DEFAULT_VBIOS_BUFFER)?;
+                    println!("{}", version.version);
+                }
+                _ => return Err(anyhow!("The context is not open, run `open` first")),
+            },
+            ["ctx"] => dump(&self.context()?),
+            ["snap"] => {
+                self.snapshot = Some(self.context()?);
+                println!("Remembered the context");
+            }
+            ["diff"] => {
+                let after = self.context()?;
+                let before = self
+                    .snapshot
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Nothing to diff against, run `snap` first"))?;
+                let changes = context_diff::changes(before, &after);
+                if changes.is_empty() {
+                    println!("No changes");
+                }
+                context_diff::print(&changes);
+            }
+            ["blob"] => println!("{}", digest::hex(&self.blob)),
+            ["blob", hex @ ..] => self.blob = call::parse_blob(&hex.concat())?,
+            ["convention", name] => {
+                self.convention = clap::ValueEnum::from_str(name, true)
+                    .map_err(|e| anyhow!("Unknown convention '{}': {}", name, e))?
+            }
+            ["call", export, args @ ..] => self.call(export, args)?,
+            _ => return Err(anyhow!("Unknown command '{}', `help` lists them", line)),
+        };
+
+        Ok(())
+    }
+
+    fn load(&mut self, dll: PathBuf) -> Result<()> {
+        self.close()?;
+        let amvideo = AmVideo::with_exports(&dll, &export_map::get())?;
+        println!(
+            "Loaded {} @ {:#x}",
+            dll.display(),
+            **amvideo.library() as usize
+        );
+        self.handle = Some(Handle::Closed(amvideo));
+        self.snapshot = None;
+
+        Ok(())
+    }
+
+    fn library(&self) -> Result<&amvideo::library_handle::LibraryHandle> {
+        match &self.handle {
+            Some(Handle::Closed(amvideo)) => Ok(amvideo.library()),
+            Some(Handle::Open(amvideo)) => Ok(amvideo.library()),
+            None => Err(anyhow!("No DLL loaded, run `load` first")),
+        }
+    }
+
+    fn exports(&self) -> Result<()> {
+        let library = self.library()?;
+        let file = DataFile::read(library.path()?)?;
+        for (ordinal, name) in file.exports().unwrap_or_default() {
+            let address = unsafe { library.get_func_named_ordinal("export", ordinal) }
+                .map_or(0, |func| func as usize);
+            println!(
+                "#{:<4} {:#x}  {}",
+                ordinal,
+                address,
+                name.as_deref().unwrap_or("(no name)")
+            );
+        }
+
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<()> {
+        match self.handle.take() {
+            Some(Handle::Closed(amvideo)) => match amvideo.open() {
+                Ok(opened) => {
+                    println!("Opened");
+                    self.handle = Some(Handle::Open(opened));
+                }
+                Err(e) => {
+                    let error = anyhow!("{}", e.error());
+                    self.handle = Some(Handle::Closed(e.into_closed()));
+                    return Err(error);
+                }
+            },
+            Some(open @ Handle::Open(_)) => {
+                self.handle = Some(open);
+                println!("Already open");
+            }
+            None => return Err(anyhow!("No DLL loaded, run `load` first")),
+        };
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some(Handle::Open(opened)) = self.handle.take() {
+            match opened.close() {
+                Ok(closed) => {
+                    println!("Closed");
+                    self.handle = Some(Handle::Closed(closed));
+                }
+                Err(e) => {
+                    let error = anyhow!("{}", e.error());
+                    self.handle = Some(Handle::Closed(e.into_closed()));
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn context_ptr(&mut self) -> Result<*mut AmVideoContext> {
+        let context = match &mut self.handle {
+            Some(Handle::Closed(amvideo)) => unsafe { amvideo.raw_exports().context },
+            Some(Handle::Open(amvideo)) => unsafe { amvideo.raw_exports().context },
+            None => return Err(anyhow!("No DLL loaded, run `load` first")),
+        };
+
+        Ok(context as *mut _)
+    }
+
+    /// Copy of the context's bytes, version included
+    fn context(&mut self) -> Result<Vec<u8>> {
+        let context = self.context_ptr()?;
+        let bytes = unsafe {
+            slice::from_raw_parts(context as *const u8, mem::size_of::<AmVideoContext>())
+        };
+
+        Ok(bytes.to_vec())
+    }
+
+    fn call(&mut self, export: &str, args: &[&str]) -> Result<()> {
+        safe_mode::check("Calling raw exports")?;
+        let context = self.context_ptr()? as usize;
+        let blob = self.blob.as_mut_ptr() as usize;
+        let args = args
+            .iter()
+            .map(|arg| call::parse_arg(arg, context, blob))
+            .collect::<Result<Vec<usize>>>()?;
+        if args.len() > call::MAX_ARGS {
+            return Err(anyhow!(
+                "At most {} arguments can be passed",
+                call::MAX_ARGS
+            ));
+        }
+
+        let library = self.library()?;
+        let func = unsafe {
+            match export.strip_prefix('#').map(str::parse::<u16>) {
+                Some(Ok(ordinal)) => library.get_func_named_ordinal("export", ordinal),
+                Some(Err(_)) => return Err(anyhow!("Invalid ordinal '{}'", export)),
+                None => library.get_func_named(export),
+            }
+        }
+        .map_err(|_| anyhow!("Failed to find export {} in the DLL", export))?;
+
+        let result = unsafe { call::invoke(func, self.convention, &args) };
+        println!("Returned {} ({:#x})", result as isize, result);
+        if !self.blob.is_empty() {
+            println!("Blob: {}", digest::hex(&self.blob));
+        }
+
+        Ok(())
+    }
+
+    fn remember(&mut self, line: &str) {
+        self.history.push(line.to_string());
+        // History is a convenience, a session without it is still useful
+        if let Ok(path) = history_path() {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+}
+
+fn history_path() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    Ok(exe.with_file_name(HISTORY_NAME))
+}
+
+fn load_history() -> Vec<String> {
+    history_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|history| history.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Hex dump of the rows that are not all zero, 16 bytes to a row
+fn dump(bytes: &[u8]) {
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        if chunk.iter().any(|&b| b != 0) {
+            println!("{:#06x}  {}", row * 16, digest::hex(chunk));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+
+use crate::audio_endpoint;
+use crate::audit::{self, Event};
+use crate::custom_resolution;
+use crate::kiosk;
+use crate::state;
+use crate::vrr;
+use crate::{prompt, GlobalOpts};
+
+/// `amvideo revert`: put back the display settings stashed before the latest apply, by whichever
+/// process made it, and undo what that apply left behind
+pub fn run(global: &GlobalOpts) -> Result<()> {
+    let stash = state::load()?
+        .stash
+        .ok_or_else(|| anyhow!("Nothing to revert: no apply has stashed display settings"))?;
+
+    let mut summary = format!(
+        "About to revert to the display settings from before the apply {} ago:",
+        state::ago(stash.timestamp_ms)
+    );
+    for settings in &stash.snapshot.displays {
+        summary.push_str(&format!("\n  {}: {}", settings.device, settings.mode()));
+    }
+    prompt(global, &summary)?;
+
+    let result = stash.snapshot.restore();
+    audit::record(Event::Restore {
+        snapshot: &stash.snapshot,
+        ok: result.is_ok(),
+    });
+    result.context("Failed to restore the stashed display settings")?;
+    println!("Reverted display settings");
+
+    // Reverting twice would put back the same settings, so the stash is spent
+    state::take_stash()?;
+    restore_extras()?;
+    state::clear();
+
+    Ok(())
+}
+
+/// Undo what applies changed beyond the display settings: kiosk mode, the custom resolutions
+/// created, the default audio endpoint, and the G-SYNC mode. Shared by every restore path.
+pub fn restore_extras() -> Result<()> {
+    kiosk::restore()?;
+    custom_resolution::remove_created();
+    audio_endpoint::restore();
+    vrr::restore();
+    Ok(())
+}
@@ -0,0 +1,41 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(cfg!(feature = "safe-mode"));
+
+/// Refuse everything beyond the documented DLL interface from now on, as requested with
+/// `--safe-mode`. A build with the `safe-mode` feature starts out this way.
+pub fn enable() {
+    SAFE_MODE.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+/// Fail if `feature`, which reads or writes the DLL's memory behind its interface, is off in
+/// safe mode. `--force` does not override this.
+pub fn check(feature: &str) -> Result<()> {
+    if enabled() {
+        return Err(anyhow!("{} is disabled in safe mode", feature));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,329 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use serde::Deserialize;
+
+use amvideo::display;
+use amvideo::snapshot::Snapshot;
+use amvideo::AmVideoResolution;
+
+use crate::audit::{self, Event};
+use crate::config::Config;
+use crate::headless;
+use crate::revert;
+use crate::state;
+use crate::timeout::{self, Stage};
+use crate::timing;
+use crate::{apply_profile, GlobalOpts};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Args)]
+pub struct ScenarioOpts {
+    #[command(subcommand)]
+    command: ScenarioCommand,
+}
+
+#[derive(Subcommand)]
+enum ScenarioCommand {
+    /// Execute the steps of a scenario file in order
+    Run {
+        /// YAML scenario file
+        plan: PathBuf,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Plan {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Step {
+    #[serde(flatten)]
+    action: Action,
+    /// Seconds the step may take before it is considered failed. Waits and verifies without one
+    /// take the `ready` and `verify` timeouts.
+    timeout: Option<u64>,
+    #[serde(default)]
+    on_failure: OnFailure,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+enum Action {
+    /// Wait until a display (or the named one) is attached to the desktop
+    WaitForDisplay { device: Option<String> },
+    /// Apply a profile from the config
+    Apply { profile: String },
+    /// Check the primary display runs at `resolution`, or the last applied profile's resolution
+    Verify {
+        resolution: Option<AmVideoResolution>,
+    },
+    /// Run a command and wait for it to exit successfully
+    Run {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        /// Run the command as DPI aware so Windows scaling does not stretch it
+        #[serde(default, rename = "dpi-aware")]
+        dpi_aware: bool,
+    },
+    /// Put the displays back the way they were when the scenario started
+    Restore,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OnFailure {
+    /// Stop the scenario and report the failure
+    #[default]
+    Abort,
+    /// Report the failure and carry on with the next step
+    Continue,
+    /// Restore the starting display settings, then stop the scenario
+    Restore,
+}
+
+struct Runner<'a> {
+    global: &'a GlobalOpts,
+    config: Option<Config>,
+    snapshot: Snapshot,
+    last_applied: Option<AmVideoResolution>,
+}
+
+pub fn run(global: &GlobalOpts, opts: &ScenarioOpts) -> Result<()> {
+    match &opts.command {
+        ScenarioCommand::Run { plan } => run_plan(global, plan),
+    }
+}
+
+fn run_plan(global: &GlobalOpts, path: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read scenario '{}'", path.display()))?;
+    let plan: Plan = serde_yaml::from_str(&contents)
+        .with_context(|| format!("Failed to parse scenario '{}'", path.display()))?;
+
+    let mut runner = Runner {
+        global,
+        config: None,
+        snapshot: Snapshot::capture().context("Failed to capture the starting display settings")?,
+        last_applied: None,
+    };
+    let total = plan.steps.len();
+    let mut failures = 0;
+
+    for (i, step) in plan.steps.iter().enumerate() {
+        println!("[{}/{}] {}", i + 1, total, step.action);
+
+        let timeout = step.timeout.map(Duration::from_secs);
+        let error = match runner.run_step(&step.action, timeout) {
+            Ok(()) => continue,
+            Err(e) => e,
+        };
+
+        eprintln!("[{}/{}] Failed: {:#}", i + 1, total, error);
+        failures += 1;
+
+        // Whatever the next step does would race the apply still running for the DLL
+        if error.is::<Abandoned>() {
+            return Err(error.context(format!(
+                "Scenario step {} timed out, stopping without running further steps",
+                i + 1
+            )));
+        }
+
+        match step.on_failure {
+            OnFailure::Continue => continue,
+            OnFailure::Abort => {}
+            OnFailure::Restore => runner.restore()?,
+        };
+
+        return Err(error.context(format!("Scenario step {} failed", i + 1)));
+    }
+
+    if failures == 0 {
+        println!("Scenario finished");
+        Ok(())
+    } else {
+        Err(anyhow!("Scenario finished with {} failed steps", failures))
+    }
+}
+
+impl Runner<'_> {
+    fn run_step(&mut self, action: &Action, timeout: Option<Duration>) -> Result<()> {
+        match action {
+            Action::WaitForDisplay { device } => {
+                let timeout = timeout
+                    .or_else(|| timeout::limit(Stage::Ready))
+                    .unwrap_or(Duration::MAX);
+                let display = display::wait_for_display(device.as_deref(), timeout)?;
+                println!("Found {} ({})", display.name, display.description);
+            }
+            Action::Apply { profile: name } => {
+                if self.config.is_none() {
+                    self.config = Some(Config::load(self.global.config.as_deref())?);
+                }
+                let profile = self.config.as_ref().unwrap().profile(name)?;
+                // `match_aspect` may apply another mode than the one asked for
+                let resolution = profile.fitted_resolution();
+
+                let (result, duration) =
+                    timing::time(|| with_timeout(timeout, move || apply_profile(&profile)));
+                state::record(Some(name), &result, duration);
+                result?;
+                self.last_applied = Some(resolution);
+            }
+            Action::Verify { .. } if headless::enabled().is_some() => {
+                println!("Skipped verify: {}", headless::STATUS);
+            }
+            Action::Verify { resolution } => {
+                let expected = resolution
+                    .or(self.last_applied)
+                    .ok_or_else(|| anyhow!("Nothing to verify: no resolution given or applied"))?;
+                // Poll until the mode settles
+                let limit = timeout.or_else(|| timeout::limit(Stage::Verify));
+                let result = timeout::poll(limit, || display::verify(&expected));
+                audit::record_verify(&expected, &result);
+                println!("Verified {}", result?);
+            }
+            Action::Run {
+                command,
+                args,
+                dpi_aware,
+            } => {
+                let mut child = process::Command::new(command);
+                child.args(args);
+                if *dpi_aware {
+                    // Application compatibility layers are inherited through the environment
+                    let layers = env::var("__COMPAT_LAYER").unwrap_or_default();
+                    child.env("__COMPAT_LAYER", format!("{} HighDpiAware", layers).trim());
+                }
+                let child = child
+                    .spawn()
+                    .with_context(|| format!("Failed to start '{}'", command))?;
+                wait_child(child, timeout)?;
+            }
+            Action::Restore => self.restore()?,
+        };
+
+        Ok(())
+    }
+
+    fn restore(&self) -> Result<()> {
+        let result = self.snapshot.restore();
+        audit::record(Event::Restore {
+            snapshot: &self.snapshot,
+            ok: result.is_ok(),
+        });
+        result.context("Failed to restore the starting display settings")?;
+
+        println!("Restored display settings");
+        revert::restore_extras()
+    }
+}
+
+/// Apply still running on its worker thread when the step's timeout passed
+#[derive(Debug)]
+struct Abandoned(Duration);
+
+impl fmt::Display for Abandoned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Timed out after {:?}, the apply is still running",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for Abandoned {}
+
+/// Run `f` on a worker thread, giving up on it after `timeout`. A timed out DLL call cannot be
+/// cancelled, so the worker is left behind to finish (or hang) on its own and the error is
+/// [`Abandoned`], which ends the scenario.
+fn with_timeout<F>(timeout: Option<Duration>, f: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return f(),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(f()));
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Abandoned(timeout).into()),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(anyhow!("Worker thread panicked")),
+    }
+}
+
+fn wait_child(mut child: Child, timeout: Option<Duration>) -> Result<()> {
+    let start = Instant::now();
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() >= timeout) {
+            child.kill().context("Failed to kill timed out command")?;
+            child.wait()?;
+            return Err(anyhow!("Command timed out after {:?}", timeout));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Command exited with {}", status))
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::WaitForDisplay {
+                device: Some(device),
+            } => write!(f, "wait-for-display {}", device),
+            Self::WaitForDisplay { device: None } => write!(f, "wait-for-display"),
+            Self::Apply { profile } => write!(f, "apply {}", profile),
+            Self::Verify {
+                resolution: Some(resolution),
+            } => write!(f, "verify {}", resolution),
+            Self::Verify { resolution: None } => write!(f, "verify"),
+            Self::Run { command, .. } => write!(f, "run {}", command),
+            Self::Restore => write!(f, "restore"),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::Subcommand;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use winapi::um::minwinbase::SYSTEMTIME;
+use winapi::um::sysinfoapi::GetLocalTime;
+
+use crate::config::ScheduleRule;
+use crate::daemon::Daemon;
+use crate::notify;
+
+/// How often the daemon checks whether the schedule calls for another profile
+const INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Subcommand)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScheduleAction {
+    /// Stop switching profiles on schedule until `resume`
+    Hold,
+    /// Switch on schedule again, applying the profile due now
+    Resume,
+}
+
+/// Why the schedule is not being followed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Hold {
+    /// A profile was applied or restored by hand, until the schedule next calls for another
+    UntilNext,
+    /// Until `schedule resume`
+    Indefinite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+/// Local time of day to the minute, written `HH:MM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay {
+    minutes: u16,
+}
+
+/// Local day and time a schedule is evaluated at
+#[derive(Clone, Copy, Debug)]
+pub struct Moment {
+    pub day: Weekday,
+    pub time: TimeOfDay,
+}
+
+impl Weekday {
+    const ALL: [Self; 7] = [
+        Self::Sun,
+        Self::Mon,
+        Self::Tue,
+        Self::Wed,
+        Self::Thu,
+        Self::Fri,
+        Self::Sat,
+    ];
+
+    fn previous(self) -> Self {
+        Self::ALL[(self as usize + 6) % 7]
+    }
+}
+
+impl Moment {
+    pub fn now() -> Self {
+        let mut now: SYSTEMTIME = unsafe { std::mem::zeroed() };
+        unsafe { GetLocalTime(&mut now) };
+        Self {
+            day: Weekday::ALL[usize::from(now.wDayOfWeek) % 7],
+            time: TimeOfDay {
+                minutes: now.wHour * 60 + now.wMinute,
+            },
+        }
+    }
+}
+
+impl ScheduleRule {
+    /// Whether the rule's window is open at `now`. A window ending at or before its start runs
+    /// past midnight, and belongs to the day it starts on.
+    fn covers(&self, now: Moment) -> bool {
+        let on = |day| self.days.is_empty() || self.days.contains(&day);
+        if self.from < self.to {
+            on(now.day) && self.from <= now.time && now.time < self.to
+        } else {
+            (on(now.day) && now.time >= self.from) || (on(now.day.previous()) && now.time < self.to)
+        }
+    }
+}
+
+/// Profile the first rule open at `now` names, if any
+pub fn due(rules: &[ScheduleRule], now: Moment) -> Option<&str> {
+    rules
+        .iter()
+        .find(|rule| rule.covers(now))
+        .map(|rule| rule.profile.as_str())
+}
+
+/// Follow the config's schedule on a background thread
+pub fn spawn(daemon: Arc<Daemon>) {
+    thread::spawn(move || loop {
+        match daemon.follow_schedule(false) {
+            Ok(Some(profile)) => notify::success(&format!("Switched to '{}' on schedule", profile)),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Failed to apply the scheduled profile: {:#}", e);
+                notify::failure("Applying the scheduled profile", &e);
+            }
+        }
+        thread::sleep(INTERVAL);
+    });
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a time like '22:30', got '{}'", s);
+        let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.trim().parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.trim().parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> TimeOfDay {
+        time.parse().unwrap()
+    }
+
+    fn rule(profile: &str, from: &str, to: &str, days: &[Weekday]) -> ScheduleRule {
+        ScheduleRule {
+            profile: profile.to_string(),
+            from: at(from),
+            to: at(to),
+            days: days.to_vec(),
+        }
+    }
+
+    fn moment(day: Weekday, time: &str) -> Moment {
+        Moment {
+            day,
+            time: at(time),
+        }
+    }
+
+    #[test]
+    fn parses_time_of_day() {
+        assert_eq!(
+            at("22:30"),
+            TimeOfDay {
+                minutes: 22 * 60 + 30
+            }
+        );
+        assert_eq!(
+            at(" 7 : 05 "),
+            TimeOfDay {
+                minutes: 7 * 60 + 5
+            }
+        );
+        assert_eq!(at("00:00").to_string(), "00:00");
+        assert_eq!(at("9:05").to_string(), "09:05");
+        for invalid in ["24:00", "12:60", "12", "12:", ":30", "ab:cd", "-1:00", ""] {
+            assert!(invalid.parse::<TimeOfDay>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn covers_window() {
+        let rule = rule("day", "09:00", "17:00", &[]);
+        assert!(!rule.covers(moment(Weekday::Mon, "08:59")));
+        assert!(rule.covers(moment(Weekday::Mon, "09:00")));
+        assert!(rule.covers(moment(Weekday::Sun, "16:59")));
+        assert!(!rule.covers(moment(Weekday::Mon, "17:00")));
+    }
+
+    #[test]
+    fn covers_overnight_window_from_start_day() {
+        let rule = rule("night", "22:00", "06:00", &[Weekday::Fri]);
+        assert!(!rule.covers(moment(Weekday::Fri, "05:00")));
+        assert!(rule.covers(moment(Weekday::Fri, "22:00")));
+        assert!(rule.covers(moment(Weekday::Sat, "05:59")));
+        assert!(!rule.covers(moment(Weekday::Sat, "06:00")));
+        assert!(!rule.covers(moment(Weekday::Sat, "22:00")));
+    }
+
+    #[test]
+    fn covers_overnight_window_across_week() {
+        let rule = rule("night", "23:00", "01:00", &[Weekday::Sat]);
+        assert!(rule.covers(moment(Weekday::Sun, "00:30")));
+        assert!(!rule.covers(moment(Weekday::Mon, "00:30")));
+    }
+
+    #[test]
+    fn first_open_rule_is_due() {
+        let rules = [
+            rule("weekend", "10:00", "20:00", &[Weekday::Sat, Weekday::Sun]),
+            rule("day", "08:00", "22:00", &[]),
+        ];
+        assert_eq!(due(&rules, moment(Weekday::Sat, "12:00")), Some("weekend"));
+        assert_eq!(due(&rules, moment(Weekday::Mon, "12:00")), Some("day"));
+        assert_eq!(due(&rules, moment(Weekday::Mon, "23:00")), None);
+        assert_eq!(due(&[], moment(Weekday::Mon, "12:00")), None);
+    }
+}
@@ -0,0 +1,145 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::mem;
+use std::ptr;
+
+use winapi::shared::windef::HDC;
+use winapi::um::wingdi::{
+    BitBlt, CreateCompatibleBitmap, CreateCompatibleDC, CreateDCW, DeleteDC, DeleteObject,
+    GetDIBits, GetDeviceCaps, SelectObject, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, CAPTUREBLT,
+    DESKTOPHORZRES, DESKTOPVERTRES, DIB_RGB_COLORS, SRCCOPY,
+};
+
+use crate::display;
+use crate::wide::to_wide;
+
+/// Captured screen contents, top row first
+#[derive(Debug)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Four bytes per pixel: red, green, blue, and an opaque alpha
+    pub rgba: Vec<u8>,
+}
+
+struct Dc(HDC);
+
+impl Drop for Dc {
+    fn drop(&mut self) {
+        unsafe { DeleteDC(self.0) };
+    }
+}
+
+/// Capture what `device` shows, or the primary display if `None`
+pub fn capture(device: Option<&str>) -> io::Result<Image> {
+    let device = match device {
+        Some(device) => device.to_string(),
+        None => display::attached_displays()
+            .into_iter()
+            .find(|display| display.primary)
+            .map(|display| display.name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No primary display"))?,
+    };
+
+    let name = to_wide(&device);
+    let screen = unsafe { CreateDCW(name.as_ptr(), ptr::null(), ptr::null(), ptr::null()) };
+    if screen.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let screen = Dc(screen);
+    let memory = unsafe { CreateCompatibleDC(screen.0) };
+    if memory.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let memory = Dc(memory);
+
+    // Physical pixels, whatever DPI scaling the process sees
+    let width = unsafe { GetDeviceCaps(screen.0, DESKTOPHORZRES) };
+    let height = unsafe { GetDeviceCaps(screen.0, DESKTOPVERTRES) };
+    let bitmap = unsafe { CreateCompatibleBitmap(screen.0, width, height) };
+    if bitmap.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = unsafe {
+        let previous = SelectObject(memory.0, bitmap as _);
+        let copied = BitBlt(
+            memory.0,
+            0,
+            0,
+            width,
+            height,
+            screen.0,
+            0,
+            0,
+            SRCCOPY | CAPTUREBLT,
+        );
+        SelectObject(memory.0, previous);
+        if copied == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            read_pixels(memory.0, bitmap, width, height)
+        }
+    };
+    unsafe { DeleteObject(bitmap as _) };
+
+    Ok(Image {
+        width: width as u32,
+        height: height as u32,
+        rgba: result?,
+    })
+}
+
+/// Pixels of `bitmap` as top-down RGBA
+unsafe fn read_pixels(
+    dc: HDC,
+    bitmap: winapi::shared::windef::HBITMAP,
+    width: i32,
+    height: i32,
+) -> io::Result<Vec<u8>> {
+    let mut info: BITMAPINFO = mem::zeroed();
+    info.bmiHeader.biSize = mem::size_of::<BITMAPINFOHEADER>() as u32;
+    info.bmiHeader.biWidth = width;
+    // Negative for top-down rows
+    info.bmiHeader.biHeight = -height;
+    info.bmiHeader.biPlanes = 1;
+    info.bmiHeader.biBitCount = 32;
+    info.bmiHeader.biCompression = BI_RGB;
+
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    let lines = GetDIBits(
+        dc,
+        bitmap,
+        0,
+        height as u32,
+        pixels.as_mut_ptr() as *mut _,
+        &mut info,
+        DIB_RGB_COLORS,
+    );
+    if lines == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // GDI hands out BGRX
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+        pixel[3] = 0xFF;
+    }
+
+    Ok(pixels)
+}
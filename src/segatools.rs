@@ -0,0 +1,149 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use amvideo::display;
+
+use crate::config::SegatoolsConfig;
+use crate::elevation::{self, Skipped};
+
+const GFX: &str = "gfx";
+
+/// Value of `key` in `[section]`, ignoring case like `GetPrivateProfileString` does
+fn get(contents: &str, section: &str, key: &str) -> Option<String> {
+    let mut in_section = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = name.trim().eq_ignore_ascii_case(section);
+        } else if in_section {
+            if let Some((k, v)) = line.split_once('=') {
+                if k.trim().eq_ignore_ascii_case(key) {
+                    return Some(v.trim().to_string());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// `contents` with `key` in `[section]` set to `value`, adding the key or section if missing and
+/// leaving every other line, comments included, untouched
+fn set(contents: &str, section: &str, key: &str, value: &str) -> String {
+    let mut out = Vec::new();
+    let mut in_section = false;
+    let mut done = false;
+
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if in_section && !done {
+                out.push(format!("{}={}", key, value));
+                done = true;
+            }
+            in_section = name.trim().eq_ignore_ascii_case(section);
+        } else if in_section && !done {
+            let is_key = trimmed
+                .split_once('=')
+                .is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case(key));
+            if is_key {
+                out.push(format!("{}={}", key, value));
+                done = true;
+                continue;
+            }
+        }
+        out.push(line.to_string());
+    }
+
+    if !done {
+        if !in_section {
+            out.push(format!("[{}]", section));
+        }
+        out.push(format!("{}={}", key, value));
+    }
+
+    let mut contents = out.join("\r\n");
+    contents.push_str("\r\n");
+    contents
+}
+
+/// Index segatools' `[gfx] monitor` uses for `device`, in attach order
+fn monitor_index(device: &str) -> Option<usize> {
+    display::attached_displays()
+        .iter()
+        .position(|display| display.name.eq_ignore_ascii_case(device))
+}
+
+/// Check segatools.ini against the display setup just applied, and fix `[gfx] monitor` if the
+/// profile allows writing to it. Otherwise the game opens on the wrong screen.
+pub fn sync(config: &SegatoolsConfig, skipped: &mut Skipped) -> Result<()> {
+    let path: &Path = &config.path;
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+    if get(&contents, GFX, "windowed").as_deref() == Some("1") {
+        eprintln!(
+            "Warning: {} runs the game windowed, so it will not fill the applied mode",
+            path.display()
+        );
+    }
+
+    let primary = display::attached_displays()
+        .into_iter()
+        .find(|display| display.primary)
+        .ok_or_else(|| anyhow!("No primary display is attached"))?;
+    let expected = monitor_index(&primary.name)
+        .ok_or_else(|| anyhow!("{} is not attached", primary.name))?
+        .to_string();
+    let actual = get(&contents, GFX, "monitor").unwrap_or_else(|| "0".to_string());
+    if actual == expected {
+        return Ok(());
+    }
+
+    if !config.write {
+        eprintln!(
+            "Warning: {} sets [gfx] monitor={}, but the primary display {} is monitor {}",
+            path.display(),
+            actual,
+            primary.name,
+            expected
+        );
+        return Ok(());
+    }
+
+    // Game directories under Program Files are only writable as administrator
+    match fs::write(path, set(&contents, GFX, "monitor", &expected)) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !elevation::elevated() => {
+            skipped.allow("segatools.ini update");
+            return Ok(());
+        }
+        result => result.with_context(|| format!("Failed to write '{}'", path.display()))?,
+    };
+    println!(
+        "Set [gfx] monitor={} in {} for {}",
+        expected,
+        path.display(),
+        primary.name
+    );
+
+    Ok(())
+}
@@ -0,0 +1,213 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::mem;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use amvideo::{setting_size, AmVideoContext, AmVideoSetting, AM_VIDEO_CONTEXT_DATA_SIZE};
+
+use crate::safe_mode;
+use crate::{load, GlobalOpts, OutputFormat};
+
+/// Filler the DLL is not expected to write, so any byte that differs afterwards was touched
+const CANARY: u8 = 0xA5;
+/// Spare bytes after each buffer, which no call should touch
+const SLACK: usize = 0x400;
+/// Size the VBIOS read is told it has
+const VBIOS_SIZE: u32 = 0x40;
+
+/// Context-sized buffer with slack, aligned like the real context
+#[repr(C, align(8))]
+struct CanaryContext([u8; 0x400 + SLACK]);
+
+/// Outcome of one layout check
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, ok: bool, detail: String) -> Self {
+        Self { name, ok, detail }
+    }
+}
+
+/// `amvideo selftest`: check our struct layouts against what the loaded build does with them,
+/// by handing it oversized buffers of canary bytes and seeing which bytes it wrote. Never sets a
+/// resolution.
+pub fn run(global: &GlobalOpts) -> Result<()> {
+    safe_mode::check("The self-test")?;
+    let mut checks = layout_checks();
+
+    let mut amvideo = load()?;
+    let context_version = amvideo.context_version();
+    let setting_version = amvideo.setting_version();
+    checks.push(Check::new(
+        "setting layout",
+        true,
+        format!(
+            "{} sends version {} settings of {:#x} bytes",
+            amvideo
+                .build()
+                .map_or("this unlisted build", |build| build.banner),
+            setting_version,
+            setting_size(setting_version)
+        ),
+    ));
+
+    // The handle's own context is left alone, the probe calls get a canary buffer instead
+    let exports = unsafe { amvideo.raw_exports() };
+    let mut context = Box::new(CanaryContext([CANARY; 0x400 + SLACK]));
+    context.0[..4].copy_from_slice(&context_version.to_le_bytes());
+    let ctx = context.0.as_mut_ptr() as *mut AmVideoContext;
+
+    let result = unsafe { (exports.open)(ctx) };
+    if result != 0 {
+        checks.push(Check::new(
+            "context size",
+            false,
+            format!("amDllVideoOpen failed: {} ({:#x})", result, result),
+        ));
+        return report(global, &checks);
+    }
+    checks.push(context_check("context size after open", &context.0));
+
+    let mut vbios = vec![CANARY; VBIOS_SIZE as usize + SLACK];
+    let result = unsafe { (exports.get_vbios_version)(ctx, vbios.as_mut_ptr(), VBIOS_SIZE) };
+    let overrun = touched_past(&vbios, VBIOS_SIZE as usize);
+    checks.push(Check::new(
+        "VBIOS buffer size",
+        overrun.is_none(),
+        match overrun {
+            Some(end) => format!(
+                "wrote {:#x} bytes into a buffer it was told holds {:#x}",
+                end, VBIOS_SIZE
+            ),
+            None => format!(
+                "stays within the {:#x} bytes it is given (status {:#x})",
+                VBIOS_SIZE, result
+            ),
+        },
+    ));
+
+    let result = unsafe { (exports.close)(ctx) };
+    checks.push(context_check("context size after close", &context.0));
+    if result != 0 {
+        checks.push(Check::new(
+            "close",
+            false,
+            format!("amDllVideoClose failed: {} ({:#x})", result, result),
+        ));
+    }
+
+    report(global, &checks)
+}
+
+/// Sizes the FFI declarations must have, whatever the build
+fn layout_checks() -> Vec<Check> {
+    let expected: [(&'static str, usize, usize, usize); 3] = [
+        (
+            "AmVideoContext",
+            mem::size_of::<AmVideoContext>(),
+            mem::align_of::<AmVideoContext>(),
+            0x400,
+        ),
+        (
+            "AmVideoSetting",
+            mem::size_of::<AmVideoSetting>(),
+            mem::align_of::<AmVideoSetting>(),
+            0x14,
+        ),
+        ("AmVideoSettingV2", setting_size(2), 4, 0x24),
+    ];
+
+    let mut checks: Vec<Check> = expected
+        .iter()
+        .map(|&(name, size, align, expected)| {
+            Check::new(
+                name,
+                size == expected && align == 4,
+                format!(
+                    "{:#x} bytes aligned to {}, expected {:#x} aligned to 4",
+                    size, align, expected
+                ),
+            )
+        })
+        .collect();
+    checks.push(Check::new(
+        "AmVideoContext data",
+        AM_VIDEO_CONTEXT_DATA_SIZE + 4 == 0x400,
+        format!("{:#x} bytes after the version", AM_VIDEO_CONTEXT_DATA_SIZE),
+    ));
+
+    checks
+}
+
+/// Whether the DLL kept to the declared context, and how much of it it used
+fn context_check(name: &'static str, context: &[u8]) -> Check {
+    let size = mem::size_of::<AmVideoContext>();
+    match touched_past(context, size) {
+        Some(end) => Check::new(
+            name,
+            false,
+            format!(
+                "the DLL wrote up to {:#x} into a {:#x}-byte context, which overruns the stack \
+                 or heap of every caller",
+                end, size
+            ),
+        ),
+        None => {
+            let used = context[..size]
+                .iter()
+                .rposition(|&b| b != CANARY)
+                .map_or(0, |last| last + 1);
+            Check::new(
+                name,
+                true,
+                format!("uses {:#x} of the {:#x} bytes declared", used, size),
+            )
+        }
+    }
+}
+
+/// End of the last byte written past `size`, if any was
+fn touched_past(buffer: &[u8], size: usize) -> Option<usize> {
+    buffer[size..]
+        .iter()
+        .rposition(|&b| b != CANARY)
+        .map(|last| size + last + 1)
+}
+
+fn report(global: &GlobalOpts, checks: &[Check]) -> Result<()> {
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(checks)?),
+        OutputFormat::Text => {
+            for check in checks {
+                let status = if check.ok { "ok" } else { "FAIL" };
+                println!("[{:>4}] {}: {}", status, check.name, check.detail);
+            }
+        }
+    };
+
+    match checks.iter().filter(|check| !check.ok).count() {
+        0 => Ok(()),
+        failed => Err(anyhow!("{} of {} checks failed", failed, checks.len())),
+    }
+}
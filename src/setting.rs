@@ -0,0 +1,245 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct AmVideoSetting {
+    pub version: u32,
+    pub use_segatiming: u32,
+    pub mode: AmVideoMode,
+    pub resolution_1: AmVideoResolution,
+    pub resolution_2: AmVideoResolution,
+}
+
+/// Per-display refresh rate and rotation, which only builds taking the version 2 setting layout
+/// read. Zero leaves either at the driver default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AmVideoSettingExtras {
+    /// Refresh rates in Hz
+    pub refresh_rate_1: u32,
+    pub refresh_rate_2: u32,
+    /// Clockwise rotations in degrees
+    pub rotation_1: u32,
+    pub rotation_2: u32,
+}
+
+/// Setting layout of newer builds, which adds per-display refresh rate and rotation. Zero leaves
+/// either at the driver default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub(crate) struct AmVideoSettingV2 {
+    pub version: u32,
+    pub use_segatiming: u32,
+    pub mode: AmVideoMode,
+    pub resolution_1: AmVideoResolution,
+    pub resolution_2: AmVideoResolution,
+    pub refresh_rate_1: u32,
+    pub refresh_rate_2: u32,
+    pub rotation_1: u32,
+    pub rotation_2: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[repr(u32)]
+pub enum AmVideoMode {
+    /// Single display mode using `resolution_1`
+    #[serde(rename = "single")]
+    Single = 0,
+    /// Single or dual display mode using `resolution_1` for both displays. Does not fail if a
+    /// second display is not connected.
+    #[serde(rename = "clone")]
+    CloneVideoMode = 1,
+    /// Dual display mode using both `resolution_1` and `resolution_2`
+    #[serde(rename = "dual")]
+    DualVideoMode = 4,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AmVideoResolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Debug)]
+pub enum ParseResolutionError {
+    MissingSeparator,
+    InvalidDimension(ParseIntError),
+}
+
+// Ensure structure sizes are correct
+const_assert_eq!(mem::size_of::<AmVideoSetting>(), 0x14);
+const_assert_eq!(mem::size_of::<AmVideoSettingV2>(), 0x24);
+
+/// Size in bytes of the setting layout of `version`, as sent to `amDllVideoSetResolution`
+pub const fn setting_size(version: u32) -> usize {
+    match version {
+        2 => mem::size_of::<AmVideoSettingV2>(),
+        _ => mem::size_of::<AmVideoSetting>(),
+    }
+}
+
+impl AmVideoSetting {
+    /// Version 1 setting using SegaTiming for the given mode and resolutions
+    pub const fn new(
+        mode: AmVideoMode,
+        resolution_1: AmVideoResolution,
+        resolution_2: AmVideoResolution,
+    ) -> Self {
+        Self {
+            version: 1,
+            use_segatiming: 1,
+            mode,
+            resolution_1,
+            resolution_2,
+        }
+    }
+}
+
+impl AmVideoSettingV2 {
+    pub const fn new(setting: &AmVideoSetting, extras: &AmVideoSettingExtras) -> Self {
+        Self {
+            version: 2,
+            use_segatiming: setting.use_segatiming,
+            mode: setting.mode,
+            resolution_1: setting.resolution_1,
+            resolution_2: setting.resolution_2,
+            refresh_rate_1: extras.refresh_rate_1,
+            refresh_rate_2: extras.refresh_rate_2,
+            rotation_1: extras.rotation_1,
+            rotation_2: extras.rotation_2,
+        }
+    }
+}
+
+impl AmVideoResolution {
+    pub const fn new(width: u16, height: u16) -> Self {
+        Self { width, height }
+    }
+}
+
+impl FromStr for AmVideoResolution {
+    type Err = ParseResolutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once(['x', 'X'])
+            .ok_or(ParseResolutionError::MissingSeparator)?;
+        let width = width
+            .trim()
+            .parse()
+            .map_err(ParseResolutionError::InvalidDimension)?;
+        let height = height
+            .trim()
+            .parse()
+            .map_err(ParseResolutionError::InvalidDimension)?;
+
+        Ok(Self { width, height })
+    }
+}
+
+impl fmt::Display for AmVideoResolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+// Resolutions are written as "1920x1080" everywhere a human might read or edit them
+impl Serialize for AmVideoResolution {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AmVideoResolution {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for ParseResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingSeparator => write!(f, "expected a resolution like '1920x1080'"),
+            Self::InvalidDimension(e) => write!(f, "invalid dimension: {}", e),
+        }
+    }
+}
+
+impl Error for ParseResolutionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::MissingSeparator => None,
+            Self::InvalidDimension(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v2_layout() {
+        assert_eq!(mem::offset_of!(AmVideoSettingV2, resolution_1), 0x0c);
+        assert_eq!(mem::offset_of!(AmVideoSettingV2, refresh_rate_1), 0x14);
+        assert_eq!(mem::offset_of!(AmVideoSettingV2, refresh_rate_2), 0x18);
+        assert_eq!(mem::offset_of!(AmVideoSettingV2, rotation_1), 0x1c);
+        assert_eq!(mem::offset_of!(AmVideoSettingV2, rotation_2), 0x20);
+        assert_eq!(setting_size(2), 0x24);
+    }
+
+    #[test]
+    fn v2_carries_setting_and_extras() {
+        let mut setting = AmVideoSetting::new(
+            AmVideoMode::DualVideoMode,
+            AmVideoResolution::new(1920, 1080),
+            AmVideoResolution::new(1080, 1920),
+        );
+        setting.use_segatiming = 0;
+        let extras = AmVideoSettingExtras {
+            refresh_rate_1: 120,
+            refresh_rate_2: 60,
+            rotation_1: 0,
+            rotation_2: 90,
+        };
+
+        assert_eq!(
+            AmVideoSettingV2::new(&setting, &extras),
+            AmVideoSettingV2 {
+                version: 2,
+                use_segatiming: 0,
+                mode: AmVideoMode::DualVideoMode,
+                resolution_1: AmVideoResolution::new(1920, 1080),
+                resolution_2: AmVideoResolution::new(1080, 1920),
+                refresh_rate_1: 120,
+                refresh_rate_2: 60,
+                rotation_1: 0,
+                rotation_2: 90,
+            }
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use crate::dll_path;
+use crate::error::{AmVideoCrateError, Result};
+use crate::platform;
+use crate::registry::dll_name;
+use crate::setting::{AmVideoSetting, AmVideoSettingExtras};
+use crate::video::{AmVideo, Closed, Open, VbiosVersion};
+
+/// Cloneable handle to one loaded DLL for use from several threads. Every call takes a mutex, so
+/// the DLL never sees two at once, and the lifecycle is tracked at runtime rather than in the
+/// type; calls made in the wrong state fail with [`AmVideoCrateError::WrongState`].
+#[derive(Clone)]
+pub struct ArcAmVideo {
+    inner: Arc<Mutex<Handle>>,
+}
+
+assert_impl_all!(ArcAmVideo: Send, Sync);
+
+/// Instance handed out by [`global`], once one has been opened
+static GLOBAL: OnceLock<ArcAmVideo> = OnceLock::new();
+/// Held while loading the global instance, so concurrent first calls load the DLL only once
+static GLOBAL_INIT: Mutex<()> = Mutex::new(());
+
+/// Process-wide instance of the configured DLL, loaded and opened on first use, for hook DLLs
+/// and other embedders that would otherwise each load the vendor DLL themselves. A failed load
+/// or open is not cached, so the next call tries again. The DLL is found with
+/// [`dll_path::resolve_checked`], so one that another user could have planted is refused.
+///
+/// The instance is never dropped, so its context is not closed on its own; call
+/// [`close_open_contexts`](crate::close_open_contexts) on the way out, e.g. from
+/// `DLL_PROCESS_DETACH`.
+pub fn global() -> Result<&'static ArcAmVideo> {
+    if let Some(amvideo) = GLOBAL.get() {
+        return Ok(amvideo);
+    }
+
+    let _init = GLOBAL_INIT.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(amvideo) = GLOBAL.get() {
+        return Ok(amvideo);
+    }
+
+    let name = match dll_name() {
+        Ok(name) => name,
+        Err(e) => match platform::detect().platform.default_dll_name() {
+            Some(default) => default.into(),
+            None => return Err(e),
+        },
+    };
+    let resolved = dll_path::resolve_checked(&name)?;
+    let amvideo = ArcAmVideo::new(AmVideo::new(resolved.path)?);
+    amvideo.open()?;
+
+    Ok(GLOBAL.get_or_init(|| amvideo))
+}
+
+enum Handle {
+    Closed(AmVideo<Closed>),
+    Open(AmVideo<Open>),
+    /// Only seen while a transition is in progress, or after one panicked
+    Gone,
+}
+
+impl ArcAmVideo {
+    pub fn new(amvideo: AmVideo<Closed>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Handle::Closed(amvideo))),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Handle> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(*self.lock(), Handle::Open(_))
+    }
+
+    /// Context version used by the next `open`, or accepted by the last one
+    pub fn context_version(&self) -> Option<u32> {
+        match &*self.lock() {
+            Handle::Closed(amvideo) => Some(amvideo.context_version()),
+            Handle::Open(amvideo) => Some(amvideo.context_version()),
+            Handle::Gone => None,
+        }
+    }
+
+    /// Returns the context version the DLL accepted
+    pub fn open(&self) -> Result<u32> {
+        let mut handle = self.lock();
+        let amvideo = match mem::replace(&mut *handle, Handle::Gone) {
+            Handle::Closed(amvideo) => amvideo,
+            other => {
+                *handle = other;
+                return Err(AmVideoCrateError::WrongState { expected: "closed" });
+            }
+        };
+
+        match amvideo.open() {
+            Ok(amvideo) => {
+                let version = amvideo.context_version();
+                *handle = Handle::Open(amvideo);
+                Ok(version)
+            }
+            Err(e) => {
+                let (amvideo, source) = e.into_parts();
+                *handle = Handle::Closed(amvideo);
+                Err(source)
+            }
+        }
+    }
+
+    pub fn close(&self) -> Result<()> {
+        let mut handle = self.lock();
+        let amvideo = match mem::replace(&mut *handle, Handle::Gone) {
+            Handle::Open(amvideo) => amvideo,
+            other => {
+                *handle = other;
+                return Err(AmVideoCrateError::WrongState { expected: "open" });
+            }
+        };
+
+        match amvideo.close() {
+            Ok(amvideo) => {
+                *handle = Handle::Closed(amvideo);
+                Ok(())
+            }
+            Err(e) => {
+                let (amvideo, source) = e.into_parts();
+                *handle = Handle::Closed(amvideo);
+                Err(source)
+            }
+        }
+    }
+
+    pub fn set_resolution(
+        &self,
+        setting: &AmVideoSetting,
+        extras: &AmVideoSettingExtras,
+    ) -> Result<()> {
+        self.with_open(|amvideo| amvideo.set_resolution(setting, extras))
+    }
+
+    pub fn vbios_version(&self, buffer_size: u32) -> Result<VbiosVersion> {
+        self.with_open(|amvideo| amvideo.vbios_version(buffer_size))
+    }
+
+    /// Run `f` on the open handle with the lock held, e.g. to make several calls no other
+    /// thread may come between
+    pub fn with_open<T>(&self, f: impl FnOnce(&mut AmVideo<Open>) -> Result<T>) -> Result<T> {
+        match &mut *self.lock() {
+            Handle::Open(amvideo) => f(amvideo),
+            _ => Err(AmVideoCrateError::WrongState { expected: "open" }),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+use winapi::um::wingdi::{
+    DM_BITSPERPEL, DM_DISPLAYFREQUENCY, DM_DISPLAYORIENTATION, DM_PELSHEIGHT, DM_PELSWIDTH,
+    DM_POSITION,
+};
+
+use crate::display::{self, DisplaySettings};
+use crate::error::Result;
+
+/// Display settings captured before a mode change so they can be put back afterwards
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub displays: Vec<DisplaySettings>,
+}
+
+impl Snapshot {
+    /// Capture the settings of every display attached to the desktop
+    pub fn capture() -> Result<Self> {
+        let displays = display::attached_displays()
+            .iter()
+            .map(|device| display::current_settings(&device.name))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { displays })
+    }
+
+    /// Stage every captured display's settings, then commit them in one mode change
+    pub fn restore(&self) -> Result<()> {
+        for settings in &self.displays {
+            let mut dev_mode = display::current_dev_mode(Some(&settings.device))?;
+            dev_mode.dmPelsWidth = settings.width;
+            dev_mode.dmPelsHeight = settings.height;
+            dev_mode.dmDisplayFrequency = settings.frequency;
+            dev_mode.dmBitsPerPel = settings.bits_per_pixel;
+            unsafe {
+                let s2 = dev_mode.u1.s2_mut();
+                s2.dmPosition.x = settings.position.0;
+                s2.dmPosition.y = settings.position.1;
+                s2.dmDisplayOrientation = settings.orientation;
+            }
+            dev_mode.dmFields = DM_PELSWIDTH
+                | DM_PELSHEIGHT
+                | DM_DISPLAYFREQUENCY
+                | DM_BITSPERPEL
+                | DM_POSITION
+                | DM_DISPLAYORIENTATION;
+
+            display::stage(&settings.device, &mut dev_mode, 0)?;
+        }
+
+        display::commit()
+    }
+}
@@ -0,0 +1,406 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use amvideo::display;
+use amvideo::snapshot::Snapshot;
+use amvideo::AmVideoResolution;
+
+use crate::headless;
+use crate::trace;
+use crate::{GlobalOpts, OutputFormat};
+
+/// Last successful apply and recent attempts, kept next to the executable so `status` never
+/// needs the DLL
+const STATE_NAME: &str = "amvideo-state.json";
+/// Attempts kept in the history, oldest dropped first
+const HISTORY_LEN: usize = 100;
+
+/// VBIOS version read by this process's last open, recorded with the next apply
+static VBIOS: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Args)]
+pub struct StatusOpts {
+    /// List the recent apply attempts and their success rate and durations
+    #[arg(long)]
+    history: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    /// Last successful apply
+    pub last: Option<Applied>,
+    #[serde(default)]
+    pub history: VecDeque<Attempt>,
+    /// Custom resolutions created by applies, to remove again on restore
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub custom_resolutions: Vec<CustomResolution>,
+    /// Default audio endpoint before an apply switched it, to switch back to on restore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_endpoint: Option<String>,
+    /// G-SYNC mode before an apply changed it, to put back on restore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vrr_mode: Option<DriverSetting>,
+    /// Display settings from before the latest apply, for `revert`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stash: Option<Stash>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Stash {
+    pub timestamp_ms: u128,
+    pub snapshot: Snapshot,
+}
+
+/// Driver setting as read through NVAPI, `value` is `None` when it was at the driver default
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DriverSetting {
+    pub value: Option<u32>,
+}
+
+/// Mode added to a display's mode list through NVAPI
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CustomResolution {
+    pub display: String,
+    pub resolution: AmVideoResolution,
+    pub refresh: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Applied {
+    /// Profile applied, `None` for the built-in mode of a plain invocation
+    pub profile: Option<String>,
+    pub timestamp_ms: u128,
+    /// Mode the primary display verified at after the apply, or the headless status
+    pub verified: Option<String>,
+    /// VBIOS version the DLL reported during the apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vbios: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Attempt {
+    pub profile: Option<String>,
+    pub timestamp_ms: u128,
+    pub ok: bool,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Success rate and durations over a set of attempts
+#[derive(Debug, Default, Serialize)]
+struct Stats {
+    attempts: usize,
+    failures: usize,
+    mean_ms: u64,
+    max_ms: u64,
+}
+
+pub fn run(global: &GlobalOpts, opts: &StatusOpts) -> Result<()> {
+    let state = load()?;
+    if let OutputFormat::Json = global.output {
+        if opts.history {
+            let stats = by_profile(&state.history);
+            let report = serde_json::json!({ "history": state.history, "stats": stats });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            println!("{}", serde_json::to_string_pretty(&state.last)?);
+        }
+        return Ok(());
+    }
+
+    match &state.last {
+        Some(last) => {
+            let ago = now_ms().saturating_sub(last.timestamp_ms) / 1000;
+            println!(
+                "{} applied {}h {}m ago, running {}",
+                describe(&last.profile),
+                ago / 3600,
+                ago % 3600 / 60,
+                last.verified.as_deref().unwrap_or("an unknown mode")
+            );
+        }
+        None => println!("Nothing applied yet"),
+    }
+    if !opts.history {
+        return Ok(());
+    }
+
+    println!();
+    for attempt in &state.history {
+        let ago = now_ms().saturating_sub(attempt.timestamp_ms) / 1000;
+        println!(
+            "{:>4}h {:>2}m ago  {:<24} {:>6} ms  {}",
+            ago / 3600,
+            ago % 3600 / 60,
+            describe(&attempt.profile),
+            attempt.duration_ms,
+            match &attempt.error {
+                None => "ok".to_string(),
+                Some(error) => format!("failed: {}", error),
+            }
+        );
+    }
+    println!();
+    for (profile, stats) in by_profile(&state.history) {
+        println!(
+            "{}: {} of {} attempts failed ({:.1}%), {} ms mean, {} ms max",
+            profile,
+            stats.failures,
+            stats.attempts,
+            100.0 * stats.failures as f64 / stats.attempts as f64,
+            stats.mean_ms,
+            stats.max_ms
+        );
+    }
+
+    Ok(())
+}
+
+/// Record an apply attempt of `profile` that took `duration`, and if it succeeded, the mode it
+/// left the display in. Failing to write the file only costs `status` its data, so it is
+/// reported rather than failing the apply.
+pub fn record<T, E: fmt::Display>(
+    profile: Option<&str>,
+    result: &std::result::Result<T, E>,
+    duration: Duration,
+) {
+    // A capture changes nothing on the display
+    if trace::enabled() {
+        return;
+    }
+
+    let saved = load().and_then(|mut state| {
+        let timestamp_ms = now_ms();
+        if result.is_ok() {
+            let verified = match headless::enabled() {
+                Some(_) => Some(headless::STATUS.to_string()),
+                None => display::current_mode().ok().map(|mode| mode.to_string()),
+            };
+            // An apply that left out the mode switch never asked the DLL, and the card is the same
+            let vbios = VBIOS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take()
+                .or_else(|| state.last.take().and_then(|last| last.vbios));
+            state.last = Some(Applied {
+                profile: profile.map(str::to_string),
+                timestamp_ms,
+                verified,
+                vbios,
+            });
+        }
+
+        state.history.push_back(Attempt {
+            profile: profile.map(str::to_string),
+            timestamp_ms,
+            ok: result.is_ok(),
+            duration_ms: duration.as_millis() as u64,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+        while state.history.len() > HISTORY_LEN {
+            state.history.pop_front();
+        }
+        save(&state)
+    });
+    if let Err(e) = saved {
+        eprintln!("Failed to record the apply: {:#}", e);
+    }
+}
+
+/// Remember the VBIOS version the DLL reported, for the apply being recorded
+pub fn note_vbios(version: &str) {
+    *VBIOS.lock().unwrap_or_else(|e| e.into_inner()) = Some(version.to_string());
+}
+
+/// Remember a custom resolution to remove on restore
+pub fn add_custom_resolution(custom: CustomResolution) -> Result<()> {
+    let mut state = load()?;
+    if !state.custom_resolutions.contains(&custom) {
+        state.custom_resolutions.push(custom);
+    }
+    save(&state)
+}
+
+/// The custom resolutions to remove, forgetting them
+pub fn take_custom_resolutions() -> Result<Vec<CustomResolution>> {
+    let mut state = load()?;
+    let custom = std::mem::take(&mut state.custom_resolutions);
+    if !custom.is_empty() {
+        save(&state)?;
+    }
+    Ok(custom)
+}
+
+/// Capture the display settings before an apply changes them, replacing the previous stash.
+/// Failing only costs `revert` its data, so it is reported rather than failing the apply.
+pub fn stash() {
+    // A capture changes nothing on the display
+    if trace::enabled() {
+        return;
+    }
+
+    let saved = Snapshot::capture()
+        .context("Failed to capture the display settings")
+        .and_then(|snapshot| {
+            let mut state = load()?;
+            state.stash = Some(Stash {
+                timestamp_ms: now_ms(),
+                snapshot,
+            });
+            save(&state)
+        });
+    if let Err(e) = saved {
+        eprintln!("Failed to stash the display settings for revert: {:#}", e);
+    }
+}
+
+/// The stashed display settings, forgetting them
+pub fn take_stash() -> Result<Option<Stash>> {
+    let mut state = load()?;
+    let stash = state.stash.take();
+    if stash.is_some() {
+        save(&state)?;
+    }
+    Ok(stash)
+}
+
+/// How long ago `timestamp_ms` was, as `1h 5m`
+pub fn ago(timestamp_ms: u128) -> String {
+    let ago = now_ms().saturating_sub(timestamp_ms) / 1000;
+    format!("{}h {}m", ago / 3600, ago % 3600 / 60)
+}
+
+/// Remember the default audio endpoint to switch back to, unless an earlier apply already did.
+/// Only the first one is the user's own choice.
+pub fn note_audio_endpoint(id: &str) -> Result<()> {
+    let mut state = load()?;
+    if state.audio_endpoint.is_none() {
+        state.audio_endpoint = Some(id.to_string());
+        save(&state)?;
+    }
+    Ok(())
+}
+
+/// The audio endpoint to switch back to, forgetting it
+pub fn take_audio_endpoint() -> Result<Option<String>> {
+    let mut state = load()?;
+    let id = state.audio_endpoint.take();
+    if id.is_some() {
+        save(&state)?;
+    }
+    Ok(id)
+}
+
+/// Remember the G-SYNC mode to put back, unless an earlier apply already did
+pub fn note_vrr_mode(value: Option<u32>) -> Result<()> {
+    let mut state = load()?;
+    if state.vrr_mode.is_none() {
+        state.vrr_mode = Some(DriverSetting { value });
+        save(&state)?;
+    }
+    Ok(())
+}
+
+/// The G-SYNC mode to put back, forgetting it
+pub fn take_vrr_mode() -> Result<Option<DriverSetting>> {
+    let mut state = load()?;
+    let setting = state.vrr_mode.take();
+    if setting.is_some() {
+        save(&state)?;
+    }
+    Ok(setting)
+}
+
+/// Forget the last apply once the display is back to its starting settings, keeping the history
+pub fn clear() {
+    let result = load().and_then(|mut state| {
+        state.last = None;
+        save(&state)
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to clear the applied state: {:#}", e);
+    }
+}
+
+/// The recorded state, empty if nothing was recorded yet
+pub fn load() -> Result<State> {
+    let path = path()?;
+    match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse '{}'", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    }
+}
+
+fn save(state: &State) -> Result<()> {
+    let path = path()?;
+    let data = serde_json::to_vec_pretty(state)?;
+    fs::write(&path, data).with_context(|| format!("Failed to write '{}'", path.display()))
+}
+
+/// Stats per profile over `history`, plus `(all)` over every attempt
+fn by_profile(history: &VecDeque<Attempt>) -> BTreeMap<String, Stats> {
+    let mut stats: BTreeMap<String, Stats> = BTreeMap::new();
+    let mut total_ms: BTreeMap<String, u64> = BTreeMap::new();
+    for attempt in history {
+        let name = attempt.profile.as_deref().unwrap_or("(default)");
+        for key in [name, "(all)"] {
+            let entry = stats.entry(key.to_string()).or_default();
+            entry.attempts += 1;
+            entry.failures += usize::from(!attempt.ok);
+            entry.max_ms = entry.max_ms.max(attempt.duration_ms);
+            *total_ms.entry(key.to_string()).or_default() += attempt.duration_ms;
+        }
+    }
+    for (key, entry) in &mut stats {
+        entry.mean_ms = total_ms[key] / entry.attempts as u64;
+    }
+    stats
+}
+
+fn describe(profile: &Option<String>) -> String {
+    match profile {
+        Some(profile) => format!("'{}'", profile),
+        None => "The default mode".to_string(),
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+fn path() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    Ok(exe.with_file_name(STATE_NAME))
+}
@@ -0,0 +1,68 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+use crate::audit::{self, Event};
+
+static CONTINUE: AtomicBool = AtomicBool::new(false);
+
+/// Go on with the remaining steps of an operation after one fails from now on, as requested with
+/// `--continue-on-error`
+pub fn enable() {
+    CONTINUE.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    CONTINUE.load(Ordering::Relaxed)
+}
+
+/// Steps of one multi-step operation that failed, reported together at the end
+#[derive(Debug, Default)]
+pub struct Failures(Vec<&'static str>);
+
+impl Failures {
+    /// Run `step`, passing on its failure, or noting it and letting the operation go on under
+    /// `--continue-on-error`
+    pub fn run<F: FnOnce() -> Result<()>>(&mut self, step: &'static str, f: F) -> Result<()> {
+        match f() {
+            Err(e) if enabled() => {
+                let reason = format!("{:#}", e);
+                eprintln!("Error: {} failed, continuing: {}", step, reason);
+                audit::record(Event::StepFailed { step, reason });
+                self.0.push(step);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Fail with every step that failed, after the others were done
+    pub fn finish(self) -> Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "{} step{} failed: {}",
+            self.0.len(),
+            if self.0.len() == 1 { "" } else { "s" },
+            self.0.join(", ")
+        ))
+    }
+}
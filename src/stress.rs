@@ -0,0 +1,201 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+
+use amvideo::display::{self, DisplayMode};
+use amvideo::{AmVideoMode, AmVideoResolution, AmVideoSetting, AmVideoSettingExtras};
+
+use crate::audit::{self, Event};
+use crate::load;
+use crate::timeout::{self, Stage};
+use crate::timing::{self, CallStats};
+
+#[derive(Args)]
+pub struct StressOpts {
+    /// Number of open/apply/verify/close cycles to run
+    #[arg(short = 'n', long, default_value_t = 100)]
+    iterations: u32,
+
+    /// Resolutions to alternate between, in order
+    #[arg(
+        short,
+        long = "resolution",
+        value_name = "WIDTHxHEIGHT",
+        default_values = ["1920x1080", "1280x720"]
+    )]
+    resolutions: Vec<AmVideoResolution>,
+
+    /// Time to let the display settle after applying before verifying, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    settle_ms: u64,
+}
+
+/// Failure counters collected over a stress run
+#[derive(Debug, Default)]
+struct StressStats {
+    open_failures: u32,
+    set_failures: u32,
+    verify_failures: u32,
+    close_failures: u32,
+    driver_resets: u32,
+    open: CallStats,
+    set_resolution: CallStats,
+    close: CallStats,
+}
+
+impl StressStats {
+    fn failures(&self) -> u32 {
+        self.open_failures + self.set_failures + self.verify_failures + self.close_failures
+    }
+}
+
+pub fn run(opts: &StressOpts) -> Result<()> {
+    if opts.resolutions.is_empty() {
+        return Err(anyhow!("At least one resolution is required"));
+    }
+
+    let mut amvideo = load()?;
+    let settle = Duration::from_millis(opts.settle_ms);
+
+    let mut stats = StressStats::default();
+    let mut last_verified: Option<DisplayMode> = None;
+
+    for (i, resolution) in (0..opts.iterations).zip(opts.resolutions.iter().cycle()) {
+        let iteration = i + 1;
+
+        // A mode that differs from the one verified at the end of the previous iteration (or a
+        // display that vanished entirely) means something reset the display in between.
+        if let Some(expected) = last_verified.take() {
+            match display::current_mode() {
+                Ok(mode) if mode == expected => {}
+                Ok(mode) => {
+                    stats.driver_resets += 1;
+                    eprintln!(
+                        "[{}/{}] Driver reset detected: mode changed to {}",
+                        iteration, opts.iterations, mode
+                    );
+                }
+                Err(e) => {
+                    stats.driver_resets += 1;
+                    eprintln!(
+                        "[{}/{}] Driver reset detected: {}",
+                        iteration, opts.iterations, e
+                    );
+                }
+            };
+        }
+
+        let (result, duration) =
+            timing::time(|| timeout::watch(Stage::Dll, "amDllVideoOpen", || amvideo.open()));
+        stats.open.add(duration);
+        audit::record(Event::Open {
+            outcome: audit::outcome(result.as_ref().err().map(|e| e.error())),
+            duration_us: timing::micros(duration),
+        });
+        let mut opened = match result {
+            Ok(opened) => opened,
+            Err(e) => {
+                stats.open_failures += 1;
+                eprintln!(
+                    "[{}/{}] Open failed: {}",
+                    iteration,
+                    opts.iterations,
+                    e.error()
+                );
+                amvideo = e.into_closed();
+                continue;
+            }
+        };
+
+        let setting = AmVideoSetting::new(AmVideoMode::Single, *resolution, *resolution);
+        let (result, duration) = timing::time(|| {
+            timeout::watch(Stage::Dll, "amDllVideoSetResolution", || {
+                opened.set_resolution(&setting, &AmVideoSettingExtras::default())
+            })
+        });
+        stats.set_resolution.add(duration);
+        audit::record(Event::SetResolution {
+            setting: &setting,
+            outcome: audit::outcome(result.as_ref().err()),
+            duration_us: timing::micros(duration),
+        });
+        match result {
+            Ok(()) => {
+                thread::sleep(settle);
+
+                let result = display::verify(resolution);
+                audit::record_verify(resolution, &result);
+                match result {
+                    Ok(mode) => {
+                        println!("[{}/{}] Applied {}", iteration, opts.iterations, mode);
+                        last_verified = Some(mode);
+                    }
+                    Err(e) => {
+                        stats.verify_failures += 1;
+                        eprintln!("[{}/{}] Verify failed: {}", iteration, opts.iterations, e);
+                    }
+                };
+            }
+            Err(e) => {
+                stats.set_failures += 1;
+                eprintln!(
+                    "[{}/{}] Set resolution {} failed: {}",
+                    iteration, opts.iterations, resolution, e
+                );
+            }
+        };
+
+        let (result, duration) =
+            timing::time(|| timeout::watch(Stage::Dll, "amDllVideoClose", || opened.close()));
+        stats.close.add(duration);
+        audit::record(Event::Close {
+            outcome: audit::outcome(result.as_ref().err().map(|e| e.error())),
+            duration_us: timing::micros(duration),
+        });
+        amvideo = match result {
+            Ok(closed) => closed,
+            Err(e) => {
+                stats.close_failures += 1;
+                eprintln!(
+                    "[{}/{}] Close failed: {}",
+                    iteration,
+                    opts.iterations,
+                    e.error()
+                );
+                e.into_closed()
+            }
+        };
+    }
+
+    println!("Stress test finished: {:#?}", stats);
+    println!("amDllVideoOpen: {}", stats.open);
+    println!("amDllVideoSetResolution: {}", stats.set_resolution);
+    println!("amDllVideoClose: {}", stats.close);
+
+    match stats.failures() {
+        0 => Ok(()),
+        failures => Err(anyhow!(
+            "{} failures over {} iterations",
+            failures,
+            opts.iterations
+        )),
+    }
+}
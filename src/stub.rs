@@ -0,0 +1,598 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+
+use amvideo::pe::{DataFile, Exports};
+
+use crate::{prompt, GlobalOpts};
+
+/// Exports in ordinal order, starting at ordinal 1
+pub const EXPORTS: [&str; 4] = [
+    "amDllVideoOpen",
+    "amDllVideoClose",
+    "amDllVideoSetResolution",
+    "amDllVideoGetVBiosVersion",
+];
+
+const FILE_ALIGNMENT: usize = 0x200;
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const SECTION_RVA: u32 = SECTION_ALIGNMENT;
+
+#[derive(Args)]
+pub struct GenStubOpts {
+    /// Version string copied out by `amDllVideoGetVBiosVersion`
+    #[arg(long, default_value = "amvideo-rs stub")]
+    vbios: String,
+
+    /// Return 0 from every export. This is the default unless `--result` is given.
+    #[arg(long, conflicts_with = "results")]
+    always_succeed: bool,
+
+    /// Return CODE from one export instead of 0, e.g. `set-resolution=3`
+    #[arg(long = "result", value_name = "EXPORT=CODE")]
+    results: Vec<StubResult>,
+
+    /// Architecture of the generated DLL, which must match the process that loads it
+    #[arg(long, value_enum, default_value_t = Machine::X64)]
+    machine: Machine,
+
+    /// Forward every other export of this vendor DLL to it, by name or ordinal. The DLL must be
+    /// installed next to the stub under the same file name.
+    #[arg(long, value_name = "DLL")]
+    forward: Option<PathBuf>,
+
+    /// Where to write the DLL
+    out: PathBuf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Machine {
+    X86,
+    X64,
+}
+
+impl Machine {
+    /// `IMAGE_FILE_HEADER::Machine` value
+    const fn id(self) -> u16 {
+        match self {
+            Self::X86 => 0x014c,
+            Self::X64 => 0x8664,
+        }
+    }
+}
+
+/// Vendor DLL whose remaining exports the stub forwards to
+struct Forward {
+    /// Module name used in forwarder strings, the file name without `.dll`
+    module: String,
+    /// Exports not intercepted by the stub
+    exports: Exports,
+}
+
+/// Return code override for one export
+#[derive(Clone, Copy)]
+struct StubResult {
+    export: usize,
+    code: u32,
+}
+
+impl FromStr for StubResult {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (export, code) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected EXPORT=CODE, got '{}'", s))?;
+        let export = match export.trim() {
+            "open" => 0,
+            "close" => 1,
+            "set-resolution" => 2,
+            "vbios" => 3,
+            other => {
+                return Err(format!(
+                    "unknown export '{}', expected open, close, set-resolution, or vbios",
+                    other
+                ))
+            }
+        };
+        let code = code.trim();
+        let code = match code.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => code.parse(),
+        }
+        .map_err(|e| format!("invalid code '{}': {}", code, e))?;
+
+        Ok(Self { export, code })
+    }
+}
+
+pub fn run(global: &GlobalOpts, opts: &GenStubOpts) -> Result<()> {
+    // Usually pointed at the DLL the registry names, so don't clobber it without asking
+    if opts.out.exists() {
+        prompt(
+            global,
+            &format!("About to overwrite {} with a stub DLL", opts.out.display()),
+        )?;
+    }
+
+    let mut codes = [0; 4];
+    for result in &opts.results {
+        codes[result.export] = result.code;
+    }
+
+    let forward = opts
+        .forward
+        .as_ref()
+        .map(|path| Forward::read(path, opts.machine))
+        .transpose()?;
+
+    let image = build(
+        opts.machine,
+        &codes,
+        opts.vbios.as_bytes(),
+        forward.as_ref(),
+    );
+    fs::write(&opts.out, image)
+        .with_context(|| format!("Failed to write {}", opts.out.display()))?;
+
+    println!("Wrote stub DLL to {}", opts.out.display());
+    for (name, code) in EXPORTS.iter().zip(codes.iter()) {
+        println!("  {} returns {}", name, code);
+    }
+    if let Some(forward) = &forward {
+        println!(
+            "  {} other exports forward to {}.dll",
+            forward.exports.len(),
+            forward.module
+        );
+    }
+
+    Ok(())
+}
+
+impl Forward {
+    fn read(path: &Path, machine: Machine) -> Result<Self> {
+        let dll =
+            DataFile::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let exports = dll
+            .exports()
+            .ok_or_else(|| anyhow!("{} is not a valid DLL", path.display()))?;
+        if dll.machine() != Some(machine.id()) {
+            return Err(anyhow!(
+                "{} is built for a different architecture than --machine",
+                path.display()
+            ));
+        }
+
+        let module = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+        Ok(Self {
+            module,
+            exports: forwardable(path, exports)?,
+        })
+    }
+}
+
+/// Exports of the vendor DLL at `path` for the stub to forward. The stub's own functions take
+/// ordinals 1 to 4 and replace the vendor's by name. Anything else there, as in variants that
+/// moved the amVideo exports, moves to a free ordinal, which only works for callers that import
+/// it by name.
+fn forwardable(path: &Path, exports: Exports) -> Result<Exports> {
+    let mut next = exports
+        .iter()
+        .map(|&(ordinal, _)| ordinal)
+        .chain([EXPORTS.len() as u16])
+        .max()
+        .unwrap_or_default();
+    let mut forwarded = Vec::new();
+    for (ordinal, name) in exports {
+        if name.as_deref().is_some_and(|name| EXPORTS.contains(&name)) {
+            continue;
+        }
+        if ordinal == 0 {
+            return Err(anyhow!(
+                "{} exports a function at ordinal 0",
+                path.display()
+            ));
+        }
+        if usize::from(ordinal) > EXPORTS.len() {
+            forwarded.push((ordinal, name));
+            continue;
+        }
+
+        let stub = EXPORTS[usize::from(ordinal) - 1];
+        let name = name.ok_or_else(|| {
+            anyhow!(
+                "{} exports an unnamed function at ordinal {}, which the stub needs for {} and \
+                 which can't be forwarded under another ordinal",
+                path.display(),
+                ordinal,
+                stub
+            )
+        })?;
+        next = next
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("{} has no ordinals left to move exports to", path.display()))?;
+        eprintln!(
+            "Warning: Forwarding {} at ordinal {} instead of {}, which the stub needs for {}; only \
+             imports by name will find it",
+            name, next, ordinal, stub
+        );
+        forwarded.push((next, Some(name)));
+    }
+
+    Ok(forwarded)
+}
+
+/// Little-endian byte buffer
+#[derive(Default)]
+struct Buffer(Vec<u8>);
+
+impl Buffer {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Pointer-sized field of the optional header
+    fn usize(&mut self, machine: Machine, value: u64) {
+        match machine {
+            Machine::X86 => self.u32(value as u32),
+            Machine::X64 => self.bytes(&value.to_le_bytes()),
+        };
+    }
+
+    fn align(&mut self, alignment: usize) {
+        let padded = self.len().div_ceil(alignment) * alignment;
+        self.0.resize(padded, 0);
+    }
+
+    /// RVA of the next byte, for a buffer loaded at the start of the section
+    fn rva(&self) -> u32 {
+        SECTION_RVA + self.len() as u32
+    }
+}
+
+/// `mov eax, code; ret`, which also zero-extends into `rax` on x64
+fn return_code(code: u32) -> Vec<u8> {
+    let mut bytes = vec![0xb8];
+    bytes.extend_from_slice(&code.to_le_bytes());
+    bytes.push(0xc3);
+    bytes
+}
+
+/// Copy the NUL-terminated string at `string_rva` into `(dst, size)`, truncating to fit, then
+/// return `code`
+fn copy_string(machine: Machine, code_rva: u32, string_rva: u32, code: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match machine {
+        Machine::X64 => {
+            // lea r9, [rip + string]
+            let disp = string_rva.wrapping_sub(code_rva + 7);
+            bytes.extend_from_slice(&[0x4c, 0x8d, 0x0d]);
+            bytes.extend_from_slice(&disp.to_le_bytes());
+            bytes.extend_from_slice(&[
+                // `size` is a u32, and the ABI leaves the upper half of r8 undefined
+                0x45, 0x89, 0xc0, // mov r8d, r8d
+                0x4d, 0x85, 0xc0, // test r8, r8
+                0x74, 0x19, // jz done
+                0x49, 0xff, 0xc8, // loop: dec r8
+                0x74, 0x11, // jz term
+                0x41, 0x8a, 0x01, // mov al, [r9]
+                0x84, 0xc0, // test al, al
+                0x74, 0x0a, // jz term
+                0x88, 0x02, // mov [rdx], al
+                0x49, 0xff, 0xc1, // inc r9
+                0x48, 0xff, 0xc2, // inc rdx
+                0xeb, 0xea, // jmp loop
+                0xc6, 0x02, 0x00, // term: mov byte [rdx], 0
+            ]);
+        }
+        Machine::X86 => {
+            // cdecl with no RIP-relative addressing, so find the string relative to the return
+            // address of a call to the next instruction
+            let disp = string_rva.wrapping_sub(code_rva + 6);
+            bytes.extend_from_slice(&[
+                0x53, // push ebx
+                0xe8, 0x00, 0x00, 0x00, 0x00, // call base
+                0x58, // base: pop eax
+                0x05, // add eax, string - base
+            ]);
+            bytes.extend_from_slice(&disp.to_le_bytes());
+            bytes.extend_from_slice(&[
+                0x8b, 0x54, 0x24, 0x0c, // mov edx, [esp + 12]
+                0x8b, 0x4c, 0x24, 0x10, // mov ecx, [esp + 16]
+                0x85, 0xc9, // test ecx, ecx
+                0x74, 0x12, // jz done
+                0x49, // loop: dec ecx
+                0x74, 0x0c, // jz term
+                0x8a, 0x18, // mov bl, [eax]
+                0x84, 0xdb, // test bl, bl
+                0x74, 0x06, // jz term
+                0x88, 0x1a, // mov [edx], bl
+                0x40, // inc eax
+                0x42, // inc edx
+                0xeb, 0xf1, // jmp loop
+                0xc6, 0x02, 0x00, // term: mov byte [edx], 0
+                0x5b, // done: pop ebx
+            ]);
+        }
+    };
+    bytes.extend_from_slice(&return_code(code));
+    bytes
+}
+
+/// Lay out a single-section DLL exporting the four amVideo functions by name and ordinal, plus
+/// forwarders for the rest of `forward`'s exports
+fn build(machine: Machine, codes: &[u32; 4], vbios: &[u8], forward: Option<&Forward>) -> Vec<u8> {
+    let mut section = Buffer::default();
+
+    let string_rva = section.rva();
+    section.bytes(vbios);
+    section.bytes(&[0]);
+
+    let mut function_rvas = [0; 4];
+    for (i, &code) in codes.iter().enumerate() {
+        section.align(16);
+        function_rvas[i] = section.rva();
+        let body = if i == 3 {
+            copy_string(machine, section.rva(), string_rva, code)
+        } else {
+            return_code(code)
+        };
+        section.bytes(&body);
+    }
+
+    // Every export as (ordinal, name, target), where the target is either code in the stub or
+    // a forwarder string
+    let mut exports: Vec<(u16, Option<&str>, Result<u32, String>)> = EXPORTS
+        .iter()
+        .zip(function_rvas.iter())
+        .enumerate()
+        .map(|(i, (&name, &rva))| (i as u16 + 1, Some(name), Ok(rva)))
+        .collect();
+    if let Some(forward) = forward {
+        for (ordinal, name) in &forward.exports {
+            let target = match name {
+                Some(name) => format!("{}.{}", forward.module, name),
+                None => format!("{}.#{}", forward.module, ordinal),
+            };
+            exports.push((*ordinal, name.as_deref(), Err(target)));
+        }
+    }
+    let function_count = exports
+        .iter()
+        .map(|&(ordinal, _, _)| ordinal)
+        .max()
+        .unwrap_or(0);
+    // The loader binary searches the name table, so it must be sorted
+    let mut named: Vec<_> = exports
+        .iter()
+        .filter_map(|(ordinal, name, _)| name.map(|name| (name, *ordinal)))
+        .collect();
+    named.sort();
+
+    // Forwarders must point inside the export directory, so its strings are laid out right
+    // after the tables
+    section.align(4);
+    let export_rva = section.rva();
+    let functions_rva = export_rva + 40;
+    let names_rva = functions_rva + 4 * u32::from(function_count);
+    let ordinals_rva = names_rva + 4 * named.len() as u32;
+    let mut strings = Buffer::default();
+    let strings_rva = ordinals_rva + 2 * named.len() as u32;
+    let string = |strings: &mut Buffer, s: &str| {
+        let rva = strings_rva + strings.len() as u32;
+        strings.bytes(s.as_bytes());
+        strings.bytes(&[0]);
+        rva
+    };
+
+    let dll_name_rva = string(&mut strings, "amVideo.dll");
+    let mut function_table = vec![0; usize::from(function_count)];
+    for (ordinal, _, target) in &exports {
+        function_table[usize::from(*ordinal) - 1] = match target {
+            Ok(rva) => *rva,
+            Err(forwarder) => string(&mut strings, forwarder),
+        };
+    }
+    let name_rvas: Vec<u32> = named
+        .iter()
+        .map(|(name, _)| string(&mut strings, name))
+        .collect();
+
+    section.u32(0); // Characteristics
+    section.u32(0); // TimeDateStamp
+    section.u32(0); // MajorVersion, MinorVersion
+    section.u32(dll_name_rva);
+    section.u32(1); // Base
+    section.u32(u32::from(function_count)); // NumberOfFunctions
+    section.u32(named.len() as u32); // NumberOfNames
+    section.u32(functions_rva);
+    section.u32(names_rva);
+    section.u32(ordinals_rva);
+    for &rva in &function_table {
+        section.u32(rva);
+    }
+    for &rva in &name_rvas {
+        section.u32(rva);
+    }
+    for &(_, ordinal) in &named {
+        section.u16(ordinal - 1);
+    }
+    section.bytes(&strings.0);
+    let export_size = section.rva() - export_rva;
+
+    let virtual_size = section.len() as u32;
+    section.align(FILE_ALIGNMENT);
+    let raw_size = section.len() as u32;
+    let image_size = SECTION_RVA + virtual_size.div_ceil(SECTION_ALIGNMENT) * SECTION_ALIGNMENT;
+
+    let (characteristics, magic, image_base, dll_characteristics) = match machine {
+        // EXECUTABLE_IMAGE | 32BIT_MACHINE | DLL; DYNAMIC_BASE | NX_COMPAT
+        Machine::X86 => (0x2102, 0x010b, 0x1000_0000, 0x0140),
+        // EXECUTABLE_IMAGE | LARGE_ADDRESS_AWARE | DLL; HIGH_ENTROPY_VA | DYNAMIC_BASE | NX_COMPAT
+        Machine::X64 => (0x2022, 0x020b, 0x1_8000_0000, 0x0160),
+    };
+    let optional_header_size = match machine {
+        Machine::X86 => 0xe0,
+        Machine::X64 => 0xf0,
+    };
+
+    let mut image = Buffer::default();
+
+    // DOS header, only `e_magic` and `e_lfanew` matter
+    image.bytes(b"MZ");
+    image.0.resize(0x3c, 0);
+    image.u32(0x40);
+
+    image.bytes(b"PE\0\0");
+    image.u16(machine.id());
+    image.u16(1); // NumberOfSections
+    image.u32(0); // TimeDateStamp
+    image.u32(0); // PointerToSymbolTable
+    image.u32(0); // NumberOfSymbols
+    image.u16(optional_header_size);
+    image.u16(characteristics);
+
+    image.u16(magic);
+    image.u16(0); // Linker version
+    image.u32(raw_size); // SizeOfCode
+    image.u32(0); // SizeOfInitializedData
+    image.u32(0); // SizeOfUninitializedData
+    image.u32(0); // AddressOfEntryPoint, none needed
+    image.u32(SECTION_RVA); // BaseOfCode
+    if let Machine::X86 = machine {
+        image.u32(0); // BaseOfData
+    }
+    image.usize(machine, image_base);
+    image.u32(SECTION_ALIGNMENT);
+    image.u32(FILE_ALIGNMENT as u32);
+    image.u16(6); // MajorOperatingSystemVersion
+    image.u16(0);
+    image.u16(0); // Image version
+    image.u16(0);
+    image.u16(6); // MajorSubsystemVersion
+    image.u16(0);
+    image.u32(0); // Win32VersionValue
+    image.u32(image_size);
+    image.u32(FILE_ALIGNMENT as u32); // SizeOfHeaders
+    image.u32(0); // CheckSum
+    image.u16(2); // Subsystem: Windows GUI
+    image.u16(dll_characteristics);
+    image.usize(machine, 0x10_0000); // SizeOfStackReserve
+    image.usize(machine, 0x1000); // SizeOfStackCommit
+    image.usize(machine, 0x10_0000); // SizeOfHeapReserve
+    image.usize(machine, 0x1000); // SizeOfHeapCommit
+    image.u32(0); // LoaderFlags
+    image.u32(16); // NumberOfRvaAndSizes
+    image.u32(export_rva);
+    image.u32(export_size);
+    for _ in 1..16 {
+        image.u32(0);
+        image.u32(0);
+    }
+
+    image.bytes(b".text\0\0\0");
+    image.u32(virtual_size);
+    image.u32(SECTION_RVA);
+    image.u32(raw_size);
+    image.u32(FILE_ALIGNMENT as u32); // PointerToRawData
+    image.u32(0); // PointerToRelocations
+    image.u32(0); // PointerToLinenumbers
+    image.u16(0); // NumberOfRelocations
+    image.u16(0); // NumberOfLinenumbers
+    image.u32(0x6000_0020); // CNT_CODE | MEM_EXECUTE | MEM_READ
+
+    image.align(FILE_ALIGNMENT);
+    image.bytes(&section.0);
+
+    image.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward(exports: &[(u16, Option<&str>)]) -> Result<Exports> {
+        let exports = exports
+            .iter()
+            .map(|&(ordinal, name)| (ordinal, name.map(str::to_string)))
+            .collect();
+        forwardable(Path::new("vendor.dll"), exports)
+    }
+
+    #[test]
+    fn drops_only_stubbed_names() {
+        let exports = forward(&[
+            (1, Some("amDllVideoOpen")),
+            (2, Some("amDllVideoClose")),
+            (5, Some("amDllVideoOpenEx")),
+            (6, None),
+            (9, Some("amDllVideoSetResolution")),
+        ])
+        .unwrap();
+        assert_eq!(
+            exports,
+            [(5, Some("amDllVideoOpenEx".to_string())), (6, None)]
+        );
+    }
+
+    #[test]
+    fn moves_other_exports_off_stub_ordinals() {
+        let exports = forward(&[
+            (1, Some("amDllVideoInit")),
+            (3, Some("amDllVideoOpen")),
+            (4, Some("amDllVideoGetInfo")),
+            (7, None),
+        ])
+        .unwrap();
+        assert_eq!(
+            exports,
+            [
+                (8, Some("amDllVideoInit".to_string())),
+                (9, Some("amDllVideoGetInfo".to_string())),
+                (7, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unnamed_exports_on_stub_ordinals() {
+        assert!(forward(&[(2, None)]).is_err());
+        assert!(forward(&[(0, Some("Zero"))]).is_err());
+        assert!(forward(&[(1, Some("Init")), (u16::MAX, None)]).is_err());
+    }
+}
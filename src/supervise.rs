@@ -0,0 +1,265 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+
+use amvideo::display;
+use amvideo::snapshot::Snapshot;
+use amvideo::AmVideoMode;
+
+use crate::audit::{self, Event};
+use crate::config::Config;
+use crate::revert;
+use crate::state;
+use crate::timing;
+use crate::{apply_profile, GlobalOpts};
+
+/// Lowest NTSTATUS with error severity; processes killed by an unhandled exception exit with
+/// the exception code, e.g. 0xC0000005 for an access violation
+const STATUS_SEVERITY_ERROR: u32 = 0xC000_0000;
+
+#[derive(Args)]
+pub struct RunOpts {
+    /// Profile to apply before starting the command
+    #[arg(short, long)]
+    profile: String,
+
+    /// Leave the profile's mode active if the command crashes, so the crash can be looked at in
+    /// the state it happened in
+    #[arg(long)]
+    keep_on_crash: bool,
+
+    /// Directory to gather the audit log, the crash's WER report, and `--log` files into when
+    /// the command crashes
+    #[arg(long, value_name = "DIR")]
+    collect: Option<PathBuf>,
+
+    /// Log file of the command to gather on a crash
+    #[arg(long = "log", value_name = "PATH", requires = "collect")]
+    logs: Vec<PathBuf>,
+
+    /// Command to run, with its arguments
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+/// How the command ended
+#[derive(Debug, Serialize)]
+struct Exit {
+    command: String,
+    code: Option<i32>,
+    crashed: bool,
+    /// Windows Error Reporting report written for the crash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<PathBuf>,
+    profile: String,
+    /// Mode the primary display ran at when the command exited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+}
+
+/// Apply a profile, run a command under it, and put the displays back once it exits, unless it
+/// crashed and the operator asked for the crash context to be kept
+pub fn run(global: &GlobalOpts, opts: &RunOpts) -> Result<()> {
+    let config = Config::load(global.config.as_deref())?;
+    let profile = config.profile(&opts.profile)?;
+    let snapshot =
+        Snapshot::capture().context("Failed to capture the starting display settings")?;
+
+    let (result, duration) = timing::time(|| apply_profile(&profile));
+    state::record(Some(&opts.profile), &result, duration);
+    result?;
+
+    let (program, args) = opts.command.split_first().expect("command is required");
+    let mut command = process::Command::new(program);
+    command.args(args);
+    // The game and its hook DLLs learn the mode without querying the hardware again
+    let resolution = profile.fitted_resolution();
+    command
+        .env("AMVIDEO_PROFILE", &opts.profile)
+        .env("AMVIDEO_APPLIED_WIDTH", resolution.width.to_string())
+        .env("AMVIDEO_APPLIED_HEIGHT", resolution.height.to_string())
+        .env("AMVIDEO_APPLIED_MODE", mode_name(profile.mode));
+    if let Some(refresh) = profile.refresh_rate {
+        command.env("AMVIDEO_APPLIED_REFRESH", refresh.to_string());
+    }
+    if let Some(vbios) = state::load()?.last.and_then(|last| last.vbios) {
+        command.env("AMVIDEO_APPLIED_VBIOS", vbios);
+    }
+
+    let started = SystemTime::now();
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to start '{}'", program))?;
+
+    let report = crash_report(program, started);
+    let exit = Exit {
+        command: program.clone(),
+        code: status.code(),
+        crashed: crashed(&status) || report.is_some(),
+        report,
+        profile: opts.profile.clone(),
+        mode: display::current_mode().ok().map(|mode| mode.to_string()),
+    };
+    audit::record(Event::ChildExit {
+        command: &exit.command,
+        code: exit.code,
+        crashed: exit.crashed,
+    });
+
+    if exit.crashed {
+        eprintln!(
+            "'{}' crashed with exit code {:#010x}",
+            program,
+            exit.code.unwrap_or_default() as u32
+        );
+        if let Some(dir) = &opts.collect {
+            match collect(global, opts, &exit, dir) {
+                Ok(dir) => println!("Collected the crash context in {}", dir.display()),
+                Err(e) => eprintln!("Failed to collect the crash context: {:#}", e),
+            }
+        }
+    }
+
+    if exit.crashed && opts.keep_on_crash {
+        println!("Leaving '{}' applied for inspection", opts.profile);
+    } else {
+        let result = snapshot.restore();
+        audit::record(Event::Restore {
+            snapshot: &snapshot,
+            ok: result.is_ok(),
+        });
+        result.context("Failed to restore the starting display settings")?;
+        println!("Restored display settings");
+        revert::restore_extras()?;
+    }
+
+    match (exit.crashed, status.success()) {
+        (true, _) => Err(anyhow!("'{}' crashed", program)),
+        (false, true) => Ok(()),
+        (false, false) => Err(anyhow!("'{}' exited with {}", program, status)),
+    }
+}
+
+/// Mode as written in profiles
+fn mode_name(mode: AmVideoMode) -> &'static str {
+    match mode {
+        AmVideoMode::Single => "single",
+        AmVideoMode::CloneVideoMode => "clone",
+        AmVideoMode::DualVideoMode => "dual",
+    }
+}
+
+/// Whether the process died of an unhandled exception rather than exiting
+fn crashed(status: &ExitStatus) -> bool {
+    status
+        .code()
+        .is_some_and(|code| code as u32 >= STATUS_SEVERITY_ERROR)
+}
+
+/// The newest WER crash report for `program` written since `since`. Reports live in
+/// `AppCrash_<exe>_...` folders under the machine's and the user's WER directories.
+fn crash_report(program: &str, since: SystemTime) -> Option<PathBuf> {
+    let exe = Path::new(program)
+        .file_name()?
+        .to_string_lossy()
+        .to_lowercase();
+    let prefix = format!("appcrash_{}_", exe);
+
+    let roots = ["ProgramData", "LOCALAPPDATA"]
+        .iter()
+        .filter_map(env::var_os)
+        .map(|root| PathBuf::from(root).join("Microsoft\\Windows\\WER"));
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    for root in roots {
+        for queue in ["ReportArchive", "ReportQueue"] {
+            let entries = match fs::read_dir(root.join(queue)) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_lowercase();
+                let modified = entry.metadata().and_then(|m| m.modified()).ok();
+                let newer = |modified: SystemTime| {
+                    modified >= since && newest.as_ref().is_none_or(|(time, _)| modified > *time)
+                };
+                match modified {
+                    Some(modified) if name.starts_with(&prefix) && newer(modified) => {
+                        newest = Some((modified, entry.path()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    newest.map(|(_, path)| path)
+}
+
+/// Copy the audit log, the WER report, and the command's logs into a new folder under `dir`,
+/// along with `crash.json` describing the exit
+fn collect(global: &GlobalOpts, opts: &RunOpts, exit: &Exit, dir: &Path) -> Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let exe = Path::new(&exit.command)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let target = dir.join(format!("{}-{}", exe, timestamp));
+    fs::create_dir_all(&target)
+        .with_context(|| format!("Failed to create '{}'", target.display()))?;
+
+    fs::write(target.join("crash.json"), serde_json::to_vec_pretty(exit)?)
+        .context("Failed to write crash.json")?;
+
+    let mut files: Vec<PathBuf> = global.audit_log.iter().cloned().collect();
+    files.extend(opts.logs.iter().cloned());
+    if let Some(report) = &exit.report {
+        let target = target.join("wer");
+        fs::create_dir_all(&target)?;
+        for entry in fs::read_dir(report)?.flatten() {
+            let path = entry.path();
+            if path.is_file() {
+                copy(&path, &target.join(entry.file_name()));
+            }
+        }
+    }
+    for file in &files {
+        match file.file_name() {
+            Some(name) => copy(file, &target.join(name)),
+            None => eprintln!("Skipping '{}': not a file", file.display()),
+        }
+    }
+
+    Ok(target)
+}
+
+/// Copy one file into the collection, reporting rather than failing if it is missing
+fn copy(from: &Path, to: &Path) {
+    if let Err(e) = fs::copy(from, to) {
+        eprintln!("Failed to collect '{}': {}", from.display(), e);
+    }
+}
@@ -0,0 +1,131 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+
+use amvideo::snapshot::Snapshot;
+
+use crate::config;
+use crate::crash_report;
+use crate::doctor;
+use crate::inspect;
+use crate::state;
+use crate::version;
+use crate::zip::Archive;
+use crate::GlobalOpts;
+
+/// Config keys whose values are left out of a bundle, matched anywhere in the key
+const SECRET_KEYS: &[&str] = &["token", "password", "secret"];
+
+#[derive(Args)]
+pub struct SupportBundleOpts {
+    /// Zip file to write
+    out: PathBuf,
+}
+
+/// `amvideo support-bundle`: collect what an issue or the game's support needs to know about
+/// this machine into one zip. Each part that cannot be gathered is noted in the bundle instead.
+pub fn run(global: &GlobalOpts, opts: &SupportBundleOpts) -> Result<()> {
+    let mut archive = Archive::default();
+    let mut missing = Vec::new();
+    let mut add = |name: &str, part: Result<Vec<u8>>| match part {
+        Ok(data) => archive.add(name, data),
+        Err(e) => {
+            eprintln!("Leaving {} out: {:#}", name, e);
+            missing.push(format!("{}: {:#}", name, e));
+        }
+    };
+
+    add("doctor.json", json(&doctor::checks()));
+    add("version.json", json(&version::report(true)));
+    add(
+        "dll.json",
+        inspect::report(None, true).and_then(|report| json(&report)),
+    );
+    add("config.toml", redacted_config(global));
+    add("state.json", state::load().and_then(|state| json(&state)));
+    add(
+        "snapshot.json",
+        Snapshot::capture()
+            .context("Failed to capture the display settings")
+            .and_then(|snapshot| json(&snapshot)),
+    );
+    if let Some(path) = &global.audit_log {
+        add(
+            "audit-tail.jsonl",
+            crash_report::log_tail(path)
+                .with_context(|| format!("Failed to read '{}'", path.display())),
+        );
+    }
+    if !missing.is_empty() {
+        archive.add("missing.txt", missing.join("\n").into_bytes());
+    }
+
+    fs::write(&opts.out, archive.to_bytes())
+        .with_context(|| format!("Failed to write '{}'", opts.out.display()))?;
+    println!("Wrote support bundle to {}:", opts.out.display());
+    for (name, data) in &archive.entries {
+        println!("  {} ({} bytes)", name, data.len());
+    }
+
+    Ok(())
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec_pretty(value).map_err(Into::into)
+}
+
+/// The config with every token, password, and secret replaced, as written
+fn redacted_config(global: &GlobalOpts) -> Result<Vec<u8>> {
+    let path = match &global.config {
+        Some(path) => path.clone(),
+        None => config::default_path()?,
+    };
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config '{}'", path.display()))?;
+    // Parsed rather than copied, so secrets in comments or unparseable lines never get through
+    let mut table: toml::Table = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse config '{}'", path.display()))?;
+    redact(&mut table);
+
+    Ok(toml::to_string_pretty(&table)?.into_bytes())
+}
+
+fn redact(table: &mut toml::Table) {
+    for (key, value) in table.iter_mut() {
+        let key = key.to_lowercase();
+        if SECRET_KEYS.iter().any(|secret| key.contains(secret)) && !key.ends_with("_file") {
+            *value = toml::Value::String("<redacted>".to_string());
+            continue;
+        }
+        match value {
+            toml::Value::Table(table) => redact(table),
+            toml::Value::Array(values) => {
+                for value in values {
+                    if let toml::Value::Table(table) = value {
+                        redact(table);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use winapi::um::dbghelp::{
+    SymCleanup, SymFromNameW, SymInitializeW, SymLoadModuleExW, SYMBOL_INFOW,
+};
+use winapi::um::processthreadsapi::GetCurrentProcess;
+
+use crate::builds::Build;
+use crate::pe::DataFile;
+use crate::wide::to_wide;
+
+/// Global or function inside the amVideo DLL that amvideo reads or patches
+#[derive(Debug)]
+pub struct Target {
+    /// Undecorated name in the PDB or linker map
+    pub symbol: &'static str,
+    /// Code that references the target, for builds with neither symbols nor a build database
+    /// entry
+    pub signature: Option<Signature>,
+}
+
+/// Byte pattern around an instruction whose 32-bit operand addresses a target
+#[derive(Debug)]
+pub struct Signature {
+    /// Bytes to match, with `None` matching any byte
+    pub pattern: &'static [Option<u8>],
+    /// Offset of the operand into the match
+    pub operand: usize,
+    /// Whether the operand is relative to the end of the instruction (x64) rather than an
+    /// absolute address (x86)
+    pub relative: bool,
+    /// Length of the instruction from the operand, for relative operands
+    pub instruction_end: usize,
+}
+
+/// Where a target's address came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Pdb,
+    LinkerMap(PathBuf),
+    BuildDatabase,
+    Signature,
+}
+
+/// Target located in the loaded DLL
+#[derive(Debug)]
+pub struct Resolved {
+    pub address: usize,
+    pub source: Source,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pdb => write!(f, "PDB"),
+            Self::LinkerMap(path) => write!(f, "linker map {}", path.display()),
+            Self::BuildDatabase => write!(f, "build database"),
+            Self::Signature => write!(f, "signature"),
+        }
+    }
+}
+
+/// Locate `target` in the DLL at `path` loaded at `base`: by name from a matching PDB or a
+/// linker map next to the DLL, then from the build database's offsets, then by signature
+pub fn resolve(
+    path: &Path,
+    base: usize,
+    build: Option<&Build>,
+    target: &Target,
+) -> Option<Resolved> {
+    if let Some(address) = from_pdb(path, base, target.symbol) {
+        return Some(Resolved {
+            address,
+            source: Source::Pdb,
+        });
+    }
+
+    let map = path.with_extension("map");
+    if let Some(rva) = from_linker_map(&map, target.symbol) {
+        return Some(Resolved {
+            address: base + rva,
+            source: Source::LinkerMap(map),
+        });
+    }
+
+    if let Some(&(_, rva)) = build.and_then(|build| {
+        build
+            .offsets
+            .iter()
+            .find(|(symbol, _)| *symbol == target.symbol)
+    }) {
+        return Some(Resolved {
+            address: base + rva,
+            source: Source::BuildDatabase,
+        });
+    }
+
+    let signature = target.signature.as_ref()?;
+    let rva = DataFile::read(path)
+        .ok()
+        .and_then(|dll| from_signature(&dll, signature))?;
+    Some(Resolved {
+        address: base + rva,
+        source: Source::Signature,
+    })
+}
+
+/// Address of `symbol` from the PDB DbgHelp finds for the module, next to it or on the
+/// `_NT_SYMBOL_PATH`
+fn from_pdb(path: &Path, base: usize, symbol: &str) -> Option<usize> {
+    let process = unsafe { GetCurrentProcess() };
+    let search_path = path.parent().map(to_wide);
+    let search_path = search_path.as_ref().map_or(ptr::null(), |p| p.as_ptr());
+    if unsafe { SymInitializeW(process, search_path, 0) } == 0 {
+        return None;
+    }
+
+    let image = to_wide(path);
+    let loaded = unsafe {
+        SymLoadModuleExW(
+            process,
+            ptr::null_mut(),
+            image.as_ptr(),
+            ptr::null(),
+            base as u64,
+            0,
+            ptr::null_mut(),
+            0,
+        )
+    };
+
+    let mut address = None;
+    if loaded != 0 {
+        const MAX_NAME: usize = 256;
+        // SYMBOL_INFOW ends in a variable-length name
+        let mut buffer =
+            vec![0u64; (mem::size_of::<SYMBOL_INFOW>() + MAX_NAME * 2) / mem::size_of::<u64>() + 1];
+        let info = buffer.as_mut_ptr() as *mut SYMBOL_INFOW;
+        unsafe {
+            (*info).SizeOfStruct = mem::size_of::<SYMBOL_INFOW>() as u32;
+            (*info).MaxNameLen = MAX_NAME as u32;
+        }
+        let name = to_wide(symbol);
+        if unsafe { SymFromNameW(process, name.as_ptr(), info) } != 0 {
+            address = Some(unsafe { (*info).Address } as usize);
+        }
+    }
+
+    unsafe { SymCleanup(process) };
+    address
+}
+
+/// RVA of `symbol` in an MSVC linker map, whose publics are listed as
+/// ` 0003:000005d4  _g_name  100515d4  amVideo.obj`
+fn from_linker_map(map: &Path, symbol: &str) -> Option<usize> {
+    let contents = fs::read_to_string(map).ok()?;
+    let preferred = contents.lines().find_map(|line| {
+        let base = line.trim().strip_prefix("Preferred load address is ")?;
+        usize::from_str_radix(base.trim(), 16).ok()
+    })?;
+
+    contents.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let _section = fields.next().filter(|f| f.contains(':'))?;
+        let name = fields.next()?;
+        let address = usize::from_str_radix(fields.next()?, 16).ok()?;
+        let undecorated = name.strip_prefix('_').unwrap_or(name);
+        let matches = undecorated == symbol
+            || name
+                .strip_prefix('?')
+                .and_then(|n| n.strip_prefix(symbol))
+                .is_some_and(|rest| rest.starts_with('@'));
+        matches.then(|| address.checked_sub(preferred)).flatten()
+    })
+}
+
+/// RVA of the target referenced by the code `signature` matches
+fn from_signature(dll: &DataFile, signature: &Signature) -> Option<usize> {
+    let pattern = signature.pattern;
+    let at = dll.data.windows(pattern.len()).position(|window| {
+        window
+            .iter()
+            .zip(pattern)
+            .all(|(byte, expected)| expected.is_none_or(|expected| *byte == expected))
+    })?;
+
+    let operand_offset = at + signature.operand;
+    let operand = u32::from_le_bytes(
+        dll.data
+            .get(operand_offset..operand_offset + 4)?
+            .try_into()
+            .ok()?,
+    );
+    if signature.relative {
+        let end = dll.rva(operand_offset)? as usize + signature.instruction_end;
+        Some(end.wrapping_add(operand as i32 as isize as usize))
+    } else {
+        (operand as usize).checked_sub(dll.image_base()? as usize)
+    }
+}
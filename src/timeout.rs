@@ -0,0 +1,249 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::audit::{self, Event};
+use crate::config::TimeoutsConfig;
+
+/// Exit status when a call hangs past its limit, apart from `boot`'s stage codes
+pub const EXIT_CODE: i32 = 5;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// `--timeout`, `None` inside for no limit
+static GLOBAL: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// `[timeouts]` of the config loaded last
+static CONFIGURED: Mutex<Option<TimeoutsConfig>> = Mutex::new(None);
+
+/// Call [`abandonable`] left behind, which may still be running inside the DLL
+static ABANDONED: OnceLock<String> = OnceLock::new();
+
+thread_local! {
+    /// Set on threads run by [`abandonable`], where an overrunning call is reported to the caller
+    /// instead of ending the process
+    static ON_HANG: RefCell<Option<Hook>> = const { RefCell::new(None) };
+}
+
+type Hook = Arc<dyn Fn(TimedOut) + Send + Sync>;
+
+/// A call that was still going when its stage's limit passed
+#[derive(Debug)]
+pub struct TimedOut {
+    pub stage: Stage,
+    pub operation: &'static str,
+    pub limit: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} did not finish within the {} timeout of {:?}",
+            self.operation,
+            self.stage.name(),
+            self.limit
+        )
+    }
+}
+
+impl Error for TimedOut {}
+
+/// Kind of wait a limit applies to
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    /// Each call into the DLL, directly or through the broker
+    Dll,
+    /// Waiting for a display to be attached and driven, as under `--wait-for-display`
+    Ready,
+    /// Polling for the display to report the applied mode
+    Verify,
+    /// A control request to or from the daemon
+    Ipc,
+}
+
+impl Stage {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Dll => "dll",
+            Self::Ready => "ready",
+            Self::Verify => "verify",
+            Self::Ipc => "ipc",
+        }
+    }
+
+    const fn default_limit(self) -> Duration {
+        match self {
+            Self::Dll | Self::Ready => Duration::from_secs(60),
+            Self::Verify => Duration::from_secs(10),
+            Self::Ipc => Duration::from_secs(30),
+        }
+    }
+
+    fn configured(self, config: &TimeoutsConfig) -> Option<u64> {
+        match self {
+            Self::Dll => config.dll,
+            Self::Ready => config.ready,
+            Self::Verify => config.verify,
+            Self::Ipc => config.ipc,
+        }
+    }
+}
+
+/// Limit every stage without a limit of its own in the config to `seconds`, 0 for none, as
+/// requested with `--timeout`
+pub fn set(seconds: u64) {
+    let _ = GLOBAL.set(limit_of(seconds));
+}
+
+/// Take the per-stage limits from a loaded config
+pub fn configure(config: &TimeoutsConfig) {
+    *CONFIGURED.lock().unwrap_or_else(|e| e.into_inner()) = Some(config.clone());
+}
+
+/// How long `stage` may take, `None` for no limit
+pub fn limit(stage: Stage) -> Option<Duration> {
+    limit_or(stage, stage.default_limit())
+}
+
+/// Like [`limit`], with `default` in place of the stage's own default
+pub fn limit_or(stage: Stage, default: Duration) -> Option<Duration> {
+    let configured = CONFIGURED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|config| stage.configured(config));
+    match (configured, GLOBAL.get()) {
+        (Some(seconds), _) => limit_of(seconds),
+        (None, Some(global)) => *global,
+        (None, None) => Some(default),
+    }
+}
+
+/// Run `call`, exiting the process if it has not returned once the limit of `stage` passes. A
+/// hung DLL call or pipe read cannot be cancelled, and would otherwise hang amvideo with it.
+/// Under [`abandonable`], the overrun is reported to its caller instead.
+pub fn watch<T>(stage: Stage, operation: &'static str, call: impl FnOnce() -> T) -> T {
+    let hook = ON_HANG.with(|hook| hook.borrow().clone());
+    watch_or(
+        stage,
+        operation,
+        move |timed_out| match hook {
+            Some(hook) => hook(timed_out),
+            None => process::exit(EXIT_CODE),
+        },
+        call,
+    )
+}
+
+/// Like [`watch`], running `on_timeout` from the watchdog thread once the limit passes, e.g. to
+/// kill the process `call` waits on so it returns
+pub fn watch_or<T>(
+    stage: Stage,
+    operation: &'static str,
+    on_timeout: impl FnOnce(TimedOut) + Send + 'static,
+    call: impl FnOnce() -> T,
+) -> T {
+    let limit = match limit(stage) {
+        Some(limit) => limit,
+        None => return call(),
+    };
+
+    let (done, rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(limit) {
+            eprintln!("Error: {} did not finish within {:?}", operation, limit);
+            audit::record(Event::Timeout {
+                stage: stage.name(),
+                operation,
+                limit_ms: limit.as_millis() as u64,
+            });
+            on_timeout(TimedOut {
+                stage,
+                operation,
+                limit,
+            });
+        }
+    });
+    let result = call();
+    drop(done);
+    result
+}
+
+/// Run `work` on a thread of its own and wait for it. If a call under [`watch`] in it overruns,
+/// return [`TimedOut`] right away and leave the thread stuck in the call, so a long-running
+/// process such as the daemon outlives one hung DLL call. The DLL is not safe to enter again
+/// while that call may still be inside it, so every later `abandonable` is refused until the
+/// process restarts.
+pub fn abandonable<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    if let Some(call) = ABANDONED.get() {
+        return Err(anyhow!(
+            "Refusing to call into the DLL while an earlier call may still be running ({}), \
+             restart amvideo",
+            call
+        ));
+    }
+
+    let (tx, rx) = mpsc::channel::<Result<T>>();
+    let hung = tx.clone();
+    let hook: Hook = Arc::new(move |timed_out| {
+        // Set before the caller hears of it, so the next apply is refused
+        let _ = ABANDONED.set(timed_out.to_string());
+        let _ = hung.send(Err(timed_out.into()));
+    });
+    thread::spawn(move || {
+        ON_HANG.with(|slot| *slot.borrow_mut() = Some(hook));
+        let _ = tx.send(work());
+    });
+
+    rx.recv()
+        .unwrap_or_else(|_| Err(anyhow!("The worker thread panicked")))
+}
+
+/// Call `check` until it succeeds or `limit` passes, returning its last result. Without a limit
+/// it is called until it succeeds.
+pub fn poll<T, E>(
+    limit: Option<Duration>,
+    mut check: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let deadline = limit.map(|limit| Instant::now() + limit);
+    loop {
+        let result = check();
+        if result.is_ok() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return result;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn limit_of(seconds: u64) -> Option<Duration> {
+    match seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
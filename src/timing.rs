@@ -0,0 +1,115 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::verbose;
+
+/// `amDllVideoOpen` taking longer than this is the usual sign of a struggling driver
+const SLOW_OPEN: Duration = Duration::from_secs(2);
+
+/// Run `call`, returning its result along with how long it took
+pub fn time<T>(call: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = call();
+    (result, start.elapsed())
+}
+
+/// Whole microseconds, as durations are recorded in the audit log and JSON reports
+pub fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// Print how long a call to `export` took, to the microsecond with `--verbose`, warning when an
+/// open was slow
+pub fn report(export: &str, duration: Duration) {
+    if verbose::enabled() {
+        println!("{} returned after {:?}", export, duration);
+    } else {
+        println!("{} took {}", export, Millis(duration));
+    }
+
+    if export == "amDllVideoOpen" && duration >= SLOW_OPEN {
+        eprintln!(
+            "Warning: {} took {}, which usually means the display driver is struggling",
+            export,
+            Millis(duration)
+        );
+    }
+}
+
+/// Durations of one export over several calls
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CallStats {
+    pub calls: u32,
+    pub min_us: u64,
+    pub mean_us: u64,
+    pub max_us: u64,
+    #[serde(skip)]
+    total_us: u64,
+}
+
+impl CallStats {
+    pub fn add(&mut self, duration: Duration) {
+        let us = micros(duration);
+
+        self.min_us = if self.calls == 0 {
+            us
+        } else {
+            self.min_us.min(us)
+        };
+        self.max_us = self.max_us.max(us);
+        self.calls += 1;
+        self.total_us = self.total_us.saturating_add(us);
+        self.mean_us = self.total_us / u64::from(self.calls);
+    }
+}
+
+impl fmt::Display for CallStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.calls == 0 {
+            return f.write_str("no calls");
+        }
+
+        write!(
+            f,
+            "min {}, mean {}, max {} over {} calls",
+            Millis::from_micros(self.min_us),
+            Millis::from_micros(self.mean_us),
+            Millis::from_micros(self.max_us),
+            self.calls
+        )
+    }
+}
+
+/// Duration printed in milliseconds to one decimal
+pub struct Millis(pub Duration);
+
+impl Millis {
+    pub fn from_micros(us: u64) -> Self {
+        Self(Duration::from_micros(us))
+    }
+}
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1} ms", self.0.as_secs_f64() * 1000.0)
+    }
+}
@@ -0,0 +1,625 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use serde::{Deserialize, Serialize};
+use winapi::shared::dxgi::{
+    CreateDXGIFactory1, IDXGIAdapter1, IDXGIDevice, IDXGIFactory1, IDXGIOutput, DXGI_ADAPTER_DESC1,
+    DXGI_OUTPUT_DESC,
+};
+use winapi::shared::dxgitype::{
+    DXGI_MODE_ROTATION, DXGI_MODE_ROTATION_ROTATE180, DXGI_MODE_ROTATION_ROTATE270,
+    DXGI_MODE_ROTATION_ROTATE90,
+};
+use winapi::shared::ntdef::{LARGE_INTEGER, LUID};
+use winapi::shared::windef::HMONITOR;
+use winapi::shared::winerror::{DXGI_ERROR_NOT_FOUND, SUCCEEDED};
+use winapi::um::physicalmonitorenumerationapi::GetNumberOfPhysicalMonitorsFromHMONITOR;
+use winapi::um::wingdi::{
+    DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_COMPONENT_VIDEO,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_COMPOSITE_VIDEO,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DISPLAYPORT_EMBEDDED,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DISPLAYPORT_EXTERNAL, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DVI,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_D_JPN, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_HD15,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_HDMI, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_INDIRECT_WIRED,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_INTERNAL, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_LVDS,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_MIRACAST, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_SDI,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_SDTVDONGLE, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_SVIDEO,
+    DISPLAYCONFIG_OUTPUT_TECHNOLOGY_UDI_EMBEDDED, DISPLAYCONFIG_OUTPUT_TECHNOLOGY_UDI_EXTERNAL,
+    DISPLAYCONFIG_PATH_INFO, DISPLAYCONFIG_ROTATION_IDENTITY, DISPLAYCONFIG_ROTATION_ROTATE180,
+    DISPLAYCONFIG_ROTATION_ROTATE270, DISPLAYCONFIG_ROTATION_ROTATE90, DISPLAYCONFIG_SCALING,
+    DISPLAYCONFIG_SCALING_ASPECTRATIOCENTEREDMAX, DISPLAYCONFIG_SCALING_CENTERED,
+    DISPLAYCONFIG_SCALING_PREFERRED, DISPLAYCONFIG_SCALING_STRETCHED,
+    DISPLAYCONFIG_VIDEO_OUTPUT_TECHNOLOGY,
+};
+use winapi::Interface;
+
+use crate::ccd;
+use crate::com::{check, ComPtr};
+use crate::ddc::PhysicalMonitors;
+use crate::display;
+use crate::edid;
+use crate::error::Result;
+use crate::wide::from_wide;
+
+/// GPU as enumerated by DXGI
+#[derive(Clone, Debug, Serialize)]
+pub struct Adapter {
+    /// Index passed to `IDXGIFactory1::EnumAdapters1`
+    pub index: u32,
+    pub description: String,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub luid: Luid,
+    /// User-mode driver version, e.g. `27.21.14.5671`
+    pub driver_version: Option<String>,
+}
+
+/// Adapter-unique identifier, shared by DXGI and the display configuration database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Luid(pub u64);
+
+/// Monitor connection driven by a display source, as reported by `QueryDisplayConfig`
+#[derive(Clone, Debug, Serialize)]
+pub struct Target {
+    pub source_id: u32,
+    pub target_id: u32,
+    /// Physical connector type, e.g. `HDMI` or `DisplayPort`
+    pub connector: &'static str,
+    /// Distinguishes connectors of the same type on one adapter
+    pub connector_instance: u32,
+    pub monitor_name: String,
+    pub monitor_device_path: String,
+    /// Link the connector usually carries, at its most common revision
+    pub link: Option<Link>,
+    /// Why the monitor looks like an HDMI dummy plug or phantom display rather than a panel,
+    /// filled in by [`Topology::detect_dummies`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dummy_signs: Vec<&'static str>,
+}
+
+/// Which adapter, output, and connectors drive a display attached to the desktop
+#[derive(Clone, Debug, Serialize)]
+pub struct DisplayRoute {
+    /// GDI device name, e.g. `\\.\DISPLAY1`
+    pub device: String,
+    pub primary: bool,
+    pub adapter: Option<Adapter>,
+    /// Index passed to `IDXGIAdapter::EnumOutputs`
+    pub output: Option<u32>,
+    /// Desktop rectangle as `(left, top, right, bottom)`
+    pub desktop: Option<(i32, i32, i32, i32)>,
+    pub rotation: u32,
+    /// More than one target means the source is cloned to several monitors
+    pub targets: Vec<Target>,
+    /// Physical monitors Windows associates with the display, more than the targets for a
+    /// spanned Surround or Eyefinity group
+    pub physical_monitors: u32,
+}
+
+/// Every adapter in the system and how the attached displays map onto them
+#[derive(Clone, Debug, Serialize)]
+pub struct Topology {
+    pub adapters: Vec<Adapter>,
+    pub displays: Vec<DisplayRoute>,
+}
+
+/// Video link between the GPU and a monitor, which caps the pixel clock of the modes it carries.
+/// Windows only reports the connector, so adapters and older cable revisions can be configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Link {
+    Vga,
+    SingleLinkDvi,
+    DualLinkDvi,
+    #[serde(rename = "hdmi-1.4")]
+    Hdmi14,
+    #[serde(rename = "hdmi-2.0")]
+    Hdmi20,
+    #[serde(rename = "displayport-1.2")]
+    DisplayPort12,
+    #[serde(rename = "displayport-1.4")]
+    DisplayPort14,
+}
+
+impl Link {
+    /// Most common revision behind a connector as named by [`Target::connector`]
+    pub fn of_connector(connector: &str) -> Option<Self> {
+        match connector {
+            "VGA" => Some(Self::Vga),
+            "DVI" => Some(Self::SingleLinkDvi),
+            "HDMI" => Some(Self::Hdmi14),
+            "DisplayPort" | "eDP" => Some(Self::DisplayPort12),
+            _ => None,
+        }
+    }
+
+    /// Highest pixel clock the link carries at 8 bits per color
+    pub const fn max_pixel_clock_mhz(self) -> u32 {
+        match self {
+            Self::SingleLinkDvi => 165,
+            Self::DualLinkDvi => 330,
+            Self::Hdmi14 => 340,
+            Self::Vga => 400,
+            Self::Hdmi20 => 600,
+            Self::DisplayPort12 => 720,
+            Self::DisplayPort14 => 1080,
+        }
+    }
+
+    /// Video data rate in Gbit/s at the highest pixel clock and 24 bits per pixel
+    pub fn max_bandwidth_gbps(self) -> f64 {
+        f64::from(self.max_pixel_clock_mhz()) * 24.0 / 1000.0
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Vga => "VGA",
+            Self::SingleLinkDvi => "single-link DVI",
+            Self::DualLinkDvi => "dual-link DVI",
+            Self::Hdmi14 => "HDMI 1.4",
+            Self::Hdmi20 => "HDMI 2.0",
+            Self::DisplayPort12 => "DisplayPort 1.2",
+            Self::DisplayPort14 => "DisplayPort 1.4",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How the GPU fits a mode smaller than the panel's native one onto the panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scaling {
+    /// Scale up as far as the aspect ratio allows, with black bars on the other axis
+    AspectRatio,
+    /// Show the mode pixel for pixel in the middle of the panel
+    Centered,
+    /// Fill the whole panel, distorting modes of another aspect ratio
+    Stretch,
+    /// Whatever the driver control panel is set to
+    Preferred,
+}
+
+/// Output found on an adapter while walking DXGI
+struct DxgiOutput {
+    adapter: usize,
+    index: u32,
+    desc: DXGI_OUTPUT_DESC,
+}
+
+impl From<LUID> for Luid {
+    fn from(luid: LUID) -> Self {
+        Self((u64::from(luid.HighPart as u32) << 32) | u64::from(luid.LowPart))
+    }
+}
+
+impl fmt::Display for Luid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}:{:08x}", self.0 >> 32, self.0 & 0xffff_ffff)
+    }
+}
+
+// Written the same way as it is displayed so it can be compared against other tools' output
+impl Serialize for Luid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl DisplayRoute {
+    /// Whether the display looks like one spanned across several monitors by NVIDIA Surround or
+    /// AMD Eyefinity, which the amVideo DLL cannot address correctly
+    pub fn is_spanned(&self) -> bool {
+        if self.physical_monitors as usize > self.targets.len().max(1) {
+            return true;
+        }
+
+        // No single panel is wider than 32:9, while three 16:9 panels span 48:9
+        match self.desktop {
+            Some((left, top, right, bottom)) if bottom > top => {
+                let (width, height) = (i64::from(right - left), i64::from(bottom - top));
+                width > height * 4
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Topology {
+    /// Map every display attached to the desktop to its DXGI adapter and output, and to the
+    /// targets it drives
+    pub fn query() -> Result<Self> {
+        let (adapters, outputs) = dxgi_outputs()?;
+        let (paths, _) = ccd::active_paths()?;
+
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in &paths {
+            sources.push((ccd::source_name(path)?, path));
+        }
+
+        let displays = display::attached_displays()
+            .into_iter()
+            .map(|device| {
+                let output = outputs
+                    .iter()
+                    .find(|output| from_wide(&output.desc.DeviceName) == device.name);
+
+                let targets = sources
+                    .iter()
+                    .filter(|(name, _)| *name == device.name)
+                    .map(|(_, path)| target(path))
+                    .collect::<io::Result<_>>()?;
+
+                Ok(DisplayRoute {
+                    device: device.name,
+                    primary: device.primary,
+                    adapter: output.map(|output| adapters[output.adapter].clone()),
+                    output: output.map(|output| output.index),
+                    desktop: output.map(|output| {
+                        let rect = output.desc.DesktopCoordinates;
+                        (rect.left, rect.top, rect.right, rect.bottom)
+                    }),
+                    rotation: output.map_or(0, |output| rotation(output.desc.Rotation)),
+                    targets,
+                    physical_monitors: output
+                        .map_or(0, |output| physical_monitor_count(output.desc.Monitor)),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { adapters, displays })
+    }
+}
+
+impl Topology {
+    /// Flag monitors that look like dummy plugs or phantom displays, from their EDID and whether
+    /// they answer over DDC/CI. This probes every display, so it is slower than [`query`].
+    ///
+    /// [`query`]: Self::query
+    pub fn detect_dummies(&mut self) {
+        for display in &mut self.displays {
+            let ddc = PhysicalMonitors::open(Some(&display.device))
+                .map(|monitors| monitors.respond())
+                .unwrap_or(false);
+            for target in &mut display.targets {
+                target.dummy_signs = dummy_signs(target, ddc);
+            }
+        }
+    }
+}
+
+/// Dummy plug signs of `target`; none unless its name gives it away or two signs add up, since
+/// real panels show any one of them too
+fn dummy_signs(target: &Target, ddc: bool) -> Vec<&'static str> {
+    let info = edid::read(&target.monitor_device_path)
+        .ok()
+        .and_then(|data| edid::Info::parse(&data));
+    let info = match info {
+        Some(info) if info.has_dummy_name() => return vec!["dummy monitor name"],
+        Some(info) => info,
+        None if ddc => return Vec::new(),
+        None => return vec!["no EDID", "no DDC/CI response"],
+    };
+
+    let mut signs = Vec::new();
+    if info.name.as_deref().is_none_or(str::is_empty) {
+        signs.push("no monitor name");
+    }
+    if info.width_cm == 0 && info.height_cm == 0 {
+        signs.push("no physical size");
+    }
+    if info.serial == 0 && info.serial_text.as_deref().is_none_or(str::is_empty) {
+        signs.push("no serial number");
+    }
+    if !ddc {
+        signs.push("no DDC/CI response");
+    }
+
+    if signs.len() < 2 {
+        signs.clear();
+    }
+    signs
+}
+
+/// GDI device name of the display `selector` names, matching attached displays by device or
+/// monitor name. Unmatched device names are passed through so detached displays can be named.
+pub fn resolve_display(selector: &str) -> Result<String> {
+    let topology = Topology::query()?;
+    let found = topology.displays.into_iter().find(|route| {
+        route.device.eq_ignore_ascii_case(selector)
+            || route
+                .targets
+                .iter()
+                .any(|target| target.monitor_name.eq_ignore_ascii_case(selector))
+    });
+
+    match found {
+        Some(route) => Ok(route.device),
+        None if selector.starts_with(r"\\.\") => Ok(selector.to_string()),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No display matches '{}'", selector),
+        )
+        .into()),
+    }
+}
+
+/// Make `device` the primary display by moving the desktop origin to it, keeping every display's
+/// position relative to the others
+pub fn set_primary(device: &str) -> Result<()> {
+    let (mut paths, mut modes) = ccd::active_paths()?;
+
+    let mut origin = None;
+    for path in &paths {
+        if ccd::source_name(path)?.eq_ignore_ascii_case(device) {
+            let mode = &modes[path.sourceInfo.modeInfoIdx as usize];
+            if mode.infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE {
+                origin = Some(unsafe { mode.u.sourceMode().position });
+            }
+            break;
+        }
+    }
+    let origin = origin.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not an active display", device),
+        )
+    })?;
+    if origin.x == 0 && origin.y == 0 {
+        return Ok(());
+    }
+
+    for mode in modes
+        .iter_mut()
+        .filter(|mode| mode.infoType == DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE)
+    {
+        let position = unsafe { &mut mode.u.sourceMode_mut().position };
+        position.x -= origin.x;
+        position.y -= origin.y;
+    }
+
+    ccd::apply(&mut paths, &mut modes)?;
+    Ok(())
+}
+
+/// Move `device` to `position` on the desktop and/or rotate it clockwise by `rotation` degrees,
+/// leaving every other display alone
+pub fn place(device: &str, position: Option<(i32, i32)>, rotation: Option<u32>) -> Result<()> {
+    let rotation = match rotation {
+        None => None,
+        Some(0) => Some(DISPLAYCONFIG_ROTATION_IDENTITY),
+        Some(90) => Some(DISPLAYCONFIG_ROTATION_ROTATE90),
+        Some(180) => Some(DISPLAYCONFIG_ROTATION_ROTATE180),
+        Some(270) => Some(DISPLAYCONFIG_ROTATION_ROTATE270),
+        Some(degrees) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Rotation must be 0, 90, 180, or 270 degrees, got {}",
+                    degrees
+                ),
+            )
+            .into())
+        }
+    };
+    let is_portrait = |rotation| {
+        rotation == DISPLAYCONFIG_ROTATION_ROTATE90 || rotation == DISPLAYCONFIG_ROTATION_ROTATE270
+    };
+
+    let (mut paths, mut modes) = ccd::active_paths()?;
+
+    let mut found = false;
+    for path in paths.iter_mut() {
+        if !ccd::source_name(path)?.eq_ignore_ascii_case(device) {
+            continue;
+        }
+        found = true;
+
+        let mode = &mut modes[path.sourceInfo.modeInfoIdx as usize];
+        if mode.infoType != DISPLAYCONFIG_MODE_INFO_TYPE_SOURCE {
+            continue;
+        }
+        let source = unsafe { mode.u.sourceMode_mut() };
+
+        if let Some(rotation) = rotation {
+            // The source mode is the desktop area, which turns on its side with the panel
+            if is_portrait(rotation) != is_portrait(path.targetInfo.rotation) {
+                mem::swap(&mut source.width, &mut source.height);
+            }
+            path.targetInfo.rotation = rotation;
+        }
+        if let Some((x, y)) = position {
+            source.position.x = x;
+            source.position.y = y;
+        }
+    }
+    if !found {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not an active display", device),
+        )
+        .into());
+    }
+
+    ccd::apply(&mut paths, &mut modes)?;
+    Ok(())
+}
+
+/// Switch the GPU scaling of `device`, or of every active display if `None`. Done by the GPU
+/// driver, so it only shows while the desktop mode differs from the panel's native mode.
+pub fn set_scaling(device: Option<&str>, scaling: Scaling) -> Result<()> {
+    let scaling: DISPLAYCONFIG_SCALING = match scaling {
+        Scaling::AspectRatio => DISPLAYCONFIG_SCALING_ASPECTRATIOCENTEREDMAX,
+        Scaling::Centered => DISPLAYCONFIG_SCALING_CENTERED,
+        Scaling::Stretch => DISPLAYCONFIG_SCALING_STRETCHED,
+        Scaling::Preferred => DISPLAYCONFIG_SCALING_PREFERRED,
+    };
+
+    let (mut paths, mut modes) = ccd::active_paths()?;
+
+    let mut found = false;
+    for path in paths.iter_mut() {
+        if let Some(device) = device {
+            if !ccd::source_name(path)?.eq_ignore_ascii_case(device) {
+                continue;
+            }
+        }
+        found = true;
+        path.targetInfo.scaling = scaling;
+    }
+    if !found {
+        let what = device.unwrap_or("any display");
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not an active display", what),
+        )
+        .into());
+    }
+
+    ccd::apply(&mut paths, &mut modes)?;
+    Ok(())
+}
+
+/// Walk every DXGI adapter and its outputs
+fn dxgi_outputs() -> io::Result<(Vec<Adapter>, Vec<DxgiOutput>)> {
+    let factory = unsafe {
+        let mut factory = ptr::null_mut();
+        check(CreateDXGIFactory1(&IDXGIFactory1::uuidof(), &mut factory))?;
+        ComPtr::from_raw(factory as *mut IDXGIFactory1)
+    };
+
+    let mut adapters = Vec::new();
+    let mut outputs = Vec::new();
+
+    for index in 0.. {
+        let mut adapter: *mut IDXGIAdapter1 = ptr::null_mut();
+        let hr = unsafe { factory.EnumAdapters1(index, &mut adapter) };
+        if hr == DXGI_ERROR_NOT_FOUND {
+            break;
+        }
+        check(hr)?;
+        let adapter = unsafe { ComPtr::from_raw(adapter) };
+
+        let mut desc: DXGI_ADAPTER_DESC1 = unsafe { std::mem::zeroed() };
+        check(unsafe { adapter.GetDesc1(&mut desc) })?;
+
+        for output_index in 0.. {
+            let mut output: *mut IDXGIOutput = ptr::null_mut();
+            let hr = unsafe { adapter.EnumOutputs(output_index, &mut output) };
+            if hr == DXGI_ERROR_NOT_FOUND {
+                break;
+            }
+            check(hr)?;
+            let output = unsafe { ComPtr::from_raw(output) };
+
+            let mut output_desc: DXGI_OUTPUT_DESC = unsafe { std::mem::zeroed() };
+            check(unsafe { output.GetDesc(&mut output_desc) })?;
+
+            outputs.push(DxgiOutput {
+                adapter: adapters.len(),
+                index: output_index,
+                desc: output_desc,
+            });
+        }
+
+        // Only answers for IDXGIDevice, for which it reports the driver version instead
+        let mut umd_version: LARGE_INTEGER = unsafe { std::mem::zeroed() };
+        let hr = unsafe { adapter.CheckInterfaceSupport(&IDXGIDevice::uuidof(), &mut umd_version) };
+        let driver_version = SUCCEEDED(hr).then(|| {
+            let version = unsafe { *umd_version.QuadPart() } as u64;
+            format!(
+                "{}.{}.{}.{}",
+                version >> 48,
+                (version >> 32) & 0xffff,
+                (version >> 16) & 0xffff,
+                version & 0xffff
+            )
+        });
+
+        adapters.push(Adapter {
+            index,
+            description: from_wide(&desc.Description),
+            vendor_id: desc.VendorId,
+            device_id: desc.DeviceId,
+            luid: desc.AdapterLuid.into(),
+            driver_version,
+        });
+    }
+
+    Ok((adapters, outputs))
+}
+
+fn physical_monitor_count(monitor: HMONITOR) -> u32 {
+    let mut count = 0;
+    if unsafe { GetNumberOfPhysicalMonitorsFromHMONITOR(monitor, &mut count) } == 0 {
+        return 0;
+    }
+    count
+}
+
+fn target(path: &DISPLAYCONFIG_PATH_INFO) -> io::Result<Target> {
+    let name = ccd::target_name(path)?;
+
+    Ok(Target {
+        source_id: path.sourceInfo.id,
+        target_id: path.targetInfo.id,
+        connector: connector(name.outputTechnology),
+        connector_instance: name.connectorInstance,
+        monitor_name: from_wide(&name.monitorFriendlyDeviceName),
+        monitor_device_path: from_wide(&name.monitorDevicePath),
+        link: Link::of_connector(connector(name.outputTechnology)),
+        dummy_signs: Vec::new(),
+    })
+}
+
+fn connector(technology: DISPLAYCONFIG_VIDEO_OUTPUT_TECHNOLOGY) -> &'static str {
+    match technology {
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_HD15 => "VGA",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_SVIDEO => "S-Video",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_COMPOSITE_VIDEO => "Composite",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_COMPONENT_VIDEO => "Component",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DVI => "DVI",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_HDMI => "HDMI",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_LVDS => "LVDS",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_D_JPN => "D-Terminal",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_SDI => "SDI",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DISPLAYPORT_EXTERNAL => "DisplayPort",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DISPLAYPORT_EMBEDDED => "eDP",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_UDI_EXTERNAL => "UDI",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_UDI_EMBEDDED => "Embedded UDI",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_SDTVDONGLE => "SDTV dongle",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_MIRACAST => "Miracast",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_INDIRECT_WIRED => "Indirect",
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_INTERNAL => "Internal",
+        _ => "Other",
+    }
+}
+
+/// Clockwise rotation in degrees
+fn rotation(rotation: DXGI_MODE_ROTATION) -> u32 {
+    match rotation {
+        DXGI_MODE_ROTATION_ROTATE90 => 90,
+        DXGI_MODE_ROTATION_ROTATE180 => 180,
+        DXGI_MODE_ROTATION_ROTATE270 => 270,
+        _ => 0,
+    }
+}
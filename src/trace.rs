@@ -0,0 +1,197 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use amvideo::{AmVideoSetting, AmVideoSettingExtras, VbiosVersion};
+
+use crate::broker::{self, Call, Local, Session};
+use crate::config::Profile;
+use crate::export_map;
+use crate::timing::{self, Millis};
+use crate::{dll_to_load, version};
+
+/// Capture being recorded under `--trace`, and where it is saved
+static TRACE: Mutex<Option<(PathBuf, Capture)>> = Mutex::new(None);
+
+/// Everything a run under `--trace` asked for, in a file to attach to a bug report
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Capture {
+    pub version: String,
+    pub args: Vec<String>,
+    /// The machine the capture was taken on, as reported by `version --verbose --output json`
+    pub environment: serde_json::Value,
+    /// Profiles applied, each in full
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    /// DLL calls in the order they were requested
+    pub calls: Vec<Call>,
+}
+
+#[derive(Args)]
+pub struct ReplayOpts {
+    /// Capture file written by `--trace`
+    capture: PathBuf,
+
+    /// DLL to replay the calls against, such as a `gen-stub` DLL [default: the configured DLL]
+    #[arg(long, value_name = "PATH")]
+    dll: Option<PathBuf>,
+}
+
+/// Record into a capture saved at `path` from now on, instead of calling the DLL or changing any
+/// display settings, as requested with `--trace`
+pub fn enable(path: &Path) -> Result<()> {
+    let capture = Capture {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        args: env::args().collect(),
+        environment: serde_json::to_value(version::report(true))?,
+        profiles: Vec::new(),
+        calls: Vec::new(),
+    };
+    save(path, &capture)?;
+    *TRACE.lock().unwrap_or_else(|e| e.into_inner()) = Some((path.to_path_buf(), capture));
+
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    TRACE.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+pub fn record_profile(profile: &Profile) {
+    update(|capture| capture.profiles.push(profile.clone()));
+}
+
+fn record(call: Call) {
+    update(|capture| capture.calls.push(call));
+}
+
+/// Change the capture and save it straight away, so it survives a crash later in the run
+fn update(f: impl FnOnce(&mut Capture)) {
+    let mut trace = TRACE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((path, capture)) = trace.as_mut() {
+        f(capture);
+        if let Err(e) = save(path, capture) {
+            eprintln!("Warning: {:#}", e);
+        }
+    }
+}
+
+fn save(path: &Path, capture: &Capture) -> Result<()> {
+    let json = serde_json::to_string_pretty(capture)?;
+    fs::write(path, json).with_context(|| format!("Failed to write capture '{}'", path.display()))
+}
+
+/// Session that records every call and succeeds without loading anything
+pub struct Tracer {
+    context_version: u32,
+}
+
+impl Tracer {
+    pub fn load(dll: &str, context_version: Option<u32>) -> Self {
+        record(Call::Load {
+            dll: dll.to_string(),
+            context_version,
+            exports: export_map::get(),
+        });
+        Self {
+            context_version: context_version.unwrap_or(1),
+        }
+    }
+}
+
+impl Session for Tracer {
+    fn open(&mut self) -> Result<u32> {
+        record(Call::Open);
+        Ok(self.context_version)
+    }
+
+    fn vbios_version(&mut self, buffer: u32) -> Result<VbiosVersion> {
+        record(Call::VbiosVersion { buffer });
+        Ok(VbiosVersion {
+            version: "(traced)".to_string(),
+            raw: Vec::new(),
+            truncated: false,
+        })
+    }
+
+    fn set_resolution(
+        &mut self,
+        setting: &AmVideoSetting,
+        extras: &AmVideoSettingExtras,
+    ) -> Result<()> {
+        record(Call::SetResolution {
+            setting: setting.clone(),
+            extras: *extras,
+        });
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        record(Call::Close);
+        Ok(())
+    }
+}
+
+/// `amvideo replay`: make the calls of a capture, in order, against a DLL in this process
+pub fn run(opts: &ReplayOpts) -> Result<()> {
+    let json = fs::read_to_string(&opts.capture)
+        .with_context(|| format!("Failed to read capture '{}'", opts.capture.display()))?;
+    let capture: Capture = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse capture '{}'", opts.capture.display()))?;
+    println!(
+        "Replaying {} calls captured by amvideo {}",
+        capture.calls.len(),
+        capture.version
+    );
+
+    let dll = match &opts.dll {
+        Some(dll) => dll.as_os_str().to_owned(),
+        None => dll_to_load()?,
+    };
+    let mut session = Local::Gone;
+    for call in capture.calls {
+        // Every load goes to the replay DLL, whatever the capturing machine had configured
+        let call = match call {
+            Call::Load {
+                context_version, ..
+            } => Call::Load {
+                dll: dll.to_string_lossy().into_owned(),
+                context_version,
+                exports: export_map::get(),
+            },
+            call => call,
+        };
+        let line = serde_json::to_string(&call)?;
+        let (reply, duration) = timing::time(|| broker::handle(&mut session, call));
+        let reply = reply.with_context(|| format!("{} failed", line))?;
+        println!(
+            "{} -> {} in {}",
+            line,
+            serde_json::to_string(&reply)?,
+            Millis(duration)
+        );
+    }
+
+    Ok(())
+}
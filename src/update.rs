@@ -0,0 +1,261 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::digest;
+use crate::http;
+use crate::{prompt, GlobalOpts};
+
+#[derive(Args)]
+pub struct SelfUpdateOpts {
+    /// Release manifest to check instead of the configured `update.url`, e.g. `E:\amvideo.json`
+    #[arg(long)]
+    url: Option<String>,
+
+    /// Only report whether a newer release is available
+    #[arg(long)]
+    check: bool,
+}
+
+/// Release manifest, a JSON file next to the executable it describes
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    version: String,
+    /// Executable, relative to the manifest
+    file: String,
+    /// SHA-256 of the executable, in hex
+    sha256: String,
+    /// DER-encoded ECDSA P-256 signature of [`Manifest::signed`], in hex, as written by
+    /// `openssl dgst -sha256 -sign`
+    signature: String,
+}
+
+impl Manifest {
+    /// What the signature covers: the version and file name as well as the hash, so an old
+    /// signed release can't be passed off as a newer one
+    fn signed(&self) -> String {
+        format!(
+            "amvideo-release\nversion={}\nfile={}\nsha256={}\n",
+            self.version,
+            self.file,
+            self.sha256.trim().to_ascii_lowercase()
+        )
+    }
+}
+
+/// `file` relative to the manifest at `url`
+fn resolve(url: &str, file: &str) -> String {
+    if file.contains("://") {
+        return file.to_string();
+    }
+    match http::local_path(url) {
+        Some(path) => path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(file)
+            .display()
+            .to_string(),
+        None => match url.rfind('/') {
+            Some(end) => format!("{}/{}", &url[..end], file),
+            None => file.to_string(),
+        },
+    }
+}
+
+/// Compare dotted version numbers, missing parts counting as 0
+fn compare_versions(a: &str, b: &str) -> Result<Ordering> {
+    let parse = |version: &str| -> Result<Vec<u64>> {
+        version
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| {
+                part.parse()
+                    .with_context(|| format!("Invalid version '{}'", version))
+            })
+            .collect()
+    };
+    let (mut a, mut b) = (parse(a)?, parse(b)?);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+
+    Ok(a.cmp(&b))
+}
+
+/// Check the manifest's signature against `key`, `data` against its digest, and that it names a
+/// version newer than `current`
+fn verify(manifest: &Manifest, data: &[u8], key: &[u8; 64], current: &str) -> Result<()> {
+    let signature = digest::unhex(manifest.signature.trim())
+        .and_then(|der| digest::der_signature(&der))
+        .ok_or_else(|| anyhow!("Manifest signature is not a DER ECDSA P-256 signature"))?;
+    let signed = digest::sha256(manifest.signed().as_bytes())?;
+    if !digest::verify_p256(key, &signed, &signature)? {
+        return Err(anyhow!(
+            "Release signature does not match the configured public key"
+        ));
+    }
+
+    if !digest::hex(&digest::sha256(data)?).eq_ignore_ascii_case(manifest.sha256.trim()) {
+        return Err(anyhow!(
+            "Downloaded file does not match the manifest's SHA-256"
+        ));
+    }
+    // Checked again now that the version is known to be signed
+    if compare_versions(&manifest.version, current)? != Ordering::Greater {
+        return Err(anyhow!(
+            "Release {} is not newer than the running {}",
+            manifest.version,
+            current
+        ));
+    }
+
+    Ok(())
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Put `data` in place of the running executable. Windows lets a running executable be renamed
+/// but not deleted, so it is moved aside to `.old`, which the next update cleans up.
+fn swap(exe: &Path, data: &[u8]) -> Result<()> {
+    let new = with_suffix(exe, ".new");
+    let old = with_suffix(exe, ".old");
+
+    match fs::remove_file(&old) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to remove '{}'", old.display()))
+        }
+        _ => {}
+    }
+    fs::write(&new, data).with_context(|| format!("Failed to write '{}'", new.display()))?;
+    fs::rename(exe, &old).with_context(|| format!("Failed to move '{}' aside", exe.display()))?;
+    if let Err(e) = fs::rename(&new, exe) {
+        let _ = fs::rename(&old, exe);
+        return Err(e).with_context(|| format!("Failed to replace '{}'", exe.display()));
+    }
+
+    Ok(())
+}
+
+/// `amvideo self-update`: fetch the release manifest, and if it names a newer version, download
+/// and verify that release and replace this executable with it
+pub fn run(global: &GlobalOpts, opts: &SelfUpdateOpts) -> Result<()> {
+    let config = Config::load(global.config.as_deref())?
+        .update
+        .ok_or_else(|| anyhow!("No [update] section in the config"))?;
+    let key = digest::public_key(&config.public_key).context("Invalid update.public_key")?;
+    let url = opts
+        .url
+        .as_ref()
+        .or(config.url.as_ref())
+        .ok_or_else(|| anyhow!("No release manifest given, set update.url or pass --url"))?;
+
+    let manifest: Manifest = serde_json::from_slice(&http::fetch(url)?)
+        .with_context(|| format!("Failed to parse release manifest '{}'", url))?;
+    let current = env!("CARGO_PKG_VERSION");
+    if compare_versions(&manifest.version, current)? != Ordering::Greater {
+        println!(
+            "amvideo {} is up to date (latest is {})",
+            current, manifest.version
+        );
+        return Ok(());
+    }
+    if opts.check {
+        println!(
+            "amvideo {} is available (running {})",
+            manifest.version, current
+        );
+        return Ok(());
+    }
+
+    let file = resolve(url, &manifest.file);
+    let data = http::fetch(&file)?;
+    verify(&manifest, &data, &key, current)
+        .with_context(|| format!("Refusing to install '{}'", file))?;
+
+    let exe = env::current_exe()?;
+    prompt(
+        global,
+        &format!(
+            "About to replace amvideo {} at '{}' with {}",
+            current,
+            exe.display(),
+            manifest.version
+        ),
+    )?;
+    swap(&exe, &data)?;
+    println!("Updated to amvideo {}", manifest.version);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_versions_numerically() {
+        let cases = [
+            ("1.2.0", "1.2.0", Ordering::Equal),
+            ("1.2", "1.2.0", Ordering::Equal),
+            ("v1.2.1", "1.2.1", Ordering::Equal),
+            ("1.10.0", "1.9.0", Ordering::Greater),
+            ("1.2.0", "1.2.1", Ordering::Less),
+            ("2", "1.99.99", Ordering::Greater),
+            ("1.0.0.1", "1.0", Ordering::Greater),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(compare_versions(a, b).unwrap(), expected, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn compare_versions_rejects_non_numeric() {
+        for version in ["", "1.x", "1..2", "1.2-beta", "-1"] {
+            assert!(compare_versions(version, "1.0").is_err(), "{}", version);
+            assert!(compare_versions("1.0", version).is_err(), "{}", version);
+        }
+    }
+
+    #[test]
+    fn signed_covers_version_and_file() {
+        let manifest = Manifest {
+            version: "1.2.0".to_string(),
+            file: "amvideo.exe".to_string(),
+            sha256: " ABCDEF\n".to_string(),
+            signature: String::new(),
+        };
+        assert_eq!(
+            manifest.signed(),
+            "amvideo-release\nversion=1.2.0\nfile=amvideo.exe\nsha256=abcdef\n"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::mem;
+use std::path::Path;
+use std::ptr;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use winapi::um::winnt::LPCWSTR;
+use winapi::um::winver::{GetFileVersionInfoSizeW, GetFileVersionInfoW, VerQueryValueW};
+
+use amvideo::dll_name;
+use amvideo::pe::DataFile;
+use amvideo::platform;
+use amvideo::topology::Topology;
+use amvideo::wide::to_wide;
+
+use crate::{GlobalOpts, OutputFormat};
+
+/// `VS_FIXEDFILEINFO` from `verrsrc.h`, which winapi does not bind
+#[repr(C)]
+struct FixedFileInfo {
+    signature: u32,
+    struct_version: u32,
+    file_version_ms: u32,
+    file_version_ls: u32,
+    product_version_ms: u32,
+    product_version_ls: u32,
+    file_flags_mask: u32,
+    file_flags: u32,
+    file_os: u32,
+    file_type: u32,
+    file_subtype: u32,
+    file_date_ms: u32,
+    file_date_ls: u32,
+}
+
+/// Everything worth pasting into a bug report, in one place
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    version: &'static str,
+    commit: &'static str,
+    dll: Option<DllVersion>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dll_error: Option<String>,
+    gpus: Vec<Gpu>,
+    os: Option<String>,
+    platform: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DllVersion {
+    path: String,
+    file_version: Option<String>,
+    /// Build banner compiled into the DLL, e.g. `amVideoNvidia Build:Jan 30 2015 18:51:29`
+    banner: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Gpu {
+    description: String,
+    driver_version: Option<String>,
+}
+
+/// `FILEVERSION` from the version resource of the file at `path`
+pub fn file_version(path: &Path) -> Option<String> {
+    let name = to_wide(path);
+    let size = unsafe { GetFileVersionInfoSizeW(name.as_ptr(), ptr::null_mut()) };
+    if size == 0 {
+        return None;
+    }
+
+    let mut data = vec![0u8; size as usize];
+    if unsafe { GetFileVersionInfoW(name.as_ptr(), 0, size, data.as_mut_ptr() as *mut _) } == 0 {
+        return None;
+    }
+
+    let root: Vec<u16> = to_wide("\\");
+    let mut info = ptr::null_mut();
+    let mut length = 0;
+    let found = unsafe {
+        VerQueryValueW(
+            data.as_ptr() as *const _,
+            root.as_ptr() as LPCWSTR,
+            &mut info,
+            &mut length,
+        )
+    };
+    if found == 0 || (length as usize) < mem::size_of::<FixedFileInfo>() {
+        return None;
+    }
+
+    let info = unsafe { &*(info as *const FixedFileInfo) };
+    Some(format!(
+        "{}.{}.{}.{}",
+        info.file_version_ms >> 16,
+        info.file_version_ms & 0xffff,
+        info.file_version_ls >> 16,
+        info.file_version_ls & 0xffff
+    ))
+}
+
+/// Versions of the configured DLL, read from the file so none of its code runs
+fn dll_version() -> Result<DllVersion> {
+    let name = dll_name()?;
+    let dll = DataFile::open(&name)
+        .with_context(|| format!("Failed to find '{}'", name.to_string_lossy()))?;
+
+    Ok(DllVersion {
+        path: dll.path.display().to_string(),
+        file_version: file_version(&dll.path),
+        banner: dll.banner(),
+    })
+}
+
+/// Tool version, plus the DLL, driver, and OS if `verbose`
+pub fn report(verbose: bool) -> Report {
+    let mut report = Report {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: option_env!("AMVIDEO_COMMIT").unwrap_or("unknown"),
+        ..Report::default()
+    };
+
+    if verbose {
+        match dll_version() {
+            Ok(dll) => report.dll = Some(dll),
+            Err(e) => report.dll_error = Some(format!("{:#}", e)),
+        }
+        if let Ok(topology) = Topology::query() {
+            report.gpus = topology
+                .adapters
+                .into_iter()
+                .map(|adapter| Gpu {
+                    description: adapter.description,
+                    driver_version: adapter.driver_version,
+                })
+                .collect();
+        }
+        report.os = platform::windows_build();
+        report.platform = Some(platform::detect().platform.to_string());
+    }
+
+    report
+}
+
+/// `amvideo version`: the tool version, plus the DLL, driver, and OS with `--verbose`
+pub fn run(global: &GlobalOpts) -> Result<()> {
+    let verbose = global.verbose;
+    let report = report(verbose);
+
+    match global.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Text => print_text(&report, verbose),
+    };
+
+    Ok(())
+}
+
+fn print_text(report: &Report, verbose: bool) {
+    println!("amvideo {} (commit {})", report.version, report.commit);
+    if !verbose {
+        return;
+    }
+
+    match (&report.dll, &report.dll_error) {
+        (Some(dll), _) => {
+            println!("amVideo DLL: {}", dll.path);
+            println!(
+                "  File version: {}",
+                dll.file_version.as_deref().unwrap_or("none")
+            );
+            println!("  Build: {}", dll.banner.as_deref().unwrap_or("unknown"));
+        }
+        (None, Some(error)) => println!("amVideo DLL: {}", error),
+        (None, None) => {}
+    }
+    for gpu in &report.gpus {
+        println!(
+            "GPU: {}, driver {}",
+            gpu.description,
+            gpu.driver_version.as_deref().unwrap_or("unknown")
+        );
+    }
+    println!("OS: {}", report.os.as_deref().unwrap_or("unknown"));
+    if let Some(platform) = &report.platform {
+        println!("Platform: {}", platform);
+    }
+}
@@ -0,0 +1,729 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error as StdError;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread::{self, ThreadId};
+
+use serde::{Deserialize, Serialize};
+use winapi::ctypes::c_void;
+use winapi::shared::minwindef::FARPROC;
+
+use crate::amlib::{self, ExportMap};
+use crate::builds::{self, Build};
+use crate::error::{AmVideoCrateError, Result};
+use crate::library_handle::LibraryHandle;
+use crate::setting::{AmVideoSetting, AmVideoSettingExtras, AmVideoSettingV2};
+use crate::symbols::{self, Resolved, Target};
+
+/// VBIOS buffer size used unless one is configured, which fits most board strings
+pub const DEFAULT_VBIOS_BUFFER: u32 = 255;
+/// Largest buffer a truncated VBIOS version is retried with
+const MAX_VBIOS_BUFFER: u32 = 0x10000;
+
+/// Whether logged errors are checked against the log level
+#[cfg(not(feature = "safe-mode"))]
+const VALIDATE_LOG_LEVEL: Target = Target {
+    symbol: "g_validateLogLevel",
+    signature: None,
+};
+/// Most verbose level written to amVideo's log
+#[cfg(not(feature = "safe-mode"))]
+const LOG_LEVEL: Target = Target {
+    symbol: "g_logLevel",
+    signature: None,
+};
+
+/// Contexts opened through the safe API and not yet closed, for [`close_open_contexts`]
+static OPEN_CONTEXTS: Mutex<Vec<OpenContext>> = Mutex::new(Vec::new());
+
+struct OpenContext {
+    /// Address of the boxed `AmVideoContext`, which stays put until the handle is dropped
+    ctx: usize,
+    lib: Arc<Library>,
+    /// The handle's `Inner::closed_early`
+    closed_early: Arc<AtomicBool>,
+    thread: ThreadId,
+}
+
+pub const AM_VIDEO_CONTEXT_DATA_SIZE: usize = 0x400 - mem::size_of::<u32>();
+
+/// Opaque state the DLL keeps between calls, tagged with the layout version
+#[repr(C)]
+pub struct AmVideoContext {
+    pub version: u32,
+    pub data: [u8; AM_VIDEO_CONTEXT_DATA_SIZE],
+}
+
+// Ensure structure sizes are correct
+const_assert_eq!(mem::size_of::<AmVideoContext>(), 0x400);
+
+pub type AmDllVideoOpen = unsafe extern "C" fn(ctx: *mut AmVideoContext) -> usize;
+pub type AmDllVideoClose = unsafe extern "C" fn(ctx: *mut AmVideoContext) -> usize;
+// The setting layout depends on the build, see `Inner::setting_version`
+pub type AmDllVideoSetResolution =
+    unsafe extern "C" fn(ctx: *mut AmVideoContext, setting: *const c_void) -> usize;
+pub type AmDllVideoGetVBiosVersion =
+    unsafe extern "C" fn(ctx: *mut AmVideoContext, dst: *mut u8, size: u32) -> usize;
+
+/// Typed exports and the live context of a loaded DLL, for call sequences [`AmVideo`] does not
+/// model
+pub struct RawAmVideoExports<'a> {
+    pub open: AmDllVideoOpen,
+    pub close: AmDllVideoClose,
+    pub set_resolution: AmDllVideoSetResolution,
+    pub get_vbios_version: AmDllVideoGetVBiosVersion,
+    pub context: &'a mut AmVideoContext,
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Lifecycle state of an [`AmVideo`] handle
+pub trait State: sealed::Sealed {}
+
+/// The DLL is loaded but `amDllVideoOpen` has not been called
+#[derive(Debug)]
+pub enum Closed {}
+
+/// `amDllVideoOpen` succeeded and the context is live
+#[derive(Debug)]
+pub enum Open {}
+
+impl sealed::Sealed for Closed {}
+impl sealed::Sealed for Open {}
+impl State for Closed {}
+impl State for Open {}
+
+/// Loaded amVideo DLL and its context.
+///
+/// Some DLL builds crash when `amDllVideoSetResolution` or `amDllVideoGetVBiosVersion` are called
+/// on a context that was never opened, so those are only available on `AmVideo<Open>`.
+pub struct AmVideo<S: State = Closed> {
+    inner: Inner,
+    _state: PhantomData<S>,
+}
+
+/// Loaded DLL shared by every context created with `new_context`
+struct Library {
+    handle: LibraryHandle,
+    video_open: AmDllVideoOpen,
+    video_close: AmDllVideoClose,
+    video_set_resolution: AmDllVideoSetResolution,
+    video_get_v_bios_version: AmDllVideoGetVBiosVersion,
+    /// Held for every call into the DLL, so contexts on several threads never enter it at once
+    calls: Mutex<()>,
+}
+
+/// State shared by every lifecycle stage. Closing on drop lives here, rather than on `AmVideo`,
+/// so state transitions can move it between handles.
+struct Inner {
+    /// Shared by every context created from this one with `new_context`
+    lib: Arc<Library>,
+    // Boxed so the context address stays stable across state transitions, in case the DLL holds
+    // on to it after `amDllVideoOpen`
+    ctx: Box<AmVideoContext>,
+    opened: bool,
+    /// Set when `close_open_contexts` or `close_thread_contexts` closed the context behind the
+    /// handle's back, after which it refuses every call
+    closed_early: Arc<AtomicBool>,
+    /// Context version `open` starts from, which `new_context` contexts start from too
+    requested_version: u32,
+    /// Entry for this DLL in the build database, if it is listed
+    build: Option<&'static Build>,
+    /// `AmVideoSetting` layout to marshal for `amDllVideoSetResolution`
+    setting_version: u32,
+}
+
+// `Send` and `Sync` follow from `LibraryHandle`. Moving a handle between threads is fine since
+// nothing ties the DLL to the thread that loaded it. Sharing one is fine as well: every call into
+// the DLL holds `Library::calls`, which all contexts from `new_context` share, so its exports are
+// never entered twice at once, whichever threads the contexts live on. To call one context from
+// several threads, share an `ArcAmVideo`.
+assert_impl_all!(AmVideo<Closed>: Send, Sync);
+assert_impl_all!(AmVideo<Open>: Send, Sync);
+
+/// VBIOS version string along with the bytes it was decoded from
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VbiosVersion {
+    pub version: String,
+    /// Buffer contents up to the NUL terminator, or all of it if truncated
+    pub raw: Vec<u8>,
+    /// No NUL terminator fit even in the largest buffer tried
+    pub truncated: bool,
+}
+
+/// Failed `open` or `close`. The handle is handed back in the `Closed` state either way.
+#[derive(Debug)]
+pub struct LifecycleError {
+    action: &'static str,
+    video: AmVideo<Closed>,
+    source: AmVideoCrateError,
+}
+
+impl<S: State> AmVideo<S> {
+    const fn transition<T: State>(inner: Inner) -> AmVideo<T> {
+        AmVideo {
+            inner,
+            _state: PhantomData,
+        }
+    }
+
+    pub fn library(&self) -> &LibraryHandle {
+        &self.inner.lib.handle
+    }
+
+    /// Another context against the same loaded DLL, starting closed at the context version
+    /// requested for this one, before `open` negotiated it, e.g. one per adapter on dual-GPU
+    /// setups. Calls on the contexts take turns in the DLL. The DLL stays loaded until every
+    /// context sharing it is dropped.
+    pub fn new_context(&self) -> AmVideo<Closed> {
+        let inner = &self.inner;
+        Self::transition(Inner {
+            lib: Arc::clone(&inner.lib),
+            ctx: Box::new(AmVideoContext {
+                version: inner.requested_version,
+                data: [0; AM_VIDEO_CONTEXT_DATA_SIZE],
+            }),
+            opened: false,
+            closed_early: Arc::default(),
+            requested_version: inner.requested_version,
+            build: inner.build,
+            setting_version: inner.setting_version,
+        })
+    }
+
+    /// Build database entry matching the DLL's banner
+    pub fn build(&self) -> Option<&'static Build> {
+        self.inner.build
+    }
+
+    /// `AmVideoSetting` layout sent to `amDllVideoSetResolution`
+    pub const fn setting_version(&self) -> u32 {
+        self.inner.setting_version
+    }
+
+    /// `AmVideoContext` version, which `open` may have negotiated down from the one requested
+    pub fn context_version(&self) -> u32 {
+        self.inner.ctx.version
+    }
+
+    /// Names and addresses of the resolved DLL exports
+    pub fn exports(&self) -> [(&'static str, FARPROC); 4] {
+        [
+            ("amDllVideoOpen", self.inner.lib.video_open as FARPROC),
+            ("amDllVideoClose", self.inner.lib.video_close as FARPROC),
+            (
+                "amDllVideoSetResolution",
+                self.inner.lib.video_set_resolution as FARPROC,
+            ),
+            (
+                "amDllVideoGetVBiosVersion",
+                self.inner.lib.video_get_v_bios_version as FARPROC,
+            ),
+        ]
+    }
+
+    /// Exports whose first instruction is a jump, the usual sign of an inline hook installed by
+    /// another framework
+    pub fn hooked_exports(&self) -> Vec<&'static str> {
+        self.exports()
+            .iter()
+            .filter(|(_, func)| {
+                let code = *func as *const u8;
+                // Reading the first bytes of a resolved export is always in bounds
+                let bytes = unsafe { [*code, *code.add(1)] };
+                // jmp rel32, jmp rel8, or jmp [rip+disp32]
+                matches!(bytes, [0xE9, _] | [0xEB, _] | [0xFF, 0x25])
+            })
+            .map(|&(name, _)| name)
+            .collect()
+    }
+
+    /// Copy of the context, version included, e.g. to save it between runs
+    pub fn context_bytes(&self) -> Vec<u8> {
+        let ctx: &AmVideoContext = &self.inner.ctx;
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                ctx as *const AmVideoContext as *const u8,
+                mem::size_of::<AmVideoContext>(),
+            )
+        };
+
+        bytes.to_vec()
+    }
+
+    /// Typed function pointers and the context, bypassing the lifecycle tracking
+    ///
+    /// # Safety
+    ///
+    /// Calls through these are not tracked. Closing a context that was opened through the safe
+    /// API, or leaving one open that was not, desynchronizes the state the handle closes on
+    /// drop. The pointers and context are only valid while this handle, and so the DLL, lives.
+    /// Calls through them take no lock, so no context sharing the DLL may be used meanwhile.
+    pub unsafe fn raw_exports(&mut self) -> RawAmVideoExports<'_> {
+        let inner = &mut self.inner;
+        RawAmVideoExports {
+            open: inner.lib.video_open,
+            close: inner.lib.video_close,
+            set_resolution: inner.lib.video_set_resolution,
+            get_vbios_version: inner.lib.video_get_v_bios_version,
+            context: &mut inner.ctx,
+        }
+    }
+
+    /// Where `target` lives in this DLL, see [`symbols::resolve`]
+    pub fn resolve(&self, target: &Target) -> Option<Resolved> {
+        let path = self.inner.lib.handle.path().ok()?;
+        symbols::resolve(
+            &path,
+            *self.inner.lib.handle as usize,
+            self.inner.build,
+            target,
+        )
+    }
+
+    /// Enable amVideo's built-in error logging, returning where each global was found. Not
+    /// built with the `safe-mode` feature, as it writes to the DLL's data.
+    #[cfg(not(feature = "safe-mode"))]
+    pub fn enable_logging(&mut self) -> Result<Vec<Resolved>> {
+        let targets = [&VALIDATE_LOG_LEVEL, &LOG_LEVEL];
+        let resolved: Vec<Option<Resolved>> =
+            targets.iter().map(|target| self.resolve(target)).collect();
+        if resolved.iter().any(Option::is_none) {
+            return Err(AmVideoCrateError::Unresolved {
+                targets: targets
+                    .iter()
+                    .zip(&resolved)
+                    .filter(|(_, resolved)| resolved.is_none())
+                    .map(|(target, _)| target.symbol)
+                    .collect(),
+            });
+        }
+
+        let resolved: Vec<Resolved> = resolved.into_iter().flatten().collect();
+        for global in &resolved {
+            // Both globals are 32-bit levels inside the module's writable data
+            unsafe { *(global.address as *mut u32) = 1 };
+        }
+
+        Ok(resolved)
+    }
+}
+
+impl AmVideo<Closed> {
+    pub fn new<T: AsRef<OsStr>>(name: T) -> Result<Self> {
+        Self::with_exports(name, &ExportMap::default())
+    }
+
+    /// Load a DLL variant whose exports are not at the usual ordinals
+    pub fn with_exports<T: AsRef<OsStr>>(name: T, map: &ExportMap) -> Result<Self> {
+        let lib = amlib::load(name)?;
+
+        // get functions, bound in the order `AM_VIDEO` lists them
+        let video_open: AmDllVideoOpen;
+        let video_close: AmDllVideoClose;
+        let video_set_resolution: AmDllVideoSetResolution;
+        let video_get_v_bios_version: AmDllVideoGetVBiosVersion;
+        unsafe {
+            let funcs = amlib::bind_mapped(&lib, map)?;
+
+            video_open = mem::transmute::<FARPROC, AmDllVideoOpen>(funcs[0]);
+            video_close = mem::transmute::<FARPROC, AmDllVideoClose>(funcs[1]);
+            video_set_resolution = mem::transmute::<FARPROC, AmDllVideoSetResolution>(funcs[2]);
+            video_get_v_bios_version =
+                mem::transmute::<FARPROC, AmDllVideoGetVBiosVersion>(funcs[3]);
+        }
+
+        // Unlisted builds, or a DLL that cannot be read back, get the version-1 layout
+        let build = lib
+            .path()
+            .and_then(fs::read)
+            .ok()
+            .and_then(|image| builds::banner(&image))
+            .and_then(|banner| builds::find(&banner));
+        let setting_version = build.map_or(1, |build| build.setting_version);
+        let context_version = build.map_or(1, |build| build.context_version);
+
+        let ctx = Box::new(AmVideoContext {
+            version: context_version,
+            data: [0; AM_VIDEO_CONTEXT_DATA_SIZE],
+        });
+
+        Ok(Self::transition(Inner {
+            lib: Arc::new(Library {
+                handle: lib,
+                video_open,
+                video_close,
+                video_set_resolution,
+                video_get_v_bios_version,
+                calls: Mutex::new(()),
+            }),
+            ctx,
+            opened: false,
+            closed_early: Arc::default(),
+            requested_version: context_version,
+            build,
+            setting_version,
+        }))
+    }
+
+    /// Use a different `AmVideoContext` version than the build database's, or 1 for unlisted
+    /// builds
+    pub fn set_context_version(&mut self, version: u32) {
+        self.inner.ctx.version = version;
+        self.inner.requested_version = version;
+    }
+
+    /// Take over a context saved by an earlier run, see [`AmVideo::context_bytes`], as though
+    /// `amDllVideoOpen` had just filled it in, without calling it. Whether the DLL tolerates a
+    /// resumed context is build-specific, and finding out is what this is for.
+    pub fn resume(self, saved: &[u8]) -> Result<AmVideo<Open>, LifecycleError> {
+        let mut inner = self.inner;
+        if saved.len() != mem::size_of::<AmVideoContext>() {
+            return Err(LifecycleError {
+                action: "resume",
+                video: Self::transition(inner),
+                source: AmVideoCrateError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "saved context is {} bytes instead of {}",
+                        saved.len(),
+                        mem::size_of::<AmVideoContext>()
+                    ),
+                )),
+            });
+        }
+
+        let (version, data) = saved.split_at(mem::size_of::<u32>());
+        inner.ctx.version = u32::from_le_bytes([version[0], version[1], version[2], version[3]]);
+        inner.ctx.data.copy_from_slice(data);
+        inner.track_open();
+
+        Ok(Self::transition(inner))
+    }
+
+    /// Open the context, stepping the context version down each time the build reports that it
+    /// does not support it
+    pub fn open(self) -> Result<AmVideo<Open>, LifecycleError> {
+        let mut inner = self.inner;
+        let bad_version_codes = inner.build.map_or(&[][..], |build| build.bad_version_codes);
+
+        loop {
+            let result = {
+                let _call = inner.lib.lock_calls();
+                unsafe { (inner.lib.video_open)(&mut *inner.ctx) }
+            };
+            if result == 0 {
+                inner.track_open();
+                return Ok(Self::transition(inner));
+            }
+
+            if inner.ctx.version > 1 && bad_version_codes.contains(&result) {
+                inner.ctx.version -= 1;
+                inner.ctx.data = [0; AM_VIDEO_CONTEXT_DATA_SIZE];
+                continue;
+            }
+
+            return Err(LifecycleError {
+                action: "open",
+                video: Self::transition(inner),
+                source: AmVideoCrateError::DllCall { code: result },
+            });
+        }
+    }
+}
+
+impl AmVideo<Open> {
+    /// Fails with [`AmVideoCrateError::WrongState`] if the context was already closed by
+    /// [`close_open_contexts`] or [`close_thread_contexts`], handing back the closed handle
+    pub fn close(self) -> Result<AmVideo<Closed>, LifecycleError> {
+        let mut inner = self.inner;
+        if let Err(e) = inner.check_open() {
+            inner.opened = false;
+            return Err(LifecycleError {
+                action: "close",
+                video: Self::transition(inner),
+                source: e,
+            });
+        }
+
+        match inner.close() {
+            Ok(()) => Ok(Self::transition(inner)),
+            Err(e) => Err(LifecycleError {
+                action: "close",
+                video: Self::transition(inner),
+                source: e,
+            }),
+        }
+    }
+
+    /// Apply `setting`, along with `extras` for builds taking the version 2 layout
+    pub fn set_resolution(
+        &mut self,
+        setting: &AmVideoSetting,
+        extras: &AmVideoSettingExtras,
+    ) -> Result<()> {
+        let inner = &mut self.inner;
+        inner.check_open()?;
+        let _call = inner.lib.lock_calls();
+
+        let result = match inner.setting_version {
+            2 => {
+                let setting = AmVideoSettingV2::new(setting, extras);
+                unsafe {
+                    (inner.lib.video_set_resolution)(
+                        &mut *inner.ctx,
+                        &setting as *const AmVideoSettingV2 as *const c_void,
+                    )
+                }
+            }
+            _ => unsafe {
+                (inner.lib.video_set_resolution)(
+                    &mut *inner.ctx,
+                    setting as *const AmVideoSetting as *const c_void,
+                )
+            },
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(AmVideoCrateError::DllCall { code: result })
+        }
+    }
+
+    pub fn get_vbios_version(&mut self) -> Result<String> {
+        let vbios = self.vbios_version(DEFAULT_VBIOS_BUFFER)?;
+
+        let version = str::from_utf8(&vbios.raw).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to interpret VBIOS version string as UTF-8: {}", e),
+            )
+        })?;
+        Ok(version.to_string())
+    }
+
+    /// Read the VBIOS version into a `buffer_size` buffer, doubling it while the string fills
+    /// the buffer without a NUL terminator
+    pub fn vbios_version(&mut self, buffer_size: u32) -> Result<VbiosVersion> {
+        let inner = &mut self.inner;
+        inner.check_open()?;
+
+        let mut size = buffer_size.max(1);
+        loop {
+            let mut data = vec![0; size as usize];
+            let result = {
+                let _call = inner.lib.lock_calls();
+                unsafe {
+                    (inner.lib.video_get_v_bios_version)(&mut *inner.ctx, data.as_mut_ptr(), size)
+                }
+            };
+            if result != 0 {
+                return Err(AmVideoCrateError::DllCall { code: result });
+            }
+
+            let terminator = data.iter().position(|&c| c == 0);
+            if terminator.is_none() && size < MAX_VBIOS_BUFFER {
+                size = size.saturating_mul(2).min(MAX_VBIOS_BUFFER);
+                continue;
+            }
+
+            data.truncate(terminator.unwrap_or(data.len()));
+            return Ok(VbiosVersion {
+                version: String::from_utf8_lossy(&data).into_owned(),
+                raw: data,
+                truncated: terminator.is_none(),
+            });
+        }
+    }
+}
+
+impl Inner {
+    /// Mark the context open, tracking it for `close_open_contexts`
+    fn track_open(&mut self) {
+        self.opened = true;
+        self.closed_early = Arc::default();
+        lock_open_contexts().push(OpenContext {
+            ctx: &*self.ctx as *const AmVideoContext as usize,
+            lib: Arc::clone(&self.lib),
+            closed_early: Arc::clone(&self.closed_early),
+            thread: thread::current().id(),
+        });
+    }
+
+    /// Refuse calls on a context closed behind the handle's back, which the DLL would take as a
+    /// live one
+    fn check_open(&self) -> Result<()> {
+        if self.closed_early.load(Ordering::Acquire) {
+            return Err(AmVideoCrateError::WrongState { expected: "open" });
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.opened = false;
+
+        // Already closed by `close_open_contexts`
+        let ctx = &*self.ctx as *const AmVideoContext as usize;
+        let mut open = lock_open_contexts();
+        let before = open.len();
+        open.retain(|open| open.ctx != ctx);
+        if open.len() == before {
+            return Ok(());
+        }
+        drop(open);
+
+        let result = {
+            let _call = self.lib.lock_calls();
+            unsafe { (self.lib.video_close)(&mut *self.ctx) }
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(AmVideoCrateError::DllCall { code: result })
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.opened {
+            if thread::panicking() {
+                eprintln!("Closing amVideo context left open by a panic");
+            }
+            if let Err(e) = self.close() {
+                eprintln!("Failed to close amVideo: {}", e);
+            }
+        }
+    }
+}
+
+fn lock_open_contexts() -> MutexGuard<'static, Vec<OpenContext>> {
+    OPEN_CONTEXTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Library {
+    fn lock_calls(&self) -> MutexGuard<'_, ()> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Close every context opened through the safe API that is still open, logging each. Dropping
+/// the handle closes its context, but nothing is dropped when a panic aborts the process or on
+/// `process::exit`, and some builds leak driver state for a context that is never closed. Meant
+/// for a panic hook. Every later call on the handles fails with
+/// [`AmVideoCrateError::WrongState`], so they are only good for dropping afterwards.
+pub fn close_open_contexts() {
+    close_contexts(false, |_| true);
+}
+
+/// Like [`close_open_contexts`], but only for contexts opened on the calling thread, leaving
+/// those other threads are still using alone
+pub fn close_thread_contexts() {
+    let current = thread::current().id();
+    close_contexts(true, |context| context.thread == current);
+}
+
+/// Close the open contexts `filter` picks. Unless `wait`, a context whose DLL is in a call on
+/// another thread is left open rather than waited for, as that thread may never return.
+fn close_contexts(wait: bool, filter: impl Fn(&OpenContext) -> bool) {
+    // A panic while the list was locked would deadlock here, so leave the contexts in that case
+    let closing: Vec<OpenContext> = match OPEN_CONTEXTS.try_lock() {
+        Ok(mut open) => open.extract_if(.., |context| filter(context)).collect(),
+        Err(TryLockError::Poisoned(e)) => e
+            .into_inner()
+            .extract_if(.., |context| filter(context))
+            .collect(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    for context in closing {
+        let _call = match context.lib.calls.try_lock() {
+            Ok(call) => call,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) if wait => context.lib.lock_calls(),
+            Err(TryLockError::WouldBlock) => {
+                eprintln!(
+                    "Leaving amVideo context at {:#x} open, the DLL is busy on another thread",
+                    context.ctx
+                );
+                continue;
+            }
+        };
+        context.closed_early.store(true, Ordering::Release);
+        eprintln!("Closing amVideo context left open at {:#x}", context.ctx);
+        let result = unsafe { (context.lib.video_close)(context.ctx as *mut AmVideoContext) };
+        if result != 0 {
+            eprintln!(
+                "Failed to close amVideo: {}",
+                AmVideoCrateError::DllCall { code: result }
+            );
+        }
+    }
+}
+
+impl<S: State> fmt::Debug for AmVideo<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AmVideo")
+            .field("lib", &self.inner.lib.handle)
+            .field("opened", &self.inner.opened)
+            .finish()
+    }
+}
+
+impl LifecycleError {
+    pub const fn error(&self) -> &AmVideoCrateError {
+        &self.source
+    }
+
+    /// Recover the handle so the operation can be retried
+    pub fn into_closed(self) -> AmVideo<Closed> {
+        self.video
+    }
+
+    /// Recover the handle along with the error, unchanged
+    pub fn into_parts(self) -> (AmVideo<Closed>, AmVideoCrateError) {
+        (self.video, self.source)
+    }
+}
+
+impl fmt::Display for LifecycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to {} amVideo", self.action)
+    }
+}
+
+impl From<LifecycleError> for AmVideoCrateError {
+    fn from(e: LifecycleError) -> Self {
+        e.source
+    }
+}
+
+impl StdError for LifecycleError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.source)
+    }
+}
@@ -0,0 +1,112 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use amvideo::display;
+
+use crate::config::{Config, VirtualDisplayConfig};
+use crate::GlobalOpts;
+
+#[derive(Args)]
+pub struct VirtualDisplayOpts {
+    #[command(subcommand)]
+    action: VirtualDisplayAction,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Subcommand)]
+#[serde(rename_all = "kebab-case")]
+pub enum VirtualDisplayAction {
+    /// Add a virtual monitor, e.g. as the second display of a dual mode title
+    Create,
+    /// Remove the virtual monitor again
+    Destroy,
+}
+
+pub fn run(global: &GlobalOpts, opts: &VirtualDisplayOpts) -> Result<()> {
+    let config = Config::load(global.config.as_deref())?;
+
+    match opts.action {
+        VirtualDisplayAction::Create => {
+            let device = create(configured(&config)?)?;
+            println!("Created virtual display {}", device);
+        }
+        VirtualDisplayAction::Destroy => {
+            destroy(configured(&config)?)?;
+            println!("Removed the virtual display");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn configured(config: &Config) -> Result<&VirtualDisplayConfig> {
+    config.virtual_display.as_ref().ok_or_else(|| {
+        anyhow!("No virtual display driver is configured, add a [virtual_display] section")
+    })
+}
+
+/// Add the virtual monitor and wait for Windows to attach it to the desktop, returning its GDI
+/// device name
+pub fn create(config: &VirtualDisplayConfig) -> Result<String> {
+    let before: Vec<String> = display::attached_displays()
+        .into_iter()
+        .map(|display| display.name)
+        .collect();
+
+    invoke(&config.tool, &config.create)?;
+
+    let deadline = Instant::now() + Duration::from_secs(config.timeout);
+    loop {
+        let added = display::attached_displays()
+            .into_iter()
+            .find(|display| !before.contains(&display.name));
+        if let Some(display) = added {
+            return Ok(display.name);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "No display attached within {} seconds of adding the virtual monitor",
+                config.timeout
+            ));
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+pub fn destroy(config: &VirtualDisplayConfig) -> Result<()> {
+    invoke(&config.tool, &config.destroy)
+}
+
+fn invoke(tool: &Path, args: &[String]) -> Result<()> {
+    let status = Command::new(tool)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run '{}'", tool.display()))?;
+    if !status.success() {
+        return Err(anyhow!("'{}' exited with {}", tool.display(), status));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result};
+
+use amvideo::nvapi::{NvApi, VrrMode};
+
+use crate::state;
+
+/// Set the driver-wide G-SYNC mode, remembering the one it replaces. Saving the driver's global
+/// profile needs elevation.
+pub fn switch(mode: VrrMode) -> Result<()> {
+    let nvapi = NvApi::load().context("Failed to load NVAPI")?;
+    let previous = nvapi
+        .vrr_mode_value()
+        .context("Failed to read the G-SYNC mode")?;
+    state::note_vrr_mode(previous).context("Failed to record the G-SYNC mode to restore")?;
+    nvapi
+        .set_vrr_mode(mode)
+        .context("Failed to set G-SYNC mode")?;
+    println!("Set G-SYNC mode to {:?}", mode);
+    Ok(())
+}
+
+/// Put back the G-SYNC mode from before the first apply that changed it. The setting outlives
+/// the process in the driver profile, so a failure is reported.
+pub fn restore() {
+    let setting = match state::take_vrr_mode() {
+        Ok(Some(setting)) => setting,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Failed to read the G-SYNC mode to restore: {:#}", e);
+            return;
+        }
+    };
+
+    match NvApi::load().and_then(|nvapi| nvapi.restore_vrr_mode(setting.value)) {
+        Ok(()) => println!("Restored the G-SYNC mode"),
+        Err(e) => eprintln!("Failed to restore the G-SYNC mode: {}", e),
+    }
+}
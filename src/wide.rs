@@ -0,0 +1,30 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::ffi::OsStr;
+use std::iter;
+use std::os::windows::ffi::OsStrExt;
+
+/// NUL-terminated UTF-16 copy of `s` for passing to `W` functions
+pub fn to_wide<S: AsRef<OsStr> + ?Sized>(s: &S) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(iter::once(0)).collect()
+}
+
+/// Convert a fixed-size, NUL-padded UTF-16 buffer to a `String`
+pub fn from_wide(buf: &[u16]) -> String {
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf16_lossy(&buf[..len])
+}
@@ -0,0 +1,259 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryInto;
+use std::io;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+
+/// Minimal ZIP archive with stored (uncompressed) entries, which every unzip tool can open.
+/// Bundles are a few small text files and ICC profiles, so compression isn't worth a dependency.
+#[derive(Debug, Default)]
+pub struct Archive {
+    pub entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Archive {
+    pub fn add(&mut self, name: &str, data: Vec<u8>) {
+        self.entries.push((name.to_string(), data));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+
+        for (name, data) in &self.entries {
+            let offset = out.len() as u32;
+            let crc = crc32(data);
+
+            put_u32(&mut out, LOCAL_HEADER);
+            put_common(&mut out, name, data, crc);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(data);
+
+            put_u32(&mut central, CENTRAL_HEADER);
+            put_u16(&mut central, 20); // version made by
+            put_common(&mut central, name, data, crc);
+            put_u16(&mut central, 0); // comment length
+            put_u16(&mut central, 0); // disk number
+            put_u16(&mut central, 0); // internal attributes
+            put_u32(&mut central, 0); // external attributes
+            put_u32(&mut central, offset);
+            central.extend_from_slice(name.as_bytes());
+        }
+
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+
+        put_u32(&mut out, END_OF_CENTRAL_DIRECTORY);
+        put_u16(&mut out, 0); // this disk
+        put_u16(&mut out, 0); // disk with the central directory
+        put_u16(&mut out, self.entries.len() as u16);
+        put_u16(&mut out, self.entries.len() as u16);
+        put_u32(&mut out, central.len() as u32);
+        put_u32(&mut out, central_offset);
+        put_u16(&mut out, 0); // comment length
+
+        out
+    }
+
+    /// Read an archive of stored entries, such as one written by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        // The end record is the last 22 bytes unless the archive has a comment
+        let end = (0..=data.len().saturating_sub(22))
+            .rev()
+            .find(|&i| u32_at(data, i) == Some(END_OF_CENTRAL_DIRECTORY))
+            .ok_or_else(|| invalid("missing end of central directory"))?;
+        let count = u16_at(data, end + 10).ok_or_else(|| invalid("truncated"))?;
+        let mut pos = u32_at(data, end + 16).ok_or_else(|| invalid("truncated"))? as usize;
+
+        let mut archive = Self::default();
+        for _ in 0..count {
+            if u32_at(data, pos) != Some(CENTRAL_HEADER) {
+                return Err(invalid("bad central directory entry"));
+            }
+            let field = |offset| u16_at(data, pos + offset).ok_or_else(|| invalid("truncated"));
+            let method = field(10)?;
+            let size = u32_at(data, pos + 20).ok_or_else(|| invalid("truncated"))? as usize;
+            let name_len = usize::from(field(28)?);
+            let extra_len = usize::from(field(30)?);
+            let comment_len = usize::from(field(32)?);
+            let offset = u32_at(data, pos + 42).ok_or_else(|| invalid("truncated"))? as usize;
+            let name = data
+                .get(pos + 46..pos + 46 + name_len)
+                .ok_or_else(|| invalid("truncated"))?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            if method != 0 {
+                return Err(invalid(&format!("'{}' is compressed", name)));
+            }
+
+            let local_name_len = u16_at(data, offset + 26).ok_or_else(|| invalid("truncated"))?;
+            let local_extra_len = u16_at(data, offset + 28).ok_or_else(|| invalid("truncated"))?;
+            let start = offset + 30 + usize::from(local_name_len) + usize::from(local_extra_len);
+            let contents = data
+                .get(start..start + size)
+                .ok_or_else(|| invalid("truncated"))?
+                .to_vec();
+            if crc32(&contents) != u32_at(data, pos + 16).unwrap_or_default() {
+                return Err(invalid(&format!("'{}' is corrupt", name)));
+            }
+
+            archive.entries.push((name, contents));
+            pos += 46 + name_len + extra_len + comment_len;
+        }
+
+        Ok(archive)
+    }
+}
+
+/// Header fields shared by local and central directory records, from "version needed" to the
+/// extra field length
+fn put_common(out: &mut Vec<u8>, name: &str, data: &[u8], crc: u32) {
+    put_u16(out, 20); // version needed
+    put_u16(out, 1 << 11); // flags: UTF-8 names
+    put_u16(out, 0); // method: stored
+    put_u16(out, 0); // modification time
+    put_u16(out, 0x21); // modification date: 1980-01-01
+    put_u32(out, crc);
+    put_u32(out, data.len() as u32);
+    put_u32(out, data.len() as u32);
+    put_u16(out, name.len() as u16);
+    put_u16(out, 0); // extra field length
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid bundle archive: {}", message),
+    )
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Archive {
+        let mut archive = Archive::default();
+        archive.add("manifest.json", b"{\"version\": 1}".to_vec());
+        archive.add("files/calibration.icc", (0..=255).collect());
+        archive.add("empty", Vec::new());
+        archive
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let archive = sample();
+        let read = Archive::from_bytes(&archive.to_bytes()).unwrap();
+        assert_eq!(read.entries, archive.entries);
+        assert_eq!(
+            read.get("files/calibration.icc").map(<[u8]>::len),
+            Some(256)
+        );
+        assert_eq!(read.get("empty"), Some(&[][..]));
+        assert_eq!(read.get("missing"), None);
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let read = Archive::from_bytes(&Archive::default().to_bytes()).unwrap();
+        assert!(read.entries.is_empty());
+    }
+
+    #[test]
+    fn rejects_corrupt_contents() {
+        let mut data = sample().to_bytes();
+        // First byte of the first entry's contents, after its 30-byte header and name
+        data[30 + "manifest.json".len()] ^= 1;
+        let error = Archive::from_bytes(&data).unwrap_err();
+        assert!(error.to_string().contains("corrupt"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_compressed_entries() {
+        let mut data = sample().to_bytes();
+        let central = u32_at(&data, data.len() - 6).unwrap() as usize;
+        data[central + 10] = 8;
+        let error = Archive::from_bytes(&data).unwrap_err();
+        assert!(error.to_string().contains("compressed"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let data = sample().to_bytes();
+        for len in 0..data.len() {
+            assert!(Archive::from_bytes(&data[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_offsets() {
+        let data = sample().to_bytes();
+        let end = data.len() - 22;
+
+        let mut bad_directory = data.clone();
+        bad_directory[end + 16..end + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Archive::from_bytes(&bad_directory).is_err());
+
+        let mut bad_count = data;
+        bad_count[end + 10..end + 12].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(Archive::from_bytes(&bad_count).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+/// Name the stub is written under, and the DLL the binary is pointed at
+pub const STUB: &str = "amVideo.dll";
+
+/// A scratch directory holding a copy of the binary, so the state file, crash reports, and
+/// default config it keeps next to itself stay separate between tests
+pub struct Cab {
+    dir: PathBuf,
+    exe: PathBuf,
+}
+
+impl Cab {
+    pub fn new(name: &str) -> Self {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create the test directory");
+
+        let built = Path::new(env!("CARGO_BIN_EXE_amvideo"));
+        let exe = dir.join(built.file_name().unwrap());
+        fs::copy(built, &exe).expect("Failed to copy the binary");
+
+        Self { dir, exe }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Run the binary with `args`, pointed at the stub rather than the registry's DLL and with
+    /// no terminal to prompt on
+    pub fn run(&self, args: &[&str]) -> Output {
+        Command::new(&self.exe)
+            .args(args)
+            .current_dir(&self.dir)
+            .env("AMVIDEO_DLL", self.path(STUB))
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to run the binary")
+    }
+
+    /// Write the stub DLL with `gen-stub`, passing `args` through
+    pub fn stub(&self, args: &[&str]) {
+        let machine = if cfg!(target_pointer_width = "32") {
+            "x86"
+        } else {
+            "x64"
+        };
+        let out = self.path(STUB);
+        let mut all = vec!["gen-stub", "--yes", "--machine", machine];
+        all.extend_from_slice(args);
+        all.push(out.to_str().unwrap());
+
+        let output = self.run(&all);
+        assert_success(&output);
+        assert!(out.is_file(), "gen-stub did not write {}", out.display());
+    }
+}
+
+impl Drop for Cab {
+    fn drop(&mut self) {
+        // Left behind on a failure, to look at
+        if !thread::panicking() {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+pub fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "exited with {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        stdout(output),
+        stderr(output)
+    );
+}
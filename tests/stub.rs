@@ -0,0 +1,143 @@
+// amVideo-rs
+// Copyright (C) 2020  Matt Bilker <me@mbilker.us>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+mod common;
+
+use serde_json::Value;
+
+use common::{assert_success, stderr, stdout, Cab};
+
+#[test]
+fn headless_apply_drives_the_stub() {
+    let cab = Cab::new("headless_apply_drives_the_stub");
+    cab.stub(&["--always-succeed"]);
+
+    let output = cab.run(&["--headless", "--yes"]);
+    assert_success(&output);
+    let printed = stdout(&output);
+    for export in [
+        "amDllVideoOpen",
+        "amDllVideoClose",
+        "amDllVideoSetResolution",
+    ] {
+        assert!(
+            printed.contains(&format!("Loaded {}", export)),
+            "{}",
+            printed
+        );
+    }
+    assert!(
+        printed.contains("Done: applied-unverified (headless)"),
+        "{}",
+        printed
+    );
+
+    let output = cab.run(&["status"]);
+    assert_success(&output);
+    assert!(!stdout(&output).contains("Nothing applied yet"));
+}
+
+#[test]
+fn isolated_apply_drives_the_stub() {
+    let cab = Cab::new("isolated_apply_drives_the_stub");
+    cab.stub(&["--always-succeed"]);
+
+    let output = cab.run(&["--headless", "--isolate", "--yes"]);
+    assert_success(&output);
+    assert!(stdout(&output).contains("Done: applied-unverified (headless)"));
+}
+
+#[test]
+fn failed_call_is_reported_with_its_code() {
+    let cab = Cab::new("failed_call_is_reported_with_its_code");
+    cab.stub(&["--result", "set-resolution=3"]);
+
+    let output = cab.run(&["--headless", "--yes", "--output", "json"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    let failure: Value = serde_json::from_str(stderr(&output).trim_end().lines().last().unwrap())
+        .expect("stderr should end in a JSON failure");
+    assert_eq!(failure["class"], "dll-call");
+    assert_eq!(failure["code"], 3);
+
+    let output = cab.run(&["--output", "json", "status", "--history"]);
+    assert_success(&output);
+    let report: Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert!(report["history"][0]["error"].is_string(), "{}", report);
+}
+
+#[test]
+fn missing_dll_fails_before_any_call() {
+    let cab = Cab::new("missing_dll_fails_before_any_call");
+
+    let output = cab.run(&["--headless", "--yes"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("does not exist"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!stdout(&output).contains("Loaded"));
+}
+
+#[test]
+fn apply_refuses_to_prompt_without_a_terminal() {
+    let cab = Cab::new("apply_refuses_to_prompt_without_a_terminal");
+    cab.stub(&["--always-succeed"]);
+
+    let output = cab.run(&["--headless"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("pass --yes"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!stdout(&output).contains("Loaded"));
+}
+
+#[test]
+fn inspect_lists_the_stub_exports() {
+    let cab = Cab::new("inspect_lists_the_stub_exports");
+    cab.stub(&["--always-succeed"]);
+
+    let output = cab.run(&["--output", "json", "inspect"]);
+    assert_success(&output);
+    let report: Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(report["missing"], Value::Array(Vec::new()), "{}", report);
+    assert_eq!(report["exports"].as_array().map(Vec::len), Some(4));
+}
+
+#[test]
+fn trace_replays_against_the_stub() {
+    let cab = Cab::new("trace_replays_against_the_stub");
+    cab.stub(&["--always-succeed"]);
+    let capture = cab.path("capture.json");
+    let capture = capture.to_str().unwrap();
+
+    let output = cab.run(&["--headless", "--yes", "--trace", capture]);
+    assert_success(&output);
+    assert!(
+        !stdout(&output).contains("Loaded"),
+        "--trace loaded the DLL"
+    );
+
+    let output = cab.run(&["replay", capture]);
+    assert_success(&output);
+    assert!(
+        stdout(&output).contains("\"call\":\"set-resolution\""),
+        "{}",
+        stdout(&output)
+    );
+}